@@ -0,0 +1,173 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use chrono::Local;
+
+use crate::core::{EmptyResult, GenericResult};
+
+// The log is rotated on reaching this size keeping one previous file
+const MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+lazy_static! {
+    static ref AUDIT_LOG: Mutex<Option<AuditLog>> = Mutex::new(None);
+}
+
+struct AuditLog {
+    path: String,
+    file: File,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    operation: &'a str,
+    backup: Option<&'a str>,
+    destination: &'a str,
+    path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    result: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// Enables the audit log: all mutating operations on destinations are recorded to the specified file
+// as JSON objects - one per line, regardless of logging verbosity.
+pub fn init(path: &str) -> EmptyResult {
+    AUDIT_LOG.lock().unwrap().replace(AuditLog::open(path)?);
+    Ok(())
+}
+
+pub fn record<T>(
+    operation: &str, backup: Option<&str>, destination: &str, path: &str, size: Option<u64>,
+    result: &GenericResult<T>,
+) {
+    if let Some(audit_log) = AUDIT_LOG.lock().unwrap().as_mut() {
+        audit_log.record(operation, backup, destination, path, size, result);
+    }
+}
+
+impl AuditLog {
+    fn open(path: &str) -> GenericResult<AuditLog> {
+        let file = open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(AuditLog {
+            path: path.to_owned(),
+            file: file,
+            size: size,
+        })
+    }
+
+    fn record<T>(
+        &mut self, operation: &str, backup: Option<&str>, destination: &str, path: &str, size: Option<u64>,
+        result: &GenericResult<T>,
+    ) {
+        let record = Record {
+            time: Local::now().to_rfc3339(),
+            operation, backup, destination, path, size,
+            result: if result.is_ok() {"success"} else {"error"},
+            error: result.as_ref().err().map(ToString::to_string),
+        };
+
+        if let Err(err) = self.write(&record) {
+            error!("Failed to write to {:?} audit log: {}.", self.path, err);
+        }
+    }
+
+    fn write(&mut self, record: &Record) -> EmptyResult {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+
+        if self.size != 0 && self.size + line.len() as u64 > MAX_SIZE {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> EmptyResult {
+        fs::rename(&self.path, format!("{}.1", self.path)).map_err(|e| format!(
+            "Unable to rotate the log: {}", e))?;
+
+        self.file = open(&self.path)?;
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+fn open(path: &str) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn audit_log() {
+        let temp_dir = TempDir::new("audit_log");
+        let path = temp_dir.0.join("audit.log");
+        let path = path.to_str().unwrap();
+
+        let mut audit_log = AuditLog::open(path).unwrap();
+        let success: EmptyResult = Ok(());
+        let error: EmptyResult = Err!("Access denied");
+
+        audit_log.record("create-group", Some("audit-test"), "Mock", "/dst/2020.01.01", None, &success);
+        audit_log.record("upload", Some("audit-test"), "Mock", "/dst/2020.01.01/backup.tar", Some(4), &error);
+        audit_log.record("delete-group", None, "Mock", "/dst/2020.01.01", None, &success);
+
+        let records: Vec<serde_json::Value> = fs::read_to_string(path).unwrap().lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        let get = |key: &str| -> Vec<String> {
+            records.iter().map(|record| record[key].as_str().unwrap_or("").to_owned()).collect()
+        };
+
+        assert_eq!(get("operation"), vec!["create-group", "upload", "delete-group"]);
+        assert_eq!(get("backup"), vec!["audit-test", "audit-test", ""]);
+        assert_eq!(get("path"), vec!["/dst/2020.01.01", "/dst/2020.01.01/backup.tar", "/dst/2020.01.01"]);
+        assert_eq!(get("result"), vec!["success", "error", "success"]);
+        assert_eq!(get("error"), vec!["", "Access denied", ""]);
+        assert_eq!(records[1]["size"], 4);
+        assert!(records[0].get("size").is_none());
+        assert!(records.iter().all(|record| record["destination"] == "Mock" && record["time"].is_string()));
+        assert_eq!(audit_log.size, fs::metadata(path).unwrap().len());
+    }
+
+    #[test]
+    fn rotation() {
+        let temp_dir = TempDir::new("audit_log_rotation");
+        let path = temp_dir.0.join("audit.log");
+        let path = path.to_str().unwrap().to_owned();
+
+        fs::write(&path, "old\n").unwrap();
+        let mut audit_log = AuditLog {file: open(&path).unwrap(), path: path.clone(), size: MAX_SIZE - 10};
+
+        let record = Record {
+            time: Local::now().to_rfc3339(),
+            operation: "delete-group",
+            backup: None,
+            destination: "Mock",
+            path: "/dst/2020.01.01",
+            size: None,
+            result: "success",
+            error: None,
+        };
+        audit_log.write(&record).unwrap();
+
+        assert_eq!(fs::read_to_string(format!("{}.1", path)).unwrap(), "old\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), serde_json::to_string(&record).unwrap() + "\n");
+        assert_eq!(audit_log.size, fs::metadata(&path).unwrap().len());
+    }
+}
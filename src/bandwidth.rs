@@ -0,0 +1,98 @@
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref LIMITER: Mutex<Option<TokenBucket>> = Mutex::new(None);
+}
+
+// Limits the total bandwidth: the bucket is shared by all concurrent uploads and verification downloads
+pub fn init(bytes_per_second: u64) {
+    LIMITER.lock().unwrap().replace(TokenBucket::new(bytes_per_second as f64));
+}
+
+// Blocks until the specified amount of data is allowed to be transferred
+pub fn throttle(size: usize) {
+    let delay = match LIMITER.lock().unwrap().as_mut() {
+        Some(bucket) => bucket.consume(size as f64),
+        None => return,
+    };
+
+    if let Some(delay) = delay {
+        thread::sleep(delay);
+    }
+}
+
+pub struct ThrottledReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> ThrottledReader<R> {
+    pub fn new(reader: R) -> ThrottledReader<R> {
+        ThrottledReader {reader}
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.reader.read(buf)?;
+        throttle(size);
+        Ok(size)
+    }
+}
+
+// Allows bursts of up to one second of traffic. Tokens may go negative when a piece of data is larger
+// than the bucket, so the caller waits until the debt is paid off and the average rate is preserved.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: rate,
+            last_update: Instant::now(),
+        }
+    }
+
+    fn consume(&mut self, size: f64) -> Option<Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_update).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate) - size;
+        self.last_update = now;
+
+        if self.tokens < 0.0 {
+            Some(Duration::from_secs_f64(-self.tokens / self.rate))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let mut bucket = TokenBucket::new(1000.0);
+
+        // A one second burst is allowed
+        assert_eq!(bucket.consume(600.0), None);
+        assert_eq!(bucket.consume(400.0), None);
+
+        // The debt is paid off at the configured rate
+        let delay = bucket.consume(1500.0).unwrap();
+        assert!(delay > Duration::from_millis(1400) && delay <= Duration::from_millis(1500), "{:?}", delay);
+
+        // The bucket is never refilled above its capacity
+        bucket.last_update -= Duration::from_secs(10);
+        let delay = bucket.consume(1100.0).unwrap();
+        assert!(delay <= Duration::from_millis(100), "{:?}", delay);
+    }
+}
@@ -1,10 +1,17 @@
+use std::collections::BTreeMap;
 use std::time::Duration;
 
-use crate::storage::{Storage, BackupGroup};
+use crate::core::EmptyResult;
+use crate::storage::{Backup, Storage, BackupGroup};
 
-pub fn check_backups(storage: &Storage, backup_groups: &[BackupGroup], consistent: bool,
-                     max_time_without_backups: Option<Duration>) {
-    let mut last_backup = None;
+// Logs all found problems. Returns an error if there are no backups or the latest backup of the groups
+// sharing the same max time without backups is too old.
+pub fn check_backups<F>(storage: &Storage, backup_groups: &[BackupGroup], consistent: bool,
+                        get_max_time_without_backups: F) -> EmptyResult
+    where F: Fn(&str) -> Option<Duration>
+{
+    let mut has_backups = false;
+    let mut last_backups = BTreeMap::new();
 
     for group in backup_groups {
         if group.backups.is_empty() {
@@ -12,47 +19,49 @@ pub fn check_backups(storage: &Storage, backup_groups: &[BackupGroup], consisten
             if consistent {
                 error!("{}", error);
             } else {
-                warn!("{}", error);
+                warning!("{}", error);
             }
         } else {
-            last_backup = Some(group.backups.iter().rev().next().unwrap());
+            has_backups = true;
+
+            // Groups are sorted, so the latest backup wins
+            if let Some(max_time_without_backups) = get_max_time_without_backups(&group.name) {
+                last_backups.insert(max_time_without_backups, group.backups.last().unwrap());
+            }
         }
     }
 
-    let last_backup = match last_backup {
-        Some(last_backup) => last_backup,
-        None => {
-            error!("{} have no backups.", storage.name());
-            return;
-        }
-    };
+    if !has_backups {
+        return Err!("{} have no backups", storage.name());
+    }
 
-    let max_time_without_backups = match max_time_without_backups {
-        Some(duration) => duration,
-        None => return,
-    };
+    for (&max_time_without_backups, last_backup) in &last_backups {
+        check_last_backup(storage, last_backup, max_time_without_backups)?;
+    }
 
-    let last_backup_time = match storage.get_backup_time(&last_backup.name) {
-        Ok(last_backup_time) => last_backup_time,
-        Err(err) => {
-            error!("Failed to determine a time when backup has been created: {}.", err);
-            return;
-        }
-    };
+    Ok(())
+}
 
-    let time_from_last_backup = match last_backup_time.elapsed() {
-        Ok(duration) => duration,
-        Err(_) => {
+fn check_last_backup(
+    storage: &Storage, last_backup: &Backup, max_time_without_backups: Duration,
+) -> EmptyResult {
+    let time_from_last_backup = match storage.get_backup_age(&last_backup.name) {
+        Ok(Some(duration)) => duration,
+        Ok(None) => {
             error!(concat!(
                 "Failed to check last backup time: ",
                 "the latest backup ({:?}) on {} has backup time in the future."),
                 last_backup.name, storage.name());
-            return;
+            return Ok(());
+        }
+        Err(err) => {
+            error!("Failed to determine a time when backup has been created: {}.", err);
+            return Ok(());
         }
     };
 
     if time_from_last_backup < max_time_without_backups {
-        return;
+        return Ok(());
     }
 
     let minute_seconds = 60;
@@ -74,5 +83,39 @@ pub fn check_backups(storage: &Storage, backup_groups: &[BackupGroup], consisten
         }
     }
 
-    error!("{} doesn't have any backup for last {}.", storage.name(), human_durations.join(" "));
-}
\ No newline at end of file
+    Err!("{} doesn't have any backup for last {}", storage.name(), human_durations.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::clock::MockClock;
+    use crate::provider::ProviderType;
+    use crate::providers::mock::MockProvider;
+
+    use super::*;
+
+    #[test]
+    fn last_backup_age() {
+        let provider = MockProvider::new(ProviderType::Cloud);
+        provider.add_directory("/dst");
+        provider.add_directory("/dst/2020.01.01");
+        provider.add_file("/dst/2020.01.01/2020.01.01-00:00:00.tar.gpg", b"backup");
+
+        let storage = Storage::new(provider.clone(), "/dst");
+        let backup_time = storage.get_backup_time("2020.01.01-00:00:00").unwrap();
+        let max_time_without_backups = Duration::from_secs(24 * 60 * 60);
+
+        let check = |age: Duration| {
+            let storage = Storage::new(provider.clone(), "/dst").with_clock(MockClock(backup_time + age));
+            let (groups, _) = storage.get_backup_groups(false).unwrap();
+            check_backups(&storage, &groups, true, |_| Some(max_time_without_backups))
+        };
+
+        check(Duration::from_secs(23 * 60 * 60)).unwrap();
+
+        let err = check(Duration::from_secs(25 * 60 * 60 + 30 * 60)).unwrap_err().to_string();
+        assert_eq!(err, "Mock doesn't have any backup for last 1 days 1 hours 30 minutes");
+    }
+}
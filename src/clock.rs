@@ -0,0 +1,25 @@
+use std::time::SystemTime;
+
+// Source of the current time for retention and backup freshness checks
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// Clock which is stopped at the specified time
+#[cfg(test)]
+pub struct MockClock(pub SystemTime);
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
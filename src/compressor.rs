@@ -0,0 +1,76 @@
+use std::io::{self, Write};
+
+use bzip2::write::BzEncoder;
+use flate2::write::GzEncoder;
+
+use crate::config::Compression;
+use crate::core::EmptyResult;
+use crate::encryptor::Encryptor;
+
+#[derive(Clone, Copy)]
+pub struct CompressionOptions {
+    pub compression: Compression,
+    pub level: Option<u32>,
+}
+
+impl CompressionOptions {
+    pub fn none() -> CompressionOptions {
+        CompressionOptions {
+            compression: Compression::None,
+            level: None,
+        }
+    }
+}
+
+// Compresses the data before passing it to the encryptor
+pub enum Compressor {
+    None(Encryptor),
+    Gzip(GzEncoder<Encryptor>),
+    Bzip2(BzEncoder<Encryptor>),
+}
+
+impl Compressor {
+    pub fn new(encryptor: Encryptor, options: CompressionOptions) -> Compressor {
+        match options.compression {
+            Compression::None => Compressor::None(encryptor),
+            Compression::Gzip => {
+                let level = options.level.map_or_else(flate2::Compression::default, flate2::Compression::new);
+                Compressor::Gzip(GzEncoder::new(encryptor, level))
+            },
+            Compression::Bzip2 => {
+                let level = options.level.map_or_else(bzip2::Compression::default, bzip2::Compression::new);
+                Compressor::Bzip2(BzEncoder::new(encryptor, level))
+            },
+        }
+    }
+
+    pub fn finish(self, error: Option<String>) -> EmptyResult {
+        let encryptor = match self {
+            Compressor::None(encryptor) => encryptor,
+
+            // The encoder fails only on encryptor errors, so we get the real error here in this case
+            Compressor::Gzip(encoder) => encoder.finish().map_err(|e| e.to_string())?,
+            Compressor::Bzip2(encoder) => encoder.finish().map_err(|e| e.to_string())?,
+        };
+
+        encryptor.finish(error)
+    }
+}
+
+impl Write for Compressor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Compressor::None(encryptor) => encryptor.write(buf),
+            Compressor::Gzip(encoder) => encoder.write(buf),
+            Compressor::Bzip2(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Compressor::None(encryptor) => encryptor.flush(),
+            Compressor::Gzip(encoder) => encoder.flush(),
+            Compressor::Bzip2(encoder) => encoder.flush(),
+        }
+    }
+}
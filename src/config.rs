@@ -1,40 +1,552 @@
+use std::collections::BTreeMap;
 use std::fmt;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{self, Read, Write};
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf, Component};
 use std::process;
 use std::time::Duration;
 
-use clap::{App, Arg, AppSettings};
-use regex::Regex;
+use clap::{App, Arg, AppSettings, SubCommand};
+use nix::unistd;
+use regex::{self, Regex};
 use serde::de::{self, Deserializer, Visitor};
+use serde::ser::Serializer;
+use serde_yaml::{Mapping, Value};
 
-use crate::core::GenericResult;
+use crate::compressor::CompressionOptions;
+use crate::core::{EmptyResult, GenericResult};
+use crate::encryptor::{self, Encryption, EncryptionOptions};
+use crate::http_client;
+use crate::passphrase;
+use crate::providers::dropbox;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     #[serde(skip)]
     pub path: String,
+    #[serde(skip)]
+    pub command: Command,
+    #[serde(skip)]
+    pub fail_fast: bool,
+    #[serde(skip)]
+    pub force: bool,
+    #[serde(skip)]
+    pub confirm_large_prune: bool,
+    #[serde(skip)]
+    pub dry_run: bool,
+    #[serde(skip)]
+    pub strict: bool,
+    #[serde(skip)]
+    pub copy_options: Option<CopyOptions>,
+    #[serde(skip)]
+    pub restore_options: Option<RestoreOptions>,
+    #[serde(skip)]
+    pub list_options: Option<ListOptions>,
+    #[serde(skip)]
+    pub audit_log: Option<String>,
     pub backups: Vec<Backup>,
     pub prometheus_metrics: Option<String>,
+    // Safety fence: if specified, nothing outside of these paths is ever deleted on destinations
+    #[serde(default)]
+    pub allowed_dst_prefixes: Vec<String>,
+    // Random delay before the sync in $min-$max{s|m|h|d} format. Applied only to non-interactive runs.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_duration_range")]
+    #[serde(serialize_with = "serialize_duration_range")]
+    pub startup_jitter: Option<(Duration, Duration)>,
+    // File where the time of the last successful sync is stored
+    pub state_file: Option<String>,
+    // Sync is skipped if the previous successful one has been started less than this time ago. Protects
+    // API quotas from misconfigured schedulers. Requires state_file.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[serde(serialize_with = "serialize_duration")]
+    pub min_run_interval: Option<Duration>,
+    // Enables TCP keepalive on provider connections, so NATs and firewalls don't drop them while an
+    // upload is waiting for a slow producer
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[serde(serialize_with = "serialize_duration")]
+    pub tcp_keepalive: Option<Duration>,
+    // Maximum total transfer rate in bytes per second shared by all concurrent uploads and verification
+    // downloads
+    pub upload_bandwidth_limit: Option<u64>,
+    // Maximum number of gpg processes encrypting backups at the same time. Concurrent uploads wait for a
+    // free slot before starting encryption.
+    pub max_gpg_processes: Option<usize>,
+    // Maximum total size of upload chunks buffered in memory ($number{K|M|G}). Uploads wait before reading
+    // the next chunk until the required memory is released by the others. A single chunk larger than the
+    // limit is still buffered, but only one at a time.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_size")]
+    #[serde(serialize_with = "serialize_size")]
+    pub max_inflight_bytes: Option<u64>,
+    // Shell command which is run on sync/gc completion. Gets the run report as JSON on stdin and
+    // PYVSB_COMMAND, PYVSB_STATUS and PYVSB_FAILED_BACKUPS environment variables.
+    pub notify_command: Option<String>,
+    // Alerts on sync failures and missing or outdated backups. Sending failures are only logged.
+    #[serde(default)]
+    pub notifications: Vec<Notification>,
+    // Extra request headers which values are masked and endpoints which request and response bodies are
+    // hidden in the trace logs. Authorization headers and authentication endpoints are always redacted.
+    pub trace_redaction: Option<TraceRedaction>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TraceRedaction {
+    #[serde(default)]
+    pub headers: Vec<String>,
+    // URL path suffixes
+    #[serde(default)]
+    pub endpoints: Vec<String>,
+}
+
+impl TraceRedaction {
+    pub fn parse(&self) -> GenericResult<http_client::TraceRedaction> {
+        let headers = self.headers.iter().map(|name| name.parse().map_err(|_| format!(
+            "Invalid trace redaction header name: {:?}", name))).collect::<Result<Vec<_>, _>>()?;
+        Ok(http_client::TraceRedaction::default().extend(headers, self.endpoints.clone()))
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum Notification {
+    // POSTs the alert as JSON
+    #[serde(rename = "webhook")]
+    Webhook {
+        // Webhook URLs usually contain access tokens
+        #[serde(serialize_with = "redact")]
+        url: String,
+    },
+
+    // Sends the alert via sendmail, so the SMTP relay is configured by the host's MTA
+    #[serde(rename = "email")]
+    Email {
+        to: Vec<String>,
+        #[serde(default = "default_sendmail")]
+        sendmail: String,
+    },
+}
+
+fn default_sendmail() -> String {
+    "sendmail".to_owned()
+}
+
+impl Notification {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Notification::Webhook {..} => "webhook",
+            Notification::Email {..} => "email",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Command {
+    Sync,
+    Gc,
+    Copy,
+    Restore,
+    List,
+    Verify,
+    ConfigDump,
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Sync => "sync",
+            Command::Gc => "gc",
+            Command::Copy => "copy",
+            Command::Restore => "restore",
+            Command::List => "list",
+            Command::Verify => "verify",
+            Command::ConfigDump => "config-dump",
+        }
+    }
+}
+
+impl Default for Command {
+    fn default() -> Command {
+        Command::Sync
+    }
+}
+
+// Names of the backups which destinations the backups are copied between
+pub struct CopyOptions {
+    pub from: String,
+    pub to: String,
+}
+
+pub struct RestoreOptions {
+    // Name of the backup which destination to restore the backup from
+    pub from: String,
+    pub group: String,
+    pub name: String,
+    // Local directory to save the decrypted backup to
+    pub to: String,
+}
+
+pub struct ListOptions {
+    pub backup: String,
+    // List the backup source instead of its destination
+    pub local: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct Backup {
     pub name: String,
     pub src: String,
+    // Include hidden files and directories when listing the source
+    #[serde(default)]
+    pub include_hidden: bool,
     pub dst: String,
     pub provider: Provider,
-    pub max_backup_groups: usize,
+    // Retention policy: either the maximum number of backup groups, the maximum age of backup groups
+    // (determined by their latest backup) or grandfather-father-son policy. The latest backup group is
+    // always kept.
+    #[serde(default)]
+    pub max_backup_groups: Option<usize>,
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[serde(serialize_with = "serialize_duration")]
+    pub max_backup_age: Option<Duration>,
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>,
+    // Asked interactively if not specified
+    #[serde(default)]
+    #[serde(serialize_with = "redact")]
     pub encryption_passphrase: String,
+    // Alternative passphrase sources: a file, an environment variable or a shell command printing the
+    // passphrase to stdout. A trailing newline is stripped.
+    pub encryption_passphrase_file: Option<String>,
+    pub encryption_passphrase_env: Option<String>,
+    pub encryption_passphrase_command: Option<String>,
+    // Public keys (key IDs or fingerprints) to encrypt the backups to instead of the passphrase, so
+    // the decryption key never lives on the backup host. The keys must be in gpg's keyring.
+    #[serde(default)]
+    pub encryption_recipients: Vec<String>,
+    // Cipher to encrypt the backups with (gpg's default if not specified). gpg reports the actually
+    // used cipher and the upload fails if it doesn't match the configured one.
+    pub gpg_cipher: Option<String>,
+    // gpg home directory to isolate keyrings and gpg configuration of different backups. Must be
+    // accessible only by its owner.
+    pub gpg_homedir: Option<String>,
+    // Compression of the backups before encryption. Useful for uncompressed sources - pyvsb backups are
+    // already compressed.
+    #[serde(default)]
+    pub compression: Compression,
+    // 0-9 (1-9 for bzip2), the compression's default if not specified
+    pub compression_level: Option<u32>,
+    // Resource limits for the spawned gpg processes to protect the host from a runaway encryption
+    #[serde(default)]
+    pub gpg_rlimits: GpgRlimits,
     #[serde(default)]
     #[serde(deserialize_with = "deserialize_duration")]
+    #[serde(serialize_with = "serialize_duration")]
     pub max_time_without_backups: Option<Duration>,
+    // Per-group overrides of max_time_without_backups. A group gets the limit of the first override
+    // which glob patterns match its name and max_time_without_backups is the fallback for the rest.
+    // Each limit is checked against the latest backup of the groups it applies to.
+    #[serde(default)]
+    pub max_time_without_backups_overrides: Vec<FreshnessOverride>,
+    // healthchecks.io-style dead man's switch URL which is pinged on sync start (URL/start), success
+    // (URL) and failure (URL/fail)
+    #[serde(default, serialize_with = "redact_option")]
+    pub healthcheck_url: Option<String>,
+    // Groups matching these glob patterns are never deleted and aren't counted by retention policy
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_globs")]
+    #[serde(serialize_with = "serialize_regexes")]
+    pub pinned_groups: Vec<Regex>,
+    // Retention policies for tagged backup groups. Groups are tagged by the first policy which glob
+    // patterns they match, are retained only by this policy and aren't counted by the main one.
+    #[serde(default)]
+    pub tag_retention: Vec<TagRetention>,
+    // Backups matching these glob patterns are never uploaded and aren't counted by retention policy:
+    // a group that has only excluded backups is treated as an empty one. Already uploaded excluded
+    // backups are left intact and deleted along with their groups. The local freshness check
+    // (max_time_without_backups) still takes them into account.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_globs")]
+    #[serde(serialize_with = "serialize_regexes")]
+    pub exclude_backups: Vec<Regex>,
+    // Verify the backups that are going to be kept before pruning (by downloading them and comparing
+    // their checksums with the server-side ones) and refuse to delete anything if any of them is missing
+    // or corrupted.
+    #[serde(default)]
+    pub verify_before_prune: bool,
+    // Delete temporary files left by interrupted uploads before each sync, as the gc command does.
+    // Requires an additional listing of all backup groups.
+    #[serde(default)]
+    pub cleanup_temp_files: bool,
+    #[serde(default)]
+    pub prune_order: PruneOrder,
+    // Upload new backup groups into a hidden staging group and make them visible only when all their
+    // backups are uploaded and verified. Requires provider support for renaming.
+    #[serde(default)]
+    pub staged_groups: bool,
+    // Number of backup groups listed concurrently on the destination, which speeds up listing of
+    // large archives on high-latency providers.
+    #[serde(default = "default_list_concurrency")]
+    pub list_concurrency: usize,
+    // Number of backups uploaded concurrently. New backup groups are still created sequentially before
+    // their backups are uploaded.
+    #[serde(default = "default_upload_concurrency")]
+    pub upload_concurrency: usize,
+    // Enables adaptive concurrency of API requests: it starts from the specified minimum, grows while the
+    // provider responds successfully and is halved when the provider throttles the requests (429 and 5xx
+    // responses), but never exceeds upload_concurrency.
+    pub min_upload_concurrency: Option<usize>,
+    // Number of backups downloaded and verified concurrently by the verify command
+    #[serde(default = "default_verify_concurrency")]
+    pub verify_concurrency: usize,
+    // Verify each uploaded backup against the provider: by the server-side hash if the provider
+    // exposes it or by downloading it back otherwise
+    #[serde(default)]
+    pub verify_after_upload: bool,
+    #[serde(default)]
+    pub on_corruption: CorruptionAction,
+    // Secret key to authenticate the uploaded backups with: HMAC-SHA256 of each backup is stored next
+    // to it and checked on restore and verification, which detects tampering with the cloud objects.
+    // Backups without the HMAC fail the check, so it must be set before the first upload.
+    #[serde(default, serialize_with = "redact_option")]
+    pub integrity_key: Option<String>,
+    // Maximum number of API requests per second sent to the cloud provider by all concurrent operations
+    pub api_rate_limit: Option<f64>,
+    // HTTP timeouts overriding the provider defaults. The upload timeout limits a single data transfer
+    // request: upload of a whole file or a single chunk of it and file download.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[serde(serialize_with = "serialize_duration")]
+    pub connect_timeout: Option<Duration>,
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[serde(serialize_with = "serialize_duration")]
+    pub request_timeout: Option<Duration>,
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[serde(serialize_with = "serialize_duration")]
+    pub upload_timeout: Option<Duration>,
+}
+
+fn default_list_concurrency() -> usize {
+    1
+}
+
+fn default_upload_concurrency() -> usize {
+    1
 }
 
-#[derive(Deserialize)]
+fn default_verify_concurrency() -> usize {
+    1
+}
+
+impl Backup {
+    // The first matching override wins
+    pub fn get_max_time_without_backups(&self, group_name: &str) -> Option<Duration> {
+        for freshness_override in &self.max_time_without_backups_overrides {
+            if freshness_override.groups.iter().any(|regex| regex.is_match(group_name)) {
+                return freshness_override.max_time_without_backups;
+            }
+        }
+        self.max_time_without_backups
+    }
+
+    pub fn client_options(&self) -> http_client::ClientOptions {
+        http_client::ClientOptions {
+            rate_limit: self.api_rate_limit,
+            adaptive_concurrency: self.min_upload_concurrency.map(|min| (min, self.upload_concurrency)),
+            timeouts: self.timeouts(),
+        }
+    }
+
+    pub fn timeouts(&self) -> http_client::Timeouts {
+        http_client::Timeouts {
+            connect: self.connect_timeout,
+            request: self.request_timeout,
+            upload: self.upload_timeout,
+        }
+    }
+
+    // Reads the passphrase from its configured source. Returns None if it's not configured or specified
+    // in the configuration file directly.
+    pub fn read_passphrase(&self) -> GenericResult<Option<String>> {
+        let passphrase = if let Some(ref path) = self.encryption_passphrase_file {
+            passphrase::read_file(path)
+        } else if let Some(ref name) = self.encryption_passphrase_env {
+            passphrase::read_env(name)
+        } else if let Some(ref command) = self.encryption_passphrase_command {
+            passphrase::read_command(command)
+        } else {
+            return Ok(None);
+        };
+
+        Ok(Some(passphrase.map_err(|e| format!(
+            "Unable to get encryption passphrase for {:?} backup: {}", self.name, e))?))
+    }
+
+    pub fn encryption_options(&self) -> EncryptionOptions {
+        let encryption = if self.encryption_recipients.is_empty() {
+            Encryption::Passphrase(&self.encryption_passphrase)
+        } else {
+            Encryption::Recipients(&self.encryption_recipients)
+        };
+
+        EncryptionOptions {
+            encryption,
+            cipher: self.gpg_cipher.as_deref(),
+            homedir: self.gpg_homedir.as_deref(),
+            rlimits: &self.gpg_rlimits,
+        }
+    }
+
+    pub fn compression_options(&self) -> CompressionOptions {
+        CompressionOptions {
+            compression: self.compression,
+            level: self.compression_level,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum Compression {
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "gzip")]
+    Gzip,
+    #[serde(rename = "bzip2")]
+    Bzip2,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::None
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FreshnessOverride {
+    #[serde(deserialize_with = "deserialize_globs")]
+    #[serde(serialize_with = "serialize_regexes")]
+    pub groups: Vec<Regex>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[serde(serialize_with = "serialize_duration")]
+    pub max_time_without_backups: Option<Duration>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TagRetention {
+    pub tag: String,
+    #[serde(deserialize_with = "deserialize_globs")]
+    #[serde(serialize_with = "serialize_regexes")]
+    pub groups: Vec<Regex>,
+    // Tagged groups are kept forever if no limits are specified
+    #[serde(default)]
+    pub max_backup_groups: Option<usize>,
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[serde(serialize_with = "serialize_duration")]
+    pub max_backup_age: Option<Duration>,
+}
+
+// Grandfather-father-son retention policy: keeps the latest backup group of each of the last N days,
+// weeks and months which have backup groups. Groups are dated by their names.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionPolicy {
+    #[serde(default)]
+    pub daily: usize,
+    #[serde(default)]
+    pub weekly: usize,
+    #[serde(default)]
+    pub monthly: usize,
+}
+
+impl RetentionPolicy {
+    // The upper bound of backup groups retained by the policy
+    pub fn max_backup_groups(&self) -> usize {
+        self.daily + self.weekly + self.monthly
+    }
+}
+
+#[derive(Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct GpgRlimits {
+    // RLIMIT_AS in $number{K|M|G} format
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_size")]
+    #[serde(serialize_with = "serialize_size")]
+    pub address_space: Option<u64>,
+    // RLIMIT_CPU
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[serde(serialize_with = "serialize_duration")]
+    pub cpu_time: Option<Duration>,
+}
+
+// Defines whether outdated backup groups are deleted before or after uploading the new backups.
+// Pruning before the upload frees space on space-constrained destinations, but if the upload fails
+// afterwards, the cloud ends up with less backups than it had before the sync.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum PruneOrder {
+    #[serde(rename = "after")]
+    After,
+    #[serde(rename = "before")]
+    Before,
+}
+
+impl Default for PruneOrder {
+    fn default() -> PruneOrder {
+        PruneOrder::After
+    }
+}
+
+// Defines what to do with an uploaded backup which has failed the verification: delete it, move it to
+// the hidden quarantine directory of the destination to investigate it later or just report it. The
+// quarantined backups are invisible to the sync and retention. Quarantine requires provider support for
+// renaming.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum CorruptionAction {
+    #[serde(rename = "delete")]
+    Delete,
+    #[serde(rename = "quarantine")]
+    Quarantine,
+    #[serde(rename = "warn")]
+    Warn,
+}
+
+impl Default for CorruptionAction {
+    fn default() -> CorruptionAction {
+        CorruptionAction::Delete
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ObjectLock {
+    pub mode: ObjectLockMode,
+    pub retain_days: u32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum ObjectLockMode {
+    // Users with special permissions are able to delete the objects
+    #[serde(rename = "governance")]
+    Governance,
+    // Nobody (including the root account) is able to delete the objects
+    #[serde(rename = "compliance")]
+    Compliance,
+}
+
+#[derive(Deserialize, Serialize)]
 #[serde(tag = "name")]
 pub enum Provider {
     #[serde(rename = "dropbox")]
@@ -56,18 +568,112 @@ pub enum Provider {
         curl "https://api.dropbox.com/oauth2/token" -d grant_type=refresh_token -d "refresh_token=$refresh_token" -d "client_id=$client_id" -d "client_secret=$client_secret"
          */
         client_id: String,
+        #[serde(serialize_with = "redact")]
         client_secret: String,
+        #[serde(serialize_with = "redact")]
         refresh_token: String,
+        // Extra HTTP headers for all API requests (for example, when the API is accessed via a gateway)
+        #[serde(default, serialize_with = "redact_headers")]
+        headers: BTreeMap<String, String>,
+        // Number of retries of a failed upload chunk. Each chunk (up to 150 MB) is buffered in memory
+        // when enabled.
+        #[serde(default)]
+        max_chunk_retries: usize,
+        // Upload chunk size in bytes (the API maximum of 150 MB by default). Smaller chunks make retries
+        // and chunk buffering cheaper at the cost of more requests.
+        chunk_size: Option<u64>,
     },
 
     #[serde(rename = "google_drive")]
     GoogleDrive {
         client_id: String,
+        #[serde(serialize_with = "redact")]
         client_secret: String,
+        #[serde(serialize_with = "redact")]
         refresh_token: String,
+        // Extra HTTP headers for all API requests (for example, when the API is accessed via a gateway)
+        #[serde(default, serialize_with = "redact_headers")]
+        headers: BTreeMap<String, String>,
+    },
+
+    #[serde(rename = "s3")]
+    S3 {
+        bucket: String,
+        region: String,
+        // URL of S3-compatible storage (path-style requests are used for it)
+        endpoint: Option<String>,
+        access_key_id: String,
+        #[serde(serialize_with = "redact")]
+        secret_access_key: String,
+        // Object Lock retention of the uploaded backups, so even compromised credentials can't be used to
+        // delete them before the retention expires. The bucket must have Object Lock enabled.
+        object_lock: Option<ObjectLock>,
+    },
+
+    #[serde(rename = "backblaze")]
+    Backblaze {
+        key_id: String,
+        #[serde(serialize_with = "redact")]
+        application_key: String,
+        bucket_id: String,
+    },
+
+    #[serde(rename = "webdav")]
+    WebDav {
+        url: String,
+        user: String,
+        #[serde(serialize_with = "redact")]
+        password: String,
+    },
+
+    #[serde(rename = "sftp")]
+    Sftp {
+        host: String,
+        #[serde(default = "default_ssh_port")]
+        port: u16,
+        user: String,
+        // Private key for authentication. The server's host key must be in ~/.ssh/known_hosts.
+        key_file: String,
+        // Remote directory which backup destination paths are relative to
+        #[serde(default = "default_sftp_base_dir")]
+        base_dir: String,
+    },
+
+    #[serde(rename = "filesystem")]
+    Filesystem {
+        // fsync() the uploaded backups. Can be disabled for slow media at the risk of losing the
+        // latest backups on power loss.
+        #[serde(default = "default_fsync")]
+        fsync: bool,
     },
 }
 
+impl Provider {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Provider::Dropbox {..} => "dropbox",
+            Provider::GoogleDrive {..} => "google_drive",
+            Provider::S3 {..} => "s3",
+            Provider::Backblaze {..} => "backblaze",
+            Provider::WebDav {..} => "webdav",
+            Provider::Sftp {..} => "sftp",
+            Provider::Filesystem {..} => "filesystem",
+        }
+    }
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+fn default_sftp_base_dir() -> String {
+    "/".to_owned()
+}
+
+fn default_fsync() -> bool {
+    true
+}
+
 pub fn load() -> Config {
     let default_config_path = "~/.pyvsb_to_cloud.yaml";
 
@@ -84,9 +690,104 @@ pub fn load() -> Config {
             .long("verbose")
             .multiple(true)
             .help("Sets the level of verbosity"))
+        .arg(Arg::with_name("fail_fast")
+            .long("fail-fast")
+            .help("Abort the whole run on the first error"))
+        .arg(Arg::with_name("force")
+            .long("force")
+            .help("Ignore min_run_interval"))
+        .arg(Arg::with_name("strict")
+            .long("strict")
+            .help("Fail the run if any warnings have been logged"))
+        .arg(Arg::with_name("confirm_large_prune")
+            .long("confirm-large-prune")
+            .help("Allow pruning more backup groups than the retention policy keeps"))
+        .arg(Arg::with_name("dry_run")
+            .long("dry-run")
+            .help("Log the changes sync and gc would make to backup destinations without making them"))
+        .arg(Arg::with_name("json_logs_to")
+            .long("json-logs-to")
+            .value_name("PATH")
+            .help("Record all modifications of backup destinations to the specified file as JSON lines")
+            .takes_value(true))
+        .subcommand(SubCommand::with_name("sync")
+            .about("Uploads new backups and deletes outdated ones (the default command)"))
+        .subcommand(SubCommand::with_name("gc")
+            .about("Deletes temporary files left by interrupted uploads"))
+        .subcommand(SubCommand::with_name("copy")
+            .about("Copies backups from one backup destination to another without re-encryption")
+            .arg(Arg::with_name("from")
+                .long("from")
+                .value_name("BACKUP")
+                .help("Backup name which destination to copy the backups from")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("to")
+                .long("to")
+                .value_name("BACKUP")
+                .help("Backup name which destination to copy the backups to")
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("restore")
+            .about("Downloads and decrypts the specified backup")
+            .arg(Arg::with_name("from")
+                .long("from")
+                .value_name("BACKUP")
+                .help("Backup name which destination to restore the backup from")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("group")
+                .long("group")
+                .value_name("NAME")
+                .help("Backup group name")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("name")
+                .long("name")
+                .value_name("NAME")
+                .help("Backup name")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("to")
+                .long("to")
+                .value_name("PATH")
+                .help("Directory to save the decrypted backup to")
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("list")
+            .about("Prints backup groups and backups stored on the backup destination as JSON")
+            .arg(Arg::with_name("backup")
+                .long("backup")
+                .value_name("BACKUP")
+                .help("Backup name which destination to list")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("local")
+                .long("local")
+                .help("List the backup source instead")))
+        .subcommand(SubCommand::with_name("verify")
+            .about("Checks integrity of the stored backups by downloading them"))
+        .subcommand(SubCommand::with_name("config-dump")
+            .about("Prints the effective configuration with redacted secrets"))
         .setting(AppSettings::DisableVersion)
         .get_matches();
 
+    let command = match matches.subcommand_name() {
+        Some("gc") => Command::Gc,
+        Some("copy") => Command::Copy,
+        Some("restore") => Command::Restore,
+        Some("list") => Command::List,
+        Some("verify") => Command::Verify,
+        Some("config-dump") => Command::ConfigDump,
+        Some("sync") | None => Command::Sync,
+        Some(_) => unreachable!(),
+    };
+
+    if matches.is_present("dry_run") && command != Command::Sync && command != Command::Gc {
+        let _ = writeln!(io::stderr(), "--dry-run is supported only by sync and gc commands.");
+        process::exit(1);
+    }
+
     let log_level = match matches.occurrences_of("verbose") {
         0 => log::Level::Info,
         1 => log::Level::Debug,
@@ -105,8 +806,31 @@ pub fn load() -> Config {
     let config_path = matches.value_of("config").map(ToString::to_string).unwrap_or_else(||
         shellexpand::tilde(default_config_path).to_string());
 
-    match load_config(&config_path) {
-        Ok(config) => config,
+    match load_config(&config_path, command) {
+        Ok(mut config) => {
+            config.fail_fast = matches.is_present("fail_fast");
+            config.force = matches.is_present("force");
+            config.confirm_large_prune = matches.is_present("confirm_large_prune");
+            config.dry_run = matches.is_present("dry_run");
+            config.strict = matches.is_present("strict");
+            config.copy_options = matches.subcommand_matches("copy").map(|matches| CopyOptions {
+                from: matches.value_of("from").unwrap().to_owned(),
+                to: matches.value_of("to").unwrap().to_owned(),
+            });
+            config.restore_options = matches.subcommand_matches("restore").map(|matches| RestoreOptions {
+                from: matches.value_of("from").unwrap().to_owned(),
+                group: matches.value_of("group").unwrap().to_owned(),
+                name: matches.value_of("name").unwrap().to_owned(),
+                to: shellexpand::tilde(matches.value_of("to").unwrap()).to_string(),
+            });
+            config.list_options = matches.subcommand_matches("list").map(|matches| ListOptions {
+                backup: matches.value_of("backup").unwrap().to_owned(),
+                local: matches.is_present("local"),
+            });
+            config.audit_log = matches.value_of("json_logs_to").map(|path|
+                shellexpand::tilde(path).to_string());
+            config
+        },
         Err(err) => {
             error!("Error while reading {:?} configuration file: {}.", config_path, err);
             process::exit(1);
@@ -114,24 +838,196 @@ pub fn load() -> Config {
     }
 }
 
-fn load_config(path: &str) -> GenericResult<Config> {
-    let mut data = Vec::new();
-    File::open(path)?.read_to_end(&mut data)?;
+fn load_config(path: &str, command: Command) -> GenericResult<Config> {
+    let data = read_config(Path::new(path), &mut Vec::new())?;
 
-    let mut config: Config = serde_yaml::from_slice(&data)?;
+    let mut config: Config = serde_yaml::from_value(data)?;
     config.path = path.to_owned();
+    config.command = command;
+
+    if let Some(ref redaction) = config.trace_redaction {
+        redaction.parse()?;
+    }
+
+    if config.upload_bandwidth_limit == Some(0) {
+        return Err!("Upload bandwidth limit must be positive");
+    }
+
+    if config.max_gpg_processes == Some(0) {
+        return Err!("Maximum number of gpg processes must be positive");
+    }
+
+    if config.max_inflight_bytes == Some(0) {
+        return Err!("Maximum in-flight bytes must be positive");
+    }
 
     for backup in config.backups.iter_mut() {
         backup.name = validate_name(&backup.name)?;
         backup.src = validate_local_path(&backup.src)?;
-        backup.dst = validate_path(&backup.dst)?;
+        backup.dst = match backup.provider {
+            Provider::Filesystem {..} => validate_local_path(&backup.dst)?,
+            _ => validate_path(&backup.dst)?,
+        };
+
+        match backup.provider {
+            Provider::Dropbox {ref headers, chunk_size, ..} => {
+                http_client::parse_headers(headers)?;
+                if let Some(chunk_size) = chunk_size {
+                    if chunk_size == 0 || chunk_size > dropbox::MAX_REQUEST_SIZE {
+                        return Err!("Invalid Dropbox chunk size: it must be in (0; {}] range",
+                                    dropbox::MAX_REQUEST_SIZE);
+                    }
+                }
+            },
+            Provider::GoogleDrive {ref headers, ..} => {
+                http_client::parse_headers(headers)?;
+            },
+            Provider::Sftp {ref mut key_file, ref mut base_dir, ..} => {
+                *key_file = validate_local_path(key_file)?;
+                *base_dir = validate_path(base_dir)?;
+
+                if backup.api_rate_limit.is_some() {
+                    return Err!("api_rate_limit is not supported by SFTP provider");
+                } else if backup.min_upload_concurrency.is_some() {
+                    return Err!("min_upload_concurrency is not supported by SFTP provider");
+                } else if backup.upload_timeout.is_some() {
+                    return Err!("upload_timeout is not supported by SFTP provider");
+                }
+            },
+            Provider::S3 {ref object_lock, ..} => {
+                if let Some(ObjectLock {retain_days: 0, ..}) = object_lock {
+                    return Err!("Object lock retention must be positive");
+                }
+            },
+            Provider::Backblaze {..} | Provider::WebDav {..} | Provider::Filesystem {..} => {},
+        }
+
+        match (backup.max_backup_groups, backup.max_backup_age, backup.retention.as_ref()) {
+            (Some(0), _, _) => return Err!("Maximum backup groups number must be positive"),
+            (Some(_), Some(_), _) => return Err!(
+                "max_backup_groups and max_backup_age are mutually exclusive"),
+            (Some(_), _, Some(_)) | (_, Some(_), Some(_)) => return Err!(
+                "retention is mutually exclusive with max_backup_groups and max_backup_age"),
+            (None, None, None) => return Err!(concat!(
+                "Retention policy is not specified: ",
+                "either max_backup_groups, max_backup_age or retention must be set")),
+            (_, _, Some(policy)) if policy.max_backup_groups() == 0 => return Err!(
+                "Retention policy must keep at least one backup group"),
+            _ => {},
+        }
+
+        for (index, freshness_override) in backup.max_time_without_backups_overrides.iter().enumerate() {
+            if freshness_override.groups.is_empty() {
+                return Err!("max_time_without_backups override #{} has no group patterns", index + 1);
+            }
+        }
+
+        for policy in &backup.tag_retention {
+            if policy.groups.is_empty() {
+                return Err!("{:?} tag retention policy has no group patterns", policy.tag);
+            }
+
+            match (policy.max_backup_groups, policy.max_backup_age) {
+                (Some(0), _) => return Err!(
+                    "Maximum backup groups number for {:?} tag must be positive", policy.tag),
+                (Some(_), Some(_)) => return Err!(
+                    "max_backup_groups and max_backup_age are mutually exclusive in {:?} tag policy",
+                    policy.tag),
+                _ => {},
+            }
+        }
 
-        if backup.max_backup_groups == 0 {
-            return Err!("Maximum backup groups number must be positive");
+        if backup.list_concurrency == 0 {
+            return Err!("List concurrency must be positive");
         }
 
-        if backup.encryption_passphrase.is_empty() {
-            return Err!("Encryption passphrase mustn't be empty");
+        if backup.upload_concurrency == 0 {
+            return Err!("Upload concurrency must be positive");
+        }
+
+        if let Some(min_concurrency) = backup.min_upload_concurrency {
+            if min_concurrency == 0 || min_concurrency > backup.upload_concurrency {
+                return Err!("Minimum upload concurrency must be in [1; upload_concurrency] range");
+            }
+        }
+
+        if backup.verify_concurrency == 0 {
+            return Err!("Verify concurrency must be positive");
+        }
+
+        if let Some(ref url) = backup.healthcheck_url {
+            reqwest::Url::parse(url).map_err(|e| format!("Invalid healthcheck URL: {}", e))?;
+        }
+
+        if backup.integrity_key.as_deref() == Some("") {
+            return Err!("Integrity key must not be empty");
+        }
+
+        if let Some(rate_limit) = backup.api_rate_limit {
+            if !rate_limit.is_finite() || rate_limit <= 0.0 {
+                return Err!("API rate limit must be positive");
+            }
+        }
+
+        for (name, timeout) in [
+            ("connect_timeout", backup.connect_timeout),
+            ("request_timeout", backup.request_timeout),
+            ("upload_timeout", backup.upload_timeout),
+        ].iter() {
+            if let Some(timeout) = timeout {
+                if *timeout == Duration::from_secs(0) {
+                    return Err!("Invalid {}: it must be positive", name);
+                } else if *timeout > Duration::from_secs(24 * 60 * 60) {
+                    return Err!("Invalid {}: {:?} is absurdly large", name, timeout);
+                }
+            }
+        }
+
+        let passphrase_sources = [
+            !backup.encryption_passphrase.is_empty(),
+            backup.encryption_passphrase_file.is_some(),
+            backup.encryption_passphrase_env.is_some(),
+            backup.encryption_passphrase_command.is_some(),
+        ].iter().filter(|&&specified| specified).count();
+
+        if passphrase_sources > 1 {
+            return Err!(concat!(
+                "encryption_passphrase, encryption_passphrase_file, encryption_passphrase_env and ",
+                "encryption_passphrase_command are mutually exclusive"));
+        }
+
+        if let Some(path) = backup.encryption_passphrase_file.clone() {
+            backup.encryption_passphrase_file.replace(validate_local_path(&path)?);
+        }
+
+        if !backup.encryption_recipients.is_empty() {
+            if passphrase_sources != 0 {
+                return Err!("Encryption passphrase and encryption_recipients are mutually exclusive");
+            } else if backup.encryption_recipients.iter().any(|recipient| recipient.trim().is_empty()) {
+                return Err!("Invalid encryption recipient: it mustn't be empty");
+            }
+        }
+
+        if let Some(homedir) = backup.gpg_homedir.clone() {
+            let homedir = validate_local_path(&homedir)?;
+            validate_gpg_homedir(&homedir).map_err(|e| format!(
+                "Invalid gpg home directory {:?}: {}", homedir, e))?;
+            backup.gpg_homedir.replace(homedir);
+        }
+
+        if let Some(level) = backup.compression_level {
+            if backup.compression == Compression::None {
+                return Err!("Compression level is specified without compression");
+            } else if level > 9 || (level == 0 && backup.compression == Compression::Bzip2) {
+                return Err!("Invalid compression level: {}", level);
+            }
+        }
+
+        if let Some(cipher) = backup.gpg_cipher.as_mut() {
+            *cipher = cipher.to_uppercase();
+            if encryptor::get_cipher_id(cipher).is_none() {
+                return Err!("Unsupported gpg cipher: {}", cipher);
+            }
         }
     }
 
@@ -139,9 +1035,154 @@ fn load_config(path: &str) -> GenericResult<Config> {
         config.prometheus_metrics.replace(validate_local_path(&metrics_path)?);
     }
 
+    if let Some(state_file) = config.state_file.clone() {
+        config.state_file.replace(validate_local_path(&state_file)?);
+    } else if config.min_run_interval.is_some() {
+        return Err!("min_run_interval requires state_file to be specified");
+    }
+
+    for prefix in config.allowed_dst_prefixes.iter_mut() {
+        *prefix = validate_path(prefix)?;
+    }
+
+    for notification in &config.notifications {
+        match notification {
+            Notification::Webhook {url} => {
+                reqwest::Url::parse(url).map_err(|e| format!("Invalid webhook URL: {}", e))?;
+            },
+            Notification::Email {to, sendmail} => {
+                if to.is_empty() || to.iter().any(|address| address.trim().is_empty()) {
+                    return Err!("Invalid email notification recipients");
+                } else if sendmail.is_empty() {
+                    return Err!("Invalid sendmail path");
+                }
+            },
+        }
+    }
+
+    if !config.allowed_dst_prefixes.is_empty() {
+        for backup in &config.backups {
+            if !is_path_under_prefixes(&backup.dst, &config.allowed_dst_prefixes) {
+                return Err!("{:?} backup destination is outside of allowed destination prefixes",
+                            backup.name);
+            }
+        }
+    }
+
+    // Read the passphrases and prompt only when the whole configuration is known to be valid. Restore
+    // needs the passphrase only for the backup being restored, so it's read there.
+    if command == Command::Sync {
+        for backup in config.backups.iter_mut() {
+            if let Some(passphrase) = backup.read_passphrase()? {
+                backup.encryption_passphrase = passphrase;
+            } else if backup.encryption_passphrase.is_empty() && backup.encryption_recipients.is_empty() {
+                backup.encryption_passphrase = passphrase::read_interactively(&backup.name, true)?;
+            }
+        }
+    }
+
     Ok(config)
 }
 
+// Reads the configuration file resolving its includes. Included files are merged in the order they
+// are specified and the including file overrides all of them.
+fn read_config(path: &Path, include_stack: &mut Vec<PathBuf>) -> GenericResult<Value> {
+    let canonical_path = path.canonicalize().map_err(|e| format!(
+        "Unable to open {:?}: {}", path, e))?;
+
+    if include_stack.contains(&canonical_path) {
+        return Err!("Include cycle has been detected on {:?}", path);
+    }
+
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    let mut config: Value = serde_yaml::from_slice(&data).map_err(|e| format!(
+        "Error while reading {:?}: {}", path, e))?;
+
+    let includes = match config {
+        Value::Mapping(ref mut mapping) => mapping.remove(&Value::from("include")),
+        _ => None,
+    };
+
+    let includes = match includes {
+        None => return Ok(config),
+        Some(Value::String(include)) => vec![include],
+        Some(Value::Sequence(includes)) => includes.into_iter().map(|include| match include {
+            Value::String(include) => Ok(include),
+            _ => Err(format!("Invalid include in {:?}: {:?}", path, include)),
+        }).collect::<Result<Vec<_>, _>>()?,
+        Some(include) => return Err!("Invalid include in {:?}: {:?}", path, include),
+    };
+
+    include_stack.push(canonical_path);
+
+    let base_path = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut merged_config = Value::Mapping(Mapping::new());
+
+    for include in includes {
+        let include_path = base_path.join(shellexpand::tilde(&include).as_ref());
+        let included_config = read_config(&include_path, include_stack)?;
+        merged_config = merge_configs(merged_config, included_config);
+    }
+
+    include_stack.pop();
+
+    Ok(merge_configs(merged_config, config))
+}
+
+// Deeply merges the configs. Backups are merged by their names.
+fn merge_configs(base: Value, config: Value) -> Value {
+    let (mut base, config) = match (base, config) {
+        (Value::Mapping(base), Value::Mapping(config)) => (base, config),
+        (_, config) => return config,
+    };
+
+    let backups_key = Value::from("backups");
+
+    for (key, value) in config {
+        let value = match (base.remove(&key), value) {
+            (Some(Value::Sequence(base_backups)), Value::Sequence(backups)) if key == backups_key => {
+                Value::Sequence(merge_backups(base_backups, backups))
+            },
+            (Some(base_value), value) => merge_configs(base_value, value),
+            (None, value) => value,
+        };
+        base.insert(key, value);
+    }
+
+    Value::Mapping(base)
+}
+
+fn merge_backups(mut base: Vec<Value>, backups: Vec<Value>) -> Vec<Value> {
+    let get_name = |backup: &Value| {
+        backup.get("name").and_then(Value::as_str).map(ToOwned::to_owned)
+    };
+
+    for backup in backups {
+        let name = get_name(&backup);
+        let index = name.as_ref().and_then(|name| {
+            base.iter().position(|base_backup| get_name(base_backup).as_ref() == Some(name))
+        });
+
+        match index {
+            Some(index) => {
+                let base_backup = base.remove(index);
+                base.insert(index, merge_configs(base_backup, backup));
+            },
+            None => base.push(backup),
+        }
+    }
+
+    base
+}
+
+pub fn is_path_under_prefixes(path: &str, prefixes: &[String]) -> bool {
+    prefixes.iter().any(|prefix| {
+        prefix == "/" || path == prefix || path.starts_with(&(prefix.to_owned() + "/"))
+    })
+}
+
 fn validate_name(mut name: &str) -> GenericResult<String> {
     name = name.trim();
     if name.is_empty() {
@@ -174,6 +1215,20 @@ fn validate_local_path(path: &str) -> GenericResult<String> {
     validate_path(&shellexpand::tilde(path))
 }
 
+fn validate_gpg_homedir(path: &str) -> EmptyResult {
+    let metadata = fs::metadata(path)?;
+
+    if !metadata.is_dir() {
+        return Err!("it's not a directory");
+    } else if metadata.uid() != unistd::geteuid().as_raw() {
+        return Err!("it's not owned by the current user");
+    } else if metadata.mode() & 0o077 != 0 {
+        return Err!("it must be accessible only by its owner");
+    }
+
+    Ok(())
+}
+
 fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
     where D: Deserializer<'de>
 {
@@ -197,6 +1252,166 @@ impl<'de> Visitor<'de> for DurationVisitor {
     }
 }
 
+fn deserialize_duration_range<'de, D>(deserializer: D) -> Result<Option<(Duration, Duration)>, D::Error>
+    where D: Deserializer<'de>
+{
+    let range: String = de::Deserialize::deserialize(deserializer)?;
+    parse_duration_range(&range).map(Some).map_err(de::Error::custom)
+}
+
+fn deserialize_size<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+    where D: Deserializer<'de>
+{
+    let size: String = de::Deserialize::deserialize(deserializer)?;
+    parse_size(&size).map(Some).map_err(de::Error::custom)
+}
+
+fn deserialize_globs<'de, D>(deserializer: D) -> Result<Vec<Regex>, D::Error>
+    where D: Deserializer<'de>
+{
+    let globs: Vec<String> = de::Deserialize::deserialize(deserializer)?;
+    globs.iter().map(|glob| parse_glob(glob).map_err(de::Error::custom)).collect()
+}
+
+fn parse_glob(glob: &str) -> GenericResult<Regex> {
+    if glob.is_empty() {
+        return Err!("Invalid glob pattern: {:?}", glob);
+    }
+
+    let mut regex = "^".to_owned();
+
+    for c in glob.chars() {
+        match c {
+            '*' => regex += ".*",
+            '?' => regex.push('.'),
+            _ => regex += &regex::escape(&c.to_string()),
+        }
+    }
+
+    regex.push('$');
+    Ok(Regex::new(&regex)?)
+}
+
+fn redact<S: Serializer>(value: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(if value.is_empty() {
+        ""
+    } else {
+        "<redacted>"
+    })
+}
+
+// Header values may contain credentials
+fn redact_option<S: Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => redact(value, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn redact_headers<S: Serializer>(headers: &BTreeMap<String, String>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(headers.keys().map(|name| (name, "<redacted>")))
+}
+
+fn serialize_duration<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    match duration {
+        Some(duration) => {
+            let seconds = duration.as_secs();
+            let (unit, unit_seconds) = get_duration_unit(&[seconds]);
+            serializer.serialize_some(&format!("{}{}", seconds / unit_seconds, unit))
+        },
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_duration_range<S: Serializer>(
+    range: &Option<(Duration, Duration)>, serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match range {
+        Some((min, max)) => {
+            let (min, max) = (min.as_secs(), max.as_secs());
+            let (unit, unit_seconds) = get_duration_unit(&[min, max]);
+            serializer.serialize_some(&format!("{}-{}{}", min / unit_seconds, max / unit_seconds, unit))
+        },
+        None => serializer.serialize_none(),
+    }
+}
+
+// Returns the largest time unit all the specified durations are multiple of
+fn get_duration_unit(durations: &[u64]) -> (&'static str, u64) {
+    for &(unit, unit_seconds) in &[("d", 60 * 60 * 24), ("h", 60 * 60), ("m", 60)] {
+        if durations.iter().all(|&seconds| seconds % unit_seconds == 0) {
+            return (unit, unit_seconds);
+        }
+    }
+    ("s", 1)
+}
+
+fn serialize_size<S: Serializer>(size: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+    match size {
+        Some(size) => serializer.serialize_some(&size.to_string()),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_regexes<S: Serializer>(regexes: &[Regex], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(regexes.iter().map(Regex::as_str))
+}
+
+fn parse_size(string: &str) -> GenericResult<u64> {
+    lazy_static! {
+        static ref SIZE_RE: Regex = Regex::new(
+            r"^(?P<number>[1-9]\d*)(?P<unit>[KMG])?$").unwrap();
+    }
+
+    let captures = SIZE_RE.captures(string).ok_or(format!(
+        "Invalid size specification: {:?}", string))?;
+
+    let size: u64 = captures.name("number").unwrap().as_str().parse().map_err(|_| format!(
+        "Invalid size specification: {:?}", string))?;
+
+    let multiplier: u64 = match captures.name("unit").map(|unit| unit.as_str()) {
+        None => 1,
+        Some("K") => 1024,
+        Some("M") => 1024 * 1024,
+        Some("G") => 1024 * 1024 * 1024,
+        _ => unreachable!(),
+    };
+
+    Ok(size.checked_mul(multiplier).ok_or(format!("Too big size: {:?}", string))?)
+}
+
+fn parse_duration_range(string: &str) -> GenericResult<(Duration, Duration)> {
+    lazy_static! {
+        static ref DURATION_RANGE_RE: Regex = Regex::new(
+            r"^(?P<min>\d+)-(?P<max>\d+)(?P<unit>[smhd])$").unwrap();
+    }
+
+    let error = || format!("Invalid time duration range specification: {:?}", string);
+    let captures = DURATION_RANGE_RE.captures(string).ok_or_else(error)?;
+
+    let unit = match captures.name("unit").unwrap().as_str() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => unreachable!(),
+    };
+
+    let mut range = Vec::with_capacity(2);
+
+    for name in &["min", "max"] {
+        let value: u64 = captures.name(name).unwrap().as_str().parse().map_err(|_| error())?;
+        range.push(Duration::from_secs(value.checked_mul(unit).ok_or_else(error)?));
+    }
+
+    let (min, max) = (range[0], range[1]);
+    if min > max {
+        return Err(error().into());
+    }
+
+    Ok((min, max))
+}
+
 fn parse_duration(string: &str) -> GenericResult<Duration> {
     lazy_static! {
         static ref DURATION_RE: Regex = Regex::new(
@@ -215,4 +1430,224 @@ fn parse_duration(string: &str) -> GenericResult<Duration> {
     };
 
     Ok(Duration::from_secs(duration))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::tests::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn path_prefixes() {
+        let prefixes = ["/backups".to_owned(), "/archive".to_owned()];
+
+        assert!(is_path_under_prefixes("/backups", &prefixes));
+        assert!(is_path_under_prefixes("/backups/host", &prefixes));
+        assert!(is_path_under_prefixes("/archive/host/daily", &prefixes));
+        assert!(!is_path_under_prefixes("/backups-old", &prefixes));
+        assert!(!is_path_under_prefixes("/", &prefixes));
+        assert!(!is_path_under_prefixes("/home", &prefixes));
+
+        assert!(is_path_under_prefixes("/home", &["/".to_owned()]));
+        assert!(!is_path_under_prefixes("/home", &[]));
+    }
+
+    #[test]
+    fn duration_ranges() {
+        let secs = Duration::from_secs;
+
+        assert_eq!(parse_duration_range("0-30s").unwrap(), (secs(0), secs(30)));
+        assert_eq!(parse_duration_range("5-5m").unwrap(), (secs(5 * 60), secs(5 * 60)));
+        assert_eq!(parse_duration_range("1-2h").unwrap(), (secs(60 * 60), secs(2 * 60 * 60)));
+        assert_eq!(parse_duration_range("0-1d").unwrap(), (secs(0), secs(24 * 60 * 60)));
+
+        for range in &["", "30s", "30-10s", "1-2", "1-2w", "-1-2s", "1-99999999999999999999s"] {
+            assert_eq!(parse_duration_range(range).unwrap_err().to_string(), format!(
+                "Invalid time duration range specification: {:?}", range));
+        }
+    }
+
+    #[test]
+    fn max_time_without_backups_overrides() {
+        let backup: Backup = serde_yaml::from_str(concat!(
+            "{name: test, src: /src, dst: /dst, provider: {name: filesystem}, max_time_without_backups: 1d, ",
+            "max_time_without_backups_overrides: [",
+            "{groups: [2020.01.*], max_time_without_backups: 2d}, ",
+            "{groups: [2020.*], max_time_without_backups: 3d}]}",
+        )).unwrap();
+
+        let days = |days: u64| Some(Duration::from_secs(days * 24 * 60 * 60));
+        assert_eq!(backup.get_max_time_without_backups("2020.01.01"), days(2));
+        assert_eq!(backup.get_max_time_without_backups("2020.02.01"), days(3));
+        assert_eq!(backup.get_max_time_without_backups("2021.01.01"), days(1));
+    }
+
+    #[test]
+    fn sftp_unsupported_options() {
+        let temp_dir = TempDir::new("sftp_unsupported_options");
+        let config_path = temp_dir.0.join("config.yaml");
+        let config_path = config_path.to_str().unwrap();
+
+        let load = |options: &str| {
+            fs::write(config_path, format!(concat!(
+                "{{backups: [{{name: test, src: {:?}, dst: /dst, provider: {{name: sftp, host: localhost, ",
+                "user: backup, key_file: /id_rsa, base_dir: /}}, max_backup_groups: 1, ",
+                "encryption_passphrase: secret, {}}}]}}",
+            ), temp_dir.0, options)).unwrap();
+            load_config(config_path, Command::Gc)
+        };
+
+        assert!(load("connect_timeout: 1m, request_timeout: 1m").is_ok());
+        assert_eq!(load("api_rate_limit: 10").err().unwrap().to_string(),
+                   "api_rate_limit is not supported by SFTP provider");
+        assert_eq!(load("upload_concurrency: 4, min_upload_concurrency: 1").err().unwrap().to_string(),
+                   "min_upload_concurrency is not supported by SFTP provider");
+        assert_eq!(load("upload_timeout: 1h").err().unwrap().to_string(),
+                   "upload_timeout is not supported by SFTP provider");
+    }
+
+    #[test]
+    fn config_dump() {
+        let temp_dir = TempDir::new("config_dump");
+        let config_path = temp_dir.0.join("config.yaml");
+        let config_path = config_path.to_str().unwrap();
+
+        fs::write(config_path, format!(concat!(
+            "{{startup_jitter: 30-90m, backups: [{{name: test, src: {:?}, dst: /dst, provider: {{name: dropbox, ",
+            "client_id: id, client_secret: client-secret, refresh_token: refresh-token}}, ",
+            "max_backup_age: 7d, encryption_passphrase: passphrase-secret, gpg_rlimits: {{address_space: 1G}}}}]}}",
+        ), temp_dir.0)).unwrap();
+
+        let config = load_config(config_path, Command::ConfigDump).unwrap();
+        let dump = serde_yaml::to_string(&config).unwrap();
+
+        for secret in &["client-secret", "refresh-token", "passphrase-secret"] {
+            assert!(!dump.contains(secret), "{:?} is exposed: {}", secret, dump);
+        }
+
+        let dump: serde_yaml::Value = serde_yaml::from_str(&dump).unwrap();
+        let backup = &dump["backups"][0];
+
+        assert_eq!(dump["startup_jitter"].as_str(), Some("30-90m"));
+        assert_eq!(backup["provider"]["client_id"].as_str(), Some("id"));
+        assert_eq!(backup["provider"]["client_secret"].as_str(), Some("<redacted>"));
+        assert_eq!(backup["provider"]["refresh_token"].as_str(), Some("<redacted>"));
+        assert_eq!(backup["encryption_passphrase"].as_str(), Some("<redacted>"));
+        assert_eq!(backup["max_backup_age"].as_str(), Some("7d"));
+        assert_eq!(backup["gpg_rlimits"]["address_space"].as_str(), Some("1073741824"));
+    }
+
+    #[test]
+    fn includes() {
+        let temp_dir = TempDir::new("config_includes");
+        let path = |name: &str| temp_dir.0.join(name);
+
+        fs::write(path("provider.yaml"), concat!(
+            "backups: [{name: test, provider: {name: dropbox, client_id: id, client_secret: secret, ",
+            "refresh_token: token}, max_backup_groups: 1}]",
+        )).unwrap();
+        fs::write(path("common.yaml"), concat!(
+            "include: provider.yaml\n",
+            "prometheus_metrics: /common.prom\n",
+            "backups: [{name: test, dst: /common, encryption_passphrase: common}, {name: other, dst: /other}]",
+        )).unwrap();
+        fs::write(path("config.yaml"), format!(concat!(
+            "include: [common.yaml]\n",
+            "backups: [{{name: test, src: {:?}, dst: /dst}}]",
+        ), temp_dir.0)).unwrap();
+
+        // The including file overrides the included ones, and backups are merged by their names
+        let config = read_config(&path("config.yaml"), &mut Vec::new()).unwrap();
+        let backups = config["backups"].as_sequence().unwrap();
+        assert_eq!(config["prometheus_metrics"].as_str(), Some("/common.prom"));
+        assert_eq!(backups.len(), 2);
+
+        let backup = &backups[0];
+        assert_eq!(backup["name"].as_str(), Some("test"));
+        assert_eq!(backup["src"].as_str(), temp_dir.0.to_str());
+        assert_eq!(backup["dst"].as_str(), Some("/dst"));
+        assert_eq!(backup["encryption_passphrase"].as_str(), Some("common"));
+        assert_eq!(backup["provider"]["name"].as_str(), Some("dropbox"));
+        assert_eq!(backup["max_backup_groups"].as_u64(), Some(1));
+
+        assert_eq!(backups[1]["name"].as_str(), Some("other"));
+        assert_eq!(backups[1]["dst"].as_str(), Some("/other"));
+
+        fs::write(path("provider.yaml"), "include: config.yaml").unwrap();
+        assert_eq!(
+            read_config(&path("config.yaml"), &mut Vec::new()).unwrap_err().to_string(),
+            format!("Include cycle has been detected on {:?}", path("config.yaml")));
+    }
+
+    #[test]
+    fn gpg_homedir() {
+        let temp_dir = TempDir::new("config_gpg_homedir");
+        let config_path = temp_dir.0.join("config.yaml");
+        let config_path = config_path.to_str().unwrap();
+
+        let homedir = temp_dir.0.join("gnupg");
+        fs::create_dir(&homedir).unwrap();
+
+        let load = || {
+            fs::write(config_path, format!(concat!(
+                "{{backups: [{{name: test, src: {:?}, dst: /dst, provider: {{name: filesystem}}, ",
+                "max_backup_groups: 1, encryption_passphrase: secret, gpg_homedir: {:?}}}]}}",
+            ), temp_dir.0, homedir)).unwrap();
+            load_config(config_path, Command::Sync)
+        };
+
+        fs::set_permissions(&homedir, fs::Permissions::from_mode(0o700)).unwrap();
+        let config = load().unwrap();
+        assert_eq!(config.backups[0].encryption_options().homedir, homedir.to_str());
+
+        fs::set_permissions(&homedir, fs::Permissions::from_mode(0o750)).unwrap();
+        assert_eq!(load().err().unwrap().to_string(), format!(
+            "Invalid gpg home directory {:?}: it must be accessible only by its owner", homedir));
+
+        fs::remove_dir(&homedir).unwrap();
+        fs::write(&homedir, "").unwrap();
+        assert_eq!(load().err().unwrap().to_string(), format!(
+            "Invalid gpg home directory {:?}: it's not a directory", homedir));
+    }
+
+    #[test]
+    fn passphrase_sources() {
+        let temp_dir = TempDir::new("passphrase_sources");
+        let config_path = temp_dir.0.join("config.yaml");
+        let config_path = config_path.to_str().unwrap();
+
+        let load = |passphrase: &str, command| {
+            fs::write(config_path, format!(concat!(
+                "{{backups: [{{name: test, src: {:?}, dst: /dst, provider: {{name: dropbox, client_id: id, ",
+                "client_secret: secret, refresh_token: token}}, max_backup_groups: 1, {}}}]}}",
+            ), temp_dir.0, passphrase)).unwrap();
+            load_config(config_path, command)
+        };
+
+        let config = load("encryption_passphrase_command: echo secret", Command::Sync).unwrap();
+        assert_eq!(config.backups[0].encryption_passphrase, "secret");
+
+        env::set_var("PYVSB_TO_CLOUD_TEST_CONFIG_PASSPHRASE", "secret\n");
+        let config = load("encryption_passphrase_env: PYVSB_TO_CLOUD_TEST_CONFIG_PASSPHRASE", Command::Sync).unwrap();
+        assert_eq!(config.backups[0].encryption_passphrase, "secret");
+
+        // The passphrase isn't needed for the commands which don't encrypt anything
+        let config = load("encryption_passphrase_command: exit 1", Command::Gc).unwrap();
+        assert_eq!(config.backups[0].encryption_passphrase, "");
+        assert!(config.backups[0].read_passphrase().is_err());
+
+        let err = load("encryption_passphrase_command: exit 1", Command::Sync).err().unwrap().to_string();
+        assert_eq!(err, concat!(
+            r#"Unable to get encryption passphrase for "test" backup: "#,
+            r#""exit 1" has failed: exit status: 1"#));
+
+        let err = load(
+            "encryption_passphrase: secret, encryption_passphrase_env: PASSPHRASE", Command::Sync,
+        ).err().unwrap().to_string();
+        assert!(err.ends_with("are mutually exclusive"), "{}", err);
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::core::{EmptyResult, GenericResult};
+use crate::storage::{Storage, BackupGroup};
+
+// Copies backups that are missing on the destination storage from the source one preserving the
+// backup group structure. The backups are copied as is, so both storages must be encrypted with the
+// same passphrase to be interchangeable.
+pub fn copy_backups(src_storage: &Storage, dst_storage: &mut Storage) -> EmptyResult {
+    let (src_groups, src_ok) = get_backup_groups(src_storage)?;
+    let (dst_groups, dst_ok) = get_backup_groups(dst_storage)?;
+
+    let dst_groups: BTreeMap<&str, BTreeSet<&str>> = dst_groups.iter().map(|group| {
+        let backups = group.backups.iter().map(|backup| backup.name.as_str()).collect();
+        (group.name.as_str(), backups)
+    }).collect();
+
+    let no_backups = BTreeSet::new();
+    let (mut copied, mut failed) = (0, 0);
+
+    for group in &src_groups {
+        if group.backups.is_empty() {
+            continue;
+        }
+
+        let dst_backups = match dst_groups.get(group.name.as_str()) {
+            Some(backups) => backups,
+            None => {
+                info!("Creating {:?} backup group on {}...", group.name, dst_storage.name());
+
+                if let Err(err) = dst_storage.create_backup_group(&group.name) {
+                    error!("Failed to create {:?} backup group on {}: {}.",
+                           group.name, dst_storage.name(), err);
+                    failed += group.backups.len();
+                    continue;
+                }
+
+                &no_backups
+            },
+        };
+
+        for backup in &group.backups {
+            if dst_backups.contains(backup.name.as_str()) {
+                continue;
+            }
+
+            info!("Copying {:?} backup from {} to {}...", backup.path, src_storage.name(), dst_storage.name());
+
+            if let Err(err) = dst_storage.copy_backup(src_storage, &group.name, backup) {
+                error!("Failed to copy {:?} backup to {}: {}.", backup.path, dst_storage.name(), err);
+                failed += 1;
+                continue;
+            }
+
+            copied += 1;
+        }
+    }
+
+    info!("{} backups have been copied from {} to {}.", copied, src_storage.name(), dst_storage.name());
+
+    if failed != 0 {
+        return Err!("Failed to copy {} backups", failed);
+    } else if !src_ok || !dst_ok {
+        return Err!("The backups are in inconsistent state");
+    }
+
+    Ok(())
+}
+
+fn get_backup_groups(storage: &Storage) -> GenericResult<(Vec<BackupGroup>, bool)> {
+    info!("Checking backups on {}...", storage.name());
+    storage.get_backup_groups(false).map_err(|e| format!(
+        "Failed to list backup groups on {}: {}", storage.name(), e).into())
+}
@@ -1,3 +1,5 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 pub type EmptyResult = GenericResult<()>;
 pub type GenericResult<T> = Result<T, GenericError>;
 pub type GenericError = Box<dyn std::error::Error + Send + Sync>;
@@ -5,3 +7,21 @@ pub type GenericError = Box<dyn std::error::Error + Send + Sync>;
 macro_rules! Err {
     ($($arg:tt)*) => (::std::result::Result::Err(format!($($arg)*).into()))
 }
+
+// Logs a warning counting it, so the run can be failed on warnings in strict mode
+macro_rules! warning {
+    ($($arg:tt)*) => {{
+        $crate::core::count_warning();
+        warn!($($arg)*);
+    }}
+}
+
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn count_warning() {
+    WARNINGS.fetch_add(1, Ordering::SeqCst);
+}
+
+pub fn get_warnings_count() -> usize {
+    WARNINGS.load(Ordering::SeqCst)
+}
@@ -1,8 +1,9 @@
 use std::fs::File;
 use std::io::{self, Read, BufReader, BufRead, Write, BufWriter};
 use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::process::{Command, Stdio, Child, ChildStdin, ChildStdout};
-use std::sync::mpsc;
+use std::sync::{Arc, Mutex, mpsc};
 use std::thread::JoinHandle;
 use std::time;
 
@@ -10,21 +11,164 @@ use bytes::Bytes;
 use libc::pid_t;
 use nix::{fcntl, unistd};
 
-use crate::core::{EmptyResult, GenericResult};
+use crate::config::GpgRlimits;
+use crate::core::{EmptyResult, GenericResult, GenericError};
 use crate::hash::Hasher;
+use crate::semaphore::{Semaphore, SemaphorePermit};
 use crate::stream_splitter::{DataSender, DataReceiver, Data};
 use crate::util;
 
+// OpenPGP symmetric cipher algorithm IDs (RFC 4880, section 9.2)
+const CIPHERS: &[(&str, u32)] = &[
+    ("IDEA", 1),
+    ("3DES", 2),
+    ("CAST5", 3),
+    ("BLOWFISH", 4),
+    ("AES", 7),
+    ("AES192", 8),
+    ("AES256", 9),
+    ("TWOFISH", 10),
+    ("CAMELLIA128", 11),
+    ("CAMELLIA192", 12),
+    ("CAMELLIA256", 13),
+];
+
+const STATUS_PREFIX: &str = "[GNUPG:] ";
+
+lazy_static! {
+    static ref ENCRYPTION_PROCESSES: Mutex<Option<Arc<Semaphore>>> = Mutex::new(None);
+}
+
+// Limits the number of concurrently running encryption processes
+pub fn set_max_processes(max: usize) {
+    ENCRYPTION_PROCESSES.lock().unwrap().replace(Semaphore::new(max));
+}
+
+pub fn get_cipher_id(name: &str) -> Option<u32> {
+    CIPHERS.iter().find(|&&(cipher, _)| cipher == name).map(|&(_, id)| id)
+}
+
+fn get_cipher_name(id: u32) -> String {
+    match CIPHERS.iter().find(|&&(_, cipher_id)| cipher_id == id) {
+        Some(&(name, _)) => name.to_owned(),
+        None => format!("#{}", id),
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Encryption<'a> {
+    Passphrase(&'a str),
+    // Public-key encryption to the specified recipients. Decryption uses secret keys from gpg's keyring.
+    Recipients(&'a [String]),
+}
+
+#[derive(Clone, Copy)]
+pub struct EncryptionOptions<'a> {
+    pub encryption: Encryption<'a>,
+    pub cipher: Option<&'a str>,
+    pub homedir: Option<&'a str>,
+    pub rlimits: &'a GpgRlimits,
+}
+
 pub struct Encryptor {
+    gpg: Gpg,
+    // Must be dropped after the process termination
+    _process_slot: Option<SemaphorePermit>,
+}
+
+impl Encryptor {
+    pub fn new(options: EncryptionOptions, hasher: Box<dyn Hasher>) -> GenericResult<(Encryptor, DataReceiver)> {
+        let semaphore = ENCRYPTION_PROCESSES.lock().unwrap().clone();
+        Encryptor::with_process_limit(options, hasher, semaphore)
+    }
+
+    fn with_process_limit(
+        options: EncryptionOptions, hasher: Box<dyn Hasher>, semaphore: Option<Arc<Semaphore>>,
+    ) -> GenericResult<(Encryptor, DataReceiver)> {
+        let process_slot = semaphore.map(|semaphore| {
+            debug!("Waiting for a free gpg process slot...");
+            semaphore.acquire()
+        });
+
+        let (gpg, rx) = Gpg::new(Operation::Encryption, options, hasher)?;
+        Ok((Encryptor {gpg, _process_slot: process_slot}, rx))
+    }
+
+    pub fn finish(self, error: Option<String>) -> EmptyResult {
+        self.gpg.finish(error)
+    }
+}
+
+impl io::Write for Encryptor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.gpg.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.gpg.flush()
+    }
+}
+
+// Accepts encrypted data and produces the decrypted data stream with its checksum calculated by the
+// specified hasher. The configured cipher isn't enforced: the data may have been encrypted with any.
+pub struct Decryptor(Gpg);
+
+impl Decryptor {
+    pub fn new(options: EncryptionOptions, hasher: Box<dyn Hasher>) -> GenericResult<(Decryptor, DataReceiver)> {
+        let options = EncryptionOptions {cipher: None, ..options};
+        let (gpg, rx) = Gpg::new(Operation::Decryption, options, hasher)?;
+        Ok((Decryptor(gpg), rx))
+    }
+
+    pub fn finish(self, error: Option<String>) -> EmptyResult {
+        self.0.finish(error)
+    }
+}
+
+impl io::Write for Decryptor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Operation {
+    Encryption,
+    Decryption,
+}
+
+impl Operation {
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Encryption => "encryption",
+            Operation::Decryption => "decryption",
+        }
+    }
+}
+
+struct Gpg {
+    operation: Operation,
     pid: pid_t,
     stdin: Option<BufWriter<ChildStdin>>,
     stdout_reader: Option<JoinHandle<GenericResult<String>>>,
-    encrypted_data_tx: Option<DataSender>,
+    data_tx: Option<DataSender>,
     result: Option<EmptyResult>,
 }
 
-impl Encryptor {
-    pub fn new(encryption_passphrase: &str, hasher: Box<dyn Hasher>) -> GenericResult<(Encryptor, DataReceiver)> {
+impl Gpg {
+    fn new(
+        operation: Operation, options: EncryptionOptions, hasher: Box<dyn Hasher>,
+    ) -> GenericResult<(Gpg, DataReceiver)> {
+        let expected_cipher = match options.cipher {
+            Some(cipher) => Some(get_cipher_id(cipher).ok_or_else(|| format!(
+                "Unsupported cipher: {}", cipher))?),
+            None => None,
+        };
+
         // Buffer is for the following reasons:
         // 1. Parallelization.
         // 2. To not block in drop() if we get some error during dropping the object that hasn't
@@ -33,49 +177,104 @@ impl Encryptor {
         //    * One buffer slot for our error message.
         let (tx, rx) = mpsc::sync_channel(2);
 
-        let (passphrase_read_fd, mut passphrase_write_fd) = create_passphrase_pipe()
-            .map_err(|e| format!("Unable to create a pipe: {}", e))?;
+        let passphrase_pipe = match options.encryption {
+            Encryption::Passphrase(passphrase) => Some((passphrase, create_passphrase_pipe().map_err(|e| {
+                format!("Unable to create a pipe: {}", e)
+            })?)),
+            Encryption::Recipients(_) => None,
+        };
+
+        debug!("Spawning a gpg process to handle data {}...", operation.name());
+
+        let mut command = Command::new("gpg");
+
+        // Must precede all other options
+        if let Some(homedir) = options.homedir {
+            command.arg("--homedir").arg(homedir);
+        }
+
+        command.arg("--batch").arg("--status-fd").arg("2");
 
-        debug!("Spawning a gpg process to handle data encryption...");
+        if let Some((_, (ref passphrase_read_fd, _))) = passphrase_pipe {
+            command.arg("--passphrase-fd").arg(passphrase_read_fd.as_raw_fd().to_string());
+        }
+
+        match operation {
+            Operation::Encryption => {
+                match options.encryption {
+                    Encryption::Passphrase(_) => {
+                        command.arg("--symmetric");
+                    },
+                    Encryption::Recipients(recipients) => {
+                        // The recipients are explicitly configured, so there is no need in key trust
+                        // validation which fails in batch mode for keys without ultimate trust
+                        command.arg("--encrypt").arg("--trust-model").arg("always");
+                        for recipient in recipients {
+                            command.arg("--recipient").arg(recipient);
+                        }
+                    },
+                };
+                command.arg("--compress-algo").arg("none");
+            },
+            Operation::Decryption => {
+                // Suppress the informational messages to not confuse them with errors
+                command.arg("--decrypt").arg("--quiet");
+            },
+        };
+
+        if let Some(cipher) = options.cipher {
+            command.arg("--cipher-algo").arg(cipher);
+        }
+
+        let rlimits = get_rlimits(options.rlimits);
+        if !rlimits.is_empty() {
+            unsafe {
+                command.pre_exec(move || set_rlimits(&rlimits));
+            }
+        }
 
-        let mut gpg = Command::new("gpg")
-            .arg("--batch").arg("--symmetric")
-            .arg("--passphrase-fd").arg(passphrase_read_fd.as_raw_fd().to_string())
-            .arg("--compress-algo").arg("none")
+        let mut gpg = command
             .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
             .spawn().map_err(|e| format!("Unable to spawn a gpg process: {}", e))?;
-        drop(passphrase_read_fd);
+
+        let passphrase_pipe = passphrase_pipe.map(|(passphrase, (passphrase_read_fd, passphrase_write_fd))| {
+            drop(passphrase_read_fd);
+            (passphrase, passphrase_write_fd)
+        });
 
         let pid = gpg.id() as pid_t;
         let stdin = BufWriter::new(gpg.stdin.take().unwrap());
-        let encrypted_chunks_tx = tx.clone();
+        let data_tx = tx.clone();
 
         let stdout_reader = util::spawn_thread("gpg stdout reader", move || {
-            stdout_reader(gpg, hasher, tx)
+            stdout_reader(gpg, operation, hasher, tx, expected_cipher)
         }).map_err(|e| {
             terminate_gpg(pid);
             e
         })?;
 
-        let encryptor = Encryptor {
+        let gpg = Gpg {
+            operation: operation,
             pid: pid,
             stdin: Some(stdin),
             stdout_reader: Some(stdout_reader),
-            encrypted_data_tx: Some(encrypted_chunks_tx),
+            data_tx: Some(data_tx),
             result: None,
         };
 
-        if let Err(err) = passphrase_write_fd.write_all(encryption_passphrase.as_bytes())
-            .and_then(|_| passphrase_write_fd.flush()) {
-            drop(passphrase_write_fd);
-            encryptor.finish(None)?; // Try to get the real error here
-            return Err!("Failed to pass encryption passphrase to gpg: {}", err);
+        if let Some((passphrase, mut passphrase_write_fd)) = passphrase_pipe {
+            if let Err(err) = passphrase_write_fd.write_all(passphrase.as_bytes())
+                .and_then(|_| passphrase_write_fd.flush()) {
+                drop(passphrase_write_fd);
+                gpg.finish(None)?; // Try to get the real error here
+                return Err!("Failed to pass {} passphrase to gpg: {}", operation.name(), err);
+            }
         }
 
-        Ok((encryptor, rx))
+        Ok((gpg, rx))
     }
 
-    pub fn finish(mut self, error: Option<String>) -> EmptyResult {
+    fn finish(mut self, error: Option<String>) -> EmptyResult {
         self.close(error.map_or(Ok(()), |e| Err(e.into())))
     }
 
@@ -84,11 +283,13 @@ impl Encryptor {
             return clone_empty_result(result);
         }
 
-        debug!("Closing encryptor with {:?}...", result);
+        debug!("Closing {} gpg process with {:?}...", self.operation.name(), result);
 
         if let Some(mut stdin) = self.stdin.take() {
             if let Err(err) = stdin.flush() {
-                result = Err(err.into());
+                if result.is_ok() {
+                    result = Err(get_write_error(err));
+                }
             }
 
             // Here stdin will be dropped and thus closed, so the gpg process will be expected to
@@ -96,7 +297,7 @@ impl Encryptor {
         }
 
         if let Some(stdout_reader) = self.stdout_reader.take() {
-            let tx = self.encrypted_data_tx.take().unwrap();
+            let tx = self.data_tx.take().unwrap();
 
             let message = match util::join_thread(stdout_reader) {
                 Ok(checksum) => {
@@ -115,27 +316,30 @@ impl Encryptor {
             let _ = tx.send(message);
         }
 
-        debug!("Encryptor has been closed with {:?}.", result);
+        debug!("{} gpg process has been closed with {:?}.", self.operation.name(), result);
         self.result = Some(clone_empty_result(&result));
 
         result
     }
 }
 
-impl Drop for Encryptor {
+impl Drop for Gpg {
     fn drop(&mut self) {
-        let _ = self.close(Err!("The encryptor has been dropped without finalization"));
+        let _ = self.close(Err!("The {} has been dropped without finalization", match self.operation {
+            Operation::Encryption => "encryptor",
+            Operation::Decryption => "decryptor",
+        }));
     }
 }
 
-impl io::Write for Encryptor {
+impl io::Write for Gpg {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         if let Some(ref result) = self.result {
             return Err(io_error_from(result.as_ref().unwrap_err()));
         }
 
         self.stdin.as_mut().unwrap().write(buf).map_err(|e| {
-            io_error_from(self.close(Err(e.into())).unwrap_err())
+            io_error_from(self.close(Err(get_write_error(e))).unwrap_err())
         })
     }
 
@@ -145,7 +349,7 @@ impl io::Write for Encryptor {
         }
 
         self.stdin.as_mut().unwrap().flush().map_err(|e| {
-            io_error_from(self.close(Err(e.into())).unwrap_err())
+            io_error_from(self.close(Err(get_write_error(e))).unwrap_err())
         })
     }
 }
@@ -176,23 +380,43 @@ fn create_passphrase_pipe() -> nix::Result<(File, File)> {
     Ok((read_fd, write_fd))
 }
 
-fn stdout_reader(mut gpg: Child, hasher: Box<dyn Hasher>, tx: DataSender) -> GenericResult<String> {
-    let stdout = BufReader::new(gpg.stdout.take().unwrap());
-    let mut stderr = gpg.stderr.take().unwrap();
+fn get_rlimits(config: &GpgRlimits) -> Vec<(libc::c_int, libc::rlim_t)> {
+    let mut rlimits = Vec::new();
 
-    let mut stderr_reader = Some(util::spawn_thread("gpg stderr reader", move || -> EmptyResult {
-        let mut error = String::new();
+    if let Some(size) = config.address_space {
+        rlimits.push((libc::RLIMIT_AS as libc::c_int, size as libc::rlim_t));
+    }
 
-        match stderr.read_to_string(&mut error) {
-            Ok(size) => {
-                if size == 0 {
-                    Ok(())
-                } else {
-                    Err!("gpg error: {}", error.trim_end())
-                }
-            },
-            Err(err) => Err!("gpg stderr reading error: {}", err),
+    if let Some(duration) = config.cpu_time {
+        rlimits.push((libc::RLIMIT_CPU as libc::c_int, duration.as_secs() as libc::rlim_t));
+    }
+
+    rlimits
+}
+
+// Called in the child process between fork() and exec(), so must be async-signal-safe
+fn set_rlimits(rlimits: &[(libc::c_int, libc::rlim_t)]) -> io::Result<()> {
+    for &(resource, value) in rlimits {
+        let rlimit = libc::rlimit {
+            rlim_cur: value,
+            rlim_max: value,
+        };
+
+        if unsafe { libc::setrlimit(resource as _, &rlimit) } != 0 {
+            return Err(io::Error::last_os_error());
         }
+    }
+
+    Ok(())
+}
+
+fn stdout_reader(mut gpg: Child, operation: Operation, hasher: Box<dyn Hasher>, tx: DataSender,
+                 expected_cipher: Option<u32>) -> GenericResult<String> {
+    let stdout = BufReader::new(gpg.stdout.take().unwrap());
+    let stderr = gpg.stderr.take().unwrap();
+
+    let mut stderr_reader = Some(util::spawn_thread("gpg stderr reader", move || {
+        stderr_reader(stderr)
     })?);
 
     let checksum = read_data(stdout, hasher, tx).map_err(|err| {
@@ -201,35 +425,105 @@ fn stdout_reader(mut gpg: Child, hasher: Box<dyn Hasher>, tx: DataSender) -> Gen
         err
     })?;
 
-    util::join_thread(stderr_reader.take().unwrap())?;
+    let status = util::join_thread(stderr_reader.take().unwrap())?;
 
-    let status = gpg.wait().map_err(|e| format!("Failed to wait() a child gpg process: {}", e))?;
-    if !status.success() {
-        return Err!("gpg process has terminated with an error exit code");
+    let exit_status = gpg.wait().map_err(|e| format!("Failed to wait() a child gpg process: {}", e))?;
+    if !exit_status.success() {
+        return Err!("{}", match exit_status.signal() {
+            Some(libc::SIGXCPU) => "gpg process has been killed on exceeding its CPU time limit".to_owned(),
+            Some(signal) => format!("gpg process has been killed by signal #{}", signal),
+            None => "gpg process has terminated with an error exit code".to_owned(),
+        });
     }
 
     debug!("gpg process has end its work with successful exit code.");
 
+    if let Operation::Decryption = operation {
+        if !status.decryption_okay {
+            return Err!("gpg hasn't confirmed successful decryption of the data");
+        }
+    }
+
+    if let Some(expected_cipher) = expected_cipher {
+        match status.cipher {
+            Some(cipher) if cipher == expected_cipher => {},
+            Some(cipher) => return Err!(
+                "gpg has encrypted the data using {} cipher instead of the configured {}",
+                get_cipher_name(cipher), get_cipher_name(expected_cipher)),
+            None => return Err!("Unable to determine the cipher gpg has encrypted the data with"),
+        }
+    }
+
     Ok(checksum)
 }
 
+#[derive(Default)]
+struct GpgStatus {
+    cipher: Option<u32>,
+    decryption_okay: bool,
+}
+
+// Reads gpg's stderr which is also used as its status output: status lines are parsed to get the
+// cipher gpg has actually used and the decryption result, and everything else is treated as an
+// error message.
+fn stderr_reader<R: Read>(stderr: R) -> GenericResult<GpgStatus> {
+    let mut status = GpgStatus::default();
+    let mut errors = Vec::new();
+
+    for line in BufReader::new(stderr).lines() {
+        let line = line.map_err(|e| format!("gpg stderr reading error: {}", e))?;
+
+        if let Some(status_line) = line.strip_prefix(STATUS_PREFIX) {
+            let mut args = status_line.split(' ');
+
+            match args.next() {
+                // BEGIN_ENCRYPTION <mdc_method> <sym_algo>
+                // DECRYPTION_INFO <mdc_method> <sym_algo> [<aead_algo>]
+                Some("BEGIN_ENCRYPTION") | Some("DECRYPTION_INFO") => {
+                    if let Some(algorithm) = args.nth(1) {
+                        status.cipher = Some(algorithm.parse().map_err(|_| format!(
+                            "Got an invalid gpg status line: {:?}", line))?);
+                    }
+                },
+                Some("DECRYPTION_OKAY") => status.decryption_okay = true,
+                _ => {},
+            }
+        } else {
+            errors.push(line);
+        }
+    }
+
+    let error = errors.join("\n");
+    let error = error.trim();
+
+    if !error.is_empty() {
+        return Err!("gpg error: {}", error);
+    }
+
+    if let Some(cipher) = status.cipher {
+        debug!("gpg has processed the data using {} cipher.", get_cipher_name(cipher));
+    }
+
+    Ok(status)
+}
+
 fn read_data(mut stdout: BufReader<ChildStdout>, mut hasher: Box<dyn Hasher>, tx: DataSender) -> GenericResult<String> {
     loop {
         let size = {
-            let encrypted_data = stdout.fill_buf().map_err(|e| format!(
+            let data = stdout.fill_buf().map_err(|e| format!(
                 "gpg stdout reading error: {}", e))?;
 
-            if encrypted_data.is_empty() {
+            if data.is_empty() {
                 return Ok(hasher.finish());
             }
 
-            hasher.write_all(encrypted_data).map_err(|e| format!(
-                "Unable to hash encrypted data: {}", e))?;
+            hasher.write_all(data).map_err(|e| format!(
+                "Unable to hash gpg output: {}", e))?;
 
-            tx.send(Ok(Data::Payload(Bytes::copy_from_slice(encrypted_data)))).map_err(|_|
-                "Unable to send encrypted data: the receiver has been closed".to_owned())?;
+            tx.send(Ok(Data::Payload(Bytes::copy_from_slice(data)))).map_err(|_|
+                "Unable to send gpg output: the receiver has been closed".to_owned())?;
 
-            encrypted_data.len()
+            data.len()
         };
 
         stdout.consume(size);
@@ -243,6 +537,17 @@ fn terminate_gpg(pid: pid_t) {
     }
 }
 
+// gpg closes its stdin only when it exits, which happens on errors like invalid options or resource
+// limits exceeding. The real error is reported by the stdout reader in this case and replaces the
+// write error on close, so this message is only a fallback.
+fn get_write_error(error: io::Error) -> GenericError {
+    if error.kind() == io::ErrorKind::BrokenPipe {
+        "gpg process has unexpectedly closed its input".into()
+    } else {
+        format!("Failed to write data to gpg: {}", error).into()
+    }
+}
+
 fn io_error_from<T: ToString>(error: T) -> io::Error {
     io::Error::new(io::ErrorKind::Other, error.to_string())
 }
@@ -252,4 +557,252 @@ fn clone_empty_result(result: &EmptyResult) -> EmptyResult {
         Ok(()) => Ok(()),
         Err(ref err) => Err(err.to_string().into()),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::hash::{ChunkedSha256, Md5};
+    use crate::tests::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn max_processes() {
+        let temp_dir = get_gpg_homedir("gpg_max_processes");
+        let homedir = temp_dir.0.to_str().unwrap().to_owned();
+
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let semaphore = Semaphore::new(2);
+
+        let threads: Vec<_> = (0..6).map(|_| {
+            let homedir = homedir.clone();
+            let semaphore = semaphore.clone();
+            let active = active.clone();
+            let max_active = max_active.clone();
+
+            thread::spawn(move || {
+                let rlimits = GpgRlimits::default();
+                let options = EncryptionOptions {
+                    encryption: Encryption::Passphrase("passphrase"),
+                    cipher: None,
+                    homedir: Some(&homedir),
+                    rlimits: &rlimits,
+                };
+
+                let (mut encryptor, data) = Encryptor::with_process_limit(
+                    options, Box::new(ChunkedSha256::new(1024 * 1024)), Some(semaphore)).unwrap();
+                let reader = thread::spawn(move || data.iter().count());
+
+                let processes = active.fetch_add(1, Ordering::SeqCst) + 1;
+                max_active.fetch_max(processes, Ordering::SeqCst);
+
+                encryptor.write_all(b"data").unwrap();
+                thread::sleep(Duration::from_millis(100));
+
+                active.fetch_sub(1, Ordering::SeqCst);
+                encryptor.finish(None).unwrap();
+                assert!(reader.join().unwrap() > 0);
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(max_active.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn cipher() {
+        let temp_dir = get_gpg_homedir("gpg_cipher");
+        let homedir = temp_dir.0.to_str().unwrap();
+
+        let encrypt = |cipher| -> EmptyResult {
+            let rlimits = GpgRlimits::default();
+            let options = EncryptionOptions {
+                encryption: Encryption::Passphrase("passphrase"),
+                cipher: Some(cipher),
+                homedir: Some(homedir),
+                rlimits: &rlimits,
+            };
+
+            let (mut encryptor, data) = Encryptor::new(options, Box::new(ChunkedSha256::new(1024 * 1024)))?;
+            let reader = thread::spawn(move || data.iter().count());
+
+            encryptor.write_all(b"data")?;
+            let result = encryptor.finish(None);
+            reader.join().unwrap();
+            result
+        };
+
+        // The cipher gpg has actually used is taken from its status output
+        encrypt("CAMELLIA256").unwrap();
+        assert_eq!(encrypt("ROT13").unwrap_err().to_string(), "Unsupported cipher: ROT13");
+    }
+
+    #[test]
+    fn decryption() {
+        let temp_dir = get_gpg_homedir("gpg_decryption");
+        let homedir = temp_dir.0.to_str().unwrap();
+
+        let rlimits = GpgRlimits::default();
+        let options = |passphrase| EncryptionOptions {
+            encryption: Encryption::Passphrase(passphrase),
+            cipher: Some("AES256"),
+            homedir: Some(homedir),
+            rlimits: &rlimits,
+        };
+
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+
+        let (mut encryptor, rx) = Encryptor::new(options("passphrase"), Box::new(Md5::new())).unwrap();
+        let reader = thread::spawn(move || read_data(rx));
+        encryptor.write_all(&data).unwrap();
+        encryptor.finish(None).unwrap();
+        let (encrypted_data, _) = reader.join().unwrap().unwrap();
+
+        let decrypt = |passphrase| -> GenericResult<(Vec<u8>, String)> {
+            let (mut decryptor, rx) = Decryptor::new(options(passphrase), Box::new(Md5::new()))?;
+            let reader = thread::spawn(move || read_data(rx));
+            let result = decryptor.write_all(&encrypted_data).map_err(Into::into)
+                .and_then(|_| decryptor.finish(None));
+            let data = reader.join().unwrap();
+            result?;
+            data
+        };
+
+        let (decrypted_data, checksum) = decrypt("passphrase").unwrap();
+        assert!(decrypted_data == data);
+
+        let mut hasher = Md5::new();
+        hasher.write_all(&data).unwrap();
+        assert_eq!(checksum, Box::new(hasher).finish());
+
+        assert!(decrypt("invalid").is_err());
+    }
+
+    #[test]
+    fn early_input_close() {
+        let temp_dir = get_gpg_homedir("gpg_early_input_close");
+        let homedir = temp_dir.0.to_str().unwrap();
+
+        // gpg fails on recipient lookup without reading its input
+        let recipients = vec!["Nonexistent Recipient".to_owned()];
+        let rlimits = GpgRlimits::default();
+        let options = EncryptionOptions {
+            encryption: Encryption::Recipients(&recipients),
+            cipher: None,
+            homedir: Some(homedir),
+            rlimits: &rlimits,
+        };
+
+        let (mut encryptor, rx) = Encryptor::new(options, Box::new(Md5::new())).unwrap();
+        let reader = thread::spawn(move || read_data(rx));
+
+        // The broken pipe error is replaced by the real error reported by gpg
+        let write_error = encryptor.write_all(&vec![0; 10 * 1024 * 1024]).unwrap_err().to_string();
+        assert!(write_error.starts_with("gpg error: "), "{}", write_error);
+        assert_eq!(encryptor.finish(None).unwrap_err().to_string(), write_error);
+        assert_eq!(reader.join().unwrap().unwrap_err().to_string(), write_error);
+
+        assert_eq!(get_write_error(io::ErrorKind::BrokenPipe.into()).to_string(),
+                   "gpg process has unexpectedly closed its input");
+    }
+
+    #[test]
+    fn rlimits() {
+        let temp_dir = get_gpg_homedir("gpg_rlimits");
+        let homedir = temp_dir.0.to_str().unwrap();
+
+        let encrypt = |rlimits: GpgRlimits| -> EmptyResult {
+            let options = EncryptionOptions {
+                encryption: Encryption::Passphrase("passphrase"),
+                cipher: None,
+                homedir: Some(homedir),
+                rlimits: &rlimits,
+            };
+
+            let (mut encryptor, data) = Encryptor::new(options, Box::new(ChunkedSha256::new(1024 * 1024)))?;
+            let reader = thread::spawn(move || data.iter().count());
+
+            let result = encryptor.write_all(b"data").map_err(Into::into).and_then(|_| encryptor.finish(None));
+            reader.join().unwrap();
+            result
+        };
+
+        assert_eq!(get_rlimits(&GpgRlimits {
+            address_space: Some(1024 * 1024 * 1024),
+            cpu_time: Some(Duration::from_secs(60)),
+        }), vec![
+            (libc::RLIMIT_AS as libc::c_int, 1024 * 1024 * 1024),
+            (libc::RLIMIT_CPU as libc::c_int, 60),
+        ]);
+
+        encrypt(GpgRlimits {
+            address_space: Some(1024 * 1024 * 1024),
+            cpu_time: Some(Duration::from_secs(60)),
+        }).unwrap();
+
+        // gpg is unable to even start with such a small address space
+        encrypt(GpgRlimits {
+            address_space: Some(1024 * 1024),
+            cpu_time: None,
+        }).unwrap_err();
+    }
+
+    #[test]
+    fn status_parsing() {
+        let status = stderr_reader(concat!(
+            "[GNUPG:] NEED_PASSPHRASE_SYM 9 3 2\n",
+            "[GNUPG:] BEGIN_ENCRYPTION 2 9\n",
+            "[GNUPG:] END_ENCRYPTION\n",
+        ).as_bytes()).unwrap();
+        assert_eq!(status.cipher, Some(9));
+        assert!(!status.decryption_okay);
+
+        let status = stderr_reader(concat!(
+            "[GNUPG:] DECRYPTION_INFO 2 13\n",
+            "[GNUPG:] DECRYPTION_OKAY\n",
+        ).as_bytes()).unwrap();
+        assert_eq!(status.cipher, Some(13));
+        assert!(status.decryption_okay);
+
+        assert_eq!(stderr_reader(concat!(
+            "[GNUPG:] BEGIN_ENCRYPTION 2 9\n",
+            "gpg: problem with the agent\n",
+        ).as_bytes()).err().unwrap().to_string(), "gpg error: gpg: problem with the agent");
+
+        assert!(stderr_reader("[GNUPG:] BEGIN_ENCRYPTION 2 aes\n".as_bytes()).is_err());
+    }
+
+    fn read_data(rx: DataReceiver) -> GenericResult<(Vec<u8>, String)> {
+        let mut data = Vec::new();
+
+        for message in rx.iter() {
+            match message? {
+                Data::Payload(payload) => data.extend_from_slice(&payload),
+                Data::EofWithChecksum(checksum) => return Ok((data, checksum)),
+            }
+        }
+
+        Err!("The data stream has been closed without a checksum")
+    }
+
+    fn get_gpg_homedir(name: &str) -> TempDir {
+        let temp_dir = TempDir::new(name);
+        fs::set_permissions(&temp_dir.0, fs::Permissions::from_mode(0o700)).unwrap();
+
+        // Initialize the keyring, so gpg doesn't report its creation
+        assert!(Command::new("gpg").args(&["--homedir", temp_dir.0.to_str().unwrap(), "--batch", "--list-keys"])
+            .stderr(Stdio::null()).status().unwrap().success());
+
+        temp_dir
+    }
+}
@@ -0,0 +1,101 @@
+use crate::core::EmptyResult;
+use crate::storage::Storage;
+use crate::util;
+
+// Deletes temporary files left by interrupted uploads. Files of uploads that may still be in
+// progress are left intact.
+pub fn collect_garbage(storage: &mut Storage, dry_run: bool) -> EmptyResult {
+    info!("Looking for temporary files on {}...", storage.name());
+
+    let temp_files = storage.get_temp_files().map_err(|e| format!(
+        "Failed to list temporary files on {}: {}", storage.name(), e))?;
+
+    let (mut deleted, mut failed, mut skipped) = (0, 0, 0);
+    let (mut reclaimed_size, mut size_is_known) = (0, true);
+
+    for temp_file in temp_files {
+        if !temp_file.stale {
+            info!("Skipping {:?}: it may belong to an upload that is still in progress.", temp_file.path);
+            skipped += 1;
+            continue;
+        }
+
+        if dry_run {
+            info!("Would delete {:?} from {}.", temp_file.path, storage.name());
+            continue;
+        }
+
+        info!("Deleting {:?} from {}...", temp_file.path, storage.name());
+        if let Err(err) = storage.delete_temp_file(&temp_file.path) {
+            error!("Failed to delete {:?} from {}: {}.", temp_file.path, storage.name(), err);
+            failed += 1;
+            continue;
+        }
+
+        deleted += 1;
+        match temp_file.size {
+            Some(size) => reclaimed_size += size,
+            None => size_is_known = false,
+        }
+    }
+
+    if dry_run {
+        return Ok(());
+    }
+
+    if deleted == 0 {
+        info!("There are no stale temporary files on {} ({} skipped).", storage.name(), skipped);
+    } else {
+        info!("{} temporary files have been deleted from {} ({} skipped): {}{} reclaimed.",
+              deleted, storage.name(), skipped, if size_is_known {""} else {"at least "},
+              util::format_size(reclaimed_size));
+    }
+
+    if failed != 0 {
+        return Err!("Failed to delete {} temporary files", failed);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::provider::ProviderType;
+    use crate::providers::mock::MockProvider;
+
+    use super::*;
+
+    #[test]
+    fn stale_temp_files() {
+        let provider = MockProvider::new(ProviderType::Cloud);
+        provider.add_directory("/dst");
+        provider.add_directory("/dst/2020.01.01");
+
+        let now = SystemTime::now();
+        let old = now - Duration::from_secs(2 * 24 * 60 * 60);
+
+        // Files of the runs on other hosts and of the older versions (without run ID)
+        let temp_files = [
+            ("/dst/2020.01.01/.2020.01.01-00:00:00.tar.gpg.other-host-1", old, false),
+            ("/dst/2020.01.01/.2020.01.01-01:00:00.tar.gpg.other-host-1", now, true),
+            ("/dst/2020.01.01/.2020.01.01-02:00:00.tar.gpg", old, false),
+            ("/dst/2020.01.01/.2020.01.01-03:00:00.tar.gpg", now, true),
+        ];
+
+        for &(path, modify_time, _) in &temp_files {
+            provider.add_file(path, b"data");
+            provider.set_modify_time(path, modify_time);
+        }
+
+        let mut storage = Storage::new(provider.clone(), "/dst");
+        collect_garbage(&mut storage, true).unwrap();
+        assert_eq!(provider.modifications(), Vec::<String>::new());
+
+        collect_garbage(&mut storage, false).unwrap();
+        for &(path, _, kept) in &temp_files {
+            assert_eq!(provider.exists(path), kept, "{}", path);
+        }
+    }
+}
@@ -1,6 +1,10 @@
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
+use std::sync::mpsc;
 
 use digest::Digest;
+use hmac::{Hmac, Mac, NewMac};
+
+use crate::core::GenericResult;
 
 pub trait Hasher: Write + Send {
     fn finish(self: Box<Self>) -> String;
@@ -104,4 +108,246 @@ impl Hasher for Md5 {
     fn finish(self: Box<Self>) -> String {
         format!("{:x}", self.hasher.finalize())
     }
-}
\ No newline at end of file
+}
+
+pub struct Sha1 {
+    hasher: sha1::Sha1,
+}
+
+impl Sha1 {
+    pub fn new() -> Sha1 {
+        Sha1 {hasher: sha1::Sha1::new()}
+    }
+}
+
+impl Write for Sha1 {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.hasher.flush()
+    }
+}
+
+impl Hasher for Sha1 {
+    fn finish(self: Box<Self>) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+// Hashes the data split into fixed size parts: the result is the hash of concatenated hashes of the
+// parts, optionally followed by the number of parts (this is how S3 calculates ETag of multipart
+// uploads).
+pub struct PartsHasher<D: Digest> {
+    part_size: usize,
+    part_hasher: Option<(D, usize)>,
+    result_hasher: D,
+    parts: usize,
+    with_parts_count: bool,
+}
+
+impl PartsHasher<md5::Md5> {
+    pub fn new_s3_etag(part_size: usize) -> PartsHasher<md5::Md5> {
+        PartsHasher::new(part_size, true)
+    }
+}
+
+impl PartsHasher<sha1::Sha1> {
+    pub fn new_sha1(part_size: usize) -> PartsHasher<sha1::Sha1> {
+        PartsHasher::new(part_size, false)
+    }
+}
+
+impl<D: Digest> PartsHasher<D> {
+    fn new(part_size: usize, with_parts_count: bool) -> PartsHasher<D> {
+        PartsHasher {
+            part_size,
+            part_hasher: None,
+            result_hasher: D::new(),
+            parts: 0,
+            with_parts_count,
+        }
+    }
+
+    fn consume_part(&mut self) {
+        if let Some((part_hasher, _)) = self.part_hasher.take() {
+            self.result_hasher.update(part_hasher.finalize().as_slice());
+            self.parts += 1;
+        }
+    }
+}
+
+impl<D: Digest> Write for PartsHasher<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let part_size = self.part_size;
+        let (part_hasher, available_size) = self.part_hasher.get_or_insert_with(|| {
+            (D::new(), part_size)
+        });
+
+        let consumed_size = std::cmp::min(buf.len(), *available_size);
+        part_hasher.update(&buf[..consumed_size]);
+        *available_size -= consumed_size;
+
+        if *available_size == 0 {
+            self.consume_part();
+        }
+
+        Ok(consumed_size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<D: Digest + Send> Hasher for PartsHasher<D> {
+    fn finish(mut self: Box<Self>) -> String {
+        self.consume_part();
+
+        let hash = to_hex(self.result_hasher.finalize().as_slice());
+        if self.with_parts_count {
+            format!("{}-{}", hash, self.parts)
+        } else {
+            hash
+        }
+    }
+}
+
+// Keyed hash which can't be recalculated for modified data without the key, so unlike the checksums
+// it detects not only corruption, but also tampering
+pub struct HmacSha256 {
+    mac: Hmac<sha2::Sha256>,
+}
+
+impl HmacSha256 {
+    pub fn new(key: &[u8]) -> HmacSha256 {
+        HmacSha256 {mac: Hmac::new_varkey(key).unwrap()}
+    }
+}
+
+impl Write for HmacSha256 {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.mac.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Hasher for HmacSha256 {
+    fn finish(self: Box<Self>) -> String {
+        to_hex(&self.mac.finalize().into_bytes())
+    }
+}
+
+// Passes the data to an additional hasher, whose result is sent to the returned channel when the main
+// hasher finishes
+pub struct TeeHasher {
+    hasher: Box<dyn Hasher>,
+    tee: Box<dyn Hasher>,
+    tx: mpsc::Sender<String>,
+}
+
+impl TeeHasher {
+    pub fn new(hasher: Box<dyn Hasher>, tee: Box<dyn Hasher>) -> (TeeHasher, mpsc::Receiver<String>) {
+        let (tx, rx) = mpsc::channel();
+        (TeeHasher {hasher, tee, tx}, rx)
+    }
+}
+
+impl Write for TeeHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.hasher.write(buf)?;
+        self.tee.write_all(&buf[..size])?;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Hasher for TeeHasher {
+    fn finish(self: Box<Self>) -> String {
+        let _ = self.tx.send(self.tee.finish());
+        self.hasher.finish()
+    }
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(hex: &str) -> GenericResult<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err!("Invalid hex string: {:?}", hex);
+    }
+
+    (0..hex.len()).step_by(2).map(|index| {
+        u8::from_str_radix(&hex[index..index + 2], 16).map_err(|_| format!(
+            "Invalid hex string: {:?}", hex).into())
+    }).collect()
+}
+
+// Hashes the data while it's being read and fails on EOF if the checksum doesn't match the expected
+// one
+pub struct VerifyingReader<R: Read> {
+    reader: R,
+    hasher: Option<Box<dyn Hasher>>,
+    checksum: String,
+    mismatch_error: &'static str,
+}
+
+impl<R: Read> VerifyingReader<R> {
+    pub fn new(reader: R, hasher: Box<dyn Hasher>, checksum: &str) -> VerifyingReader<R> {
+        VerifyingReader {
+            reader,
+            hasher: Some(hasher),
+            checksum: checksum.to_owned(),
+            mismatch_error: "Checksum mismatch",
+        }
+    }
+
+    pub fn with_mismatch_error(mut self, error: &'static str) -> VerifyingReader<R> {
+        self.mismatch_error = error;
+        self
+    }
+}
+
+impl<R: Read> Read for VerifyingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.reader.read(buf)?;
+
+        if size != 0 {
+            if let Some(hasher) = self.hasher.as_mut() {
+                hasher.write_all(&buf[..size])?;
+            }
+        } else if let Some(hasher) = self.hasher.take() {
+            if hasher.finish() != self.checksum {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, self.mismatch_error));
+            }
+        }
+
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac_sha256() {
+        // RFC 4231 test case 2
+        let mut hasher = Box::new(HmacSha256::new(b"Jefe"));
+        hasher.write_all(b"what do ya want for nothing?").unwrap();
+        assert_eq!(hasher.finish(), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+}
@@ -10,6 +10,7 @@ type ChunkStream = mpsc::Receiver<Message>;
 
 pub enum Body {
     String(String),
+    Bytes(Bytes),
     Stream(ChunkStream),
 }
 
@@ -25,6 +26,12 @@ impl From<String> for Body {
     }
 }
 
+impl From<Bytes> for Body {
+    fn from(data: Bytes) -> Self {
+        Body::Bytes(data)
+    }
+}
+
 impl From<ChunkStream> for Body {
     fn from(stream: ChunkStream) -> Self {
         Body::Stream(stream)
@@ -35,6 +42,7 @@ impl Into<reqwest::blocking::Body> for Body {
     fn into(self) -> reqwest::blocking::Body {
         match self {
             Body::String(data) => data.into(),
+            Body::Bytes(data) => data.to_vec().into(),
             Body::Stream(stream) => reqwest::blocking::Body::new(StreamReader {
                 stream: stream,
                 current_chunk: None,
@@ -0,0 +1,194 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+// Adapts the number of concurrent requests to the rate the server is able to sustain (AIMD): the limit
+// grows by one after each limit-sized series of successful requests and is halved when the server
+// throttles the client. Shared by all the threads sending requests through the client.
+pub struct ConcurrencyLimiter {
+    min: usize,
+    max: usize,
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+struct State {
+    limit: usize,
+    running: usize,
+    successes: usize,
+    // Incremented on each decrease, so a burst of throttled requests sent under the same limit halves
+    // it only once
+    epoch: usize,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(min: usize, max: usize) -> Arc<ConcurrencyLimiter> {
+        assert!(min > 0 && min <= max);
+
+        Arc::new(ConcurrencyLimiter {
+            min, max,
+            state: Mutex::new(State {
+                limit: min,
+                running: 0,
+                successes: 0,
+                epoch: 0,
+            }),
+            released: Condvar::new(),
+        })
+    }
+
+    // Blocks until the current limit allows one more request. The permit is returned on drop.
+    pub fn acquire(self: &Arc<ConcurrencyLimiter>) -> ConcurrencyPermit {
+        let mut state = self.state.lock().unwrap();
+        while state.running >= state.limit {
+            state = self.released.wait(state).unwrap();
+        }
+        state.running += 1;
+
+        ConcurrencyPermit {limiter: self.clone(), epoch: state.epoch}
+    }
+
+    #[cfg(test)]
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    fn on_success(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.successes += 1;
+        if state.successes >= state.limit && state.limit < self.max {
+            state.limit += 1;
+            state.successes = 0;
+            debug!("Increasing request concurrency to {}.", state.limit);
+        }
+    }
+
+    fn on_throttling(&self, epoch: usize) {
+        let mut state = self.state.lock().unwrap();
+        if state.epoch != epoch {
+            return;
+        }
+
+        state.limit = std::cmp::max(self.min, state.limit / 2);
+        state.successes = 0;
+        state.epoch += 1;
+        debug!("The server throttles the requests. Decreasing request concurrency to {}.", state.limit);
+    }
+}
+
+pub struct ConcurrencyPermit {
+    limiter: Arc<ConcurrencyLimiter>,
+    epoch: usize,
+}
+
+impl ConcurrencyPermit {
+    pub fn succeeded(self) {
+        self.limiter.on_success();
+    }
+
+    pub fn throttled(self) {
+        self.limiter.on_throttling(self.epoch);
+    }
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().running -= 1;
+        self.limiter.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn limits() {
+        let limiter = ConcurrencyLimiter::new(2, 4);
+        assert_eq!(limiter.limit(), 2);
+
+        for _ in 0..2 {
+            limiter.acquire().succeeded();
+        }
+        assert_eq!(limiter.limit(), 3);
+
+        for _ in 0..10 {
+            limiter.acquire().succeeded();
+        }
+        assert_eq!(limiter.limit(), 4);
+
+        // Requests sent under the same limit halve it only once
+        let permits: Vec<_> = (0..4).map(|_| limiter.acquire()).collect();
+        for permit in permits {
+            permit.throttled();
+        }
+        assert_eq!(limiter.limit(), 2);
+
+        limiter.acquire().throttled();
+        assert_eq!(limiter.limit(), 2);
+    }
+
+    #[test]
+    fn convergence() {
+        let threshold = 4;
+        let requests = 1000;
+        let limiter = ConcurrencyLimiter::new(1, 16);
+
+        let sent = Arc::new(AtomicUsize::new(0));
+        let running = Arc::new(AtomicUsize::new(0));
+        let throttled = Arc::new(AtomicUsize::new(0));
+        let max_limit = Arc::new(AtomicUsize::new(0));
+
+        let threads: Vec<_> = (0..16).map(|_| {
+            let limiter = limiter.clone();
+            let sent = sent.clone();
+            let running = running.clone();
+            let throttled = throttled.clone();
+            let max_limit = max_limit.clone();
+
+            thread::spawn(move || loop {
+                let permit = limiter.acquire();
+
+                let request = sent.fetch_add(1, Ordering::SeqCst);
+                if request >= requests {
+                    break;
+                }
+
+                // The server throttles the client when it sends more than the threshold number of
+                // concurrent requests
+                let concurrency = running.fetch_add(1, Ordering::SeqCst) + 1;
+                thread::sleep(Duration::from_millis(1));
+                running.fetch_sub(1, Ordering::SeqCst);
+
+                // Give the limiter some time to converge
+                if request >= requests / 2 {
+                    max_limit.fetch_max(limiter.limit(), Ordering::SeqCst);
+                    if concurrency > threshold {
+                        throttled.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+
+                if concurrency > threshold {
+                    permit.throttled();
+                } else {
+                    permit.succeeded();
+                }
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let max_limit = max_limit.load(Ordering::SeqCst);
+        let throttled = throttled.load(Ordering::SeqCst);
+        assert!(max_limit <= threshold + 2, "Unexpected concurrency limit: {}", max_limit);
+
+        // AIMD probes the limit periodically, so some requests are always throttled, but with a fixed
+        // concurrency almost all of them would be
+        assert!(throttled < requests / 2 / 4, "Too many throttled requests: {}", throttled);
+    }
+}
@@ -1,16 +1,26 @@
 mod body;
+mod concurrency_limiter;
 pub mod headers;
+mod rate_limiter;
 mod readers;
+mod redaction;
 mod request;
 mod response;
 
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use reqwest::blocking::Client;
+use reqwest::blocking::{Client, Response};
 
 use crate::core::GenericResult;
+use crate::metrics;
+
+use self::concurrency_limiter::ConcurrencyLimiter;
+use self::rate_limiter::RateLimiter;
 
 pub use reqwest::{Method, StatusCode};
 pub use reqwest::header::{HeaderMap as Headers, HeaderName, HeaderValue};
@@ -18,18 +28,109 @@ pub use self::body::*;
 pub use self::request::*;
 pub use self::response::*;
 pub use self::readers::*;
+pub use self::redaction::TraceRedaction;
+
+// Threading model: the client is Send + Sync and all its methods take &self, so a single instance can
+// be used from any number of threads concurrently. Clones share the underlying connection pool.
+// Requests are blocking: each one occupies the calling thread until the response is fully read (or
+// until the response headers are received for downloads).
+lazy_static! {
+    static ref TCP_KEEPALIVE: Mutex<Option<Duration>> = Mutex::new(None);
+    static ref TRACE_REDACTION: Mutex<TraceRedaction> = Mutex::new(TraceRedaction::default());
+}
+
+// Sets TCP keepalive interval for all clients created afterwards
+pub fn set_tcp_keepalive(interval: Option<Duration>) {
+    *TCP_KEEPALIVE.lock().unwrap() = interval;
+}
+
+// Sets headers and endpoints which data is hidden from the trace logs for all clients created afterwards
+pub fn set_trace_redaction(redaction: TraceRedaction) {
+    *TRACE_REDACTION.lock().unwrap() = redaction;
+}
 
+// Overrides of the provider's default timeouts: connection establishment, API requests and data
+// transfer requests (uploads of a single part and downloads)
+#[derive(Clone, Copy, Default)]
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    pub request: Option<Duration>,
+    pub upload: Option<Duration>,
+}
+
+// Client settings which are configured the same way for all HTTP-based providers
+#[derive(Clone, Copy, Default)]
+pub struct ClientOptions {
+    pub rate_limit: Option<f64>,
+    pub adaptive_concurrency: Option<(usize, usize)>,
+    pub timeouts: Timeouts,
+}
+
+#[derive(Clone)]
 pub struct HttpClient {
+    client: Client,
     default_headers: Headers,
+    metrics_label: Option<String>,
+    concurrency_limiter: Option<Arc<ConcurrencyLimiter>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    trace_redaction: TraceRedaction,
 }
 
 impl HttpClient {
     pub fn new() -> HttpClient {
         HttpClient {
+            client: build_client(None),
             default_headers: Headers::new(),
+            metrics_label: None,
+            concurrency_limiter: None,
+            rate_limiter: None,
+            trace_redaction: TRACE_REDACTION.lock().unwrap().clone(),
         }.with_default_header(headers::USER_AGENT, "pyvsb-to-cloud").unwrap()
     }
 
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> HttpClient {
+        self.client = build_client(Some(timeout));
+        self
+    }
+
+    // Enables counting of the sent requests in provider metrics
+    pub fn with_metrics_label(mut self, provider_name: &str) -> HttpClient {
+        self.metrics_label = Some(provider_name.to_owned());
+        self
+    }
+
+    // Limits the rate of requests sent by the client and all its clones
+    pub fn with_rate_limit(mut self, requests_per_second: f64) -> HttpClient {
+        self.rate_limiter = Some(Arc::new(RateLimiter::new(requests_per_second)));
+        self
+    }
+
+    // Adapts the number of concurrent requests sent by the client and all its clones to the rate the
+    // server is able to sustain, keeping it within the specified bounds
+    pub fn with_adaptive_concurrency(mut self, min: usize, max: usize) -> HttpClient {
+        self.concurrency_limiter = Some(ConcurrencyLimiter::new(min, max));
+        self
+    }
+
+    // Applies the options except request and upload timeouts which are passed with each request
+    pub fn with_options(mut self, options: &ClientOptions) -> HttpClient {
+        if let Some(timeout) = options.timeouts.connect {
+            self = self.with_connect_timeout(timeout);
+        }
+        if let Some(requests_per_second) = options.rate_limit {
+            self = self.with_rate_limit(requests_per_second);
+        }
+        if let Some((min, max)) = options.adaptive_concurrency {
+            self = self.with_adaptive_concurrency(min, max);
+        }
+        self
+    }
+
+    pub fn with_default_headers(mut self, headers: Headers) -> HttpClient {
+        self.default_headers.extend(headers);
+        self
+    }
+
     pub fn with_default_header<V: AsRef<str>>(mut self, name: HeaderName, value: V) -> GenericResult<HttpClient> {
         let value = value.as_ref().parse().map_err(|_| format!(
             "Invalid {:?} header value", name.as_str()))?;
@@ -37,7 +138,60 @@ impl HttpClient {
         Ok(self)
     }
 
-    pub fn send<R, E>(&self, mut request: HttpRequest<R, E>) -> Result<R, HttpClientError<E>> {
+    pub fn send<R, E>(&self, request: HttpRequest<R, E>) -> Result<R, HttpClientError<E>> {
+        let endpoint = request.endpoint;
+        let result = self.process(request);
+        self.count_request(endpoint, &result);
+        result
+    }
+
+    // Returns the response body as a stream instead of reading it into memory. Please note that the
+    // request timeout covers reading of the whole body.
+    pub fn download<R, E>(
+        &self, request: HttpRequest<R, E>,
+    ) -> Result<(Headers, Box<dyn Read + Send>), HttpClientError<E>> {
+        let endpoint = request.endpoint;
+        let result = self.process_download(request);
+        self.count_request(endpoint, &result);
+        result
+    }
+
+    // Retries are counted by the providers themselves via metrics::count_provider_retry()
+    fn count_request<T, E>(&self, endpoint: Option<&str>, result: &Result<T, HttpClientError<E>>) {
+        if let Some(ref provider) = self.metrics_label {
+            metrics::count_provider_request(provider, endpoint.unwrap_or("other"), match result {
+                Ok(_) => "success",
+                Err(HttpClientError::Api(_)) => "api-error",
+                Err(HttpClientError::Generic(_)) => "error",
+            });
+        }
+    }
+
+    fn process<R, E>(&self, mut request: HttpRequest<R, E>) -> Result<R, HttpClientError<E>> {
+        let response = self.send_request(&mut request)?;
+        let response = self.read_response(&request, response)?;
+
+        if response.status.is_success() {
+            Ok(request.reply_reader.read(response)?)
+        } else {
+            Err(read_error(&request, response))
+        }
+    }
+
+    fn process_download<R, E>(
+        &self, mut request: HttpRequest<R, E>,
+    ) -> Result<(Headers, Box<dyn Read + Send>), HttpClientError<E>> {
+        let response = self.send_request(&mut request)?;
+
+        if response.status().is_success() {
+            trace!("Got {} response. Streaming its body...", response.status());
+            Ok((response.headers().clone(), Box::new(response)))
+        } else {
+            Err(read_error(&request, self.read_response(&request, response)?))
+        }
+    }
+
+    fn send_request<R, E>(&self, request: &mut HttpRequest<R, E>) -> GenericResult<Response> {
         let mut headers = self.default_headers.clone();
         for (name, value) in request.headers.drain() {
             headers.insert(name.unwrap(), value);
@@ -48,15 +202,16 @@ impl HttpClient {
 
             if !headers.is_empty() {
                 extra_info += "\n";
-                extra_info += &headers.iter()
-                    .map(|(name, value)| format!(
-                        "{}: {}", name, value.to_str().unwrap_or("[non-ASCII data]")))
-                    .collect::<Vec<_>>().join("\n");
+                extra_info += &self.trace_redaction.format_headers(&headers);
             }
 
-            if let Some(body) = request.trace_body {
+            if let Some(ref body) = request.trace_body {
                 extra_info += "\n";
-                extra_info += &body;
+                if self.trace_redaction.is_sensitive_endpoint(&request.url) {
+                    extra_info += "[redacted]";
+                } else {
+                    extra_info += body;
+                }
             }
 
             if extra_info.is_empty() {
@@ -69,37 +224,48 @@ impl HttpClient {
                    method=request.method, url=request.url, extra_info=extra_info);
         }
 
-        let response = self.send_request(
-            request.method, &request.url, headers, request.body, request.timeout)?;
+        if let Some(ref rate_limiter) = self.rate_limiter {
+            rate_limiter.wait();
+        }
 
-        if response.status.is_success() {
-            Ok(request.reply_reader.read(response)?)
-        } else if response.status.is_client_error() || response.status.is_server_error() {
-            Err(HttpClientError::Api(request.error_reader.read(response)?))
-        } else {
-            Err!("Server returned an error: {}", response.status)
+        let mut http_request = self.client.request(request.method.clone(), &request.url)
+            .headers(headers).timeout(request.timeout);
+
+        if let Some(body) = request.body.take() {
+            http_request = http_request.body(body);
         }
-    }
 
-    fn send_request(&self, method: Method, url: &str, headers: Headers, body: Option<Body>,
-                    timeout: Duration) -> GenericResult<HttpResponse>
-    {
-        let client = Client::builder().timeout(timeout).build().map_err(|e| format!(
-            "Unable to create HTTP client: {}", e))?;
+        let permit = self.concurrency_limiter.as_ref().map(|limiter| limiter.acquire());
+        let response = http_request.send()?;
 
-        let mut request = client.request(method, url).headers(headers);
-        if let Some(body) = body {
-            request = request.body(body);
+        if let Some(permit) = permit {
+            let status = response.status();
+            if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+                permit.throttled();
+            } else if status.is_success() {
+                permit.succeeded();
+            }
         }
 
-        let mut response = request.send()?;
+        Ok(response)
+    }
+
+    fn read_response<R, E>(
+        &self, request: &HttpRequest<R, E>, mut response: Response,
+    ) -> GenericResult<HttpResponse> {
         let status = response.status();
 
         let mut body = Vec::new();
         response.copy_to(&mut body)?;
 
-        trace!("Got {} response: {}", status,
-               String::from_utf8_lossy(&body).trim_end_matches('\n'));
+        if log_enabled!(log::Level::Trace) {
+            if self.trace_redaction.is_sensitive_endpoint(&request.url) {
+                trace!("Got {} response: [redacted]", status);
+            } else {
+                trace!("Got {} response: {}", status,
+                       String::from_utf8_lossy(&body).trim_end_matches('\n'));
+            }
+        }
 
         Ok(HttpResponse {
             status: status,
@@ -109,6 +275,39 @@ impl HttpClient {
     }
 }
 
+pub fn parse_headers(headers: &BTreeMap<String, String>) -> GenericResult<Headers> {
+    let mut parsed = Headers::new();
+
+    for (name, value) in headers {
+        let name: HeaderName = name.parse().map_err(|_| format!("Invalid header name: {:?}", name))?;
+        let value: HeaderValue = value.parse().map_err(|_| format!(
+            "Invalid {:?} header value", name.as_str()))?;
+        parsed.insert(name, value);
+    }
+
+    Ok(parsed)
+}
+
+fn read_error<R, E>(request: &HttpRequest<R, E>, response: HttpResponse) -> HttpClientError<E> {
+    if response.status.is_client_error() || response.status.is_server_error() ||
+        response.status.is_redirection() && request.read_redirect_errors {
+        match request.error_reader.read(response) {
+            Ok(error) => HttpClientError::Api(error),
+            Err(err) => err.into(),
+        }
+    } else {
+        HttpClientError::Generic(format!("Server returned an error: {}", response.status))
+    }
+}
+
+fn build_client(connect_timeout: Option<Duration>) -> Client {
+    let mut builder = Client::builder().tcp_keepalive(*TCP_KEEPALIVE.lock().unwrap());
+    if let Some(timeout) = connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    builder.build().unwrap()
+}
+
 #[derive(Debug)]
 pub enum HttpClientError<T> {
     Generic(String),
@@ -143,4 +342,165 @@ impl<T> From<Box<dyn Error + Send + Sync>> for HttpClientError<T> {
     fn from(err: Box<dyn Error + Send + Sync>) -> HttpClientError<T> {
         HttpClientError::Generic(err.to_string())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Instant;
+
+    use prometheus::proto::MetricFamily;
+
+    use crate::tests::{TestRequest, TestResponse, TestServer};
+
+    use super::*;
+
+    #[test]
+    fn shared_client() {
+        let server = TestServer::new(|request: &TestRequest| {
+            if request.path == "/slow" {
+                thread::sleep(Duration::from_millis(500));
+            }
+            TestResponse::new(200).with_body("text/plain", request.path.as_bytes())
+        });
+
+        let client = HttpClient::new();
+
+        // The timeout is a property of the request, not of the shared client
+        match get(&client, format!("{}/slow", server.url), Duration::from_millis(100)) {
+            Err(HttpClientError::Generic(err)) => assert!(err.contains("timed out"), "{}", err),
+            _ => panic!("The request hasn't timed out"),
+        }
+
+        let threads: Vec<_> = (0..4).map(|thread_id| {
+            let client = client.clone();
+            let path = format!("/thread-{}", thread_id);
+            let url = server.url.clone() + &path;
+
+            thread::spawn(move || {
+                for _ in 0..5 {
+                    let response = get(&client, url.clone(), Duration::from_secs(10)).unwrap();
+                    assert_eq!(response.body, path.as_bytes());
+                }
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(server.stop().len(), 1 + 4 * 5);
+    }
+
+    fn get(client: &HttpClient, url: String, timeout: Duration) -> Result<HttpResponse, HttpClientError<EmptyResponse>> {
+        client.send(HttpRequest::new(
+            Method::GET, url, timeout, RawResponseReader::new(), JsonErrorReader::<EmptyResponse>::new()))
+    }
+
+    #[test]
+    fn rate_limit() {
+        let server = TestServer::new(|_: &TestRequest| TestResponse::new(200));
+        let client = HttpClient::new().with_rate_limit(20.0);
+        let start_time = Instant::now();
+
+        // The limit is shared by all clones of the client
+        let threads: Vec<_> = (0..4).map(|_| {
+            let client = client.clone();
+            let url = server.url.clone();
+
+            thread::spawn(move || {
+                for _ in 0..3 {
+                    get(&client, url.clone(), Duration::from_secs(10)).unwrap();
+                }
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let duration = start_time.elapsed();
+        assert!(duration >= Duration::from_millis(11 * 50), "The requests have been sent in {:?}", duration);
+        assert_eq!(server.stop().len(), 12);
+    }
+
+    #[test]
+    fn tcp_keepalive() {
+        assert!(!get_keepalive_timer());
+
+        set_tcp_keepalive(Some(Duration::from_secs(60)));
+        let enabled = get_keepalive_timer();
+        set_tcp_keepalive(None);
+
+        assert!(enabled);
+    }
+
+    // Sends a request to a server that never responds and checks whether the kernel has armed the
+    // keepalive timer on the client socket while it waits
+    fn get_keepalive_timer() -> bool {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let url = format!("http://127.0.0.1:{}/", port);
+
+        let client = HttpClient::new();
+        let request = thread::spawn(move || get(&client, url, Duration::from_millis(500)));
+        let (_connection, _) = listener.accept().unwrap();
+
+        // /proc/net/tcp columns: sl, local_address, rem_address, st, tx_queue:rx_queue, tr:tm->when
+        let remote_address = format!("0100007F:{:04X}", port);
+        let mut keepalive = false;
+
+        for _ in 0..20 {
+            let connections = fs::read_to_string("/proc/net/tcp").unwrap();
+            keepalive = connections.lines().skip(1).any(|line| {
+                let columns: Vec<&str> = line.split_whitespace().collect();
+                columns[2] == remote_address && columns[5].starts_with("02:")
+            });
+            if keepalive {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(request.join().unwrap().is_err());
+        keepalive
+    }
+
+    #[test]
+    fn metrics() {
+        let client = HttpClient::new().with_metrics_label("metrics-test");
+
+        let success: Result<(), HttpClientError<String>> = Ok(());
+        let api_error: Result<(), HttpClientError<String>> = Err(HttpClientError::Api("error".to_owned()));
+        let error: Result<(), HttpClientError<String>> = Err(HttpClientError::Generic("error".to_owned()));
+
+        client.count_request(Some("upload"), &success);
+        client.count_request(Some("upload"), &error);
+        metrics::count_provider_retry("metrics-test", "upload");
+        client.count_request(Some("upload"), &success);
+        client.count_request(Some("list"), &api_error);
+        client.count_request(None, &success);
+
+        let families = prometheus::gather();
+
+        let requests = "pyvsb_provider_requests_total";
+        assert_eq!(get_counter(&families, requests, &[("endpoint", "upload"), ("result", "success")]), 2);
+        assert_eq!(get_counter(&families, requests, &[("endpoint", "upload"), ("result", "error")]), 1);
+        assert_eq!(get_counter(&families, requests, &[("endpoint", "list"), ("result", "api-error")]), 1);
+        assert_eq!(get_counter(&families, requests, &[("endpoint", "other"), ("result", "success")]), 1);
+        assert_eq!(get_counter(&families, "pyvsb_provider_retries_total", &[("endpoint", "upload")]), 1);
+    }
+
+    fn get_counter(families: &[MetricFamily], name: &str, labels: &[(&str, &str)]) -> u64 {
+        let family = families.iter().find(|family| family.get_name() == name).unwrap();
+
+        family.get_metric().iter().filter(|metric| {
+            let has_label = |name: &str, value: &str| metric.get_label().iter().any(|label| {
+                label.get_name() == name && label.get_value() == value
+            });
+            has_label("provider", "metrics-test") && labels.iter().all(|&(name, value)| has_label(name, value))
+        }).map(|metric| metric.get_counter().get_value() as u64).sum()
+    }
+}
@@ -0,0 +1,37 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Spreads requests evenly to not exceed the specified rate. Shared by all the threads sending requests
+// through the client.
+pub struct RateLimiter {
+    interval: Duration,
+    next_request_time: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: f64) -> RateLimiter {
+        RateLimiter {
+            interval: Duration::from_secs_f64(1.0 / requests_per_second),
+            next_request_time: Mutex::new(None),
+        }
+    }
+
+    pub fn wait(&self) {
+        let now = Instant::now();
+
+        let request_time = {
+            let mut next_request_time = self.next_request_time.lock().unwrap();
+            let request_time = match *next_request_time {
+                Some(time) if time > now => time,
+                _ => now,
+            };
+            next_request_time.replace(request_time + self.interval);
+            request_time
+        };
+
+        if request_time > now {
+            thread::sleep(request_time - now);
+        }
+    }
+}
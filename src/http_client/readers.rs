@@ -77,25 +77,83 @@ impl<T: de::DeserializeOwned> ResponseReader for JsonErrorReader<T> {
     type Result = T;
 
     fn read(&self, response: HttpResponse) -> GenericResult<Self::Result> {
-        let content_type = response.get_header(headers::CONTENT_TYPE)?.ok_or_else(|| format!(
-            "Server returned {} response without Content-Type", response.status))?.to_owned();
+        let content_type = response.get_header(headers::CONTENT_TYPE)?.map(ToOwned::to_owned);
+        let mime = content_type.as_ref().and_then(|content_type| Mime::from_str(content_type).ok());
+
+        if let Some(ref mime) = mime {
+            if mime.type_() == mime::APPLICATION && mime.subtype() == mime::JSON {
+                return Ok(serde_json::from_slice(&response.body).map_err(|e| format!(
+                    "Server returned an invalid JSON response: {}", e))?);
+            }
+        }
 
-        match &Mime::from_str(&content_type) {
-            Ok(content_type) if content_type.type_() == mime::APPLICATION && content_type.subtype() == mime::JSON => {
-                Ok(serde_json::from_slice(&response.body).map_err(|e| format!(
-                    "Server returned an invalid JSON response: {}", e))?)
+        // Misbehaving servers and proxies may return JSON errors with a missing or wrong content type
+        if let Ok(error) = serde_json::from_slice(&response.body) {
+            return Ok(error);
+        }
+
+        match (content_type, mime) {
+            (None, _) => {
+                Err!("Server returned an error: {}", self.read_plain_text_error(response))
             },
-            Ok(content_type) if content_type.type_() == mime::TEXT && content_type.subtype() == mime::PLAIN => {
+            (_, Some(ref mime)) if mime.type_() == mime::TEXT && mime.subtype() == mime::PLAIN => {
                 Err!("Server returned an error: {}", self.read_plain_text_error(response))
             },
-            _ => {
+            (Some(content_type), _) => {
                 Err!("Server returned {} error with an invalid content type: {}",
                     response.status, content_type)
-            }
+            },
+        }
+    }
+}
+
+pub struct XmlReplyReader<T> {
+    phantom: PhantomData<T>,
+}
+
+impl<T: de::DeserializeOwned> XmlReplyReader<T> {
+    pub fn new() -> XmlReplyReader<T> {
+        XmlReplyReader{
+            phantom: PhantomData
         }
     }
 }
 
+impl<T: de::DeserializeOwned> ResponseReader for XmlReplyReader<T> {
+    type Result = T;
+
+    fn read(&self, response: HttpResponse) -> GenericResult<Self::Result> {
+        Ok(quick_xml::de::from_reader(response.body.as_slice()).map_err(|e| format!(
+            "Server returned an invalid XML response: {}", e))?)
+    }
+}
+
+pub struct XmlErrorReader<T> {
+    phantom: PhantomData<T>,
+}
+
+impl<T: de::DeserializeOwned> XmlErrorReader<T> {
+    pub fn new() -> XmlErrorReader<T> {
+        XmlErrorReader{
+            phantom: PhantomData
+        }
+    }
+}
+
+impl<T: de::DeserializeOwned> ResponseReader for XmlErrorReader<T> {
+    type Result = T;
+
+    fn read(&self, response: HttpResponse) -> GenericResult<Self::Result> {
+        // Responses to HEAD requests and some proxy errors have no body
+        if response.body.is_empty() {
+            return Err!("Server returned an error: {}", response.status);
+        }
+
+        Ok(quick_xml::de::from_reader(response.body.as_slice()).map_err(|e| format!(
+            "Server returned {} error with an invalid XML body: {}", response.status, e))?)
+    }
+}
+
 pub struct RawResponseReader {
 }
 
@@ -111,4 +169,48 @@ impl ResponseReader for RawResponseReader {
     fn read(&self, response: HttpResponse) -> GenericResult<Self::Result> {
         Ok(response)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http_client::{StatusCode, Headers};
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct ApiError {
+        message: String,
+    }
+
+    fn read_error(content_type: Option<&str>, body: &str) -> GenericResult<ApiError> {
+        let mut headers = Headers::new();
+        if let Some(content_type) = content_type {
+            headers.insert(headers::CONTENT_TYPE, content_type.parse().unwrap());
+        }
+
+        JsonErrorReader::<ApiError>::new().read(HttpResponse {
+            status: StatusCode::BAD_GATEWAY,
+            headers,
+            body: body.as_bytes().to_vec(),
+        })
+    }
+
+    #[test]
+    fn json_errors() {
+        let json = r#"{"message": "Access denied"}"#;
+
+        for content_type in &[Some("application/json; charset=utf-8"), Some("text/html"), Some("text/plain"), None] {
+            assert_eq!(read_error(*content_type, json).unwrap().message, "Access denied");
+        }
+
+        assert_eq!(read_error(Some("application/json"), "Bad gateway").unwrap_err().to_string(),
+                   "Server returned an invalid JSON response: expected value at line 1 column 1");
+
+        assert_eq!(read_error(None, "Bad gateway.\nDetails").unwrap_err().to_string(),
+                   "Server returned an error: Bad gateway");
+        assert_eq!(read_error(Some("text/plain"), "").unwrap_err().to_string(),
+                   "Server returned an error: 502 Bad Gateway");
+        assert_eq!(read_error(Some("text/html"), "<html></html>").unwrap_err().to_string(),
+                   "Server returned 502 Bad Gateway error with an invalid content type: text/html");
+    }
+}
@@ -0,0 +1,126 @@
+use reqwest::Url;
+use reqwest::header;
+
+use super::{Headers, HeaderName};
+
+const REDACTED: &str = "***";
+
+// Specifies which request data mustn't get into the trace logs: values of the sensitive headers are
+// masked and bodies of requests to (and responses from) the authorization endpoints are hidden
+#[derive(Clone)]
+pub struct TraceRedaction {
+    headers: Vec<HeaderName>,
+    endpoints: Vec<String>,
+}
+
+impl TraceRedaction {
+    // Endpoints are matched by the URL path suffix
+    pub fn new(headers: Vec<HeaderName>, endpoints: Vec<String>) -> TraceRedaction {
+        TraceRedaction {headers, endpoints}
+    }
+
+    // Adds extra headers and endpoints to the redacted ones
+    pub fn extend(mut self, headers: Vec<HeaderName>, endpoints: Vec<String>) -> TraceRedaction {
+        self.headers.extend(headers);
+        self.endpoints.extend(endpoints);
+        self
+    }
+
+    pub fn format_headers(&self, headers: &Headers) -> String {
+        headers.iter().map(|(name, value)| {
+            let value = value.to_str().unwrap_or("[non-ASCII data]");
+
+            if self.headers.contains(name) {
+                format!("{}: {}", name, redact_credentials(value))
+            } else {
+                format!("{}: {}", name, value)
+            }
+        }).collect::<Vec<_>>().join("\n")
+    }
+
+    pub fn is_sensitive_endpoint(&self, url: &str) -> bool {
+        let path = match Url::parse(url) {
+            Ok(url) => url.path().to_owned(),
+            Err(_) => return true,
+        };
+
+        self.endpoints.iter().any(|endpoint| path.ends_with(endpoint.as_str()))
+    }
+}
+
+impl Default for TraceRedaction {
+    fn default() -> TraceRedaction {
+        TraceRedaction::new(vec![
+            header::AUTHORIZATION,
+            header::PROXY_AUTHORIZATION,
+            header::COOKIE,
+            HeaderName::from_static("x-api-key"),
+            HeaderName::from_static("x-amz-security-token"),
+        ], vec![
+            "/oauth2/token".to_owned(),
+            "/b2_authorize_account".to_owned(),
+            "/b2_get_upload_url".to_owned(),
+            "/b2_get_upload_part_url".to_owned(),
+        ])
+    }
+}
+
+// Preserves the authentication scheme to keep the logs useful for debugging: "Bearer ***"
+fn redact_credentials(value: &str) -> String {
+    if let Some(index) = value.find(' ') {
+        let scheme = &value[..index];
+        if !scheme.is_empty() && scheme.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return format!("{} {}", scheme, REDACTED);
+        }
+    }
+    REDACTED.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers() {
+        let mut headers = Headers::new();
+        headers.insert(header::AUTHORIZATION, "Bearer secret-token".parse().unwrap());
+        headers.insert(HeaderName::from_static("x-api-key"), "secret-key".parse().unwrap());
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+
+        let trace = TraceRedaction::default().format_headers(&headers);
+        assert!(!trace.contains("secret"), "{}", trace);
+        assert!(trace.contains("authorization: Bearer ***"), "{}", trace);
+        assert!(trace.contains("x-api-key: ***"), "{}", trace);
+        assert!(trace.contains("content-type: application/json"), "{}", trace);
+    }
+
+    #[test]
+    fn extended_headers() {
+        let mut headers = Headers::new();
+        headers.insert(header::AUTHORIZATION, "Bearer secret-token".parse().unwrap());
+        headers.insert(HeaderName::from_static("x-custom-token"), "secret-value".parse().unwrap());
+
+        let redaction = TraceRedaction::default().extend(
+            vec![HeaderName::from_static("x-custom-token")], Vec::new());
+
+        let trace = redaction.format_headers(&headers);
+        assert!(!trace.contains("secret"), "{}", trace);
+    }
+
+    #[test]
+    fn endpoints() {
+        let redaction = TraceRedaction::default().extend(Vec::new(), vec!["/custom/auth".to_owned()]);
+
+        for url in &[
+            "https://www.dropbox.com/oauth2/token",
+            "https://accounts.google.com/o/oauth2/token",
+            "https://api.backblazeb2.com/b2api/v2/b2_authorize_account",
+            "https://api001.backblazeb2.com/b2api/v2/b2_get_upload_url",
+            "https://example.com/custom/auth",
+        ] {
+            assert!(redaction.is_sensitive_endpoint(url), "{}", url);
+        }
+
+        assert!(!redaction.is_sensitive_endpoint("https://api.dropboxapi.com/2/files/list_folder"));
+    }
+}
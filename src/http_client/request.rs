@@ -13,6 +13,8 @@ pub struct HttpRequest<'a, R, E> {
     pub url: String,
     pub headers: Headers,
     pub timeout: Duration,
+    pub endpoint: Option<&'static str>,
+    pub read_redirect_errors: bool,
 
     pub body: Option<Body>,
     pub trace_body: Option<String>,
@@ -35,6 +37,8 @@ impl<'a, R, E> HttpRequest<'a, R, E> {
             headers: Headers::new(),
             body: None,
             timeout: timeout,
+            endpoint: None,
+            read_redirect_errors: false,
 
             trace_body: None,
 
@@ -43,6 +47,19 @@ impl<'a, R, E> HttpRequest<'a, R, E> {
         }
     }
 
+    // Sets the API endpoint name the request is accounted under in provider metrics. It can't be derived
+    // from the URL which may contain object IDs.
+    pub fn with_endpoint(mut self, name: &'static str) -> HttpRequest<'a, R, E> {
+        self.endpoint = Some(name);
+        self
+    }
+
+    // Passes unfollowed 3xx responses to the error reader which by default gets only 4xx and 5xx ones
+    pub fn with_redirect_errors(mut self) -> HttpRequest<'a, R, E> {
+        self.read_redirect_errors = true;
+        self
+    }
+
     pub fn with_params<P: ser::Serialize>(mut self, params: &P) -> HttpRequestBuildingResult<'a, R, E> {
         let query_string = serde_urlencoded::to_string(params)
             .map_err(HttpRequestBuildingError::new)?;
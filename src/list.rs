@@ -0,0 +1,85 @@
+use std::io::{self, Write};
+
+use crate::core::{EmptyResult, GenericResult};
+use crate::storage::Storage;
+
+#[derive(Serialize)]
+struct Listing {
+    ok: bool,
+    groups: Vec<Group>,
+}
+
+#[derive(Serialize)]
+struct Group {
+    name: String,
+    backups: Vec<String>,
+}
+
+// Prints backup groups stored on the storage as JSON. Groups and backups are sorted by their names (which
+// is also the chronological order), so the output is stable.
+pub fn list_backups(storage: &Storage) -> EmptyResult {
+    let listing = get_listing(storage)?;
+
+    let mut stdout = io::stdout();
+    writeln!(stdout, "{}", listing)?;
+    stdout.flush()?;
+
+    Ok(())
+}
+
+fn get_listing(storage: &Storage) -> GenericResult<String> {
+    let (groups, ok) = storage.get_backup_groups(false).map_err(|e| format!(
+        "Failed to list backup groups on {}: {}", storage.name(), e))?;
+
+    let mut groups: Vec<Group> = groups.into_iter().map(|group| {
+        let mut backups: Vec<String> = group.backups.into_iter().map(|backup| backup.name).collect();
+        backups.sort();
+        Group {name: group.name, backups}
+    }).collect();
+    groups.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(serde_json::to_string_pretty(&Listing {ok, groups})?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::provider::ProviderType;
+    use crate::providers::mock::MockProvider;
+
+    use super::*;
+
+    #[test]
+    fn listing() {
+        let provider = MockProvider::new(ProviderType::Cloud);
+        provider.add_directory("/dst");
+
+        for &(group_name, backup_names) in &[
+            ("2020.01.02", &["2020.01.03-00:00:00", "2020.01.02-00:00:00"][..]),
+            ("2020.01.01", &["2020.01.01-00:00:00"][..]),
+        ] {
+            provider.add_directory(&format!("/dst/{}", group_name));
+            for backup_name in backup_names {
+                provider.add_file(&format!("/dst/{}/{}.tar.gpg", group_name, backup_name), b"backup");
+            }
+        }
+
+        let storage = Storage::new(provider.clone(), "/dst");
+        let listing: serde_json::Value = serde_json::from_str(&get_listing(&storage).unwrap()).unwrap();
+        assert_eq!(listing, serde_json::json!({
+            "ok": true,
+            "groups": [{
+                "name": "2020.01.01",
+                "backups": ["2020.01.01-00:00:00"],
+            }, {
+                "name": "2020.01.02",
+                "backups": ["2020.01.02-00:00:00", "2020.01.03-00:00:00"],
+            }],
+        }));
+
+        // Unexpected files are reported via the listing status
+        provider.add_file("/dst/2020.01.01/unexpected", b"data");
+        let listing: serde_json::Value = serde_json::from_str(&get_listing(&storage).unwrap()).unwrap();
+        assert_eq!(listing["ok"], false);
+        assert_eq!(listing["groups"].as_array().unwrap().len(), 2);
+    }
+}
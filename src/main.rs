@@ -1,16 +1,22 @@
+extern crate base64;
 extern crate bytes;
 extern crate bzip2;
 extern crate chrono;
 extern crate clap;
 extern crate digest;
 extern crate easy_logging;
+extern crate flate2;
+extern crate hmac;
 #[macro_use] extern crate lazy_static;
 extern crate libc;
 #[macro_use] extern crate log;
 extern crate md5;
 extern crate mime;
 extern crate nix;
+extern crate percent_encoding;
 #[macro_use] extern crate prometheus;
+extern crate quick_xml;
+extern crate rand;
 extern crate regex;
 extern crate reqwest;
 extern crate serde;
@@ -18,37 +24,65 @@ extern crate serde;
 extern crate serde_json;
 extern crate serde_urlencoded;
 extern crate serde_yaml;
+extern crate sha1;
 extern crate sha2;
 extern crate shellexpand;
+extern crate ssh2;
 extern crate tar;
 
-use std::fs::File;
+use std::fs::{self, File};
+use std::io;
 use std::os::unix::io::AsRawFd;
 use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 use nix::errno::Errno;
 use nix::fcntl::{self, FlockArg};
+use nix::unistd;
+use rand::Rng;
 
-mod check;
 #[macro_use] mod core;
+mod audit;
+mod bandwidth;
+mod check;
+mod clock;
+mod compressor;
 mod config;
+mod copy;
 mod encryptor;
+mod gc;
 mod hash;
 mod http_client;
+mod list;
+mod memory;
 mod metrics;
+mod notify;
 mod oauth;
+mod passphrase;
+mod pause;
+mod progress;
 mod provider;
 mod providers;
+mod restore;
+mod semaphore;
+mod state;
 mod storage;
 mod stream_splitter;
 mod sync;
 mod util;
+mod verify;
 
+use crate::config::Command;
 use crate::core::{EmptyResult, GenericResult};
 use crate::easy_logging::GlobalContext;
+use crate::providers::backblaze::Backblaze;
 use crate::providers::dropbox::Dropbox;
 use crate::providers::filesystem::Filesystem;
 use crate::providers::google_drive::GoogleDrive;
+use crate::providers::s3::{self, S3};
+use crate::providers::sftp::Sftp;
+use crate::providers::webdav::WebDav;
 use crate::storage::{Storage, BackupGroup};
 
 fn main() {
@@ -63,29 +97,190 @@ fn main() {
 
 fn run() -> GenericResult<i32> {
     let config = config::load();
+
+    if let Command::ConfigDump = config.command {
+        print!("{}", serde_yaml::to_string(&config).map_err(|e| format!(
+            "Failed to serialize the configuration: {}", e))?);
+        return Ok(0);
+    }
+
+    if let Some(ref redaction) = config.trace_redaction {
+        http_client::set_trace_redaction(redaction.parse()?);
+    }
+
+    // Listing is read-only, so it doesn't interfere with other runs
+    if let Command::List = config.command {
+        list_backups(&config)?;
+        return Ok(0);
+    }
+
     let _lock = acquire_lock(&config.path)?;
 
+    http_client::set_tcp_keepalive(config.tcp_keepalive);
+    if let Some(limit) = config.upload_bandwidth_limit {
+        bandwidth::init(limit);
+    }
+    if let Some(max) = config.max_gpg_processes {
+        encryptor::set_max_processes(max);
+    }
+    if let Some(max) = config.max_inflight_bytes {
+        memory::init(max);
+    }
+    pause::init()?;
+
+    if let Some(path) = config.audit_log.as_ref() {
+        audit::init(path).map_err(|e| format!("Unable to open {:?} audit log: {}", path, e))?;
+    }
+
+    if let Command::Copy = config.command {
+        copy_backups(&config)?;
+        return Ok(0);
+    }
+
+    if let Command::Restore = config.command {
+        restore_backup(&config)?;
+        return Ok(0);
+    }
+
+    run_backups(&config)
+}
+
+// Runs the command for each backup returning the exit code
+fn run_backups(config: &config::Config) -> GenericResult<i32> {
+    // Dry run shouldn't postpone the next real run
+    let run_time = SystemTime::now();
+    let state_file = match config.command {
+        Command::Sync if !config.dry_run => config.state_file.as_ref(),
+        _ => None,
+    };
+
+    if let Some(state_file) = state_file {
+        if !config.force && !check_run_interval(state_file, config.min_run_interval)? {
+            return Ok(0);
+        }
+    }
+
+    if let (Command::Sync, Some((min, max))) = (config.command, config.startup_jitter) {
+        sleep_jitter(min, max);
+    }
+
     let mut exit_code = 0;
+    let mut results = Vec::new();
 
     for backup in config.backups.iter() {
         let _context = GlobalContext::new(&backup.name);
 
-        if let Err(err) = sync_backups(backup) {
-            error!("Sync failed: {}.", err);
+        let result = match config.command {
+            Command::Sync => sync_backup(config, backup),
+            Command::Gc => {
+                let result = collect_garbage(config, backup);
+
+                if let Err(ref err) = result {
+                    error!("Garbage collection failed: {}.", err);
+                }
+
+                result
+            },
+            Command::Verify => {
+                let result = verify_backups(config, backup);
+
+                if let Err(ref err) = result {
+                    error!("Verification failed: {}.", err);
+                }
+
+                result
+            },
+            Command::Copy | Command::Restore | Command::List | Command::ConfigDump => unreachable!(),
+        };
+
+        if result.is_err() {
             exit_code = 1;
         }
+        results.push(notify::BackupResult::new(&backup.name, &result));
+
+        if exit_code != 0 && config.fail_fast {
+            break;
+        }
     }
 
-    if let Some(path) = config.prometheus_metrics.as_ref() {
+    if let (Command::Sync, Some(path)) = (config.command, config.prometheus_metrics.as_ref()) {
         if let Err(err) = metrics::save(path) {
             error!("Failed to save Prometheus metrics to {:?}: {}.", path, err);
             exit_code = 1;
         }
     }
 
+    if config.strict && exit_code == 0 {
+        let warnings = crate::core::get_warnings_count();
+        if warnings != 0 {
+            error!("Failing the run in strict mode: got {} warnings.", warnings);
+            exit_code = 1;
+        }
+    }
+
+    // Only successful syncs postpone the next one, so a failed sync is retried by the next scheduled run
+    if let (Some(state_file), 0) = (state_file, exit_code) {
+        if let Err(err) = state::save_last_run_time(state_file, run_time) {
+            error!("Failed to save the last run time to {:?}: {}.", state_file, err);
+            exit_code = 1;
+        }
+    }
+
+    if let Some(notify_command) = config.notify_command.as_ref() {
+        notify::notify(notify_command, config.command.name(), exit_code == 0, &results);
+    }
+
     Ok(exit_code)
 }
 
+// Saves a report for troubleshooting on sync failure and removes the stale one on success
+fn sync_backup(config: &config::Config, backup_config: &config::Backup) -> EmptyResult {
+    if let Some(url) = backup_config.healthcheck_url.as_ref() {
+        notify::ping_healthcheck_start(url);
+    }
+
+    let mut problems = Vec::new();
+    let result = sync_backups(config, backup_config, &mut problems);
+
+    if let Some(url) = backup_config.healthcheck_url.as_ref() {
+        notify::ping_healthcheck_result(url, &result);
+    }
+
+    if let Err(ref err) = result {
+        error!("Sync failed: {}.", err);
+        problems.push(err.to_string());
+    }
+
+    // All problems of the backup are reported by a single alert
+    if !problems.is_empty() {
+        notify::alert(&config.notifications, &backup_config.name, backup_config.provider.name(),
+                      &problems.join(". "));
+    }
+
+    if let Some(state_file) = config.state_file.as_ref() {
+        update_error_report(state_file, backup_config, &result);
+    }
+
+    result
+}
+
+fn update_error_report(state_file: &str, backup_config: &config::Backup, result: &EmptyResult) {
+    let path = state::get_error_report_path(state_file, &backup_config.name);
+
+    let result = match result {
+        Ok(()) => state::remove_error_report(&path),
+        Err(err) => serde_yaml::to_string(backup_config).map_err(Into::into).and_then(|config| {
+            state::save_error_report(&path, &format!(
+                "Time: {}\nError: {}\n\nConfiguration (secrets are redacted):\n{}\n",
+                chrono::Local::now().to_rfc3339(), err, config.trim_start_matches("---\n")))
+        }),
+    };
+
+    if let Err(err) = result {
+        error!("Failed to update {:?} error report: {}.", path, err);
+    }
+}
+
 fn acquire_lock(config_path: &str) -> GenericResult<File> {
     let file = File::open(config_path).map_err(|e| format!(
         "Unable to open {:?}: {}", config_path, e))?;
@@ -103,39 +298,259 @@ fn acquire_lock(config_path: &str) -> GenericResult<File> {
     Ok(file)
 }
 
-fn sync_backups(backup_config: &config::Backup) -> EmptyResult {
-    let local_storage = Storage::new_read_only(Filesystem::new(), &backup_config.src);
+fn check_run_interval(state_file: &str, min_run_interval: Option<Duration>) -> GenericResult<bool> {
+    let min_run_interval = match min_run_interval {
+        Some(interval) => interval,
+        None => return Ok(true),
+    };
+
+    let last_run_time = match state::get_last_run_time(state_file).map_err(|e| format!(
+        "Failed to read the last run time from {:?}: {}", state_file, e))? {
+        Some(time) => time,
+        None => return Ok(true),
+    };
+
+    // The last run time may be in the future if the clock has been moved backwards: don't let it
+    // block the syncs for longer than the interval.
+    let elapsed = match SystemTime::now().duration_since(last_run_time) {
+        Ok(elapsed) => elapsed,
+        Err(err) => {
+            if err.duration() >= min_run_interval {
+                return Ok(true);
+            }
+            Duration::from_secs(0)
+        },
+    };
+
+    if elapsed < min_run_interval {
+        info!(concat!(
+            "Skipping the sync: the previous successful one has been started {} seconds ago ",
+            "(use --force to override)."), elapsed.as_secs());
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+// Spreads the load on providers when the program is run by cron on many hosts at the same time
+fn sleep_jitter(min: Duration, max: Duration) {
+    if unistd::isatty(libc::STDIN_FILENO).unwrap_or(false) {
+        debug!("Skipping startup jitter for the interactive run.");
+        return;
+    }
+
+    let delay = Duration::from_secs(rand::thread_rng().gen_range(min.as_secs(), max.as_secs() + 1));
+    if delay.as_secs() != 0 {
+        info!("Sleeping for {} seconds of startup jitter...", delay.as_secs());
+        thread::sleep(delay);
+    }
+}
+
+// Problems which don't fail the sync are collected to the specified list
+fn sync_backups(
+    config: &config::Config, backup_config: &config::Backup, problems: &mut Vec<String>,
+) -> EmptyResult {
+    check_source(&backup_config.src)?;
+
+    let local_storage = Storage::new_read_only(
+        Filesystem::new(backup_config.include_hidden), &backup_config.src);
     let (local_backup_groups, local_ok) = get_backup_groups(&local_storage, true)?;
-    check::check_backups(&local_storage, &local_backup_groups,
-                         local_ok, backup_config.max_time_without_backups);
+    check_backups(backup_config, &local_storage, &local_backup_groups, local_ok, problems);
 
     if let Err(err) = metrics::collect(&backup_config.name, &local_backup_groups) {
         error!("Failed to collect metrics: {}.", err);
     }
 
-    let mut cloud_storage = match backup_config.provider {
-        config::Provider::Dropbox {ref client_id, ref client_secret, ref refresh_token} =>
-            Storage::new(Dropbox::new(&client_id, &client_secret, &refresh_token)?, &backup_config.dst),
-        config::Provider::GoogleDrive {ref client_id, ref client_secret, ref refresh_token} =>
-            Storage::new(GoogleDrive::new(&client_id, &client_secret, &refresh_token), &backup_config.dst),
-    };
+    let mut cloud_storage = get_cloud_storage(config, backup_config)?;
     let (cloud_backup_groups, cloud_ok) = get_backup_groups(&cloud_storage, false)?;
 
     info!("Syncing...");
     let sync_ok = sync::sync_backups(
         &local_storage, &local_backup_groups,
         &mut cloud_storage, &cloud_backup_groups, local_ok && cloud_ok,
-        backup_config.max_backup_groups, &backup_config.encryption_passphrase);
+        backup_config, config.fail_fast, config.confirm_large_prune, config.dry_run);
 
-    let (cloud_backup_groups, cloud_ok) = match get_backup_groups(&cloud_storage, false) {
-        Ok(result) => result,
-        Err(err) => {
-            error!("Unable to check backups on {}: {}.", cloud_storage.name(), err);
-            return Ok(());
+    if !sync_ok && config.fail_fast {
+        return Err!("Aborting on the errors above");
+    }
+
+    match get_backup_groups(&cloud_storage, false) {
+        Ok((cloud_backup_groups, cloud_ok)) => check_backups(
+            backup_config, &cloud_storage, &cloud_backup_groups, sync_ok && cloud_ok, problems),
+        Err(err) => error!("Unable to check backups on {}: {}.", cloud_storage.name(), err),
+    }
+
+    if !sync_ok {
+        return Err!("The sync has failed: see the errors above");
+    }
+
+    Ok(())
+}
+
+fn check_backups(
+    backup_config: &config::Backup, storage: &Storage, backup_groups: &[BackupGroup], consistent: bool,
+    problems: &mut Vec<String>,
+) {
+    if let Err(err) = check::check_backups(storage, backup_groups, consistent, |group_name| {
+        backup_config.get_max_time_without_backups(group_name)
+    }) {
+        error!("{}.", err);
+        problems.push(err.to_string());
+    }
+}
+
+fn collect_garbage(config: &config::Config, backup_config: &config::Backup) -> EmptyResult {
+    let mut cloud_storage = get_cloud_storage(config, backup_config)?;
+    gc::collect_garbage(&mut cloud_storage, config.dry_run)
+}
+
+fn verify_backups(config: &config::Config, backup_config: &config::Backup) -> EmptyResult {
+    let cloud_storage = get_cloud_storage(config, backup_config)?;
+    verify::verify_backups(&cloud_storage, backup_config.verify_concurrency)
+}
+
+fn copy_backups(config: &config::Config) -> EmptyResult {
+    let options = config.copy_options.as_ref().unwrap();
+
+    let get_backup_config = |name: &str| {
+        config.backups.iter().find(|backup| backup.name == name).ok_or_else(|| format!(
+            "Unknown backup: {:?}", name))
+    };
+
+    if options.from == options.to {
+        return Err!("Source and destination backups must be different");
+    }
+
+    let src_backup_config = get_backup_config(&options.from)?;
+    let dst_backup_config = get_backup_config(&options.to)?;
+
+    let src_storage = get_cloud_storage(config, src_backup_config)?;
+    let mut dst_storage = get_cloud_storage(config, dst_backup_config)?;
+
+    copy::copy_backups(&src_storage, &mut dst_storage)
+}
+
+fn restore_backup(config: &config::Config) -> EmptyResult {
+    let options = config.restore_options.as_ref().unwrap();
+
+    let backup_config = config.backups.iter().find(|backup| backup.name == options.from).ok_or_else(|| {
+        format!("Unknown backup: {:?}", options.from)
+    })?;
+
+    let passphrase;
+    let mut encryption = backup_config.encryption_options();
+
+    // In recipients mode the secret key is taken from gpg's keyring
+    if let encryptor::Encryption::Passphrase(configured_passphrase) = encryption.encryption {
+        passphrase = match backup_config.read_passphrase()? {
+            Some(passphrase) => passphrase,
+            None if configured_passphrase.is_empty() => passphrase::read_interactively(&backup_config.name, false)?,
+            None => configured_passphrase.to_owned(),
+        };
+        encryption.encryption = encryptor::Encryption::Passphrase(&passphrase);
+    }
+
+    let storage = get_cloud_storage(config, backup_config)?;
+
+    restore::restore_backup(&storage, &options.group, &options.name, encryption, &options.to)
+}
+
+fn list_backups(config: &config::Config) -> EmptyResult {
+    let options = config.list_options.as_ref().unwrap();
+
+    let backup_config = config.backups.iter().find(|backup| backup.name == options.backup).ok_or_else(|| {
+        format!("Unknown backup: {:?}", options.backup)
+    })?;
+
+    let storage = if options.local {
+        Storage::new_read_only(Filesystem::new(backup_config.include_hidden), &backup_config.src)
+    } else {
+        get_cloud_storage(config, backup_config)?
+    };
+
+    list::list_backups(&storage)
+}
+
+fn get_cloud_storage(config: &config::Config, backup_config: &config::Backup) -> GenericResult<Storage> {
+    let client_options = backup_config.client_options();
+
+    let storage = match backup_config.provider {
+        config::Provider::Dropbox {
+            ref client_id, ref client_secret, ref refresh_token, ref headers, max_chunk_retries, chunk_size,
+        } => {
+            let mut provider = Dropbox::new(&client_id, &client_secret, &refresh_token)?
+                .with_headers(http_client::parse_headers(headers)?)
+                .with_max_chunk_retries(max_chunk_retries)
+                .with_client_options(client_options);
+            if let Some(chunk_size) = chunk_size {
+                provider = provider.with_chunk_size(chunk_size);
+            }
+            Storage::new(provider, &backup_config.dst)
         },
+        config::Provider::GoogleDrive {ref client_id, ref client_secret, ref refresh_token, ref headers} => {
+            let provider = GoogleDrive::new(&client_id, &client_secret, &refresh_token)
+                .with_headers(http_client::parse_headers(headers)?)
+                .with_client_options(client_options);
+            Storage::new(provider, &backup_config.dst)
+        },
+        config::Provider::S3 {
+            ref bucket, ref region, ref endpoint, ref access_key_id, ref secret_access_key, ref object_lock,
+        } => {
+            let mut provider = S3::new(
+                bucket, region, endpoint.as_deref(), access_key_id, secret_access_key)?
+                .with_client_options(client_options);
+            if let Some(object_lock) = object_lock {
+                let mode = match object_lock.mode {
+                    config::ObjectLockMode::Governance => s3::ObjectLockMode::Governance,
+                    config::ObjectLockMode::Compliance => s3::ObjectLockMode::Compliance,
+                };
+                provider = provider.with_object_lock(mode, object_lock.retain_days);
+            }
+            Storage::new(provider, &backup_config.dst)
+        },
+        config::Provider::Backblaze {ref key_id, ref application_key, ref bucket_id} => {
+            let provider = Backblaze::new(key_id, application_key, bucket_id)
+                .with_client_options(client_options);
+            Storage::new(provider, &backup_config.dst)
+        },
+        config::Provider::WebDav {ref url, ref user, ref password} => {
+            let provider = WebDav::new(url, user, password)?.with_client_options(client_options);
+            Storage::new(provider, &backup_config.dst)
+        },
+        config::Provider::Sftp {ref host, port, ref user, ref key_file, ref base_dir} => {
+            let provider = Sftp::new(host, port, user, key_file, base_dir)
+                .with_timeouts(backup_config.timeouts());
+            Storage::new(provider, &backup_config.dst)
+        },
+        config::Provider::Filesystem {fsync} =>
+            Storage::new(Filesystem::new_destination(fsync), &backup_config.dst),
     };
-    check::check_backups(&cloud_storage, &cloud_backup_groups,
-                         sync_ok && cloud_ok, backup_config.max_time_without_backups);
+
+    Ok(storage
+        .with_list_concurrency(backup_config.list_concurrency)
+        .with_upload_verification(backup_config.verify_after_upload)
+        .with_corruption_action(backup_config.on_corruption)
+        .with_integrity_key(backup_config.integrity_key.as_deref())
+        .with_delete_fence(&config.allowed_dst_prefixes)
+        .with_backup_name(&backup_config.name))
+}
+
+fn check_source(path: &str) -> EmptyResult {
+    // Check it explicitly before any cloud interaction: an unmounted source must never be treated
+    // as an empty one.
+    match fs::metadata(path) {
+        Ok(metadata) => {
+            if !metadata.is_dir() {
+                return Err!("Source {:?} is not a directory", path);
+            }
+        },
+        Err(err) => {
+            if err.kind() == io::ErrorKind::NotFound {
+                return Err!("Source {:?} does not exist or is not mounted", path);
+            }
+            return Err!("Unable to access source {:?}: {}", path, err);
+        },
+    }
 
     Ok(())
 }
@@ -152,12 +567,463 @@ fn get_backup_groups(storage: &Storage, verify: bool) -> GenericResult<(Vec<Back
             debug!("Backup groups on {}:", storage.name());
             for group in &groups {
                 let backup_names = group.backups.iter()
-                    .map(|backup| backup.name.as_str())
-                    .collect::<Vec<&str>>().join(", ");
+                    .map(|backup| match backup.size {
+                        Some(size) => format!("{} ({})", backup.name, util::format_size(size)),
+                        None => backup.name.clone(),
+                    })
+                    .collect::<Vec<String>>().join(", ");
                 debug!("{}: {}", group.name, backup_names);
             }
         }
     }
 
     Ok((groups, ok))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn source_check() {
+        let path = TempDir::new("source_check");
+        let path_str = path.0.to_str().unwrap();
+
+        let missing_path = path.0.join("missing");
+        let err = check_source(missing_path.to_str().unwrap()).unwrap_err().to_string();
+        assert!(err.contains("does not exist or is not mounted"), "{}", err);
+
+        // The first run has nothing to back up yet
+        check_source(path_str).unwrap();
+
+        let file_path = path.0.join("file");
+        fs::write(&file_path, "").unwrap();
+        let err = check_source(file_path.to_str().unwrap()).unwrap_err().to_string();
+        assert!(err.contains("is not a directory"), "{}", err);
+
+        check_source(path_str).unwrap();
+    }
+
+    #[test]
+    fn run_interval() {
+        let path = TempDir::new("run_interval");
+        let state_file = path.0.join("state");
+        let state_file = state_file.to_str().unwrap();
+        let interval = Some(Duration::from_secs(60 * 60));
+
+        assert!(check_run_interval(state_file, interval).unwrap());
+        assert!(check_run_interval(state_file, None).unwrap());
+
+        state::save_last_run_time(state_file, SystemTime::now() - Duration::from_secs(60)).unwrap();
+        assert!(!check_run_interval(state_file, interval).unwrap());
+        assert!(check_run_interval(state_file, Some(Duration::from_secs(30))).unwrap());
+        assert!(check_run_interval(state_file, None).unwrap());
+
+        // The clock has been moved backwards
+        state::save_last_run_time(state_file, SystemTime::now() + Duration::from_secs(2 * 60 * 60)).unwrap();
+        assert!(check_run_interval(state_file, interval).unwrap());
+    }
+
+    #[test]
+    fn failed_sync_run_time() {
+        let path = TempDir::new("failed_sync_run_time");
+        let state_file = path.0.join("state");
+        let config = get_sync_config(&path, "", &format!(
+            "state_file: {:?}, min_run_interval: 1h", state_file));
+        let state_file = state_file.to_str().unwrap();
+
+        // A failed sync doesn't postpone the next one
+        fs::write(path.0.join("dst/2020.01.01"), "").unwrap();
+        assert_eq!(run_backups(&config).unwrap(), 1);
+        assert_eq!(state::get_last_run_time(state_file).unwrap(), None);
+
+        fs::remove_file(path.0.join("dst/2020.01.01")).unwrap();
+        assert_eq!(run_backups(&config).unwrap(), 0);
+        assert!(state::get_last_run_time(state_file).unwrap().is_some());
+        assert!(path.0.join("dst/2020.01.01").exists());
+
+        // The next run is skipped
+        fs::remove_dir_all(path.0.join("dst/2020.01.01")).unwrap();
+        assert_eq!(run_backups(&config).unwrap(), 0);
+        assert!(!path.0.join("dst/2020.01.01").exists());
+    }
+
+    #[test]
+    fn strict_mode() {
+        let path = TempDir::new("strict_mode");
+        let state_file = path.0.join("state");
+        let mut config = get_sync_config(&path, "", &format!("state_file: {:?}", state_file));
+        let state_file = state_file.to_str().unwrap();
+
+        // Warnings are counted for the whole process, so make sure there is at least one
+        let warnings = crate::core::get_warnings_count();
+        warning!("Strict mode test warning.");
+        assert!(crate::core::get_warnings_count() > warnings);
+
+        config.strict = true;
+        assert_eq!(run_backups(&config).unwrap(), 1);
+        assert!(path.0.join("dst/2020.01.01").exists());
+        assert_eq!(state::get_last_run_time(state_file).unwrap(), None);
+
+        config.strict = false;
+        assert_eq!(run_backups(&config).unwrap(), 0);
+        assert!(state::get_last_run_time(state_file).unwrap().is_some());
+    }
+
+    #[test]
+    fn error_report() {
+        let path = TempDir::new("error_report");
+        let state_file = path.0.join("state");
+        let config = get_sync_config(&path, "", &format!("state_file: {:?}", state_file));
+        let report_path = state::get_error_report_path(state_file.to_str().unwrap(), "test");
+
+        fs::write(path.0.join("dst/2020.01.01"), "").unwrap();
+        sync_backup(&config, &config.backups[0]).unwrap_err();
+
+        let report = fs::read_to_string(&report_path).unwrap();
+        assert!(report.contains("Error: The sync has failed"), "{}", report);
+        assert!(report.contains(r#"encryption_passphrase: "<redacted>""#), "{}", report);
+        assert!(!report.contains(": secret"), "{}", report);
+
+        fs::remove_file(path.0.join("dst/2020.01.01")).unwrap();
+        sync_backup(&config, &config.backups[0]).unwrap();
+        assert!(!report_path.exists());
+    }
+
+    #[test]
+    fn notify_command() {
+        let path = TempDir::new("notify_command");
+        let (report_path, env_path) = (path.0.join("report.json"), path.0.join("env"));
+
+        let notify_command = format!(
+            "cat > {:?} && echo $PYVSB_COMMAND $PYVSB_STATUS $PYVSB_FAILED_BACKUPS > {:?}",
+            report_path, env_path);
+        let mut config = get_sync_config(&path, "", &format!("notify_command: {:?}", notify_command));
+
+        fs::write(path.0.join("dst/2020.01.01"), "").unwrap();
+        assert_eq!(run_backups(&config).unwrap(), 1);
+
+        let report: serde_json::Value = serde_json::from_slice(&fs::read(&report_path).unwrap()).unwrap();
+        assert_eq!(report["command"], "sync");
+        assert_eq!(report["success"], false);
+        assert_eq!(report["backups"][0]["name"], "test");
+        assert_eq!(report["backups"][0]["success"], false);
+        assert!(report["backups"][0]["error"].as_str().unwrap().contains("The sync has failed"), "{}", report);
+        assert_eq!(fs::read_to_string(&env_path).unwrap(), "sync failure test\n");
+
+        fs::remove_file(path.0.join("dst/2020.01.01")).unwrap();
+        assert_eq!(run_backups(&config).unwrap(), 0);
+
+        let report: serde_json::Value = serde_json::from_slice(&fs::read(&report_path).unwrap()).unwrap();
+        assert_eq!(report["success"], true);
+        assert_eq!(report["backups"][0]["success"], true);
+        assert!(report["backups"][0].get("error").is_none());
+        assert_eq!(fs::read_to_string(&env_path).unwrap(), "sync success\n");
+
+        // The command's failures don't affect the run result
+        config.notify_command = Some("echo Notification error >&2; exit 1".to_owned());
+        assert_eq!(run_backups(&config).unwrap(), 0);
+    }
+
+    #[test]
+    fn sync_failure_alert() {
+        let server = TestServer::new(|_| TestResponse::new(200));
+
+        let path = TempDir::new("sync_failure_alert");
+        let src = path.0.join("missing");
+
+        let config: config::Config = serde_yaml::from_str(&format!(concat!(
+            "{{backups: [{{name: test, src: {:?}, dst: /dst, provider: {{name: dropbox, client_id: id, ",
+            "client_secret: secret, refresh_token: token}}}}], notifications: [{{type: webhook, url: {:?}}}]}}",
+        ), src.to_str().unwrap(), server.url)).unwrap();
+
+        let err = sync_backup(&config, &config.backups[0]).unwrap_err().to_string();
+        assert!(err.contains("does not exist or is not mounted"), "{}", err);
+
+        // The alert is sent synchronously, so it has been already received
+        let requests = server.stop();
+        assert_eq!(requests.len(), 1);
+
+        let alert = requests[0].json();
+        assert_eq!(alert["backup"], "test");
+        assert_eq!(alert["provider"], "dropbox");
+        assert_eq!(alert["error"], err.as_str());
+        assert!(chrono::DateTime::parse_from_rfc3339(alert["timestamp"].as_str().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn upload_failure_alert() {
+        let server = TestServer::new(|_| TestResponse::new(200));
+
+        let path = TempDir::new("upload_failure_alert");
+        let state_file = path.0.join("state");
+        let config = get_sync_config(&path, "", &format!(
+            "notifications: [{{type: webhook, url: {:?}}}], state_file: {:?}", server.url, state_file));
+
+        // The backup group can't be created on the destination
+        fs::write(path.0.join("dst/2020.01.01"), "").unwrap();
+
+        let err = sync_backup(&config, &config.backups[0]).unwrap_err().to_string();
+        assert_eq!(err, "The sync has failed: see the errors above");
+
+        let requests = server.stop();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].json()["error"].as_str().unwrap().ends_with(&err));
+
+        let report_path = state::get_error_report_path(state_file.to_str().unwrap(), "test");
+        assert!(fs::read_to_string(report_path).unwrap().contains(&err));
+    }
+
+    #[test]
+    fn healthcheck_pings() {
+        let server = TestServer::new(|_| TestResponse::new(200));
+        let url = format!("{}/check", server.url);
+
+        let path = TempDir::new("healthcheck_pings");
+        let config = get_sync_config(&path, &format!("healthcheck_url: {:?}", url), "");
+
+        sync_backup(&config, &config.backups[0]).unwrap();
+        assert!(path.0.join("dst/2020.01.01").exists());
+
+        fs::remove_dir_all(path.0.join("dst/2020.01.01")).unwrap();
+        fs::write(path.0.join("dst/2020.01.01"), "").unwrap();
+        sync_backup(&config, &config.backups[0]).unwrap_err();
+
+        let requests: Vec<String> = server.stop().iter().map(|request| format!(
+            "{} {}", request.method, request.path)).collect();
+
+        assert_eq!(requests, vec![
+            "POST /check/start", "POST /check",
+            "POST /check/start", "POST /check/fail",
+        ]);
+    }
+
+    // Creates a source with one backup and an empty filesystem destination for it
+    fn get_sync_config(path: &TempDir, backup_options: &str, options: &str) -> config::Config {
+        let (src, dst) = (path.0.join("src"), path.0.join("dst"));
+
+        let backup_path = src.join("2020.01.01/2020.01.01-00:00:00");
+        fs::create_dir_all(&backup_path).unwrap();
+        fs::write(backup_path.join("metadata.bz2"), get_backup_metadata()).unwrap();
+        fs::write(backup_path.join("data.tar.gz"), "data").unwrap();
+        fs::create_dir(&dst).unwrap();
+
+        serde_yaml::from_str(&format!(concat!(
+            "{{backups: [{{name: test, src: {:?}, dst: {:?}, provider: {{name: filesystem}}, ",
+            "max_backup_groups: 1, encryption_passphrase: secret, {}}}], {}}}",
+        ), src, dst, backup_options, options)).unwrap()
+    }
+
+    // Returns pyvsb metadata of a backup with a single unique file
+    pub fn get_backup_metadata() -> Vec<u8> {
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        encoder.write_all(b"d41d8cd98f00b204e9800998ecf8427e unique 1577836800:4 data\n").unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[derive(Clone)]
+    pub struct TestRequest {
+        pub method: String,
+        pub path: String,
+        pub headers: HashMap<String, String>,
+        pub body: Vec<u8>,
+    }
+
+    impl TestRequest {
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers.get(name).map(String::as_str)
+        }
+
+        pub fn json(&self) -> serde_json::Value {
+            serde_json::from_slice(&self.body).unwrap()
+        }
+    }
+
+    pub struct TestResponse {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl TestResponse {
+        pub fn new(status: u16) -> TestResponse {
+            TestResponse {status, headers: Vec::new(), body: Vec::new()}
+        }
+
+        pub fn with_header(mut self, name: &str, value: &str) -> TestResponse {
+            self.headers.push((name.to_owned(), value.to_owned()));
+            self
+        }
+
+        pub fn with_body(self, content_type: &str, body: &[u8]) -> TestResponse {
+            let mut response = self.with_header("Content-Type", content_type);
+            response.body = body.to_vec();
+            response
+        }
+    }
+
+    // HTTP server on a random local port. Each connection is handled by its own thread and serves a single
+    // request, so the server may be used concurrently.
+    pub struct TestServer {
+        pub url: String,
+        stop: Arc<AtomicBool>,
+        requests: Arc<Mutex<Vec<TestRequest>>>,
+        thread: Option<thread::JoinHandle<()>>,
+    }
+
+    impl TestServer {
+        pub fn new<F>(handler: F) -> TestServer
+            where F: Fn(&TestRequest) -> TestResponse + Send + Sync + 'static
+        {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            listener.set_nonblocking(true).unwrap();
+
+            let stop = Arc::new(AtomicBool::new(false));
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let handler = Arc::new(handler);
+
+            let thread = {
+                let stop = stop.clone();
+                let requests = requests.clone();
+
+                thread::spawn(move || {
+                    let mut connections = Vec::new();
+
+                    while !stop.load(Ordering::SeqCst) {
+                        let (connection, _) = match listener.accept() {
+                            Ok(connection) => connection,
+                            Err(_) => {
+                                thread::sleep(Duration::from_millis(10));
+                                continue;
+                            },
+                        };
+
+                        let handler = handler.clone();
+                        let requests = requests.clone();
+
+                        connections.push(thread::spawn(move || {
+                            handle_request(connection, handler.as_ref(), &requests);
+                        }));
+                    }
+
+                    for connection in connections {
+                        connection.join().unwrap();
+                    }
+                })
+            };
+
+            TestServer {url, stop, requests, thread: Some(thread)}
+        }
+
+        // Returns all received requests in the order they have been handled
+        pub fn stop(mut self) -> Vec<TestRequest> {
+            self.shutdown();
+            self.requests.lock().unwrap().clone()
+        }
+
+        fn shutdown(&mut self) {
+            self.stop.store(true, Ordering::SeqCst);
+            if let Some(thread) = self.thread.take() {
+                thread.join().unwrap();
+            }
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            if !thread::panicking() {
+                self.shutdown();
+            }
+        }
+    }
+
+    fn handle_request<F: Fn(&TestRequest) -> TestResponse>(
+        mut connection: TcpStream, handler: &F, requests: &Mutex<Vec<TestRequest>>,
+    ) {
+        connection.set_nonblocking(false).unwrap();
+        let mut reader = BufReader::new(connection.try_clone().unwrap());
+
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap().to_owned();
+        let path = parts.next().unwrap().to_owned();
+
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+
+            let (name, value) = line.split_at(line.find(':').unwrap());
+            headers.insert(name.to_lowercase(), value[1..].trim().to_owned());
+        }
+
+        let mut body = Vec::new();
+        if headers.get("transfer-encoding").map(String::as_str) == Some("chunked") {
+            loop {
+                let mut size = String::new();
+                reader.read_line(&mut size).unwrap();
+                let size = usize::from_str_radix(size.trim(), 16).unwrap();
+
+                let mut chunk = vec![0; size + 2];
+                reader.read_exact(&mut chunk).unwrap();
+                if size == 0 {
+                    break;
+                }
+                body.extend_from_slice(&chunk[..size]);
+            }
+        } else if let Some(size) = headers.get("content-length") {
+            body.resize(size.parse().unwrap(), 0);
+            reader.read_exact(&mut body).unwrap();
+        }
+
+        let request = TestRequest {method, path, headers, body};
+        let mut response = handler(&request);
+        if request.method == "HEAD" {
+            response.body.clear();
+        }
+        requests.lock().unwrap().push(request);
+
+        let status = http_client::StatusCode::from_u16(response.status).unwrap();
+        let mut data = format!("HTTP/1.1 {} {}\r\n", status.as_u16(), status.canonical_reason().unwrap_or(""));
+        for (name, value) in &response.headers {
+            data += &format!("{}: {}\r\n", name, value);
+        }
+        if !response.headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("Content-Length")) {
+            data += &format!("Content-Length: {}\r\n", response.body.len());
+        }
+        data += "Connection: close\r\n\r\n";
+
+        // The client may close the connection without reading the whole response
+        let _ = connection.write_all(data.as_bytes()).and_then(|_| connection.write_all(&response.body));
+    }
+
+    pub struct TempDir(pub PathBuf);
+
+    impl TempDir {
+        pub fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!("pyvsb-to-cloud-{}-{}", name, process::id()));
+            fs::create_dir(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+}
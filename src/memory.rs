@@ -0,0 +1,85 @@
+use std::sync::{Arc, Mutex};
+
+use crate::semaphore::{Semaphore, SemaphorePermit};
+
+lazy_static! {
+    static ref BUDGET: Mutex<Option<MemoryBudget>> = Mutex::new(None);
+}
+
+// Limits the total size of upload data buffered in memory: the budget is shared by all concurrent uploads
+pub fn init(max_bytes: u64) {
+    BUDGET.lock().unwrap().replace(MemoryBudget::new(max_bytes));
+}
+
+// Blocks until a buffer of the specified maximum size is allowed to be allocated. The reservation is
+// released on drop.
+pub fn reserve(size: u64) -> Option<SemaphorePermit> {
+    let budget = BUDGET.lock().unwrap().clone();
+    budget.map(|budget| budget.reserve(size))
+}
+
+#[derive(Clone)]
+struct MemoryBudget {
+    semaphore: Arc<Semaphore>,
+    size: u64,
+}
+
+impl MemoryBudget {
+    fn new(size: u64) -> MemoryBudget {
+        MemoryBudget {
+            semaphore: Semaphore::new(size as usize),
+            size,
+        }
+    }
+
+    // Buffers larger than the whole budget reserve all of it, so they are buffered one at a time instead
+    // of blocking forever. The caller must not hold other reservations while waiting for a new one.
+    fn reserve(&self, size: u64) -> SemaphorePermit {
+        self.semaphore.acquire_many(size.min(self.size) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn budget() {
+        let budget = MemoryBudget::new(100);
+        let reserved = Arc::new(AtomicU64::new(0));
+        let max_reserved = Arc::new(AtomicU64::new(0));
+        let buffered = Arc::new(AtomicU64::new(0));
+
+        let threads: Vec<_> = [30, 40, 50, 60, 200, 10, 70, 100].iter().map(|&size| {
+            let budget = budget.clone();
+            let reserved = reserved.clone();
+            let max_reserved = max_reserved.clone();
+            let buffered = buffered.clone();
+
+            thread::spawn(move || {
+                for _ in 0..3 {
+                    let _reservation = budget.reserve(size);
+
+                    let size = size.min(100);
+                    let current = reserved.fetch_add(size, Ordering::SeqCst) + size;
+                    max_reserved.fetch_max(current, Ordering::SeqCst);
+
+                    thread::sleep(Duration::from_millis(10));
+                    buffered.fetch_add(size, Ordering::SeqCst);
+                    reserved.fetch_sub(size, Ordering::SeqCst);
+                }
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert!(max_reserved.load(Ordering::SeqCst) <= 100);
+        assert_eq!(buffered.load(Ordering::SeqCst), 3 * (30 + 40 + 50 + 60 + 100 + 10 + 70 + 100));
+    }
+}
@@ -1,7 +1,7 @@
 use std::io::{BufWriter, Write};
 use std::fs::{self, File};
 
-use prometheus::{self, TextEncoder, Encoder, GaugeVec};
+use prometheus::{self, TextEncoder, Encoder, GaugeVec, IntCounterVec};
 
 use crate::core::{EmptyResult, GenericError};
 use crate::storage::BackupGroup;
@@ -12,11 +12,21 @@ lazy_static! {
 
     static ref SIZE: GaugeVec = register("size", "Last backup size.");
     static ref TOTAL_SIZE: GaugeVec = register("total_size", "Total size of all backups.");
+    static ref GROUP_SIZE: GaugeVec = register_gauge_vec!(
+        "backup_group_size", "Total size of backups in the backup group.", &["name", "group"]).unwrap();
+
+    static ref PROVIDER_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "pyvsb_provider_requests_total", "Number of API requests sent to cloud providers.",
+        &["provider", "endpoint", "result"]).unwrap();
+    static ref PROVIDER_RETRIES: IntCounterVec = register_int_counter_vec!(
+        "pyvsb_provider_retries_total", "Number of retried API requests to cloud providers.",
+        &["provider", "endpoint"]).unwrap();
 }
 
 pub fn collect(name: &str, groups: &[BackupGroup]) -> EmptyResult {
     collect_last_backup(name, groups)?;
     collect_total(name, groups)?;
+    collect_groups(name, groups)?;
     Ok(())
 }
 
@@ -87,6 +97,31 @@ fn collect_total(name: &str, groups: &[BackupGroup]) -> EmptyResult {
     Ok(())
 }
 
+// Per-group sizes allow to observe the backups growth rate over time
+fn collect_groups(name: &str, groups: &[BackupGroup]) -> EmptyResult {
+    for group in groups {
+        let mut size = 0;
+
+        for backup in &group.backups {
+            let stat = backup.outer_stat.as_ref().ok_or(
+                "The backup has no collected statistics")?;
+            size += stat.metadata_size + stat.data_size;
+        }
+
+        GROUP_SIZE.with_label_values(&[name, &group.name]).set(size as f64);
+    }
+
+    Ok(())
+}
+
+pub fn count_provider_request(provider: &str, endpoint: &str, result: &str) {
+    PROVIDER_REQUESTS.with_label_values(&[provider, endpoint, result]).inc();
+}
+
+pub fn count_provider_retry(provider: &str, endpoint: &str) {
+    PROVIDER_RETRIES.with_label_values(&[provider, endpoint]).inc();
+}
+
 pub fn save(path: &str) -> EmptyResult {
     let encoder = TextEncoder::new();
     let metrics = prometheus::gather();
@@ -109,4 +144,43 @@ pub fn save(path: &str) -> EmptyResult {
 
 fn register(name: &str, help: &str) -> GaugeVec {
     register_gauge_vec!(&format!("backup_{}", name), help, &["name", "type"]).unwrap()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::provider::ProviderType;
+    use crate::providers::mock::MockProvider;
+    use crate::storage::Storage;
+    use crate::tests::get_backup_metadata;
+
+    use super::*;
+
+    #[test]
+    fn group_sizes() {
+        let metadata = get_backup_metadata();
+        let provider = MockProvider::new(ProviderType::Local);
+        provider.add_directory("/src");
+
+        for &(group_name, backups) in &[("2020.01.01", 1), ("2020.01.02", 2)] {
+            provider.add_directory(&format!("/src/{}", group_name));
+
+            for backup_id in 0..backups {
+                let backup_path = format!("/src/{0}/{0}-00:00:0{1}", group_name, backup_id);
+                provider.add_directory(&backup_path);
+                provider.add_file(&format!("{}/metadata.bz2", backup_path), &metadata);
+                provider.add_file(&format!("{}/data.tar.gz", backup_path), b"data");
+            }
+        }
+
+        let (groups, ok) = Storage::new_read_only(provider, "/src").get_backup_groups(true).unwrap();
+        assert!(ok);
+        collect("metrics-test", &groups).unwrap();
+
+        let backup_size = (metadata.len() + 4) as f64;
+        assert_eq!(GROUP_SIZE.with_label_values(&["metrics-test", "2020.01.01"]).get(), backup_size);
+        assert_eq!(GROUP_SIZE.with_label_values(&["metrics-test", "2020.01.02"]).get(), 2.0 * backup_size);
+        assert_eq!(
+            TOTAL_SIZE.with_label_values(&["metrics-test", "metadata"]).get() +
+            TOTAL_SIZE.with_label_values(&["metrics-test", "data"]).get(), 3.0 * backup_size);
+    }
+}
@@ -0,0 +1,218 @@
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use chrono::Local;
+
+use crate::config::Notification;
+use crate::core::{EmptyResult, GenericResult};
+use crate::http_client::{
+    HttpClient, HttpRequest, HttpResponse, Method, StatusCode, ResponseReader, RawResponseReader,
+};
+
+const WEBHOOK_REQUEST_TIMEOUT: u64 = 60;
+// Pings mustn't delay the backups
+const HEALTHCHECK_REQUEST_TIMEOUT: u64 = 10;
+
+#[derive(Serialize)]
+pub struct BackupResult {
+    name: String,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl BackupResult {
+    pub fn new(name: &str, result: &EmptyResult) -> BackupResult {
+        BackupResult {
+            name: name.to_owned(),
+            success: result.is_ok(),
+            error: result.as_ref().err().map(ToString::to_string),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    command: &'a str,
+    success: bool,
+    backups: &'a [BackupResult],
+}
+
+// Runs the notification command passing it the run report as JSON via stdin. The command's failures
+// are logged, but don't affect the run result.
+pub fn notify(notify_command: &str, command: &str, success: bool, results: &[BackupResult]) {
+    debug!("Running the notification command...");
+
+    if let Err(err) = run(notify_command, command, success, results) {
+        error!("Notification command has failed: {}.", err);
+    }
+}
+
+fn run(notify_command: &str, command: &str, success: bool, results: &[BackupResult]) -> EmptyResult {
+    let report = serde_json::to_string(&Report {command, success, backups: results})?;
+    let failed_backups = results.iter()
+        .filter(|result| !result.success)
+        .map(|result| result.name.as_str())
+        .collect::<Vec<_>>().join(",");
+
+    let mut process = Command::new("sh")
+        .arg("-c").arg(notify_command)
+        .env("PYVSB_COMMAND", command)
+        .env("PYVSB_STATUS", if success {"success"} else {"failure"})
+        .env("PYVSB_FAILED_BACKUPS", failed_backups)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn().map_err(|e| format!("Unable to spawn a process: {}", e))?;
+
+    // The report is small enough to not deadlock on the output pipes
+    let write_result = write_input(&mut process, &report);
+    let output = process.wait_with_output()?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !stdout.trim().is_empty() {
+        debug!("Notification command output:\n{}", stdout.trim_end());
+    }
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return match stderr.trim() {
+            "" => Err!("{}", output.status),
+            stderr => Err!("{}: {}", output.status, stderr),
+        };
+    }
+
+    write_result
+}
+
+#[derive(Serialize)]
+struct Alert<'a> {
+    backup: &'a str,
+    provider: &'a str,
+    error: &'a str,
+    timestamp: String,
+}
+
+// Sends the alert to all configured notification channels. Failures are logged, but don't affect the
+// run result.
+pub fn alert(notifications: &[Notification], backup: &str, provider: &str, error: &str) {
+    let alert = Alert {backup, provider, error, timestamp: Local::now().to_rfc3339()};
+
+    for notification in notifications {
+        debug!("Sending {} notification...", notification.name());
+
+        let result = match notification {
+            Notification::Webhook {url} => send_webhook(url, &alert),
+            Notification::Email {to, sendmail} => send_email(sendmail, to, &alert),
+        };
+
+        if let Err(err) = result {
+            error!("Failed to send {} notification: {}.", notification.name(), err);
+        }
+    }
+}
+
+fn send_webhook(url: &str, alert: &Alert) -> EmptyResult {
+    let request = HttpRequest::new(
+        Method::POST, url.to_owned(), Duration::from_secs(WEBHOOK_REQUEST_TIMEOUT),
+        RawResponseReader::new(), StatusErrorReader {},
+    ).with_json(alert)?;
+
+    HttpClient::new().send(request)?;
+    Ok(())
+}
+
+pub fn ping_healthcheck_start(url: &str) {
+    ping_healthcheck(&format!("{}/start", url.trim_end_matches('/')), "");
+}
+
+// The error is passed to the failure endpoint as the ping body
+pub fn ping_healthcheck_result(url: &str, result: &EmptyResult) {
+    match result {
+        Ok(()) => ping_healthcheck(url, ""),
+        Err(err) => ping_healthcheck(&format!("{}/fail", url.trim_end_matches('/')), &err.to_string()),
+    }
+}
+
+fn ping_healthcheck(url: &str, body: &str) {
+    // The URL contains the check's secret, so it's not logged
+    debug!("Pinging the healthcheck...");
+
+    if let Err(err) = send_ping(url, body) {
+        error!("Failed to ping the healthcheck: {}.", err);
+    }
+}
+
+fn send_ping(url: &str, body: &str) -> EmptyResult {
+    let request = HttpRequest::new(
+        Method::POST, url.to_owned(), Duration::from_secs(HEALTHCHECK_REQUEST_TIMEOUT),
+        RawResponseReader::new(), StatusErrorReader {},
+    ).with_text_body("text/plain", body)?;
+
+    HttpClient::new().send(request)?;
+    Ok(())
+}
+
+fn send_email(sendmail: &str, to: &[String], alert: &Alert) -> EmptyResult {
+    let message = format!(
+        concat!(
+            "To: {to}\nSubject: {backup} backup alert\n\n",
+            "Backup: {backup}\nProvider: {provider}\nTime: {time}\n\n{error}\n",
+        ),
+        to=to.join(", "), backup=alert.backup, provider=alert.provider, time=alert.timestamp,
+        error=alert.error);
+
+    let mut process = Command::new(sendmail)
+        .args(&["-t", "-i"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn().map_err(|e| format!("Unable to spawn {:?}: {}", sendmail, e))?;
+
+    let write_result = write_input(&mut process, &message);
+    let output = process.wait_with_output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return match stderr.trim() {
+            "" => Err!("{} {}", sendmail, output.status),
+            stderr => Err!("{} {}: {}", sendmail, output.status, stderr),
+        };
+    }
+
+    write_result
+}
+
+fn write_input(process: &mut Child, input: &str) -> EmptyResult {
+    let mut stdin = process.stdin.take().unwrap();
+    stdin.write_all(input.as_bytes())?;
+    Ok(())
+}
+
+struct StatusErrorReader {
+}
+
+impl ResponseReader for StatusErrorReader {
+    type Result = StatusError;
+
+    fn read(&self, response: HttpResponse) -> GenericResult<Self::Result> {
+        Ok(StatusError {status: response.status})
+    }
+}
+
+#[derive(Debug)]
+struct StatusError {
+    status: StatusCode,
+}
+
+impl Error for StatusError {
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The server returned an error: {}", self.status)
+    }
+}
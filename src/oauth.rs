@@ -4,7 +4,7 @@ use std::sync::Mutex;
 use std::time::{Instant, Duration};
 
 use crate::core::GenericResult;
-use crate::http_client::{HttpClient, HttpRequest, Method, headers};
+use crate::http_client::{HttpClient, HttpRequest, HttpClientError, Method, headers};
 
 pub struct OauthClient {
     client_id: String,
@@ -25,7 +25,8 @@ const API_REQUEST_TIMEOUT: u64 = 5;
 const ACCESS_TOKEN_MIN_EXPIRE_TIME: u64 = 60;
 
 impl OauthClient {
-    pub fn new(url: &str, client_id: &str, client_secret: &str, refresh_token: &str) -> OauthClient {
+    pub fn new(provider_name: &str, url: &str, client_id: &str, client_secret: &str,
+               refresh_token: &str) -> OauthClient {
         OauthClient {
             client_id: client_id.to_owned(),
             client_secret: client_secret.to_owned(),
@@ -33,7 +34,7 @@ impl OauthClient {
             access_token: Mutex::new(None),
 
             url: url.to_owned(),
-            client: HttpClient::new(),
+            client: HttpClient::new().with_metrics_label(provider_name),
         }
     }
 
@@ -73,7 +74,7 @@ impl OauthClient {
         let request = HttpRequest::<Response, OauthApiError>::new_json(
             Method::POST, format!("{}/token", self.url),
             Duration::from_secs(API_REQUEST_TIMEOUT)
-        ).with_form(&Request {
+        ).with_endpoint("token").with_form(&Request {
             client_id: &self.client_id,
             client_secret: &self.client_secret,
             refresh_token: &self.refresh_token,
@@ -81,7 +82,11 @@ impl OauthClient {
         })?;
 
         let request_time = Instant::now();
-        let response = self.client.send(request)?;
+        let response = self.client.send(request).map_err(|e| match e {
+            HttpClientError::Api(ref err) if err.is_invalid_credentials() => format!(
+                "the credentials are expired or invalid ({})", err.error_description),
+            _ => e.to_string(),
+        })?;
 
         *access_token = Some(AccessToken {
             token: response.access_token.to_owned(),
@@ -94,9 +99,17 @@ impl OauthClient {
 
 #[derive(Debug, Deserialize)]
 struct OauthApiError {
+    #[serde(default)]
+    error: String,
     error_description: String,
 }
 
+impl OauthApiError {
+    fn is_invalid_credentials(&self) -> bool {
+        self.error == "invalid_grant" || self.error == "invalid_client"
+    }
+}
+
 impl Error for OauthApiError {
 }
 
@@ -104,4 +117,60 @@ impl fmt::Display for OauthApiError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "OAuth error: {}", self.error_description)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::http_client::{EmptyResponse, HttpResponse, JsonErrorReader, RawResponseReader};
+    use crate::tests::{TestRequest, TestResponse, TestServer};
+
+    use super::*;
+
+    #[test]
+    fn invalid_credentials() {
+        let error = Arc::new(Mutex::new(""));
+
+        let server = {
+            let error = error.clone();
+            TestServer::new(move |_: &TestRequest| match *error.lock().unwrap() {
+                "" => TestResponse::new(200).with_body(
+                    "application/json", br#"{"access_token": "token", "expires_in": 3600}"#),
+                error => TestResponse::new(400).with_body("application/json", format!(
+                    r#"{{"error": "{}", "error_description": "Token has been expired or revoked."}}"#, error,
+                ).as_bytes()),
+            })
+        };
+
+        for (oauth_error, expected) in &[
+            ("invalid_grant", "the credentials are expired or invalid (Token has been expired or revoked.)"),
+            ("invalid_client", "the credentials are expired or invalid (Token has been expired or revoked.)"),
+            ("invalid_request", "OAuth error: Token has been expired or revoked."),
+        ] {
+            *error.lock().unwrap() = oauth_error;
+
+            let client = OauthClient::new("oauth-test", &server.url, "id", "secret", "token");
+            let err = client.authenticate(get_request()).err().unwrap().to_string();
+            assert_eq!(err, format!("Unable obtain OAuth token: {}", expected));
+        }
+
+        // The access token is cached until it expires
+        *error.lock().unwrap() = "";
+        let client = OauthClient::new("oauth-test", &server.url, "id", "secret", "token");
+        for _ in 0..2 {
+            client.authenticate(get_request()).unwrap();
+        }
+
+        let requests = server.stop();
+        assert_eq!(requests.len(), 4);
+        assert!(requests.iter().all(|request| request.path == "/token"));
+        assert!(String::from_utf8_lossy(&requests[3].body).contains("grant_type=refresh_token"));
+    }
+
+    fn get_request() -> HttpRequest<'static, HttpResponse, EmptyResponse> {
+        HttpRequest::new(
+            Method::GET, "http://localhost/".to_owned(), Duration::from_secs(1),
+            RawResponseReader::new(), JsonErrorReader::new())
+    }
+}
@@ -0,0 +1,145 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::io::AsRawFd;
+use std::process::{Command, Stdio};
+
+use nix::sys::termios::{self, LocalFlags, SetArg};
+use nix::unistd;
+
+use crate::core::GenericResult;
+
+// Asks the user for the passphrase on the controlling terminal with disabled echo. Encryption
+// operations should always require a confirmation to not make the backups unrecoverable by a typo.
+pub fn read_interactively(backup_name: &str, confirm: bool) -> GenericResult<String> {
+    if !unistd::isatty(libc::STDIN_FILENO).unwrap_or(false) {
+        return Err!(concat!(
+            "Encryption passphrase for {:?} backup is not specified and can't be prompted for: ",
+            "not running on a TTY"), backup_name);
+    }
+
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty").map_err(|e| format!(
+        "Unable to open the terminal to prompt for the encryption passphrase: {}", e))?;
+
+    loop {
+        let passphrase = prompt(&mut tty, &format!(
+            "Enter encryption passphrase for {:?} backup: ", backup_name))?;
+
+        if passphrase.is_empty() {
+            writeln!(tty, "Encryption passphrase mustn't be empty.")?;
+            continue;
+        }
+
+        if !confirm || prompt(&mut tty, "Repeat the passphrase: ")? == passphrase {
+            return Ok(passphrase);
+        }
+
+        writeln!(tty, "The passphrases don't match. Try again.")?;
+    }
+}
+
+pub fn read_file(path: &str) -> GenericResult<String> {
+    let data = fs::read_to_string(path).map_err(|e| format!("Unable to read {:?}: {}", path, e))?;
+    validate(strip_newline(&data))
+}
+
+pub fn read_env(name: &str) -> GenericResult<String> {
+    let value = env::var(name).map_err(|e| format!("Unable to read ${} environment variable: {}", name, e))?;
+    validate(strip_newline(&value))
+}
+
+// Runs the command via shell and takes the passphrase from its stdout
+pub fn read_command(command: &str) -> GenericResult<String> {
+    let output = Command::new("sh")
+        .arg("-c").arg(command)
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped())
+        .output().map_err(|e| format!("Unable to spawn {:?}: {}", command, e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return match stderr.trim() {
+            "" => Err!("{:?} has failed: {}", command, output.status),
+            stderr => Err!("{:?} has failed: {}: {}", command, output.status, stderr),
+        };
+    }
+
+    let stdout = String::from_utf8(output.stdout).map_err(|_| format!(
+        "{:?} returned a non-UTF-8 passphrase", command))?;
+
+    validate(strip_newline(&stdout))
+}
+
+fn strip_newline(data: &str) -> &str {
+    data.strip_suffix('\n').map(|data| data.strip_suffix('\r').unwrap_or(data)).unwrap_or(data)
+}
+
+fn validate(passphrase: &str) -> GenericResult<String> {
+    if passphrase.is_empty() {
+        return Err!("it's empty");
+    }
+    Ok(passphrase.to_owned())
+}
+
+fn prompt(tty: &mut File, message: &str) -> GenericResult<String> {
+    write!(tty, "{}", message)?;
+    tty.flush()?;
+
+    let fd = tty.as_raw_fd();
+    let original_attrs = termios::tcgetattr(fd)?;
+
+    let mut attrs = original_attrs.clone();
+    attrs.local_flags.remove(LocalFlags::ECHO);
+    attrs.local_flags.insert(LocalFlags::ECHONL);
+    termios::tcsetattr(fd, SetArg::TCSAFLUSH, &attrs)?;
+
+    let mut line = String::new();
+    let result = BufReader::new(&*tty).read_line(&mut line);
+
+    termios::tcsetattr(fd, SetArg::TCSAFLUSH, &original_attrs)?;
+
+    if result? == 0 {
+        return Err!("Unable to read the encryption passphrase: got EOF");
+    }
+
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn file() {
+        let temp_dir = TempDir::new("passphrase_file");
+        let path = temp_dir.0.join("passphrase");
+        let path = path.to_str().unwrap();
+
+        fs::write(path, "secret\r\n").unwrap();
+        assert_eq!(read_file(path).unwrap(), "secret");
+
+        fs::write(path, "\n").unwrap();
+        assert_eq!(read_file(path).unwrap_err().to_string(), "it's empty");
+
+        fs::remove_file(path).unwrap();
+        assert!(read_file(path).is_err());
+    }
+
+    #[test]
+    fn environment() {
+        env::set_var("PYVSB_TO_CLOUD_TEST_PASSPHRASE", "secret\n");
+        assert_eq!(read_env("PYVSB_TO_CLOUD_TEST_PASSPHRASE").unwrap(), "secret");
+        assert!(read_env("PYVSB_TO_CLOUD_TEST_MISSING_PASSPHRASE").is_err());
+    }
+
+    #[test]
+    fn command() {
+        assert_eq!(read_command("echo secret").unwrap(), "secret");
+        assert_eq!(read_command("printf 'secret\\n\\n'").unwrap(), "secret\n");
+        assert_eq!(read_command("echo").unwrap_err().to_string(), "it's empty");
+        assert_eq!(read_command("echo failure >&2; exit 3").unwrap_err().to_string(),
+                   r#""echo failure >&2; exit 3" has failed: exit status: 3: failure"#);
+    }
+}
@@ -0,0 +1,84 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use libc::c_int;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+use crate::core::EmptyResult;
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Installs SIGUSR1 (pause) and SIGUSR2 (resume) handlers. The uploads are paused on chunk boundaries,
+// so in-flight requests are never aborted.
+pub fn init() -> EmptyResult {
+    let action = SigAction::new(SigHandler::Handler(handle_signal), SaFlags::SA_RESTART, SigSet::empty());
+
+    for &signal in &[Signal::SIGUSR1, Signal::SIGUSR2] {
+        unsafe {
+            signal::sigaction(signal, &action).map_err(|e| format!(
+                "Unable to set {:?} handler: {}", signal, e))?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn wait_if_paused() {
+    wait_while_paused(&PAUSED);
+}
+
+fn wait_while_paused(paused: &AtomicBool) {
+    if !paused.load(Ordering::SeqCst) {
+        return;
+    }
+
+    info!("Pausing the upload on SIGUSR1...");
+    while paused.load(Ordering::SeqCst) {
+        thread::sleep(CHECK_INTERVAL);
+    }
+    info!("Resuming the upload on SIGUSR2...");
+}
+
+extern "C" fn handle_signal(signal: c_int) {
+    update_state(&PAUSED, signal);
+}
+
+fn update_state(paused: &AtomicBool, signal: c_int) {
+    paused.store(signal == libc::SIGUSR1, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn pause() {
+        let paused = Arc::new(AtomicBool::new(false));
+        wait_while_paused(&paused);
+
+        update_state(&paused, libc::SIGUSR1);
+        assert!(paused.load(Ordering::SeqCst));
+
+        let (resumed_tx, resumed_rx) = mpsc::channel();
+        let waiter = {
+            let paused = paused.clone();
+            thread::spawn(move || {
+                wait_while_paused(&paused);
+                resumed_tx.send(()).unwrap();
+            })
+        };
+
+        assert!(resumed_rx.recv_timeout(CHECK_INTERVAL * 2).is_err());
+
+        update_state(&paused, libc::SIGUSR2);
+        assert!(!paused.load(Ordering::SeqCst));
+
+        resumed_rx.recv_timeout(CHECK_INTERVAL * 2).unwrap();
+        waiter.join().unwrap();
+    }
+}
@@ -0,0 +1,76 @@
+use std::time::{Duration, Instant};
+
+use crate::util;
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+// Periodically logs progress of an upload. Reporting is time-based to not spam the log on small chunks.
+pub struct Progress {
+    name: String,
+    total_size: Option<u64>,
+    uploaded_size: u64,
+    last_report_time: Instant,
+}
+
+impl Progress {
+    // The total size is an estimation: the data is compressed and encrypted on the fly
+    pub fn new(name: &str, total_size: Option<u64>) -> Progress {
+        Progress {
+            name: name.to_owned(),
+            total_size,
+            uploaded_size: 0,
+            last_report_time: Instant::now(),
+        }
+    }
+
+    pub fn add(&mut self, size: u64) {
+        if let Some(report) = self.update(size, Instant::now()) {
+            info!("{}", report);
+        }
+    }
+
+    fn update(&mut self, size: u64, now: Instant) -> Option<String> {
+        self.uploaded_size += size;
+
+        if now.duration_since(self.last_report_time) < REPORT_INTERVAL {
+            return None;
+        }
+        self.last_report_time = now;
+
+        Some(match self.total_size {
+            Some(total_size) if total_size != 0 => {
+                let percent = std::cmp::min(99, self.uploaded_size * 100 / total_size);
+                format!("{}: uploaded {} ({}%).", self.name, util::format_size(self.uploaded_size), percent)
+            },
+            _ => format!("{}: uploaded {}.", self.name, util::format_size(self.uploaded_size)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress() {
+        let mut progress = Progress::new("backup", Some(4 * 1024 * 1024));
+        let start_time = progress.last_report_time;
+        let minute = Duration::from_secs(60);
+
+        assert_eq!(progress.update(1024 * 1024, start_time + Duration::from_secs(30)), None);
+        assert_eq!(progress.update(1024 * 1024, start_time + minute),
+                   Some(format!("backup: uploaded {} (50%).", util::format_size(2 * 1024 * 1024))));
+
+        // The interval is counted from the last report
+        assert_eq!(progress.update(1024 * 1024, start_time + minute + Duration::from_secs(30)), None);
+
+        // The estimated total size may be exceeded
+        assert_eq!(progress.update(4 * 1024 * 1024, start_time + 2 * minute),
+                   Some(format!("backup: uploaded {} (99%).", util::format_size(7 * 1024 * 1024))));
+
+        let mut progress = Progress::new("backup", None);
+        let start_time = progress.last_report_time;
+        assert_eq!(progress.update(1024, start_time + minute),
+                   Some(format!("backup: uploaded {}.", util::format_size(1024))));
+    }
+}
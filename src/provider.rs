@@ -1,6 +1,8 @@
 use std::fmt;
 use std::io;
+use std::time::SystemTime;
 
+use crate::bandwidth::ThrottledReader;
 use crate::core::{GenericResult, EmptyResult};
 use crate::hash::Hasher;
 use crate::stream_splitter::ChunkStreamReceiver;
@@ -13,21 +15,57 @@ pub trait Provider: Send + Sync {
 pub trait ReadProvider: Provider {
     fn list_directory(&self, path: &str) -> GenericResult<Option<Vec<File>>>;
 
-    fn open_file(&self, _path: &str) -> GenericResult<Box<dyn io::Read>> {
+    fn open_file(&self, _path: &str) -> GenericResult<Box<dyn io::Read + Send>> {
         Err!("{} provider doesn't support file opening functionality", self.name())
     }
 }
 
-pub trait WriteProvider: Provider {
+pub trait WriteProvider: ReadProvider {
     fn hasher(&self) -> Box<dyn Hasher>;
     fn max_request_size(&self) -> Option<u64>;
 
     fn create_directory(&self, path: &str) -> EmptyResult;
     fn upload_file(&self, directory_path: &str, temp_name: &str, name: &str,
                    chunk_streams: ChunkStreamReceiver) -> EmptyResult;
+
+    // Server-side rename which must never overwrite an existing file
+    fn supports_rename(&self) -> bool {
+        false
+    }
+
+    fn rename(&self, _src: &str, _dst: &str) -> EmptyResult {
+        Err!("{} provider doesn't support file renaming functionality", self.name())
+    }
+
     fn delete(&self, path: &str) -> EmptyResult;
+
+    // Returns the server-side hash of the file (in the format of the provider's hasher) if the provider
+    // exposes it
+    fn get_checksum(&self, _path: &str) -> GenericResult<Option<String>> {
+        Ok(None)
+    }
+
+    // Downloads the file and calculates its checksum
+    fn download_checksum(&self, path: &str) -> GenericResult<String> {
+        let mut hasher = self.hasher();
+        io::copy(&mut ThrottledReader::new(self.open_file(path)?), &mut hasher).map_err(|e| format!(
+            "Failed to download the file: {}", e))?;
+        Ok(hasher.finish())
+    }
+
+    // Verifies the uploaded file against the checksum calculated during its upload. Uses the server-side
+    // hash if it's available and re-downloads the file otherwise. Returns false on checksum mismatch.
+    fn verify(&self, path: &str, checksum: &str) -> GenericResult<bool> {
+        let actual_checksum = match self.get_checksum(path)? {
+            Some(checksum) => checksum,
+            None => self.download_checksum(path)?,
+        };
+
+        Ok(actual_checksum == checksum)
+    }
 }
 
+#[derive(Clone, Copy)]
 pub enum ProviderType {
     Local,
     Cloud,
@@ -38,6 +76,8 @@ pub struct File {
     pub name: String,
     pub type_: FileType,
     pub size: Option<u64>,
+    // Is used to find stale temporary files. Not all providers report it.
+    pub modify_time: Option<SystemTime>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -0,0 +1,676 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use digest::Digest;
+use serde::{ser, de};
+
+use crate::core::{EmptyResult, GenericResult};
+use crate::hash::{self, Hasher, PartsHasher, Sha1, VerifyingReader};
+use crate::http_client::{
+    HttpClient, HttpClientError, HttpRequest, Method, RawResponseReader, JsonErrorReader, EmptyResponse,
+    ClientOptions, headers,
+};
+use crate::memory;
+use crate::metrics;
+use crate::provider::{Provider, ProviderType, ReadProvider, WriteProvider, File, FileType};
+use crate::stream_splitter::{self, ChunkStreamReceiver, ChunkStream, ChunkReceiver};
+
+const NAME: &str = "Backblaze B2";
+const API_ENDPOINT: &str = "https://api.backblazeb2.com/b2api/v2";
+
+const API_REQUEST_TIMEOUT: u64 = 60;
+const UPLOAD_REQUEST_TIMEOUT: u64 = 60 * 60;
+
+// B2 requires Content-Length and SHA-1 for each uploaded file and large file part, so parts are
+// buffered in memory during the upload
+const PART_SIZE: u64 = 100 * 1000 * 1000;
+const MAX_UPLOAD_ATTEMPTS: usize = 5;
+
+// Authorization tokens are valid for 24 hours
+const AUTHORIZATION_TTL: Duration = Duration::from_secs(23 * 60 * 60);
+
+// B2 has no real directories: they are emulated via file name prefixes and empty marker files
+const DIRECTORY_MARKER_NAME: &str = ".bzEmpty";
+
+pub struct Backblaze {
+    client: HttpClient,
+    request_timeout: Duration,
+    upload_timeout: Duration,
+
+    key_id: String,
+    application_key: String,
+    bucket_id: String,
+
+    authorization: Mutex<Option<Authorization>>,
+}
+
+#[derive(Clone)]
+struct Authorization {
+    api_url: String,
+    download_url: String,
+    token: String,
+    time: Instant,
+}
+
+impl Backblaze {
+    pub fn new(key_id: &str, application_key: &str, bucket_id: &str) -> Backblaze {
+        Backblaze {
+            client: HttpClient::new().with_metrics_label(NAME),
+            request_timeout: Duration::from_secs(API_REQUEST_TIMEOUT),
+            upload_timeout: Duration::from_secs(UPLOAD_REQUEST_TIMEOUT),
+
+            key_id: key_id.to_owned(),
+            application_key: application_key.to_owned(),
+            bucket_id: bucket_id.to_owned(),
+
+            authorization: Mutex::new(None),
+        }
+    }
+
+    pub fn with_client_options(mut self, options: ClientOptions) -> Backblaze {
+        self.client = self.client.with_options(&options);
+        self.request_timeout = options.timeouts.request.unwrap_or(self.request_timeout);
+        self.upload_timeout = options.timeouts.upload.unwrap_or(self.upload_timeout);
+        self
+    }
+
+    fn authorize(&self) -> GenericResult<Authorization> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Response {
+            authorization_token: String,
+            api_url: String,
+            download_url: String,
+        }
+
+        let mut authorization = self.authorization.lock().unwrap();
+
+        if let Some(ref authorization) = *authorization {
+            if authorization.time.elapsed() < AUTHORIZATION_TTL {
+                return Ok(authorization.clone());
+            }
+        }
+
+        let credentials = base64::encode(format!("{}:{}", self.key_id, self.application_key));
+        let request = HttpRequest::<Response, ApiError>::new_json(
+            Method::GET, API_ENDPOINT.to_owned() + "/b2_authorize_account",
+            self.request_timeout,
+        ).with_endpoint("b2_authorize_account").with_header(
+            headers::AUTHORIZATION, format!("Basic {}", credentials))?;
+
+        let response = self.client.send(request).map_err(|e| format!(
+            "Unable to authorize on {}: {}", NAME, e))?;
+
+        Ok(authorization.get_or_insert(Authorization {
+            api_url: response.api_url,
+            download_url: response.download_url,
+            token: response.authorization_token,
+            time: Instant::now(),
+        }).clone())
+    }
+
+    fn api_request<I, O>(&self, method: &'static str, request: &I) -> Result<O, HttpClientError<ApiError>>
+        where I: ser::Serialize,
+              O: de::DeserializeOwned,
+    {
+        let mut reauthorized = false;
+
+        loop {
+            let authorization = self.authorize()?;
+            let http_request = HttpRequest::<O, ApiError>::new_json(
+                Method::POST, format!("{}/b2api/v2/{}", authorization.api_url, method),
+                self.request_timeout,
+            ).with_endpoint(method).with_header(headers::AUTHORIZATION, authorization.token)?
+                .with_json(request)?;
+
+            match self.client.send(http_request) {
+                Err(HttpClientError::Api(ref err)) if err.code == "expired_auth_token" && !reauthorized => {
+                    self.authorization.lock().unwrap().take();
+                    reauthorized = true;
+                },
+                result => return result,
+            }
+        }
+    }
+
+    // Returns files and folders (when delimiter is specified) matching the specified prefix
+    fn list_files(&self, prefix: &str, delimiter: Option<&str>) -> GenericResult<Vec<FileVersion>> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Response {
+            files: Vec<FileVersion>,
+            next_file_name: Option<String>,
+        }
+
+        let mut files = Vec::new();
+
+        let mut start_file_name: Option<String> = None;
+        let (mut page, page_limit) = (1, 1000);
+
+        loop {
+            let request = ListRequest {
+                bucket_id: &self.bucket_id,
+                prefix: Some(prefix),
+                delimiter,
+                start_file_name: start_file_name.as_deref(),
+                max_file_count: 1000,
+            };
+
+            // Partial listing must never be treated as a complete one
+            let response: Response = self.api_request("b2_list_file_names", &request).map_err(|e| format!(
+                "Listing has been interrupted on page #{}: {}", page, e))?;
+
+            files.extend(response.files);
+
+            start_file_name = match response.next_file_name {
+                Some(next_file_name) => Some(next_file_name),
+                None => break,
+            };
+
+            if page >= page_limit {
+                return Err!("Listing page limit has exceeded");
+            }
+            page += 1;
+        }
+
+        Ok(files)
+    }
+
+    fn get_file(&self, file_name: &str) -> GenericResult<Option<FileVersion>> {
+        #[derive(Deserialize)]
+        struct Response {
+            files: Vec<FileVersion>,
+        }
+
+        let request = ListRequest {
+            bucket_id: &self.bucket_id,
+            prefix: None,
+            delimiter: None,
+            start_file_name: Some(file_name),
+            max_file_count: 1,
+        };
+
+        let response: Response = self.api_request("b2_list_file_names", &request)?;
+        Ok(response.files.into_iter().next().filter(|file| file.file_name == file_name))
+    }
+
+    fn upload_small_file(&self, file_name: &str, part: Part) -> EmptyResult {
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Request<'a> {
+            bucket_id: &'a str,
+        }
+
+        let file_name = encode(file_name);
+        let headers = [("X-Bz-File-Name", file_name.as_str()), ("X-Bz-Content-Sha1", part.sha1.as_str())];
+
+        self.upload("b2_upload_file", || {
+            self.api_request("b2_get_upload_url", &Request {bucket_id: &self.bucket_id})
+        }, &headers, part.data)
+    }
+
+    fn start_large_file(&self, file_name: &str) -> GenericResult<LargeFile> {
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Request<'a> {
+            bucket_id: &'a str,
+            file_name: &'a str,
+            content_type: &'a str,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Response {
+            file_id: String,
+        }
+
+        let response: Response = self.api_request("b2_start_large_file", &Request {
+            bucket_id: &self.bucket_id,
+            file_name,
+            content_type: "application/octet-stream",
+        })?;
+
+        Ok(LargeFile {
+            file_id: response.file_id,
+            part_sha1s: Vec::new(),
+        })
+    }
+
+    fn upload_part(&self, file: &mut LargeFile, part: Part) -> EmptyResult {
+        let part_number = (file.part_sha1s.len() + 1).to_string();
+        let headers = [("X-Bz-Part-Number", part_number.as_str()), ("X-Bz-Content-Sha1", part.sha1.as_str())];
+
+        self.upload("b2_upload_part", || {
+            self.api_request("b2_get_upload_part_url", &FileIdRequest {file_id: &file.file_id})
+        }, &headers, part.data)?;
+
+        file.part_sha1s.push(part.sha1);
+        Ok(())
+    }
+
+    fn finish_large_file(&self, file: &LargeFile) -> EmptyResult {
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Request<'a> {
+            file_id: &'a str,
+            part_sha1_array: &'a [String],
+        }
+
+        let _: EmptyResponse = self.api_request("b2_finish_large_file", &Request {
+            file_id: &file.file_id,
+            part_sha1_array: &file.part_sha1s,
+        })?;
+
+        Ok(())
+    }
+
+    fn cancel_large_file(&self, file: &LargeFile) {
+        let result: Result<EmptyResponse, _> = self.api_request(
+            "b2_cancel_large_file", &FileIdRequest {file_id: &file.file_id});
+
+        if let Err(err) = result {
+            error!("Failed to cancel {} large file upload: {}.", NAME, err);
+        }
+    }
+
+    fn download(&self, file_id: &str) -> GenericResult<Box<dyn io::Read + Send>> {
+        let authorization = self.authorize()?;
+        let request = HttpRequest::new(
+            Method::GET, format!("{}/b2api/v2/b2_download_file_by_id", authorization.download_url),
+            self.upload_timeout,
+            RawResponseReader::new(), JsonErrorReader::<ApiError>::new(),
+        ).with_endpoint("b2_download_file_by_id")
+            .with_params(&[("fileId", file_id)])?
+            .with_header(headers::AUTHORIZATION, authorization.token)?;
+
+        let (_, data) = self.client.download(request)?;
+        Ok(data)
+    }
+
+    // B2 recommends to get a new upload URL and retry the upload on any upload failure (including
+    // 503 Service Unavailable returned when the storage pod is too busy)
+    fn upload<G>(
+        &self, endpoint: &'static str, get_upload_url: G, headers: &[(&str, &str)], data: Bytes,
+    ) -> EmptyResult
+        where G: Fn() -> Result<UploadUrl, HttpClientError<ApiError>>
+    {
+        let mut attempt = 1;
+
+        loop {
+            let result = get_upload_url().and_then(|upload_url| {
+                let mut request = HttpRequest::new(
+                    Method::POST, upload_url.upload_url, self.upload_timeout,
+                    RawResponseReader::new(), JsonErrorReader::<ApiError>::new(),
+                ).with_endpoint(endpoint)
+                    .with_header(headers::AUTHORIZATION, upload_url.authorization_token)?;
+
+                for &(name, value) in headers {
+                    request = request.with_header(name, value)?;
+                }
+
+                self.client.send(request.with_body("application/octet-stream", data.clone())?)?;
+                Ok(())
+            });
+
+            let err = match result {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+
+            let retryable = match err {
+                HttpClientError::Generic(_) => true,
+                HttpClientError::Api(ref err) => {
+                    err.status == 408 || err.status == 429 || err.status >= 500 ||
+                        err.code == "expired_auth_token"
+                },
+            };
+
+            if !retryable || attempt >= MAX_UPLOAD_ATTEMPTS {
+                return Err(err.into());
+            }
+
+            debug!("Upload to {} has failed: {}. Retrying with a new upload URL...", NAME, err);
+            metrics::count_provider_retry(NAME, endpoint);
+            thread::sleep(Duration::from_secs(attempt as u64));
+            attempt += 1;
+        }
+    }
+}
+
+impl Provider for Backblaze {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn type_(&self) -> ProviderType {
+        ProviderType::Cloud
+    }
+}
+
+impl ReadProvider for Backblaze {
+    fn list_directory(&self, path: &str) -> GenericResult<Option<Vec<File>>> {
+        let prefix = get_directory_prefix(path);
+        let entries = self.list_files(&prefix, Some("/"))?;
+
+        if entries.is_empty() {
+            return Ok(None);
+        }
+
+        let mut files = Vec::new();
+
+        for entry in entries {
+            let name = &entry.file_name[prefix.len()..];
+
+            match entry.action.as_str() {
+                "upload" if name != DIRECTORY_MARKER_NAME => files.push(File {
+                    name: name.to_owned(),
+                    type_: FileType::File,
+                    size: Some(entry.content_length),
+                    modify_time: None,
+                }),
+                "folder" => files.push(File {
+                    name: name.trim_end_matches('/').to_owned(),
+                    type_: FileType::Directory,
+                    size: None,
+                    modify_time: None,
+                }),
+                _ => {},
+            }
+        }
+
+        Ok(Some(files))
+    }
+
+    fn open_file(&self, path: &str) -> GenericResult<Box<dyn io::Read + Send>> {
+        let file = self.get_file(&get_key(path))?.ok_or("The file doesn't exist")?;
+
+        // Large files have no whole file SHA-1 (only their parts are verified on upload)
+        let checksum = match file.content_sha1 {
+            Some(ref checksum) if checksum.len() == 40 => checksum.clone(),
+            _ => return Err!(concat!(
+                "The file has been uploaded as a large file which has no checksum to verify the download ",
+                "with")),
+        };
+        let file_id = file.file_id.ok_or("Got a file without ID")?;
+
+        let data = self.download(&file_id)?;
+        Ok(Box::new(VerifyingReader::new(data, Box::new(Sha1::new()), &checksum)))
+    }
+}
+
+impl WriteProvider for Backblaze {
+    fn hasher(&self) -> Box<dyn Hasher> {
+        Box::new(PartsHasher::new_sha1(PART_SIZE as usize))
+    }
+
+    fn max_request_size(&self) -> Option<u64> {
+        Some(PART_SIZE)
+    }
+
+    fn create_directory(&self, path: &str) -> EmptyResult {
+        let data = Bytes::new();
+        let sha1 = hash::to_hex(&sha1::Sha1::digest(&data));
+        self.upload_small_file(&(get_directory_prefix(path) + DIRECTORY_MARKER_NAME), Part {data, sha1})
+    }
+
+    // Both regular and large file uploads are atomic: the file appears only on upload completion,
+    // so the file is uploaded directly under its final name. Large files must consist of at least two
+    // parts, so the first part is held until it's known whether it's the only one.
+    fn upload_file(&self, directory_path: &str, _temp_name: &str, name: &str,
+                   chunk_streams: ChunkStreamReceiver) -> EmptyResult {
+        let file_name = get_directory_prefix(directory_path) + name;
+
+        let mut first_part = None;
+        let mut first_part_reservation = None;
+        let mut large_file: Option<LargeFile> = None;
+        let mut checksum_hasher = sha1::Sha1::new();
+
+        for result in chunk_streams.iter() {
+            match result {
+                Ok(ChunkStream::Stream(_, chunk_stream)) => {
+                    // The first part is held while the second one is read, so its reservation covers both
+                    // of them: waiting for a new reservation while holding one may lead to a deadlock.
+                    let reservation = if large_file.is_some() {
+                        memory::reserve(PART_SIZE)
+                    } else if first_part.is_none() {
+                        memory::reserve(2 * PART_SIZE)
+                    } else {
+                        None
+                    };
+
+                    let result = read_part(chunk_stream, &mut checksum_hasher).and_then(|part| {
+                        if let Some(ref mut file) = large_file {
+                            return self.upload_part(file, part);
+                        }
+
+                        match first_part.take() {
+                            Some(first) => {
+                                let file = large_file.get_or_insert(self.start_large_file(&file_name)?);
+                                self.upload_part(file, first)?;
+                                self.upload_part(file, part)?;
+                                first_part_reservation = None;
+                                Ok(())
+                            },
+                            None => {
+                                first_part.replace(part);
+                                first_part_reservation = reservation;
+                                Ok(())
+                            },
+                        }
+                    });
+
+                    if let Err(err) = result {
+                        if let Some(ref file) = large_file {
+                            self.cancel_large_file(file);
+                        }
+                        return Err(stream_splitter::get_upload_error(&chunk_streams, err));
+                    }
+                },
+                Ok(ChunkStream::EofWithCheckSum(size, checksum)) => {
+                    if size == 0 {
+                        return Err!("An attempt to upload an empty file");
+                    }
+
+                    if hash::to_hex(&checksum_hasher.finalize_reset()) != checksum {
+                        if let Some(ref file) = large_file {
+                            self.cancel_large_file(file);
+                        }
+                        return Err!("Checksum mismatch");
+                    }
+
+                    return match (first_part.take(), large_file) {
+                        (Some(part), None) => self.upload_small_file(&file_name, part),
+                        (None, Some(file)) => self.finish_large_file(&file).map_err(|err| {
+                            self.cancel_large_file(&file);
+                            err
+                        }),
+                        _ => Err!("Got an unexpected upload state"),
+                    };
+                },
+                Err(err) => {
+                    if let Some(ref file) = large_file {
+                        self.cancel_large_file(file);
+                    }
+                    return Err!("Upload has been aborted: {}", err);
+                },
+            }
+        }
+
+        Err!("Chunk stream sender has been closed without a termination message")
+    }
+
+    fn delete(&self, path: &str) -> EmptyResult {
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Request<'a> {
+            file_name: &'a str,
+            file_id: &'a str,
+        }
+
+        let key = get_key(path);
+        let mut files = self.list_files(&get_directory_prefix(path), None)?;
+
+        // Delete directory contents first, so the directory marker is deleted last
+        files.sort_by(|a, b| b.file_name.cmp(&a.file_name));
+
+        if files.is_empty() {
+            files.extend(self.get_file(&key)?);
+            if files.is_empty() {
+                return Err!("{:?} doesn't exist", path);
+            }
+        }
+
+        for file in files {
+            let file_id = file.file_id.as_ref().ok_or_else(|| format!(
+                "Got {:?} file without ID", file.file_name))?;
+
+            let _: EmptyResponse = self.api_request("b2_delete_file_version", &Request {
+                file_name: &file.file_name,
+                file_id,
+            }).map_err(|e| format!("Unable to delete {:?}: {}", file.file_name, e))?;
+        }
+
+        Ok(())
+    }
+
+    // Our checksum is SHA-1 of the parts' SHA-1s, so for regular files it's calculated from the file
+    // SHA-1. Large files have no whole file SHA-1, so they have to be re-downloaded.
+    fn get_checksum(&self, path: &str) -> GenericResult<Option<String>> {
+        let file = self.get_file(&get_key(path))?.ok_or("The file doesn't exist")?;
+
+        Ok(match file.content_sha1 {
+            Some(ref sha1) if sha1.len() == 40 => {
+                Some(hash::to_hex(&sha1::Sha1::digest(&hash::from_hex(sha1)?)))
+            },
+            _ => None,
+        })
+    }
+
+    // Large files can't be opened, since there is no checksum to verify the download with
+    fn download_checksum(&self, path: &str) -> GenericResult<String> {
+        let file = self.get_file(&get_key(path))?.ok_or("The file doesn't exist")?;
+        let file_id = file.file_id.ok_or("Got a file without ID")?;
+
+        let mut hasher = self.hasher();
+        io::copy(&mut self.download(&file_id)?, &mut hasher).map_err(|e| format!(
+            "Failed to download the file: {}", e))?;
+
+        Ok(hasher.finish())
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ListRequest<'a> {
+    bucket_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prefix: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delimiter: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_file_name: Option<&'a str>,
+    max_file_count: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FileIdRequest<'a> {
+    file_id: &'a str,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileVersion {
+    file_id: Option<String>,
+    file_name: String,
+    content_length: u64,
+    content_sha1: Option<String>,
+    action: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadUrl {
+    upload_url: String,
+    authorization_token: String,
+}
+
+struct LargeFile {
+    file_id: String,
+    part_sha1s: Vec<String>,
+}
+
+struct Part {
+    data: Bytes,
+    sha1: String,
+}
+
+fn read_part(chunk_stream: ChunkReceiver, checksum_hasher: &mut sha1::Sha1) -> GenericResult<Part> {
+    let mut data = BytesMut::new();
+    for chunk in chunk_stream.iter() {
+        data.extend_from_slice(&chunk?);
+    }
+
+    let sha1 = sha1::Sha1::digest(&data);
+    checksum_hasher.update(&sha1);
+
+    Ok(Part {
+        data: data.freeze(),
+        sha1: hash::to_hex(&sha1),
+    })
+}
+
+fn get_key(path: &str) -> String {
+    path.trim_matches('/').to_owned()
+}
+
+fn get_directory_prefix(path: &str) -> String {
+    let key = get_key(path);
+    if key.is_empty() {
+        key
+    } else {
+        key + "/"
+    }
+}
+
+// Percent-encoding of file names passed via HTTP headers
+fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+
+    for &byte in value.as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            },
+            _ => encoded += &format!("%{:02X}", byte),
+        }
+    }
+
+    encoded
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiError {
+    status: u16,
+    code: String,
+    #[serde(default)]
+    message: String,
+}
+
+impl Error for ApiError {
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "B2 API error: {}", self.code)?;
+        if !self.message.is_empty() {
+            write!(f, " ({})", self.message.trim_end_matches('.'))?;
+        }
+        Ok(())
+    }
+}
@@ -1,18 +1,27 @@
 use std::error::Error;
 use std::fmt;
+use std::io::{self, Write};
 use std::ops::Add;
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
+use bytes::{Bytes, BytesMut};
+use chrono::DateTime;
 use serde::{ser, de};
 
 use crate::core::{EmptyResult, GenericResult};
-use crate::hash::{Hasher, ChunkedSha256};
+use crate::hash::{Hasher, ChunkedSha256, VerifyingReader};
 use crate::http_client::{
     HttpClient, HttpRequest, HttpRequestBuildingError, Method, Body, EmptyResponse, HttpClientError,
+    Headers, RawResponseReader, JsonErrorReader, ClientOptions,
 };
+use crate::memory;
+use crate::metrics;
 use crate::oauth::OauthClient;
 use crate::provider::{Provider, ProviderType, ReadProvider, WriteProvider, File, FileType};
-use crate::stream_splitter::{ChunkStreamReceiver, ChunkStream};
+use crate::stream_splitter::{self, ChunkStreamReceiver, ChunkStream, ChunkReceiver};
+
+const NAME: &str = "Dropbox";
 
 const OAUTH_ENDPOINT: &str = "https://www.dropbox.com/oauth2";
 
@@ -21,20 +30,139 @@ const API_REQUEST_TIMEOUT: u64 = 15;
 
 const CONTENT_ENDPOINT: &str = "https://content.dropboxapi.com/2";
 const CONTENT_REQUEST_TIMEOUT: u64 = 60 * 60;
+const CHUNK_RETRY_DELAY: u64 = 1;
+
+pub const MAX_REQUEST_SIZE: u64 = 150 * 1024 * 1024;
 
 pub struct Dropbox {
     oauth: OauthClient,
     client: HttpClient,
+    api_endpoint: String,
+    content_endpoint: String,
+    request_timeout: Duration,
+    upload_timeout: Duration,
+    max_chunk_retries: usize,
+    chunk_retry_delay: Duration,
+    chunk_size: u64,
 }
 
 impl Dropbox {
     pub fn new(client_id: &str, client_secret: &str, refresh_token: &str) -> GenericResult<Dropbox> {
         Ok(Dropbox {
-            oauth: OauthClient::new(OAUTH_ENDPOINT, client_id, client_secret, refresh_token),
-            client: HttpClient::new(),
+            oauth: OauthClient::new(NAME, OAUTH_ENDPOINT, client_id, client_secret, refresh_token),
+            client: HttpClient::new().with_metrics_label(NAME),
+            api_endpoint: API_ENDPOINT.to_owned(),
+            content_endpoint: CONTENT_ENDPOINT.to_owned(),
+            request_timeout: Duration::from_secs(API_REQUEST_TIMEOUT),
+            upload_timeout: Duration::from_secs(CONTENT_REQUEST_TIMEOUT),
+            max_chunk_retries: 0,
+            chunk_retry_delay: Duration::from_secs(CHUNK_RETRY_DELAY),
+            chunk_size: MAX_REQUEST_SIZE,
         })
     }
 
+    pub fn with_headers(mut self, headers: Headers) -> Dropbox {
+        self.client = self.client.with_default_headers(headers);
+        self
+    }
+
+    pub fn with_client_options(mut self, options: ClientOptions) -> Dropbox {
+        self.client = self.client.with_options(&options);
+        self.request_timeout = options.timeouts.request.unwrap_or(self.request_timeout);
+        self.upload_timeout = options.timeouts.upload.unwrap_or(self.upload_timeout);
+        self
+    }
+
+    pub fn with_max_chunk_retries(mut self, retries: usize) -> Dropbox {
+        self.max_chunk_retries = retries;
+        self
+    }
+
+    pub fn with_chunk_size(mut self, size: u64) -> Dropbox {
+        self.chunk_size = size;
+        self
+    }
+
+    fn append_chunk(&self, session_id: &str, offset: u64, chunk_stream: ChunkReceiver) -> EmptyResult {
+        #[derive(Serialize)]
+        struct AppendRequest<'a> {
+            cursor: Cursor<'a>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            content_hash: Option<String>,
+        }
+
+        #[derive(Serialize)]
+        struct Cursor<'a> {
+            session_id: &'a str,
+            offset: u64,
+        }
+
+        let mut request = AppendRequest {
+            cursor: Cursor {
+                session_id: session_id,
+                offset: offset,
+            },
+            content_hash: None,
+        };
+
+        if self.max_chunk_retries == 0 {
+            let _: Option<EmptyResponse> = self.content_request(
+                "/files/upload_session/append_v2", &request, chunk_stream)?;
+            return Ok(());
+        }
+
+        let _reservation = memory::reserve(self.chunk_size);
+
+        let mut chunk = BytesMut::new();
+        let mut hasher = self.hasher();
+
+        for data in chunk_stream.iter() {
+            let data = data?;
+            hasher.write_all(&data)?;
+            chunk.extend_from_slice(&data);
+        }
+
+        // The chunk is buffered, so we're able to pass its hash to make Dropbox reject the chunk if
+        // it's been corrupted in transit
+        request.content_hash.replace(hasher.finish());
+
+        let chunk: Bytes = chunk.freeze();
+        let chunk_end = offset + chunk.len() as u64;
+        let mut retry = 0;
+
+        loop {
+            let err = match self.content_request::<_, _, Option<EmptyResponse>>(
+                "/files/upload_session/append_v2", &request, chunk.clone()) {
+                Ok(_) => return Ok(()),
+                Err(err) => err,
+            };
+
+            let retriable = match err {
+                // The previous attempt has actually succeeded, but we've lost the response
+                HttpClientError::Api(ref e) if retry != 0
+                    && e.error.tag.as_deref() == Some("incorrect_offset")
+                    && e.error.correct_offset == Some(chunk_end) => return Ok(()),
+
+                HttpClientError::Api(ref e) => e.error.tag.as_deref() == Some("content_hash_mismatch"),
+                HttpClientError::Generic(_) => true,
+            };
+
+            if !retriable || retry >= self.max_chunk_retries {
+                if retry != 0 {
+                    warning!("Failed to upload a chunk at {} offset to {} after {} retries: {}.",
+                             offset, NAME, retry, err);
+                }
+                return Err(err.into());
+            }
+
+            retry += 1;
+            metrics::count_provider_retry(NAME, "/files/upload_session/append_v2");
+            warn!("Failed to upload a chunk at {} offset to {}: {}. Retrying ({}/{})...",
+                  offset, NAME, err, retry, self.max_chunk_retries);
+            thread::sleep(self.chunk_retry_delay * retry as u32);
+        }
+    }
+
     fn rename_file(&self, src: &str, dst: &str) -> EmptyResult {
         #[derive(Serialize)]
         struct Request<'a> {
@@ -50,17 +178,23 @@ impl Dropbox {
         Ok(())
     }
 
-    fn api_request<I, O>(&self, path: &str, request: &I) -> Result<O, HttpClientError<ApiError>>
+    fn delete_temp_file(&self, path: &str) {
+        if let Err(err) = self.delete(path) {
+            error!("Failed to delete a temporary {:?} file from {}: {}.", path, self.name(), err);
+        }
+    }
+
+    fn api_request<I, O>(&self, path: &'static str, request: &I) -> Result<O, HttpClientError<ApiError>>
         where I: ser::Serialize,
               O: de::DeserializeOwned,
     {
         self.send_request(HttpRequest::new_json(
-            Method::POST, API_ENDPOINT.to_owned() + path,
-            Duration::from_secs(API_REQUEST_TIMEOUT)
-        ).with_json(request)?)
+            Method::POST, self.api_endpoint.clone() + path,
+            self.request_timeout,
+        ).with_endpoint(path).with_json(request)?)
     }
 
-    fn content_request<I, B, O>(&self, path: &str, request: &I, body: B) -> Result<O, HttpClientError<ApiError>>
+    fn content_request<I, B, O>(&self, path: &'static str, request: &I, body: B) -> Result<O, HttpClientError<ApiError>>
         where I: ser::Serialize,
               B: Into<Body>,
               O: de::DeserializeOwned,
@@ -68,8 +202,8 @@ impl Dropbox {
         let request_json = serde_json::to_string(request).map_err(HttpRequestBuildingError::new)?;
 
         let http_request = HttpRequest::new_json(
-            Method::POST, CONTENT_ENDPOINT.to_owned() + path,
-            Duration::from_secs(CONTENT_REQUEST_TIMEOUT))
+            Method::POST, self.content_endpoint.clone() + path, self.upload_timeout)
+            .with_endpoint(path)
             .with_header("Dropbox-API-Arg", request_json)?
             .with_body("application/octet-stream", body)?;
 
@@ -85,7 +219,7 @@ impl Dropbox {
 
 impl Provider for Dropbox {
     fn name(&self) -> &'static str {
-        "Dropbox"
+        NAME
     }
 
     fn type_(&self) -> ProviderType {
@@ -117,6 +251,9 @@ impl ReadProvider for Dropbox {
             #[serde(rename = ".tag")]
             tag: String,
             name: String,
+            // Are set only for files
+            size: Option<u64>,
+            server_modified: Option<String>,
         }
 
         let mut cursor: Option<String> = None;
@@ -125,9 +262,11 @@ impl ReadProvider for Dropbox {
 
         loop {
             let mut response: Response = if let Some(ref cursor) = cursor {
+                // Partial listing must never be treated as a complete one
                 self.api_request("/files/list_folder/continue", &ContinueRequest {
                     cursor: &cursor
-                })
+                }).map_err(|e| format!(
+                    "Directory listing has been interrupted on page #{}: {}", page, e))
             } else {
                 let response = self.api_request("/files/list_folder", &Request {
                     path: path
@@ -143,7 +282,7 @@ impl ReadProvider for Dropbox {
                     }
                 }
 
-                response
+                response.map_err(|e| e.to_string())
             }?;
 
             for entry in response.entries.drain(..) {
@@ -154,7 +293,10 @@ impl ReadProvider for Dropbox {
                         "file" => FileType::File,
                         _ => FileType::Other,
                     },
-                    size: None,
+                    size: entry.size,
+                    modify_time: entry.server_modified.and_then(|time| {
+                        DateTime::parse_from_rfc3339(&time).ok().map(SystemTime::from)
+                    }),
                 });
             }
 
@@ -172,6 +314,41 @@ impl ReadProvider for Dropbox {
 
         Ok(Some(files))
     }
+
+    fn open_file(&self, path: &str) -> GenericResult<Box<dyn io::Read + Send>> {
+        #[derive(Serialize)]
+        struct Request<'a> {
+            path: &'a str,
+        }
+
+        #[derive(Deserialize)]
+        struct Metadata {
+            content_hash: String,
+        }
+
+        let request_json = serde_json::to_string(&Request {
+            path: path
+        }).map_err(HttpRequestBuildingError::new)?;
+
+        let request = HttpRequest::new(
+            Method::POST, self.content_endpoint.clone() + "/files/download",
+            self.upload_timeout,
+            RawResponseReader::new(), JsonErrorReader::<ApiError>::new())
+            .with_endpoint("/files/download")
+            .with_header("Dropbox-API-Arg", request_json)?;
+
+        let request = self.oauth.authenticate(request).map_err(|e| e.to_string())?;
+        let (headers, data) = self.client.download(request)?;
+
+        let metadata = headers.get("Dropbox-API-Result")
+            .and_then(|value| value.to_str().ok())
+            .ok_or("Got a response without file metadata")?;
+
+        let metadata: Metadata = serde_json::from_str(metadata).map_err(|e| format!(
+            "Got an invalid file metadata: {}", e))?;
+
+        Ok(Box::new(VerifyingReader::new(data, self.hasher(), &metadata.content_hash)))
+    }
 }
 
 impl WriteProvider for Dropbox {
@@ -180,7 +357,7 @@ impl WriteProvider for Dropbox {
     }
 
     fn max_request_size(&self) -> Option<u64> {
-        Some(150 * 1024 * 1024)
+        Some(self.chunk_size)
     }
 
     fn create_directory(&self, path: &str) -> EmptyResult {
@@ -210,11 +387,6 @@ impl WriteProvider for Dropbox {
             session_id: String,
         }
 
-        #[derive(Serialize)]
-        struct AppendRequest<'a> {
-            cursor: Cursor<'a>,
-        }
-
         #[derive(Serialize)]
         struct FinishRequest<'a> {
             cursor: Cursor<'a>,
@@ -244,13 +416,13 @@ impl WriteProvider for Dropbox {
         for result in chunk_streams.iter() {
             match result {
                 Ok(ChunkStream::Stream(offset, chunk_stream)) => {
-                    let _: Option<EmptyResponse> = self.content_request(
-                        "/files/upload_session/append_v2", &AppendRequest {
-                            cursor: Cursor {
-                                session_id: &start_response.session_id,
-                                offset: offset,
-                            },
-                        }, chunk_stream)?;
+                    let result = self.append_chunk(&start_response.session_id, offset, chunk_stream);
+
+                    // Unfinished upload sessions don't create any files and expire on Dropbox side,
+                    // so there is nothing to clean up here.
+                    if let Err(err) = result {
+                        return Err(stream_splitter::get_upload_error(&chunk_streams, err));
+                    }
                 },
                 Ok(ChunkStream::EofWithCheckSum(size, checksum)) => {
                     let finish_response: FinishResponse = self.content_request(
@@ -266,22 +438,30 @@ impl WriteProvider for Dropbox {
                         }, "")?;
 
                     if finish_response.content_hash != checksum {
-                        if let Err(err) = self.delete(&temp_path) {
-                            error!("Failed to delete a temporary {:?} file from {}: {}.",
-                                   temp_path, self.name(), err);
-                        }
+                        self.delete_temp_file(&temp_path);
                         return Err("Checksum mismatch".into());
                     }
 
-                    return self.rename_file(&temp_path, &path);
+                    return self.rename_file(&temp_path, &path).map_err(|e| {
+                        self.delete_temp_file(&temp_path);
+                        e
+                    });
                 }
-                Err(err) => return Err(err.into()),
+                Err(err) => return Err!("Upload has been aborted: {}", err),
             }
         }
 
         Err!("Chunk stream sender has been closed without a termination message")
     }
 
+    fn supports_rename(&self) -> bool {
+        true
+    }
+
+    fn rename(&self, src: &str, dst: &str) -> EmptyResult {
+        self.rename_file(src, dst)
+    }
+
     fn delete(&self, path: &str) -> EmptyResult {
         #[derive(Serialize)]
         struct Request<'a> {
@@ -294,6 +474,24 @@ impl WriteProvider for Dropbox {
 
         Ok(())
     }
+
+    fn get_checksum(&self, path: &str) -> GenericResult<Option<String>> {
+        #[derive(Serialize)]
+        struct Request<'a> {
+            path: &'a str,
+        }
+
+        #[derive(Deserialize)]
+        struct Metadata {
+            content_hash: Option<String>,
+        }
+
+        let metadata: Metadata = self.api_request("/files/get_metadata", &Request {
+            path: path
+        })?;
+
+        Ok(metadata.content_hash)
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -307,6 +505,7 @@ struct RouteError {
     #[serde(rename = ".tag")]
     tag: Option<String>,
     path: Option<PathError>,
+    correct_offset: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -322,4 +521,233 @@ impl fmt::Display for ApiError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Dropbox API error: {}", self.error_summary.trim_end_matches(|c| c == '.' || c == '/'))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, VecDeque};
+    use std::sync::{Arc, Mutex, mpsc};
+    use std::time::Instant;
+
+    use crate::http_client::{self, Timeouts};
+    use crate::stream_splitter::Data;
+    use crate::tests::{TestRequest, TestResponse, TestServer};
+
+    use super::*;
+
+    type Responses = Arc<Mutex<VecDeque<(u16, &'static str)>>>;
+
+    #[test]
+    fn chunk_retries() {
+        let responses: Responses = Arc::default();
+        let server = get_server(responses.clone());
+        let dropbox = get_dropbox(&server).with_max_chunk_retries(2);
+        let start_time = Instant::now();
+
+        let incorrect_offset = concat!(
+            r#"{"error_summary": "incorrect_offset/..", "#,
+            r#""error": {".tag": "incorrect_offset", "correct_offset": 14}}"#);
+        let not_found = r#"{"error_summary": "not_found/..", "error": {".tag": "not_found"}}"#;
+
+        for (scripted_responses, result) in &[
+            (vec![(500, "Internal error"), (200, "null")], Ok(())),
+
+            // The previous attempt has succeeded, but its response has been lost
+            (vec![(500, "Internal error"), (409, incorrect_offset)], Ok(())),
+
+            // The offset is always incorrect on the first attempt
+            (vec![(409, incorrect_offset)], Err("Dropbox API error: incorrect_offset")),
+
+            (vec![(409, not_found)], Err("Dropbox API error: not_found")),
+            (vec![(500, "Internal error"); 3], Err("Server returned an error: Internal error")),
+        ] {
+            let requests = scripted_responses.len();
+            responses.lock().unwrap().extend(scripted_responses.iter().cloned());
+
+            assert_eq!(
+                dropbox.append_chunk("session", 4, get_chunk("chunk data")).map_err(|e| e.to_string()),
+                result.map_err(ToOwned::to_owned));
+
+            assert!(responses.lock().unwrap().is_empty(), "{} requests haven't been sent", requests);
+        }
+
+        // The retries are delayed with linear backoff: 1 + 1 + (1 + 2) delays
+        assert!(start_time.elapsed() >= dropbox.chunk_retry_delay * 5);
+
+        let requests = server.stop();
+        let appends: Vec<_> = requests.iter()
+            .filter(|request| request.path == "/files/upload_session/append_v2").collect();
+
+        assert_eq!(appends.len(), 9);
+        assert!(appends.iter().all(|request| request.body == b"chunk data"));
+    }
+
+    #[test]
+    fn chunk_content_hash() {
+        let responses: Responses = Arc::default();
+        let server = get_server(responses.clone());
+        let buffering_dropbox = get_dropbox(&server).with_max_chunk_retries(1);
+        let streaming_dropbox = get_dropbox(&server);
+
+        let content_hash_mismatch = concat!(
+            r#"{"error_summary": "content_hash_mismatch/..", "#,
+            r#""error": {".tag": "content_hash_mismatch"}}"#);
+        responses.lock().unwrap().extend(vec![(409, content_hash_mismatch), (200, "null"), (200, "null")]);
+
+        // A corrupted chunk is rejected by Dropbox and resent
+        buffering_dropbox.append_chunk("session", 0, get_chunk("chunk data")).unwrap();
+
+        // Streamed chunks aren't buffered, so their hash is unknown
+        streaming_dropbox.append_chunk("session", 0, get_chunk("chunk data")).unwrap();
+
+        let mut hasher = buffering_dropbox.hasher();
+        hasher.write_all(b"chunk data").unwrap();
+        let expected_hash = hasher.finish();
+
+        let content_hashes: Vec<_> = server.stop().iter()
+            .filter(|request| request.path == "/files/upload_session/append_v2")
+            .map(|request| {
+                let args: serde_json::Value = serde_json::from_str(
+                    request.header("dropbox-api-arg").unwrap()).unwrap();
+                args.get("content_hash").map(|hash| hash.as_str().unwrap().to_owned())
+            }).collect();
+
+        assert_eq!(content_hashes, vec![Some(expected_hash.clone()), Some(expected_hash), None]);
+    }
+
+    #[test]
+    fn custom_headers() {
+        let responses: Responses = Arc::default();
+        let server = get_server(responses.clone());
+
+        let headers: BTreeMap<_, _> = vec![
+            ("X-Gateway-Token".to_owned(), "gateway-secret".to_owned()),
+        ].into_iter().collect();
+        let dropbox = get_dropbox(&server).with_headers(http_client::parse_headers(&headers).unwrap());
+
+        responses.lock().unwrap().push_back((200, "null"));
+        dropbox.append_chunk("session", 0, get_chunk("chunk data")).unwrap();
+
+        let requests = server.stop();
+        let append = requests.iter().find(|request| request.path == "/files/upload_session/append_v2").unwrap();
+        assert_eq!(append.header("x-gateway-token"), Some("gateway-secret"));
+        assert_eq!(append.header("user-agent"), Some("pyvsb-to-cloud"));
+
+        let headers: BTreeMap<_, _> = vec![("X Gateway".to_owned(), "value".to_owned())].into_iter().collect();
+        assert_eq!(http_client::parse_headers(&headers).unwrap_err().to_string(),
+                   r#"Invalid header name: "X Gateway""#);
+    }
+
+    #[test]
+    fn timeouts() {
+        let server = TestServer::new(|request: &TestRequest| {
+            if request.path == "/oauth/token" {
+                return TestResponse::new(200).with_body(
+                    "application/json", br#"{"access_token": "token", "expires_in": 3600}"#);
+            }
+
+            thread::sleep(Duration::from_millis(300));
+            TestResponse::new(200).with_body("application/json", b"null")
+        });
+
+        // API requests and data transfers have their own timeouts
+        let dropbox = get_dropbox(&server).with_client_options(ClientOptions {
+            timeouts: Timeouts {request: Some(Duration::from_millis(100)), ..Default::default()},
+            ..Default::default()
+        });
+        let err = dropbox.list_directory("/dst").err().unwrap().to_string();
+        assert!(err.contains("timed out"), "{}", err);
+        dropbox.append_chunk("session", 0, get_chunk("chunk data")).unwrap();
+
+        let dropbox = get_dropbox(&server).with_client_options(ClientOptions {
+            timeouts: Timeouts {upload: Some(Duration::from_millis(100)), ..Default::default()},
+            ..Default::default()
+        });
+        let err = dropbox.append_chunk("session", 0, get_chunk("chunk data")).unwrap_err().to_string();
+        assert!(err.contains("timed out"), "{}", err);
+
+        server.stop();
+    }
+
+    #[test]
+    fn chunk_size() {
+        let data = b"0123456789";
+        let mut hasher = Dropbox::new("id", "secret", "token").unwrap().hasher();
+        hasher.write_all(data).unwrap();
+        let checksum = hasher.finish();
+
+        let server = {
+            let checksum = checksum.clone();
+            TestServer::new(move |request: &TestRequest| {
+                let body = match request.path.as_str() {
+                    "/oauth/token" => r#"{"access_token": "token", "expires_in": 3600}"#.to_owned(),
+                    "/files/upload_session/start" => r#"{"session_id": "session"}"#.to_owned(),
+                    "/files/upload_session/finish" => format!(r#"{{"content_hash": "{}"}}"#, checksum),
+                    _ => "{}".to_owned(),
+                };
+                TestResponse::new(200).with_body("application/json", body.as_bytes())
+            })
+        };
+
+        let dropbox = get_dropbox(&server).with_chunk_size(4);
+        assert_eq!(dropbox.max_request_size(), Some(4));
+
+        let (data_tx, data_rx) = mpsc::sync_channel(2);
+        data_tx.send(Ok(Data::Payload(Bytes::from_static(data)))).unwrap();
+        data_tx.send(Ok(Data::EofWithChecksum(checksum))).unwrap();
+        drop(data_tx);
+
+        let (chunk_streams, splitter) = stream_splitter::split(
+            data_rx, dropbox.max_request_size(), None).unwrap();
+        dropbox.upload_file("/dst", "file.tmp", "file", chunk_streams).unwrap();
+        splitter.join().unwrap().unwrap();
+
+        let appends: Vec<(u64, Vec<u8>)> = server.stop().iter()
+            .filter(|request| request.path == "/files/upload_session/append_v2")
+            .map(|request| {
+                let args: serde_json::Value = serde_json::from_str(
+                    request.header("dropbox-api-arg").unwrap()).unwrap();
+                (args["cursor"]["offset"].as_u64().unwrap(), request.body.clone())
+            }).collect();
+
+        assert_eq!(appends, vec![
+            (0, b"0123".to_vec()),
+            (4, b"4567".to_vec()),
+            (8, b"89".to_vec()),
+        ]);
+    }
+
+    fn get_server(append_responses: Responses) -> TestServer {
+        TestServer::new(move |request: &TestRequest| {
+            let (status, body) = match request.path.as_str() {
+                "/oauth/token" => (200, r#"{"access_token": "token", "expires_in": 3600}"#),
+                "/files/upload_session/append_v2" => append_responses.lock().unwrap().pop_front().unwrap(),
+                _ => (404, "Not found"),
+            };
+
+            let content_type = if body.starts_with('{') || body == "null" {
+                "application/json"
+            } else {
+                "text/plain"
+            };
+
+            TestResponse::new(status).with_body(content_type, body.as_bytes())
+        })
+    }
+
+    fn get_dropbox(server: &TestServer) -> Dropbox {
+        Dropbox {
+            oauth: OauthClient::new(NAME, &format!("{}/oauth", server.url), "id", "secret", "token"),
+            api_endpoint: server.url.clone(),
+            content_endpoint: server.url.clone(),
+            chunk_retry_delay: Duration::from_millis(10),
+            ..Dropbox::new("id", "secret", "token").unwrap()
+        }
+    }
+
+    fn get_chunk(data: &str) -> ChunkReceiver {
+        let (tx, rx) = mpsc::sync_channel(1);
+        tx.send(Ok(Bytes::copy_from_slice(data.as_bytes()))).unwrap();
+        rx
+    }
+}
@@ -1,25 +1,84 @@
 use std::fs;
-use std::io;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
 
-use crate::core::GenericResult;
-use crate::provider::{Provider, ProviderType, ReadProvider, File, FileType};
+use nix::unistd;
+
+use crate::core::{EmptyResult, GenericResult};
+use crate::hash::{Hasher, Md5};
+use crate::provider::{Provider, ProviderType, ReadProvider, WriteProvider, File, FileType};
+use crate::stream_splitter::{ChunkStreamReceiver, ChunkStream};
 
 pub struct Filesystem {
+    name: &'static str,
+    type_: ProviderType,
+    include_hidden: bool,
+    fsync: bool,
 }
 
 impl Filesystem {
-    pub fn new() -> Filesystem {
-        Filesystem{}
+    // Hidden files and directories (dotfiles) are never a part of pyvsb backups, but some setups
+    // store state files of other tools next to them, so by default they are excluded from listing.
+    pub fn new(include_hidden: bool) -> Filesystem {
+        Filesystem {
+            name: "Local storage",
+            type_: ProviderType::Local,
+            include_hidden,
+            fsync: false,
+        }
+    }
+
+    // Creates a provider for storing encrypted backups in a local directory (NAS mount point, external
+    // drive, etc). With fsync enabled, a backup is reported as uploaded only when both the file and
+    // its directory entry are flushed to the disk.
+    pub fn new_destination(fsync: bool) -> Filesystem {
+        Filesystem {
+            name: "Filesystem",
+            type_: ProviderType::Cloud,
+            include_hidden: true, // Temporary files are hidden
+            fsync,
+        }
+    }
+
+    fn write_file(&self, file: &mut fs::File, chunk_streams: ChunkStreamReceiver) -> EmptyResult {
+        let mut hasher = self.hasher();
+
+        for result in chunk_streams.iter() {
+            match result {
+                Ok(ChunkStream::Stream(_, chunk_stream)) => {
+                    for chunk in chunk_stream.iter() {
+                        let chunk = chunk.map_err(|e| format!("Upload has been aborted: {}", e))?;
+                        file.write_all(&chunk)?;
+                        hasher.write_all(&chunk)?;
+                    }
+                },
+                Ok(ChunkStream::EofWithCheckSum(_, checksum)) => {
+                    if hasher.finish() != checksum {
+                        return Err!("Checksum mismatch");
+                    }
+
+                    if self.fsync {
+                        sync(file, "file")?;
+                    }
+
+                    return Ok(());
+                },
+                Err(err) => return Err!("Upload has been aborted: {}", err),
+            }
+        }
+
+        Err!("Chunk stream sender has been closed without a termination message")
     }
 }
 
 impl Provider for Filesystem {
     fn name(&self) -> &'static str {
-        "Local storage"
+        self.name
     }
 
     fn type_(&self) -> ProviderType {
-        ProviderType::Local
+        self.type_
     }
 }
 
@@ -41,6 +100,10 @@ impl ReadProvider for Filesystem {
             let name = entry.file_name().into_string().map_err(|file_name| format!(
                 "Got an invalid file name: {:?}", file_name.to_string_lossy()))?;
 
+            if !self.include_hidden && name.starts_with('.') {
+                continue;
+            }
+
             let metadata = entry.metadata().map_err(|e| format!(
                 "Unable to get metadata of {:?}: {}", entry.path().to_string_lossy(), e))?;
 
@@ -57,13 +120,193 @@ impl ReadProvider for Filesystem {
                 FileType::Directory | FileType::Other => None,
             };
 
-            files.push(File {name, type_, size})
+            let modify_time = metadata.modified().ok();
+            files.push(File {name, type_, size, modify_time})
         }
 
         Ok(Some(files))
     }
 
-    fn open_file(&self, path: &str) -> GenericResult<Box<dyn io::Read>> {
+    fn open_file(&self, path: &str) -> GenericResult<Box<dyn io::Read + Send>> {
         Ok(Box::new(fs::File::open(path)?))
     }
-}
\ No newline at end of file
+}
+
+impl WriteProvider for Filesystem {
+    fn hasher(&self) -> Box<dyn Hasher> {
+        Box::new(Md5::new())
+    }
+
+    fn max_request_size(&self) -> Option<u64> {
+        None
+    }
+
+    fn create_directory(&self, path: &str) -> EmptyResult {
+        fs::create_dir(path)?;
+
+        if self.fsync {
+            sync_parent_directory(Path::new(path))?;
+        }
+
+        Ok(())
+    }
+
+    fn upload_file(&self, directory_path: &str, temp_name: &str, name: &str,
+                   chunk_streams: ChunkStreamReceiver) -> EmptyResult {
+        let directory_path = Path::new(directory_path);
+        let temp_path = directory_path.join(temp_name);
+        let path = directory_path.join(name);
+
+        let mut file = fs::File::create(&temp_path).map_err(|e| format!(
+            "Unable to create {:?}: {}", temp_path, e))?;
+
+        let result = self.write_file(&mut file, chunk_streams).and_then(|_| {
+            fs::rename(&temp_path, &path).map_err(|e| format!(
+                "Unable to rename {:?} to {:?}: {}", temp_path, path, e).into())
+        });
+
+        if let Err(err) = result {
+            if let Err(err) = fs::remove_file(&temp_path) {
+                error!("Failed to delete a temporary {:?} file from {}: {}.", temp_path, self.name(), err);
+            }
+            return Err(err);
+        }
+
+        if self.fsync {
+            sync_parent_directory(&path)?;
+        }
+
+        Ok(())
+    }
+
+    fn supports_rename(&self) -> bool {
+        true
+    }
+
+    fn rename(&self, src: &str, dst: &str) -> EmptyResult {
+        if fs::symlink_metadata(dst).is_ok() {
+            return Err!("{:?} already exists", dst);
+        }
+
+        fs::rename(src, dst).map_err(|e| format!("Unable to rename {:?} to {:?}: {}", src, dst, e))?;
+
+        if self.fsync {
+            sync_parent_directory(Path::new(dst))?;
+        }
+
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> EmptyResult {
+        let path = Path::new(path);
+
+        if fs::symlink_metadata(path)?.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+
+        if self.fsync {
+            sync_parent_directory(path)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn sync_parent_directory(path: &Path) -> EmptyResult {
+    let directory_path = path.parent().ok_or_else(|| format!(
+        "Unable to determine parent directory of {:?}", path))?;
+
+    let directory = fs::File::open(directory_path).map_err(|e| format!(
+        "Unable to open {:?}: {}", directory_path, e))?;
+
+    sync(&directory, "directory")
+}
+
+fn sync(file: &fs::File, kind: &str) -> EmptyResult {
+    unistd::fsync(file.as_raw_fd()).map_err(|e| format!("Unable to fsync() the {}: {}", kind, e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use bytes::Bytes;
+
+    use crate::tests::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn hidden_files() {
+        let temp_dir = TempDir::new("filesystem_hidden_files");
+        fs::create_dir(temp_dir.0.join("2020.01.01")).unwrap();
+        fs::create_dir(temp_dir.0.join(".state")).unwrap();
+        fs::write(temp_dir.0.join(".lock"), b"").unwrap();
+        fs::write(temp_dir.0.join("data"), b"data").unwrap();
+
+        let list = |filesystem: Filesystem| {
+            let mut names: Vec<_> = filesystem.list_directory(temp_dir.0.to_str().unwrap()).unwrap().unwrap()
+                .into_iter().map(|file| file.name).collect();
+            names.sort();
+            names
+        };
+
+        assert_eq!(list(Filesystem::new(false)), vec!["2020.01.01", "data"]);
+        assert_eq!(list(Filesystem::new(true)), vec![".lock", ".state", "2020.01.01", "data"]);
+
+        // Temporary files are hidden, so the destination must always see them
+        assert_eq!(list(Filesystem::new_destination(false)), vec![".lock", ".state", "2020.01.01", "data"]);
+    }
+
+    #[test]
+    fn upload() {
+        let temp_dir = TempDir::new("filesystem_upload");
+        let path = temp_dir.0.to_str().unwrap();
+        let provider = Filesystem::new_destination(true);
+
+        let mut hasher = provider.hasher();
+        hasher.write_all(b"data").unwrap();
+        let checksum = hasher.finish();
+
+        let group_path = format!("{}/group", path);
+        provider.create_directory(&group_path).unwrap();
+
+        provider.upload_file(&group_path, ".backup.tmp", "backup", get_chunk_streams(
+            b"data", Ok(checksum.clone()))).unwrap();
+        assert_eq!(fs::read(format!("{}/backup", group_path)).unwrap(), b"data");
+
+        assert_eq!(provider.upload_file(&group_path, ".other.tmp", "other", get_chunk_streams(
+            b"data", Ok("invalid".to_owned()))).unwrap_err().to_string(), "Checksum mismatch");
+
+        assert_eq!(provider.upload_file(&group_path, ".other.tmp", "other", get_chunk_streams(
+            b"data", Err("Archive error".to_owned()))).unwrap_err().to_string(),
+            "Upload has been aborted: Archive error");
+
+        // Failed uploads leave nothing behind
+        let files = provider.list_directory(&group_path).unwrap().unwrap();
+        assert_eq!(files.iter().map(|file| file.name.as_str()).collect::<Vec<_>>(), vec!["backup"]);
+
+        let new_group_path = format!("{}/new-group", path);
+        provider.rename(&group_path, &new_group_path).unwrap();
+        provider.create_directory(&group_path).unwrap();
+        assert!(provider.rename(&group_path, &new_group_path).unwrap_err().to_string().contains(
+            "already exists"));
+
+        provider.delete(&new_group_path).unwrap();
+        assert!(provider.list_directory(&new_group_path).unwrap().is_none());
+    }
+
+    fn get_chunk_streams(data: &[u8], result: Result<String, String>) -> ChunkStreamReceiver {
+        let (chunk_tx, chunk_rx) = mpsc::sync_channel(1);
+        chunk_tx.send(Ok(Bytes::copy_from_slice(data))).unwrap();
+
+        let (streams_tx, streams_rx) = mpsc::sync_channel(2);
+        streams_tx.send(Ok(ChunkStream::Stream(0, chunk_rx))).unwrap();
+        streams_tx.send(result.map(|checksum| ChunkStream::EofWithCheckSum(data.len() as u64, checksum))).unwrap();
+
+        streams_rx
+    }
+}
@@ -1,18 +1,22 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
+use std::io;
 use std::ops::Add;
 use std::time::Duration;
 
 use serde::de;
 
 use crate::core::{EmptyResult, GenericResult};
-use crate::hash::{Hasher, Md5};
+use crate::hash::{Hasher, Md5, VerifyingReader};
 use crate::http_client::{HttpClient, Method, HttpRequest, HttpResponse, EmptyRequest,
-                         RawResponseReader, JsonErrorReader, HttpClientError, headers};
+                         RawResponseReader, JsonErrorReader, HttpClientError, Headers,
+                         ClientOptions, headers};
 use crate::oauth::OauthClient;
 use crate::provider::{Provider, ProviderType, ReadProvider, WriteProvider, File, FileType};
-use crate::stream_splitter::{ChunkStreamReceiver, ChunkStream};
+use crate::stream_splitter::{self, ChunkStreamReceiver, ChunkStream};
+
+const NAME: &str = "Google Drive";
 
 const OAUTH_ENDPOINT: &str = "https://accounts.google.com/o/oauth2";
 
@@ -25,16 +29,34 @@ const UPLOAD_REQUEST_TIMEOUT: u64 = 60 * 60;
 pub struct GoogleDrive {
     oauth: OauthClient,
     client: HttpClient,
+    api_endpoint: String,
+    request_timeout: Duration,
+    upload_timeout: Duration,
 }
 
 impl GoogleDrive {
     pub fn new(client_id: &str, client_secret: &str, refresh_token: &str) -> GoogleDrive {
         GoogleDrive {
-            oauth: OauthClient::new(OAUTH_ENDPOINT, client_id, client_secret, refresh_token),
-            client: HttpClient::new(),
+            oauth: OauthClient::new(NAME, OAUTH_ENDPOINT, client_id, client_secret, refresh_token),
+            client: HttpClient::new().with_metrics_label(NAME),
+            api_endpoint: API_ENDPOINT.to_owned(),
+            request_timeout: Duration::from_secs(API_REQUEST_TIMEOUT),
+            upload_timeout: Duration::from_secs(UPLOAD_REQUEST_TIMEOUT),
         }
     }
 
+    pub fn with_headers(mut self, headers: Headers) -> GoogleDrive {
+        self.client = self.client.with_default_headers(headers);
+        self
+    }
+
+    pub fn with_client_options(mut self, options: ClientOptions) -> GoogleDrive {
+        self.client = self.client.with_options(&options);
+        self.request_timeout = options.timeouts.request.unwrap_or(self.request_timeout);
+        self.upload_timeout = options.timeouts.upload.unwrap_or(self.upload_timeout);
+        self
+    }
+
     fn start_file_upload(&self, path: &str, mime_type: &str, overwrite: bool) -> GenericResult<String> {
         let (parent_id, name, file_id) = self.get_new_file_info(path)?;
         if file_id.is_some() && !overwrite {
@@ -54,8 +76,9 @@ impl GoogleDrive {
 
         let mut request = self.authenticate(
             HttpRequest::new(
-                method, url, Duration::from_secs(API_REQUEST_TIMEOUT),
-                RawResponseReader::new(), JsonErrorReader::<GoogleDriveApiError>::new())
+                method, url, self.request_timeout,
+                RawResponseReader::new(), JsonErrorReader::<GoogleDriveApiError>::new(),
+            ).with_endpoint(if file_id.is_some() {"files.update"} else {"files.create"})
         )?;
 
         request = if file_id.is_some() {
@@ -121,7 +144,8 @@ impl GoogleDrive {
 
         if path == "/" {
             let request_path = "/files/".to_owned() + &cur_dir_id;
-            let file_metadata = self.client.send(self.api_request(Method::GET, &request_path)?)?;
+            let request = self.api_request(Method::GET, &request_path)?.with_endpoint("files.get");
+            let file_metadata = self.client.send(request)?;
             return Ok(Some(file_metadata));
         } else if !path.starts_with('/') || path.ends_with('/') {
             return Err!("Invalid path: {:?}", path);
@@ -164,6 +188,8 @@ impl GoogleDrive {
         #[derive(Serialize)]
         struct RequestParams {
             q: String,
+            #[serde(rename = "pageSize")]
+            page_size: usize,
             #[serde(rename = "pageToken")]
             page_token: Option<String>,
         }
@@ -179,14 +205,20 @@ impl GoogleDrive {
 
         let mut request_params = RequestParams {
             q: format!("'{}' in parents and trashed = false", id),
+            page_size: 1000, // The maximum allowed value
             page_token: None,
         };
         let (mut page, page_limit) = (1, 1000);
         let mut files = HashMap::new();
 
         loop {
-            let request = self.api_request(Method::GET, "/files")?.with_params(&request_params)?;
-            let mut response: Response = self.client.send(request)?;
+            let request = self.api_request(Method::GET, "/files")?
+                .with_endpoint("files.list")
+                .with_params(&request_params)?;
+
+            // Partial listing must never be treated as a complete one
+            let mut response: Response = self.client.send(request).map_err(|e| format!(
+                "Directory listing has been interrupted on page #{}: {}", page, e))?;
 
             if response.incomplete_search {
                 return Err!("Got an incomplete result on directory listing")
@@ -197,7 +229,9 @@ impl GoogleDrive {
             }
 
             if let Some(next_page_token) = response.next_page_token {
-                if page >= page_limit {
+                if request_params.page_token.as_ref() == Some(&next_page_token) {
+                    return Err!("Got a looped page token on directory listing");
+                } else if page >= page_limit {
                     return Err!("Directory listing page limit has exceeded");
                 }
 
@@ -211,6 +245,43 @@ impl GoogleDrive {
         Ok(files)
     }
 
+    fn rename_file(&self, id: &str, name: &str) -> EmptyResult {
+        #[derive(Serialize)]
+        struct Request<'a> {
+            name: &'a str,
+        }
+
+        let request = self.api_request(Method::PATCH, &"/files/".to_owned().add(id))?
+            .with_endpoint("files.update")
+            .with_json(&Request {
+                name: name,
+            })?;
+        let _: GoogleDriveFile = self.client.send(request)?;
+
+        Ok(())
+    }
+
+    fn get_checksum(&self, id: &str) -> GenericResult<String> {
+        #[derive(Deserialize)]
+        struct Metadata {
+            #[serde(rename = "md5Checksum")]
+            md5_checksum: String,
+        }
+
+        let request = self.api_request(
+            Method::GET, &"/files/".to_owned().add(id).add("?fields=md5Checksum"),
+        )?.with_endpoint("files.get");
+        let metadata: Metadata = self.client.send(request)?;
+
+        Ok(metadata.md5_checksum)
+    }
+
+    fn delete_temp_file(&self, path: &str) {
+        if let Err(err) = self.delete_file(path, true) {
+            error!("Failed to delete a temporary {:?} file from {}: {}.", path, self.name(), err);
+        }
+    }
+
     fn delete_file(&self, path: &str, only_if_exists: bool) -> EmptyResult {
         let file = match self.stat_path(path)? {
             Some(file) => file,
@@ -223,7 +294,8 @@ impl GoogleDrive {
             },
         };
 
-        let request = self.delete_request(&"/files/".to_owned().add(&file.id))?;
+        let request = self.delete_request(&"/files/".to_owned().add(&file.id))?
+            .with_endpoint("files.delete");
         self.client.send(request)?;
 
         Ok(())
@@ -238,28 +310,28 @@ impl GoogleDrive {
     {
         Ok(self.authenticate(
             HttpRequest::new_json(
-                method, API_ENDPOINT.to_owned() + path,
-                Duration::from_secs(API_REQUEST_TIMEOUT))
+                method, self.api_endpoint.clone() + path,
+                self.request_timeout)
         )?)
     }
 
     fn delete_request(&self, path: &str) -> Result<HttpRequest<HttpResponse, GoogleDriveApiError>, GoogleDriveError> {
         Ok(self.authenticate(
             HttpRequest::new(
-                Method::DELETE, API_ENDPOINT.to_owned() + path,
-                Duration::from_secs(API_REQUEST_TIMEOUT),
+                Method::DELETE, self.api_endpoint.clone() + path,
+                self.request_timeout,
                 RawResponseReader::new(), JsonErrorReader::new())
         )?)
     }
 
-    fn file_upload_request(&self, location: String, timeout: u64) -> HttpRequest<GoogleDriveFile, GoogleDriveApiError> {
-        HttpRequest::new_json(Method::PUT, location, Duration::from_secs(timeout))
+    fn file_upload_request(&self, location: String, timeout: Duration) -> HttpRequest<GoogleDriveFile, GoogleDriveApiError> {
+        HttpRequest::new_json(Method::PUT, location, timeout).with_endpoint("upload")
     }
 }
 
 impl Provider for GoogleDrive {
     fn name(&self) -> &'static str {
-        "Google Drive"
+        NAME
     }
 
     fn type_(&self) -> ProviderType {
@@ -296,11 +368,30 @@ impl ReadProvider for GoogleDrive {
                 type_: file.type_(),
                 name: file.name,
                 size: None,
+                modify_time: None,
             }));
         }
 
         Ok(Some(files))
     }
+
+    fn open_file(&self, path: &str) -> GenericResult<Box<dyn io::Read + Send>> {
+        let file = self.stat_path(path)?.ok_or_else(|| format!("{:?} doesn't exist", path))?;
+        if file.type_() != FileType::File {
+            return Err!("{:?} is not a file", path);
+        }
+
+        let checksum = self.get_checksum(&file.id)?;
+
+        let request = self.authenticate(HttpRequest::new(
+            Method::GET, self.api_endpoint.clone() + "/files/" + &file.id + "?alt=media",
+            self.upload_timeout,
+            RawResponseReader::new(), JsonErrorReader::<GoogleDriveApiError>::new(),
+        ).with_endpoint("files.get_media"))?;
+
+        let (_, data) = self.client.download(request)?;
+        Ok(Box::new(VerifyingReader::new(data, self.hasher(), &checksum)))
+    }
 }
 
 impl WriteProvider for GoogleDrive {
@@ -315,7 +406,7 @@ impl WriteProvider for GoogleDrive {
     fn create_directory(&self, path: &str) -> EmptyResult {
         let content_type = DIRECTORY_MIME_TYPE;
         let upload_url = self.start_file_upload(path, content_type, false)?;
-        let request = self.file_upload_request(upload_url, API_REQUEST_TIMEOUT)
+        let request = self.file_upload_request(upload_url, self.request_timeout)
             .with_text_body(content_type, "")?;
         self.client.send(request)?;
         Ok(())
@@ -334,9 +425,17 @@ impl WriteProvider for GoogleDrive {
 
                     let content_type = "application/octet-stream";
                     let upload_url = self.start_file_upload(&temp_path, content_type, true)?;
-                    let request = self.file_upload_request(upload_url, UPLOAD_REQUEST_TIMEOUT)
+                    let request = self.file_upload_request(upload_url, self.upload_timeout)
                         .with_body(content_type, chunk_stream)?;
-                    file = Some(self.client.send(request)?);
+
+                    // The upload may overwrite a stale temporary file, so clean it up on failure
+                    file = Some(match self.client.send(request) {
+                        Ok(file) => file,
+                        Err(err) => {
+                            self.delete_temp_file(&temp_path);
+                            return Err(stream_splitter::get_upload_error(&chunk_streams, err));
+                        },
+                    });
                 },
                 Ok(ChunkStream::EofWithCheckSum(size, checksum)) => {
                     if size == 0 {
@@ -345,45 +444,21 @@ impl WriteProvider for GoogleDrive {
 
                     let file = file.unwrap();
 
-                    #[derive(Deserialize)]
-                    struct Metadata {
-                        #[serde(rename = "md5Checksum")]
-                        md5_checksum: String,
-                    }
-
-                    let request = self.api_request(
-                        Method::GET, &"/files/".to_owned().add(&file.id).add("?fields=md5Checksum"))?;
-                    let metadata: Metadata = self.client.send(request)?;
-
-                    if metadata.md5_checksum != checksum {
-                        if let Err(e) = self.delete_file(&temp_path, true) {
-                            error!("Failed to delete a temporary {:?} file from {}: {}.",
-                                   temp_path, self.name(), e);
-                        }
+                    if self.get_checksum(&file.id)? != checksum {
+                        self.delete_temp_file(&temp_path);
                         return Err!("Checksum mismatch");
                     }
 
-                    #[derive(Serialize)]
-                    struct RenameRequest<'a> {
-                        name: &'a str,
-                    }
-                    let request = self.api_request(
-                        Method::PATCH, &"/files/".to_owned().add(&file.id))?
-                        .with_json(&RenameRequest {
-                            name: name,
-                        })?;
-                    let _: GoogleDriveFile = self.client.send(request)?;
-
-                    return Ok(())
+                    return self.rename_file(&file.id, name).map_err(|e| {
+                        self.delete_temp_file(&temp_path);
+                        e
+                    });
                 }
                 Err(err) => {
                     if file.is_some() {
-                        if let Err(e) = self.delete_file(&temp_path, true) {
-                            error!("Failed to delete a temporary {:?} file from {}: {}.",
-                                   temp_path, self.name(), e);
-                        }
+                        self.delete_temp_file(&temp_path);
                     }
-                    return Err(err.into())
+                    return Err!("Upload has been aborted: {}", err);
                 },
             }
         }
@@ -391,6 +466,28 @@ impl WriteProvider for GoogleDrive {
         Err!("Chunk stream sender has been closed without a termination message")
     }
 
+    fn supports_rename(&self) -> bool {
+        true
+    }
+
+    fn rename(&self, src: &str, dst: &str) -> EmptyResult {
+        let src_parent = src.rsplitn(2, '/').nth(1);
+        let dst_parent = dst.rsplitn(2, '/').nth(1);
+        if src_parent.is_none() || src_parent != dst_parent {
+            return Err!("Unable to rename {:?} to {:?}: only renaming within a directory is supported",
+                        src, dst);
+        }
+
+        let file = self.stat_path(src)?.ok_or_else(|| format!("{:?} doesn't exist", src))?;
+
+        let (_, name, dst_id) = self.get_new_file_info(dst)?;
+        if dst_id.is_some() {
+            return Err!("{:?} already exists", dst);
+        }
+
+        self.rename_file(&file.id, &name)
+    }
+
     fn delete(&self, path: &str) -> EmptyResult {
         self.delete_file(path, false)
     }
@@ -477,3 +574,62 @@ impl fmt::Display for GoogleDriveApiError {
         write!(f, "Google Drive error: {}", self.error.message.trim_end_matches('.'))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{TestRequest, TestResponse, TestServer};
+
+    use super::*;
+
+    #[test]
+    fn listing_pagination() {
+        let server = TestServer::new(|request: &TestRequest| {
+            if request.path == "/oauth/token" {
+                return TestResponse::new(200).with_body(
+                    "application/json", br#"{"access_token": "token", "expires_in": 3600}"#);
+            }
+
+            let next_page = request.path.contains("pageToken=");
+            let body: &[u8] = if request.path.contains("looped") {
+                br#"{"files": [], "incompleteSearch": false, "nextPageToken": "page-2"}"#
+            } else if !next_page {
+                br#"{"files": [
+                    {"id": "1", "name": "2020.01.01", "mimeType": "application/vnd.google-apps.folder"}
+                ], "incompleteSearch": false, "nextPageToken": "page-2"}"#
+            } else if request.path.contains("interrupted") {
+                return TestResponse::new(400).with_body(
+                    "application/json", br#"{"error": {"message": "Invalid page token."}}"#);
+            } else {
+                br#"{"files": [
+                    {"id": "2", "name": "2020.01.02", "mimeType": "application/vnd.google-apps.folder"}
+                ], "incompleteSearch": false}"#
+            };
+
+            TestResponse::new(200).with_body("application/json", body)
+        });
+
+        let google_drive = GoogleDrive {
+            oauth: OauthClient::new(NAME, &format!("{}/oauth", server.url), "id", "secret", "token"),
+            api_endpoint: server.url.clone(),
+            ..GoogleDrive::new("id", "secret", "token")
+        };
+
+        let files = google_drive.list_children("complete").unwrap();
+        let mut names: Vec<_> = files.keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, vec!["2020.01.01", "2020.01.02"]);
+
+        assert_eq!(
+            google_drive.list_children("looped").err().unwrap().to_string(),
+            "Got a looped page token on directory listing");
+
+        assert_eq!(
+            google_drive.list_children("interrupted").err().unwrap().to_string(),
+            "Directory listing has been interrupted on page #2: Google Drive error: Invalid page token");
+
+        let requests = server.stop();
+        assert_eq!(requests.iter().filter(|request| request.path.starts_with("/files?")).count(), 6);
+        assert!(requests.iter().all(|request| request.path == "/oauth/token" ||
+            request.header("authorization") == Some("Bearer token")));
+    }
+}
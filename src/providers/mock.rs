@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::core::{EmptyResult, GenericResult};
+use crate::hash::{Hasher, ChunkedSha256};
+use crate::provider::{Provider, ProviderType, ReadProvider, WriteProvider, File, FileType};
+use crate::stream_splitter::{ChunkStream, ChunkStreamReceiver};
+
+// In-memory provider which records all modifications made through it
+#[derive(Clone)]
+pub struct MockProvider {
+    type_: ProviderType,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    // Directories are stored as entries without data
+    files: BTreeMap<String, Option<Vec<u8>>>,
+    modify_times: BTreeMap<String, SystemTime>,
+    checksum: Option<Result<String, String>>,
+    max_request_size: Option<u64>,
+    supports_rename: bool,
+    modifications: Vec<String>,
+
+    read_delay: Option<Duration>,
+    active_reads: usize,
+    max_active_reads: usize,
+}
+
+impl MockProvider {
+    pub fn new(type_: ProviderType) -> MockProvider {
+        MockProvider {type_, state: Arc::new(Mutex::new(State::default()))}
+    }
+
+    // Returns the same storage as seen by a provider of another type
+    pub fn with_type(&self, type_: ProviderType) -> MockProvider {
+        MockProvider {type_, state: self.state.clone()}
+    }
+
+    pub fn add_directory(&self, path: &str) {
+        self.state.lock().unwrap().files.insert(path.to_owned(), None);
+    }
+
+    pub fn add_file(&self, path: &str, data: &[u8]) {
+        self.state.lock().unwrap().files.insert(path.to_owned(), Some(data.to_vec()));
+    }
+
+    pub fn set_modify_time(&self, path: &str, time: SystemTime) {
+        self.state.lock().unwrap().modify_times.insert(path.to_owned(), time);
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        self.state.lock().unwrap().files.contains_key(path)
+    }
+
+    // Makes the provider report the specified server-side checksum for all files
+    pub fn set_checksum(&self, checksum: &str) {
+        self.state.lock().unwrap().checksum.replace(Ok(checksum.to_owned()));
+    }
+
+    pub fn set_checksum_error(&self, error: &str) {
+        self.state.lock().unwrap().checksum.replace(Err(error.to_owned()));
+    }
+
+    // Makes uploads to be split into chunk streams of the specified maximum size
+    pub fn set_max_request_size(&self, size: u64) {
+        self.state.lock().unwrap().max_request_size.replace(size);
+    }
+
+    pub fn enable_rename(&self) {
+        self.state.lock().unwrap().supports_rename = true;
+    }
+
+    // Makes each opened file to be read slowly, so concurrent reads overlap
+    pub fn set_read_delay(&self, delay: Duration) {
+        self.state.lock().unwrap().read_delay.replace(delay);
+    }
+
+    pub fn max_concurrent_reads(&self) -> usize {
+        self.state.lock().unwrap().max_active_reads
+    }
+
+    pub fn modifications(&self) -> Vec<String> {
+        self.state.lock().unwrap().modifications.clone()
+    }
+
+    fn modify(&self, modification: String) -> MutexGuard<'_, State> {
+        let mut state = self.state.lock().unwrap();
+        state.modifications.push(modification);
+        state
+    }
+
+    fn receive_file(&self, chunk_streams: ChunkStreamReceiver) -> GenericResult<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut hasher = self.hasher();
+        let max_request_size = self.max_request_size();
+
+        for result in chunk_streams.iter() {
+            match result {
+                Ok(ChunkStream::Stream(offset, chunk_stream)) => {
+                    if offset != data.len() as u64 {
+                        return Err!("Got a chunk stream with an unexpected offset: {}", offset);
+                    }
+
+                    for chunk in chunk_stream.iter() {
+                        let chunk = chunk.map_err(|e| format!("Upload has been aborted: {}", e))?;
+                        data.extend_from_slice(&chunk);
+                        hasher.write_all(&chunk)?;
+                    }
+
+                    if let Some(max_size) = max_request_size {
+                        if data.len() as u64 - offset > max_size {
+                            return Err!("Got a chunk stream which exceeds the maximum request size");
+                        }
+                    }
+                },
+                Ok(ChunkStream::EofWithCheckSum(_, checksum)) => {
+                    if hasher.finish() != checksum {
+                        return Err!("Checksum mismatch");
+                    }
+                    return Ok(data);
+                },
+                Err(err) => return Err!("Upload has been aborted: {}", err),
+            }
+        }
+
+        Err!("Chunk stream sender has been closed without a termination message")
+    }
+}
+
+impl Provider for MockProvider {
+    fn name(&self) -> &'static str {
+        "Mock"
+    }
+
+    fn type_(&self) -> ProviderType {
+        self.type_
+    }
+}
+
+impl ReadProvider for MockProvider {
+    fn list_directory(&self, path: &str) -> GenericResult<Option<Vec<File>>> {
+        let state = self.state.lock().unwrap();
+
+        match state.files.get(path) {
+            Some(None) => {},
+            Some(Some(_)) => return Err!("{:?} is not a directory", path),
+            None => return Ok(None),
+        }
+
+        let prefix = format!("{}/", path);
+
+        Ok(Some(state.files.iter().filter_map(|(file_path, data)| {
+            let name = file_path.strip_prefix(&prefix)?;
+            if name.contains('/') {
+                return None;
+            }
+
+            Some(File {
+                name: name.to_owned(),
+                type_: if data.is_some() {FileType::File} else {FileType::Directory},
+                size: data.as_ref().map(|data| data.len() as u64),
+                modify_time: state.modify_times.get(file_path).copied(),
+            })
+        }).collect()))
+    }
+
+    fn open_file(&self, path: &str) -> GenericResult<Box<dyn io::Read + Send>> {
+        let mut state = self.state.lock().unwrap();
+
+        let data = match state.files.get(path) {
+            Some(Some(data)) => data.clone(),
+            _ => return Err!("{:?} doesn't exist", path),
+        };
+
+        state.active_reads += 1;
+        state.max_active_reads = state.max_active_reads.max(state.active_reads);
+
+        Ok(Box::new(MockReader {
+            data: io::Cursor::new(data),
+            delay: state.read_delay,
+            state: self.state.clone(),
+        }))
+    }
+}
+
+struct MockReader {
+    data: io::Cursor<Vec<u8>>,
+    delay: Option<Duration>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Read for MockReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(delay) = self.delay.take() {
+            thread::sleep(delay);
+        }
+        self.data.read(buf)
+    }
+}
+
+impl Drop for MockReader {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().active_reads -= 1;
+    }
+}
+
+impl WriteProvider for MockProvider {
+    fn hasher(&self) -> Box<dyn Hasher> {
+        Box::new(ChunkedSha256::new(4 * 1024 * 1024))
+    }
+
+    fn max_request_size(&self) -> Option<u64> {
+        self.state.lock().unwrap().max_request_size
+    }
+
+    fn create_directory(&self, path: &str) -> EmptyResult {
+        self.modify(format!("create_directory {}", path)).files.insert(path.to_owned(), None);
+        Ok(())
+    }
+
+    fn upload_file(&self, directory_path: &str, _temp_name: &str, name: &str,
+                   chunk_streams: ChunkStreamReceiver) -> EmptyResult {
+        let path = format!("{}/{}", directory_path, name);
+        let data = self.receive_file(chunk_streams)?;
+        self.modify(format!("upload_file {}", path)).files.insert(path, Some(data));
+        Ok(())
+    }
+
+    fn supports_rename(&self) -> bool {
+        self.state.lock().unwrap().supports_rename
+    }
+
+    fn rename(&self, src: &str, dst: &str) -> EmptyResult {
+        let mut state = self.modify(format!("rename {} {}", src, dst));
+        if !state.supports_rename {
+            return Err!("Renaming is not supported");
+        } else if state.files.contains_key(dst) {
+            return Err!("{:?} already exists", dst);
+        }
+
+        let prefix = format!("{}/", src);
+        let renamed: Vec<String> = state.files.keys().filter(|path| {
+            *path == src || path.starts_with(&prefix)
+        }).cloned().collect();
+
+        if renamed.is_empty() {
+            return Err!("{:?} doesn't exist", src);
+        }
+
+        for path in renamed {
+            let new_path = format!("{}{}", dst, &path[src.len()..]);
+            let data = state.files.remove(&path).unwrap();
+            state.files.insert(new_path, data);
+        }
+
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> EmptyResult {
+        let prefix = format!("{}/", path);
+        self.modify(format!("delete {}", path)).files.retain(|file_path, _| {
+            file_path != path && !file_path.starts_with(&prefix)
+        });
+        Ok(())
+    }
+
+    fn get_checksum(&self, _path: &str) -> GenericResult<Option<String>> {
+        match self.state.lock().unwrap().checksum {
+            Some(Ok(ref checksum)) => Ok(Some(checksum.clone())),
+            Some(Err(ref err)) => Err(err.clone().into()),
+            None => Ok(None),
+        }
+    }
+}
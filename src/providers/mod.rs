@@ -1,3 +1,8 @@
+pub mod backblaze;
 pub mod dropbox;
 pub mod filesystem;
-pub mod google_drive;
\ No newline at end of file
+pub mod google_drive;
+#[cfg(test)] pub mod mock;
+pub mod s3;
+pub mod sftp;
+pub mod webdav;
\ No newline at end of file
@@ -0,0 +1,1007 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use chrono::{DateTime, Utc};
+use digest::Digest;
+use hmac::{Hmac, Mac, NewMac};
+use reqwest::Url;
+use sha2::Sha256;
+
+use crate::core::{EmptyResult, GenericResult};
+use crate::hash::{self, Hasher, Md5, PartsHasher, VerifyingReader};
+use crate::http_client::{
+    HttpClient, HttpClientError, HttpRequest, HttpRequestBuildingResult, HttpResponse, HeaderName, Method,
+    StatusCode, ResponseReader, RawResponseReader, XmlReplyReader, XmlErrorReader, ClientOptions, headers,
+};
+use crate::memory;
+use crate::provider::{Provider, ProviderType, ReadProvider, WriteProvider, File, FileType};
+use crate::stream_splitter::{self, ChunkStreamReceiver, ChunkStream, ChunkReceiver};
+
+const NAME: &str = "S3";
+
+const API_REQUEST_TIMEOUT: u64 = 60;
+const UPLOAD_REQUEST_TIMEOUT: u64 = 60 * 60;
+
+// S3 allows parts of up to 5 GiB, but it requires Content-Length for each part, so parts are buffered
+// in memory during the upload. 64 MiB parts keep the memory usage low and still allow objects of up to
+// 640 GiB (a multipart upload may have at most 10000 parts).
+const PART_SIZE: u64 = 64 * 1024 * 1024;
+
+const RETAIN_UNTIL_DATE_HEADER: &str = "x-amz-object-lock-retain-until-date";
+const BUCKET_REGION_HEADER: &str = "x-amz-bucket-region";
+
+#[derive(Clone, Copy)]
+pub enum ObjectLockMode {
+    Governance,
+    Compliance,
+}
+
+impl ObjectLockMode {
+    fn name(self) -> &'static str {
+        match self {
+            ObjectLockMode::Governance => "GOVERNANCE",
+            ObjectLockMode::Compliance => "COMPLIANCE",
+        }
+    }
+}
+
+pub struct S3 {
+    client: HttpClient,
+    request_timeout: Duration,
+    upload_timeout: Duration,
+
+    bucket: String,
+    custom_endpoint: Option<String>,
+    // May be changed on the fly if the bucket turns out to be located in another region
+    location: RwLock<Location>,
+    // Bucket path for path-style requests to S3-compatible storages
+    bucket_path: String,
+
+    access_key_id: String,
+    secret_access_key: String,
+
+    object_lock: Option<(ObjectLockMode, Duration)>,
+    part_size: u64,
+}
+
+impl S3 {
+    pub fn new(bucket: &str, region: &str, endpoint: Option<&str>, access_key_id: &str,
+               secret_access_key: &str) -> GenericResult<S3> {
+        let custom_endpoint = endpoint.map(|endpoint| endpoint.trim_end_matches('/').to_owned());
+        let location = Location::new(bucket, region, custom_endpoint.as_deref())?;
+        let bucket_path = match custom_endpoint {
+            Some(_) => format!("/{}", bucket),
+            None => String::new(),
+        };
+
+        Ok(S3 {
+            client: HttpClient::new().with_metrics_label(NAME),
+            request_timeout: Duration::from_secs(API_REQUEST_TIMEOUT),
+            upload_timeout: Duration::from_secs(UPLOAD_REQUEST_TIMEOUT),
+
+            bucket: bucket.to_owned(),
+            custom_endpoint,
+            location: RwLock::new(location),
+            bucket_path,
+
+            access_key_id: access_key_id.to_owned(),
+            secret_access_key: secret_access_key.to_owned(),
+
+            object_lock: None,
+            part_size: PART_SIZE,
+        })
+    }
+
+    pub fn with_object_lock(mut self, mode: ObjectLockMode, retain_days: u32) -> S3 {
+        self.object_lock.replace((mode, Duration::from_secs(u64::from(retain_days) * 24 * 60 * 60)));
+        self
+    }
+
+    pub fn with_client_options(mut self, options: ClientOptions) -> S3 {
+        self.client = self.client.with_options(&options);
+        self.request_timeout = options.timeouts.request.unwrap_or(self.request_timeout);
+        self.upload_timeout = options.timeouts.upload.unwrap_or(self.upload_timeout);
+        self
+    }
+
+    // Returns objects and common prefixes (when delimiter is specified) matching the specified prefix
+    fn list_objects(
+        &self, prefix: &str, delimiter: Option<&str>,
+    ) -> GenericResult<(Vec<Object>, Vec<String>)> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct Response {
+            is_truncated: bool,
+            #[serde(default)]
+            contents: Vec<Object>,
+            #[serde(default)]
+            common_prefixes: Vec<CommonPrefix>,
+            next_continuation_token: Option<String>,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct CommonPrefix {
+            prefix: String,
+        }
+
+        let mut objects = Vec::new();
+        let mut prefixes = Vec::new();
+
+        let mut continuation_token: Option<String> = None;
+        let (mut page, page_limit) = (1, 1000);
+
+        loop {
+            let mut params = vec![("list-type", "2"), ("prefix", prefix)];
+            if let Some(delimiter) = delimiter {
+                params.push(("delimiter", delimiter));
+            }
+            if let Some(ref token) = continuation_token {
+                params.push(("continuation-token", token.as_str()));
+            }
+
+            // Partial listing must never be treated as a complete one
+            let response: Response = self.send(|| self.client.send(
+                self.request(Method::GET, "", &params, self.request_timeout, XmlReplyReader::new())?
+                    .with_endpoint("ListObjectsV2"),
+            )).map_err(|e| format!("Listing has been interrupted on page #{}: {}", page, e))?;
+
+            objects.extend(response.contents);
+            prefixes.extend(response.common_prefixes.into_iter().map(|prefix| prefix.prefix));
+
+            if !response.is_truncated {
+                break;
+            }
+
+            if page >= page_limit {
+                return Err!("Listing page limit has exceeded");
+            }
+
+            continuation_token = Some(response.next_continuation_token.ok_or(
+                "Got a truncated listing without continuation token")?);
+            page += 1;
+        }
+
+        Ok((objects, prefixes))
+    }
+
+    fn initiate_upload(&self, key: &str) -> GenericResult<String> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct Response {
+            upload_id: String,
+        }
+
+        let response: Response = self.send(|| self.client.send(self.signed_request(
+            Method::POST, key, &[("uploads", "")], &self.get_object_lock_headers(),
+            self.request_timeout, XmlReplyReader::new(),
+        )?.with_endpoint("CreateMultipartUpload")))?;
+
+        Ok(response.upload_id)
+    }
+
+    fn upload_part(
+        &self, key: &str, upload_id: &str, part_number: usize, chunk_stream: ChunkReceiver,
+    ) -> GenericResult<String> {
+        let _reservation = memory::reserve(self.part_size);
+
+        let mut part = BytesMut::new();
+        for data in chunk_stream.iter() {
+            part.extend_from_slice(&data?);
+        }
+
+        // Content-MD5 makes S3 reject parts corrupted in transit instead of assembling a broken object
+        let part_number = part_number.to_string();
+        let content_md5_header = vec![("content-md5", get_content_md5(&part))];
+        let part = part.freeze();
+
+        let response = self.send(|| self.client.send(self.signed_request(
+            Method::PUT, key, &[("partNumber", part_number.as_str()), ("uploadId", upload_id)],
+            &content_md5_header, self.upload_timeout, RawResponseReader::new(),
+        )?.with_endpoint("UploadPart").with_body("application/octet-stream", part.clone())?))?;
+        get_etag(&response)
+    }
+
+    fn complete_upload(&self, key: &str, upload_id: &str, etags: &[String]) -> GenericResult<String> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct Response {
+            #[serde(rename = "ETag")]
+            etag: String,
+        }
+
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (index, etag) in etags.iter().enumerate() {
+            body += &format!("<Part><PartNumber>{}</PartNumber><ETag>\"{}\"</ETag></Part>", index + 1, etag);
+        }
+        body += "</CompleteMultipartUpload>";
+
+        // S3 may return an error with 200 status code here, which fails the response parsing
+        let response: Response = self.send(|| self.client.send(self.request(
+            Method::POST, key, &[("uploadId", upload_id)], self.upload_timeout, XmlReplyReader::new(),
+        )?.with_endpoint("CompleteMultipartUpload").with_text_body("application/xml", body.as_str())?))?;
+        Ok(response.etag.trim_matches('"').to_owned())
+    }
+
+    fn abort_upload(&self, key: &str, upload_id: &str) {
+        if let Err(err) = self.delete_object(key, &[("uploadId", upload_id)]) {
+            error!("Failed to abort {:?} multipart upload on {}: {}.", key, NAME, err);
+        }
+    }
+
+    fn delete_object(&self, key: &str, params: &[(&str, &str)]) -> EmptyResult {
+        self.send(|| self.client.send(self.request(
+            Method::DELETE, key, params, self.request_timeout, RawResponseReader::new(),
+        )?.with_endpoint(if params.is_empty() {"DeleteObject"} else {"AbortMultipartUpload"})))?;
+        Ok(())
+    }
+
+    // Returns retention expiration time if the object is still locked
+    fn get_lock_expiration(&self, key: &str) -> GenericResult<Option<DateTime<Utc>>> {
+        let response = self.send(|| self.client.send(self.request(
+            Method::HEAD, key, &[], self.request_timeout, RawResponseReader::new(),
+        )?.with_endpoint("HeadObject")))?;
+        get_lock_expiration(&response, Utc::now())
+    }
+
+    // Object Lock requires Content-MD5 for all uploads to the bucket
+    fn get_content_md5_header(&self, data: &[u8]) -> Vec<(&'static str, String)> {
+        if self.object_lock.is_none() {
+            return Vec::new();
+        }
+        vec![("content-md5", get_content_md5(data))]
+    }
+
+    fn get_object_lock_headers(&self) -> Vec<(&'static str, String)> {
+        let (mode, retention) = match self.object_lock {
+            Some(object_lock) => object_lock,
+            None => return Vec::new(),
+        };
+
+        let retain_until = Utc::now() + chrono::Duration::from_std(retention).unwrap();
+        vec![
+            ("x-amz-object-lock-mode", mode.name().to_owned()),
+            (RETAIN_UNTIL_DATE_HEADER, retain_until.format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+        ]
+    }
+
+    // Sends the request. If the bucket turns out to be located in another region, switches to it and
+    // retries the request once.
+    fn send<T, F>(&self, send: F) -> Result<T, HttpClientError<ApiError>>
+        where F: Fn() -> Result<T, HttpClientError<ApiError>>
+    {
+        match send() {
+            Err(HttpClientError::Api(ref err)) if self.switch_region(err) => send(),
+            result => result,
+        }
+    }
+
+    fn switch_region(&self, err: &ApiError) -> bool {
+        let region = match err.region {
+            Some(ref region) => region,
+            None => return false,
+        };
+
+        let mut location = self.location.write().unwrap();
+        if location.region == *region {
+            // Has been already switched by a concurrent request
+            return true;
+        }
+
+        let new_location = match Location::new(&self.bucket, region, self.custom_endpoint.as_deref()) {
+            Ok(new_location) => new_location,
+            Err(err) => {
+                error!("Unable to switch to {:?} region: {}.", region, err);
+                return false;
+            },
+        };
+
+        info!("{:?} bucket is located in {:?} region instead of the configured {:?}. Switching to it.",
+              self.bucket, region, location.region);
+        *location = new_location;
+
+        true
+    }
+
+    fn request<'a, R, RR>(
+        &self, method: Method, key: &str, params: &[(&str, &str)], timeout: Duration, reply_reader: RR,
+    ) -> HttpRequestBuildingResult<'a, R, ApiError>
+        where RR: ResponseReader<Result=R> + 'a
+    {
+        self.signed_request(method, key, params, &[], timeout, reply_reader)
+    }
+
+    // Builds a request signed with AWS Signature Version 4. The payload is left unsigned to not read it
+    // twice - HTTPS guarantees its integrity in transit. The specified extra headers (lowercase names)
+    // are signed as well.
+    fn signed_request<'a, R, RR>(
+        &self, method: Method, key: &str, params: &[(&str, &str)], extra_headers: &[(&str, String)],
+        timeout: Duration, reply_reader: RR,
+    ) -> HttpRequestBuildingResult<'a, R, ApiError>
+        where RR: ResponseReader<Result=R> + 'a
+    {
+        let path = if key.is_empty() && !self.bucket_path.is_empty() {
+            self.bucket_path.clone()
+        } else {
+            format!("{}/{}", self.bucket_path, encode(key, false))
+        };
+
+        let mut params = params.iter()
+            .map(|&(name, value)| (encode(name, true), encode(value, true)))
+            .collect::<Vec<_>>();
+        params.sort();
+
+        let query = params.iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>().join("&");
+
+        let location = self.location.read().unwrap();
+
+        let mut url = location.endpoint.clone() + &path;
+        if !query.is_empty() {
+            url.push('?');
+            url += &query;
+        }
+
+        let now = Utc::now();
+        let date = now.format("%Y%m%d").to_string();
+        let date_time = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = "UNSIGNED-PAYLOAD";
+
+        let mut headers = vec![
+            ("host", location.host.as_str()),
+            ("x-amz-content-sha256", payload_hash),
+            ("x-amz-date", date_time.as_str()),
+        ];
+        headers.extend(extra_headers.iter().map(|(name, value)| (*name, value.as_str())));
+        headers.sort();
+
+        let canonical_headers = headers.iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect::<String>();
+        let signed_headers = headers.iter()
+            .map(|&(name, _)| name)
+            .collect::<Vec<_>>().join(";");
+
+        let canonical_request = format!(
+            "{method}\n{path}\n{query}\n{canonical_headers}\n{signed_headers}\n{payload_hash}",
+            method=method, path=path, query=query, canonical_headers=canonical_headers,
+            signed_headers=signed_headers, payload_hash=payload_hash);
+
+        let scope = format!("{}/{}/s3/aws4_request", date, location.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{:x}", date_time, scope, Sha256::digest(canonical_request.as_bytes()));
+
+        let mut signing_key = hmac(format!("AWS4{}", self.secret_access_key).as_bytes(), date.as_bytes());
+        for &part in &[location.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac(&signing_key, part.as_bytes());
+        }
+
+        let signature = hash::to_hex(&hmac(&signing_key, string_to_sign.as_bytes()));
+
+        let mut request = HttpRequest::new(method, url, timeout, reply_reader, ApiErrorReader)
+            .with_redirect_errors()
+            .with_header("x-amz-date", &date_time)?
+            .with_header("x-amz-content-sha256", payload_hash)?
+            .with_header(headers::AUTHORIZATION, format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key_id, scope, signed_headers, signature))?;
+
+        for (name, value) in extra_headers {
+            request = request.with_header(name, value)?;
+        }
+
+        Ok(request)
+    }
+}
+
+impl Provider for S3 {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn type_(&self) -> ProviderType {
+        ProviderType::Cloud
+    }
+}
+
+impl ReadProvider for S3 {
+    // S3 has no real directories: they are emulated via key prefixes and empty marker objects
+    fn list_directory(&self, path: &str) -> GenericResult<Option<Vec<File>>> {
+        let prefix = get_directory_prefix(path);
+        let (objects, prefixes) = self.list_objects(&prefix, Some("/"))?;
+
+        if objects.is_empty() && prefixes.is_empty() {
+            return Ok(None);
+        }
+
+        let mut files = Vec::new();
+
+        for object in objects {
+            let name = &object.key[prefix.len()..];
+            if name.is_empty() {
+                continue;
+            }
+
+            files.push(File {
+                name: name.to_owned(),
+                type_: FileType::File,
+                size: Some(object.size),
+                modify_time: None,
+            });
+        }
+
+        for directory in prefixes {
+            files.push(File {
+                name: directory[prefix.len()..].trim_end_matches('/').to_owned(),
+                type_: FileType::Directory,
+                size: None,
+                modify_time: None,
+            });
+        }
+
+        Ok(Some(files))
+    }
+
+    fn open_file(&self, path: &str) -> GenericResult<Box<dyn io::Read + Send>> {
+        let key = get_key(path);
+        let (headers, data) = self.send(|| self.client.download(self.request(
+            Method::GET, &key, &[], self.upload_timeout, RawResponseReader::new(),
+        )?.with_endpoint("GetObject")))?;
+
+        let etag = headers.get(headers::ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim_matches('"'))
+            .ok_or("Got a response without ETag")?;
+
+        // Objects uploaded via single PUT have plain MD5 ETag
+        let hasher: Box<dyn Hasher> = if etag.contains('-') {
+            self.hasher()
+        } else {
+            Box::new(Md5::new())
+        };
+
+        Ok(Box::new(VerifyingReader::new(data, hasher, etag)))
+    }
+}
+
+impl WriteProvider for S3 {
+    fn hasher(&self) -> Box<dyn Hasher> {
+        Box::new(PartsHasher::new_s3_etag(self.part_size as usize))
+    }
+
+    // Each chunk stream is uploaded as a single multipart upload part, so the limit is our part size
+    // rather than the 5 GiB limit of a single PUT request
+    fn max_request_size(&self) -> Option<u64> {
+        Some(self.part_size)
+    }
+
+    fn create_directory(&self, path: &str) -> EmptyResult {
+        let key = get_directory_prefix(path);
+        let content_md5_header = self.get_content_md5_header(b"");
+
+        self.send(|| self.client.send(self.signed_request(
+            Method::PUT, &key, &[], &content_md5_header, self.request_timeout, RawResponseReader::new(),
+        )?.with_endpoint("PutObject").with_body("application/octet-stream", "")?))?;
+        Ok(())
+    }
+
+    // Multipart uploads are atomic: the object appears only on upload completion, so the file is
+    // uploaded directly under its final name. Interrupted uploads are aborted on errors, but if the
+    // process is killed, they remain until they're cleaned up by bucket lifecycle rules.
+    fn upload_file(&self, directory_path: &str, _temp_name: &str, name: &str,
+                   chunk_streams: ChunkStreamReceiver) -> EmptyResult {
+        let key = get_directory_prefix(directory_path) + name;
+        let mut upload_id = None;
+        let mut etags = Vec::new();
+
+        for result in chunk_streams.iter() {
+            match result {
+                Ok(ChunkStream::Stream(_, chunk_stream)) => {
+                    if upload_id.is_none() {
+                        match self.initiate_upload(&key) {
+                            Ok(id) => upload_id.replace(id),
+                            Err(err) => {
+                                // The splitter is blocked on sending to the unread chunk stream, so
+                                // close it to let the splitter deliver the original error.
+                                drop(chunk_stream);
+                                return Err(stream_splitter::get_upload_error(&chunk_streams, err));
+                            },
+                        };
+                    }
+                    let upload_id = upload_id.as_ref().unwrap();
+
+                    match self.upload_part(&key, upload_id, etags.len() + 1, chunk_stream) {
+                        Ok(etag) => etags.push(etag),
+                        Err(err) => {
+                            self.abort_upload(&key, upload_id);
+                            return Err(stream_splitter::get_upload_error(&chunk_streams, err));
+                        },
+                    }
+                },
+                Ok(ChunkStream::EofWithCheckSum(size, checksum)) => {
+                    let upload_id = match upload_id {
+                        Some(ref upload_id) if size != 0 => upload_id,
+                        _ => return Err!("An attempt to upload an empty file"),
+                    };
+
+                    let etag = match self.complete_upload(&key, upload_id, &etags) {
+                        Ok(etag) => etag,
+                        Err(err) => {
+                            self.abort_upload(&key, upload_id);
+                            return Err(err);
+                        },
+                    };
+
+                    if etag != checksum {
+                        if let Err(err) = self.delete_object(&key, &[]) {
+                            error!("Failed to delete {:?} from {}: {}.", key, NAME, err);
+                        }
+                        return Err!("Checksum mismatch");
+                    }
+
+                    return Ok(());
+                },
+                Err(err) => {
+                    if let Some(ref upload_id) = upload_id {
+                        self.abort_upload(&key, upload_id);
+                    }
+                    return Err!("Upload has been aborted: {}", err);
+                },
+            }
+        }
+
+        Err!("Chunk stream sender has been closed without a termination message")
+    }
+
+    // Objects which are still locked by Object Lock are skipped (along with their directory marker), so
+    // they will be deleted by one of the next prunes after their retention expires.
+    fn delete(&self, path: &str) -> EmptyResult {
+        let key = get_key(path);
+        let (objects, _) = self.list_objects(&get_directory_prefix(path), None)?;
+
+        // Delete directory contents first, so the directory marker is deleted last
+        let mut keys: Vec<_> = objects.into_iter().map(|object| object.key).collect();
+        keys.sort_by(|a, b| b.cmp(a));
+
+        if keys.is_empty() {
+            keys.push(key);
+        }
+
+        let mut locked = false;
+
+        for key in keys {
+            if self.object_lock.is_some() {
+                if locked && key.ends_with('/') {
+                    continue;
+                }
+
+                if let Some(expiration) = self.get_lock_expiration(&key).map_err(|e| format!(
+                    "Unable to get {:?} retention: {}", key, e))? {
+                    info!("Skipping deletion of {:?}: it's locked until {}.", key, expiration);
+                    locked = true;
+                    continue;
+                }
+            }
+
+            self.delete_object(&key, &[]).map_err(|e| format!("Unable to delete {:?}: {}", key, e))?;
+        }
+
+        Ok(())
+    }
+
+    // Objects are always uploaded via multipart upload, so ETag is comparable with our checksum
+    fn get_checksum(&self, path: &str) -> GenericResult<Option<String>> {
+        let key = get_key(path);
+        let response = self.send(|| self.client.send(self.request(
+            Method::HEAD, &key, &[], self.request_timeout, RawResponseReader::new(),
+        )?.with_endpoint("HeadObject")))?;
+        Ok(Some(get_etag(&response)?))
+    }
+}
+
+struct Location {
+    endpoint: String,
+    host: String,
+    region: String,
+}
+
+impl Location {
+    fn new(bucket: &str, region: &str, custom_endpoint: Option<&str>) -> GenericResult<Location> {
+        let endpoint = match custom_endpoint {
+            Some(endpoint) => endpoint.to_owned(),
+            None => format!("https://{}.s3.{}.amazonaws.com", bucket, region),
+        };
+
+        let url = Url::parse(&endpoint).map_err(|e| format!("Invalid S3 endpoint URL: {}", e))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_owned(),
+            (None, _) => return Err!("Invalid S3 endpoint URL: {:?}", endpoint),
+        };
+
+        Ok(Location {endpoint, host, region: region.to_owned()})
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Object {
+    key: String,
+    size: u64,
+}
+
+fn get_key(path: &str) -> String {
+    path.trim_matches('/').to_owned()
+}
+
+fn get_directory_prefix(path: &str) -> String {
+    let key = get_key(path);
+    if key.is_empty() {
+        key
+    } else {
+        key + "/"
+    }
+}
+
+fn get_content_md5(data: &[u8]) -> String {
+    base64::encode(md5::Md5::digest(data))
+}
+
+fn get_etag(response: &HttpResponse) -> GenericResult<String> {
+    Ok(response.get_header(headers::ETAG)?.ok_or("Got a response without ETag")?
+        .trim_matches('"').to_owned())
+}
+
+fn get_lock_expiration(response: &HttpResponse, now: DateTime<Utc>) -> GenericResult<Option<DateTime<Utc>>> {
+    let retain_until = match response.get_header(HeaderName::from_static(RETAIN_UNTIL_DATE_HEADER))? {
+        Some(retain_until) => retain_until,
+        None => return Ok(None),
+    };
+
+    let retain_until = DateTime::parse_from_rfc3339(retain_until).map_err(|_| format!(
+        "Got an invalid object retention time: {:?}", retain_until))?.with_timezone(&Utc);
+
+    Ok(if retain_until > now {
+        Some(retain_until)
+    } else {
+        None
+    })
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// URI encoding as required by AWS Signature Version 4
+fn encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+
+    for &byte in value.as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded += &format!("%{:02X}", byte),
+        }
+    }
+
+    encoded
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ApiError {
+    code: String,
+    #[serde(default)]
+    message: String,
+    // The actual bucket region for region redirects
+    #[serde(skip)]
+    region: Option<String>,
+}
+
+// S3 responds with 301 and the actual bucket region in the header when the bucket is accessed via an
+// endpoint of another region. Responses to HEAD requests have no body, so the header is the only source.
+struct ApiErrorReader;
+
+impl ResponseReader for ApiErrorReader {
+    type Result = ApiError;
+
+    fn read(&self, response: HttpResponse) -> GenericResult<ApiError> {
+        if response.status == StatusCode::MOVED_PERMANENTLY {
+            if let Some(region) = response.get_header(HeaderName::from_static(BUCKET_REGION_HEADER))? {
+                return Ok(ApiError {
+                    code: "PermanentRedirect".to_owned(),
+                    message: format!("The bucket is located in {:?} region", region),
+                    region: Some(region.to_owned()),
+                });
+            }
+        }
+
+        XmlErrorReader::new().read(response)
+    }
+}
+
+impl Error for ApiError {
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "S3 API error: {}", self.code)?;
+        if !self.message.is_empty() {
+            write!(f, " ({})", self.message.trim_end_matches('.'))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use bytes::Bytes;
+    use regex::Regex;
+
+    use crate::http_client::Headers;
+    use crate::stream_splitter::Data;
+    use crate::tests::{TestResponse, TestServer};
+
+    use super::*;
+
+    #[test]
+    fn object_lock_headers() {
+        let s3 = S3::new("bucket", "us-east-1", None, "key-id", "secret").unwrap()
+            .with_object_lock(ObjectLockMode::Compliance, 30);
+
+        let request = s3.signed_request(
+            Method::POST, "backup/file", &[("uploads", "")], &s3.get_object_lock_headers(),
+            s3.request_timeout, RawResponseReader::new(),
+        ).unwrap();
+
+        let get_header = |name: &str| request.headers.get(name).map(|value| value.to_str().unwrap().to_owned());
+        assert_eq!(get_header("x-amz-object-lock-mode").unwrap(), "COMPLIANCE");
+
+        let retain_until = DateTime::parse_from_rfc3339(&get_header(RETAIN_UNTIL_DATE_HEADER).unwrap()).unwrap();
+        let retention = retain_until.with_timezone(&Utc) - Utc::now();
+        assert!(retention > chrono::Duration::days(29) && retention <= chrono::Duration::days(30));
+
+        assert!(get_header("authorization").unwrap().contains(concat!(
+            "SignedHeaders=host;x-amz-content-sha256;x-amz-date;",
+            "x-amz-object-lock-mode;x-amz-object-lock-retain-until-date,")));
+
+        let request = s3.signed_request(
+            Method::PUT, "backup/", &[], &s3.get_content_md5_header(b""),
+            s3.request_timeout, RawResponseReader::new(),
+        ).unwrap();
+        assert_eq!(request.headers.get("content-md5").unwrap(), "1B2M2Y8AsgTpgAmY7PhCfg==");
+
+        let s3 = S3::new("bucket", "us-east-1", None, "key-id", "secret").unwrap();
+        assert!(s3.get_object_lock_headers().is_empty());
+        assert!(s3.get_content_md5_header(b"").is_empty());
+    }
+
+    #[test]
+    fn locked_object() {
+        let now = Utc::now();
+        let response = |retain_until: Option<&str>| {
+            let mut headers = Headers::new();
+            if let Some(retain_until) = retain_until {
+                headers.insert(RETAIN_UNTIL_DATE_HEADER, retain_until.parse().unwrap());
+            }
+            HttpResponse {status: StatusCode::OK, headers, body: Vec::new()}
+        };
+
+        assert_eq!(get_lock_expiration(&response(None), now).unwrap(), None);
+        assert_eq!(get_lock_expiration(&response(Some("2000-01-01T00:00:00Z")), now).unwrap(), None);
+
+        let retain_until = DateTime::parse_from_rfc3339("2100-01-01T00:00:00.000Z").unwrap().with_timezone(&Utc);
+        assert_eq!(get_lock_expiration(&response(Some("2100-01-01T00:00:00.000Z")), now).unwrap(),
+                   Some(retain_until));
+
+        assert!(get_lock_expiration(&response(Some("invalid")), now).is_err());
+    }
+
+    #[test]
+    fn region_switching() {
+        let s3 = S3::new("bucket", "us-east-1", None, "key-id", "secret").unwrap();
+
+        let mut redirect = ApiError {code: "PermanentRedirect".to_owned(), message: String::new(), region: None};
+        assert!(!s3.switch_region(&redirect));
+
+        redirect.region.replace("eu-west-1".to_owned());
+        assert!(s3.switch_region(&redirect));
+
+        let location = s3.location.read().unwrap();
+        assert_eq!(location.region, "eu-west-1");
+        assert_eq!(location.endpoint, "https://bucket.s3.eu-west-1.amazonaws.com");
+        assert_eq!(location.host, "bucket.s3.eu-west-1.amazonaws.com");
+    }
+
+    #[test]
+    fn region_redirect() {
+        let server = TestServer::new(|request| {
+            if request.header("authorization").unwrap().contains("/eu-west-1/s3/aws4_request") {
+                TestResponse::new(200).with_header("ETag", "\"etag\"")
+            } else {
+                TestResponse::new(301).with_header(BUCKET_REGION_HEADER, "eu-west-1")
+            }
+        });
+
+        let endpoint = server.url.clone();
+        let s3 = S3::new("bucket", "us-east-1", Some(&endpoint), "key-id", "secret").unwrap();
+        assert_eq!(s3.get_checksum("/group/backup.tar.gpg").unwrap().unwrap(), "etag");
+
+        let requests: Vec<_> = server.stop().into_iter()
+            .map(|request| format!("{} {}", request.method, request.path)).collect();
+        assert_eq!(requests, vec![
+            "HEAD /bucket/group/backup.tar.gpg".to_owned(),
+            "HEAD /bucket/group/backup.tar.gpg".to_owned(),
+        ]);
+
+        let location = s3.location.read().unwrap();
+        assert_eq!(location.region, "eu-west-1");
+        assert_eq!(location.endpoint, endpoint);
+    }
+
+    #[test]
+    fn upload_initiation_failure() {
+        let server = TestServer::new(|_| TestResponse::new(500));
+        let s3 = S3::new("bucket", "us-east-1", Some(&server.url), "key-id", "secret").unwrap();
+
+        let (data_tx, data_rx) = mpsc::sync_channel(0);
+        let (chunk_streams, splitter) = stream_splitter::split(data_rx, s3.max_request_size(), None).unwrap();
+
+        let sender = thread::spawn(move || {
+            let _ = data_tx.send(Ok(Data::Payload(Bytes::from_static(b"data"))));
+            let _ = data_tx.send(Ok(Data::EofWithChecksum("checksum".to_owned())));
+        });
+
+        let (result_tx, result_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let result = s3.upload_file("/group", "backup.tar.gpg.tmp", "backup.tar.gpg", chunk_streams);
+            result_tx.send(result.map_err(|e| e.to_string())).unwrap();
+        });
+
+        let err = result_rx.recv_timeout(Duration::from_secs(10)).expect("The upload has hung").unwrap_err();
+        assert!(err.contains("500"), "{}", err);
+
+        sender.join().unwrap();
+        assert!(splitter.join().unwrap().is_err());
+
+        let requests: Vec<_> = server.stop().into_iter()
+            .map(|request| format!("{} {}", request.method, request.path)).collect();
+        assert_eq!(requests, vec!["POST /bucket/group/backup.tar.gpg?uploads=".to_owned()]);
+    }
+
+    #[test]
+    fn corrupted_part() {
+        let server = TestServer::new(|request| {
+            if request.method == "POST" {
+                return TestResponse::new(200).with_body("application/xml", concat!(
+                    "<InitiateMultipartUploadResult><UploadId>upload-id</UploadId>",
+                    "</InitiateMultipartUploadResult>").as_bytes());
+            } else if request.method == "DELETE" {
+                return TestResponse::new(204);
+            }
+
+            // Emulate the part corruption in transit
+            let mut body = request.body.clone();
+            body[0] ^= 0xff;
+
+            if request.header("content-md5") != Some(get_content_md5(&body).as_str()) {
+                return TestResponse::new(400).with_body("application/xml", concat!(
+                    "<Error><Code>BadDigest</Code>",
+                    "<Message>The Content-MD5 you specified did not match what we received.</Message>",
+                    "</Error>").as_bytes());
+            }
+            TestResponse::new(200).with_header("ETag", "\"etag\"")
+        });
+
+        let s3 = S3::new("bucket", "us-east-1", Some(&server.url), "key-id", "secret").unwrap();
+
+        let (data_tx, data_rx) = mpsc::sync_channel(0);
+        let (chunk_streams, splitter) = stream_splitter::split(data_rx, s3.max_request_size(), None).unwrap();
+
+        let sender = thread::spawn(move || {
+            let _ = data_tx.send(Ok(Data::Payload(Bytes::from_static(b"data"))));
+            let _ = data_tx.send(Ok(Data::EofWithChecksum("checksum".to_owned())));
+        });
+
+        let err = s3.upload_file("/group", "backup.tar.gpg.tmp", "backup.tar.gpg", chunk_streams)
+            .unwrap_err().to_string();
+        assert!(err.contains("BadDigest"), "{}", err);
+
+        sender.join().unwrap();
+        let _ = splitter.join().unwrap();
+
+        let requests: Vec<_> = server.stop().into_iter()
+            .map(|request| format!("{} {}", request.method, request.path)).collect();
+        assert_eq!(requests, vec![
+            "POST /bucket/group/backup.tar.gpg?uploads=".to_owned(),
+            "PUT /bucket/group/backup.tar.gpg?partNumber=1&uploadId=upload-id".to_owned(),
+            "DELETE /bucket/group/backup.tar.gpg?uploadId=upload-id".to_owned(),
+        ]);
+    }
+
+    #[test]
+    fn part_boundaries() {
+        let data: Vec<u8> = (0..2500).map(|index| (index % 251) as u8).collect();
+
+        let server = TestServer::new(|request| {
+            if request.method == "POST" && request.path.ends_with("?uploads=") {
+                return TestResponse::new(200).with_body("application/xml", concat!(
+                    "<InitiateMultipartUploadResult><UploadId>upload-id</UploadId>",
+                    "</InitiateMultipartUploadResult>").as_bytes());
+            } else if request.method == "PUT" {
+                if request.header("content-md5") != Some(get_content_md5(&request.body).as_str()) {
+                    return TestResponse::new(400);
+                }
+                let etag = format!("\"{:x}\"", md5::Md5::digest(&request.body));
+                return TestResponse::new(200).with_header("ETag", &etag);
+            }
+
+            // Calculate multipart upload ETag from the part ETags independently of our hasher
+            let body = String::from_utf8(request.body.clone()).unwrap();
+            let etags: Vec<_> = Regex::new(r#"<ETag>"([0-9a-f]+)"</ETag>"#).unwrap()
+                .captures_iter(&body).map(|captures| captures[1].to_owned()).collect();
+
+            let mut hasher = md5::Md5::new();
+            for etag in &etags {
+                let digest: Vec<u8> = (0..etag.len()).step_by(2)
+                    .map(|index| u8::from_str_radix(&etag[index..index + 2], 16).unwrap()).collect();
+                hasher.update(&digest);
+            }
+
+            let etag = format!("{:x}-{}", hasher.finalize(), etags.len());
+            TestResponse::new(200).with_body("application/xml", format!(
+                "<CompleteMultipartUploadResult><ETag>\"{}\"</ETag></CompleteMultipartUploadResult>",
+                etag).as_bytes())
+        });
+
+        let mut s3 = S3::new("bucket", "us-east-1", Some(&server.url), "key-id", "secret").unwrap();
+        s3.part_size = 1000;
+
+        let mut hasher = s3.hasher();
+        hasher.write_all(&data).unwrap();
+        let checksum = hasher.finish();
+
+        let (data_tx, data_rx) = mpsc::sync_channel(0);
+        let (chunk_streams, splitter) = stream_splitter::split(data_rx, s3.max_request_size(), None).unwrap();
+
+        let sender = thread::spawn(move || {
+            // Payload size is deliberately not aligned to the part size
+            for chunk in data.chunks(700) {
+                data_tx.send(Ok(Data::Payload(Bytes::copy_from_slice(chunk)))).unwrap();
+            }
+            data_tx.send(Ok(Data::EofWithChecksum(checksum))).unwrap();
+            data
+        });
+
+        s3.upload_file("/group", "backup.tar.gpg.tmp", "backup.tar.gpg", chunk_streams).unwrap();
+        let data = sender.join().unwrap();
+        let (size, _) = splitter.join().unwrap().unwrap().unwrap();
+        assert_eq!(size, data.len() as u64);
+
+        let parts: Vec<_> = server.stop().into_iter()
+            .filter(|request| request.method == "PUT")
+            .map(|request| (request.path, request.body))
+            .collect();
+
+        let mut offset = 0;
+        assert_eq!(parts.len(), 3);
+
+        for (index, (path, body)) in parts.into_iter().enumerate() {
+            let size = std::cmp::min(1000, data.len() - offset);
+            assert_eq!(path, format!("/bucket/group/backup.tar.gpg?partNumber={}&uploadId=upload-id", index + 1));
+            assert_eq!(body, &data[offset..offset + size]);
+            offset += size;
+        }
+    }
+}
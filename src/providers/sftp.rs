@@ -0,0 +1,393 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use ssh2::{CheckResult, KnownHostFileKind, RenameFlags, Session};
+
+use crate::core::{EmptyResult, GenericResult, GenericError};
+use crate::hash::{Hasher, Md5, VerifyingReader};
+use crate::http_client::Timeouts;
+use crate::provider::{Provider, ProviderType, ReadProvider, WriteProvider, File, FileType};
+use crate::stream_splitter::{ChunkStreamReceiver, ChunkStream};
+
+const NAME: &str = "SFTP";
+const KNOWN_HOSTS_PATH: &str = "~/.ssh/known_hosts";
+
+// LIBSSH2_FX_NO_SUCH_FILE
+const SFTP_NO_SUCH_FILE: i32 = 2;
+
+// SFTP has no server-side hashing, so checksum of each uploaded file is stored in a hidden file next
+// to it and is used to verify the file on download
+pub struct Sftp {
+    host: String,
+    port: u16,
+    user: String,
+    key_file: String,
+    base_dir: String,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+
+    sftp: Mutex<Option<Arc<ssh2::Sftp>>>,
+}
+
+impl Sftp {
+    pub fn new(host: &str, port: u16, user: &str, key_file: &str, base_dir: &str) -> Sftp {
+        Sftp {
+            host: host.to_owned(),
+            port,
+            user: user.to_owned(),
+            key_file: key_file.to_owned(),
+            base_dir: base_dir.to_owned(),
+            connect_timeout: None,
+            request_timeout: None,
+
+            sftp: Mutex::new(None),
+        }
+    }
+
+    // The request timeout limits each blocking SSH operation. There are no separate uploads here, so the
+    // upload timeout is rejected by the configuration validation.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Sftp {
+        self.connect_timeout = timeouts.connect;
+        self.request_timeout = timeouts.request;
+        self
+    }
+
+    // The connection is established lazily and is shared between all operations. The lock is held only
+    // while connecting, so operations don't wait for each other. The connection is dropped on session
+    // and I/O errors to be reestablished by the next operation.
+    fn with_sftp<F, T>(&self, func: F) -> GenericResult<T>
+        where F: FnOnce(&ssh2::Sftp) -> GenericResult<T>
+    {
+        let sftp = self.get_sftp()?;
+        let result = func(&sftp);
+
+        if let Err(ref err) = result {
+            if is_session_error(err) {
+                self.reset_sftp(&sftp);
+            }
+        }
+
+        result
+    }
+
+    fn get_sftp(&self) -> GenericResult<Arc<ssh2::Sftp>> {
+        let mut sftp = self.sftp.lock().unwrap();
+
+        if let Some(ref sftp) = *sftp {
+            return Ok(sftp.clone());
+        }
+
+        let connection = Arc::new(self.connect().map_err(|e| format!(
+            "Unable to connect to {}:{}: {}", self.host, self.port, e))?);
+        sftp.replace(connection.clone());
+
+        Ok(connection)
+    }
+
+    fn reset_sftp(&self, connection: &Arc<ssh2::Sftp>) {
+        let mut sftp = self.sftp.lock().unwrap();
+
+        // The connection may have been already reestablished by a concurrent operation
+        match *sftp {
+            Some(ref current) if Arc::ptr_eq(current, connection) => {
+                debug!("Dropping {} connection to {}:{}.", NAME, self.host, self.port);
+                sftp.take();
+            },
+            _ => {},
+        }
+    }
+
+    fn connect(&self) -> GenericResult<ssh2::Sftp> {
+        let stream = match self.connect_timeout {
+            Some(timeout) => {
+                let address = (self.host.as_str(), self.port).to_socket_addrs()?.next().ok_or(
+                    "Unable to resolve the host")?;
+                TcpStream::connect_timeout(&address, timeout)?
+            },
+            None => TcpStream::connect((self.host.as_str(), self.port))?,
+        };
+
+        let mut session = Session::new()?;
+        if let Some(timeout) = self.request_timeout {
+            session.set_timeout(timeout.as_millis() as u32);
+        }
+        session.set_tcp_stream(stream);
+        session.handshake()?;
+
+        self.check_host_key(&session)?;
+        session.userauth_pubkey_file(&self.user, None, Path::new(&self.key_file), None)?;
+
+        Ok(session.sftp()?)
+    }
+
+    fn check_host_key(&self, session: &Session) -> EmptyResult {
+        let (key, _) = session.host_key().ok_or("Unable to get server's host key")?;
+
+        let mut known_hosts = session.known_hosts()?;
+        let known_hosts_path = shellexpand::tilde(KNOWN_HOSTS_PATH);
+        known_hosts.read_file(Path::new(known_hosts_path.as_ref()), KnownHostFileKind::OpenSSH).map_err(|e| {
+            format!("Unable to read {}: {}", KNOWN_HOSTS_PATH, e)
+        })?;
+
+        match known_hosts.check_port(&self.host, self.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => Err!("Host key verification failed: it doesn't match the one from {}",
+                                          KNOWN_HOSTS_PATH),
+            CheckResult::NotFound => Err!("The host is not found in {}", KNOWN_HOSTS_PATH),
+            CheckResult::Failure => Err!("Failed to verify the host key"),
+        }
+    }
+
+    fn get_path(&self, path: &str) -> PathBuf {
+        Path::new(&self.base_dir).join(path.trim_start_matches('/'))
+    }
+
+    fn write_file(&self, file: &mut ssh2::File, chunk_streams: ChunkStreamReceiver) -> GenericResult<String> {
+        let mut hasher = self.hasher();
+
+        for result in chunk_streams.iter() {
+            match result {
+                Ok(ChunkStream::Stream(_, chunk_stream)) => {
+                    for chunk in chunk_stream.iter() {
+                        let chunk = chunk.map_err(|e| format!("Upload has been aborted: {}", e))?;
+                        file.write_all(&chunk)?;
+                        hasher.write_all(&chunk)?;
+                    }
+                },
+                Ok(ChunkStream::EofWithCheckSum(_, checksum)) => {
+                    if hasher.finish() != checksum {
+                        return Err!("Checksum mismatch");
+                    }
+                    return Ok(checksum);
+                },
+                Err(err) => return Err!("Upload has been aborted: {}", err),
+            }
+        }
+
+        Err!("Chunk stream sender has been closed without a termination message")
+    }
+}
+
+impl Provider for Sftp {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn type_(&self) -> ProviderType {
+        ProviderType::Cloud
+    }
+}
+
+impl ReadProvider for Sftp {
+    fn list_directory(&self, path: &str) -> GenericResult<Option<Vec<File>>> {
+        self.with_sftp(|sftp| {
+            let entries = match sftp.readdir(&self.get_path(path)) {
+                Ok(entries) => entries,
+                Err(ref err) if is_not_found(err) => return Ok(None),
+                Err(err) => return Err(err.into()),
+            };
+
+            let mut files = Vec::new();
+
+            for (path, stat) in entries {
+                let name = path.file_name().and_then(|name| name.to_str()).ok_or_else(|| format!(
+                    "Got an invalid file name: {:?}", path))?.to_owned();
+
+                let type_ = if stat.is_file() {
+                    FileType::File
+                } else if stat.is_dir() {
+                    FileType::Directory
+                } else {
+                    FileType::Other
+                };
+
+                let size = match type_ {
+                    FileType::File => stat.size,
+                    FileType::Directory | FileType::Other => None,
+                };
+
+                let modify_time = stat.mtime.map(|mtime| UNIX_EPOCH + Duration::from_secs(mtime));
+                files.push(File {name, type_, size, modify_time})
+            }
+
+            Ok(Some(files))
+        })
+    }
+
+    fn open_file(&self, path: &str) -> GenericResult<Box<dyn io::Read + Send>> {
+        self.with_sftp(|sftp| {
+            let path = self.get_path(path);
+            let checksum_path = get_checksum_path(&path)?;
+
+            let checksum = read_checksum(sftp, &checksum_path).map_err(|e| format!(
+                "Unable to read {:?}: {}", checksum_path, e))?;
+
+            let file = sftp.open(&path)?;
+            let reader = VerifyingReader::new(file, Box::new(Md5::new()), &checksum);
+
+            Ok(Box::new(reader) as Box<dyn io::Read + Send>)
+        })
+    }
+}
+
+impl WriteProvider for Sftp {
+    fn hasher(&self) -> Box<dyn Hasher> {
+        Box::new(Md5::new())
+    }
+
+    fn max_request_size(&self) -> Option<u64> {
+        None
+    }
+
+    fn create_directory(&self, path: &str) -> EmptyResult {
+        self.with_sftp(|sftp| {
+            Ok(sftp.mkdir(&self.get_path(path), 0o700)?)
+        })
+    }
+
+    fn upload_file(&self, directory_path: &str, temp_name: &str, name: &str,
+                   chunk_streams: ChunkStreamReceiver) -> EmptyResult {
+        let directory_path = self.get_path(directory_path);
+        let temp_path = directory_path.join(temp_name);
+        let path = directory_path.join(name);
+        let checksum_path = get_checksum_path(&path)?;
+
+        self.with_sftp(|sftp| {
+            let mut file = sftp.create(&temp_path).map_err(|e| format!(
+                "Unable to create {:?}: {}", temp_path, e))?;
+
+            let result = self.write_file(&mut file, chunk_streams).and_then(|checksum| {
+                let mut checksum_file = sftp.create(&checksum_path)?;
+                checksum_file.write_all(checksum.as_bytes())?;
+
+                sftp.rename(&temp_path, &path, Some(RenameFlags::ATOMIC | RenameFlags::NATIVE)).map_err(|e| {
+                    format!("Unable to rename {:?} to {:?}: {}", temp_path, path, e).into()
+                })
+            });
+
+            if let Err(err) = result {
+                for path in &[&temp_path, &checksum_path] {
+                    match sftp.unlink(path) {
+                        Err(ref e) if !is_not_found(e) => {
+                            error!("Failed to delete a temporary {:?} file from {}: {}.", path, NAME, e);
+                        },
+                        _ => {},
+                    }
+                }
+                return Err(err);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn supports_rename(&self) -> bool {
+        true
+    }
+
+    fn rename(&self, src: &str, dst: &str) -> EmptyResult {
+        let (src, dst) = (self.get_path(src), self.get_path(dst));
+
+        self.with_sftp(|sftp| {
+            if sftp.lstat(&dst).is_ok() {
+                return Err!("{:?} already exists", dst);
+            }
+
+            sftp.rename(&src, &dst, Some(RenameFlags::ATOMIC | RenameFlags::NATIVE)).map_err(|e| format!(
+                "Unable to rename {:?} to {:?}: {}", src, dst, e))?;
+
+            Ok(())
+        })
+    }
+
+    fn delete(&self, path: &str) -> EmptyResult {
+        let path = self.get_path(path);
+
+        self.with_sftp(|sftp| {
+            if sftp.lstat(&path)?.is_dir() {
+                return delete_directory(sftp, &path);
+            }
+
+            sftp.unlink(&path)?;
+
+            match sftp.unlink(&get_checksum_path(&path)?) {
+                Err(err) if !is_not_found(&err) => Err(err.into()),
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+fn delete_directory(sftp: &ssh2::Sftp, path: &Path) -> EmptyResult {
+    for (entry_path, stat) in sftp.readdir(path)? {
+        if stat.is_dir() {
+            delete_directory(sftp, &entry_path)?;
+        } else {
+            sftp.unlink(&entry_path).map_err(|e| format!("Unable to delete {:?}: {}", entry_path, e))?;
+        }
+    }
+
+    sftp.rmdir(path).map_err(|e| format!("Unable to delete {:?}: {}", path, e))?;
+    Ok(())
+}
+
+fn read_checksum(sftp: &ssh2::Sftp, path: &Path) -> GenericResult<String> {
+    let mut checksum = String::new();
+    sftp.open(path)?.read_to_string(&mut checksum)?;
+    Ok(checksum.trim().to_owned())
+}
+
+fn get_checksum_path(path: &Path) -> GenericResult<PathBuf> {
+    let name = path.file_name().and_then(|name| name.to_str()).ok_or_else(|| format!(
+        "Invalid file path: {:?}", path))?;
+    Ok(path.with_file_name(format!(".{}.md5", name)))
+}
+
+// SFTP status codes are positive unlike the session error codes
+fn is_not_found(err: &ssh2::Error) -> bool {
+    err.code() == SFTP_NO_SUCH_FILE
+}
+
+// Session errors (including timeouts) and I/O errors leave the connection in unknown state
+fn is_session_error(err: &GenericError) -> bool {
+    if let Some(err) = err.downcast_ref::<ssh2::Error>() {
+        return err.code() < 0;
+    }
+    err.is::<io::Error>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LIBSSH2_ERROR_TIMEOUT
+    const SESSION_TIMEOUT: i32 = -9;
+
+    #[test]
+    fn paths() {
+        let sftp = Sftp::new("localhost", 22, "user", "id_rsa", "/backups");
+        let path = sftp.get_path("/group/backup.tar.gpg");
+
+        assert_eq!(path, Path::new("/backups/group/backup.tar.gpg"));
+        assert_eq!(sftp.get_path("/"), Path::new("/backups"));
+        assert_eq!(get_checksum_path(&path).unwrap(), Path::new("/backups/group/.backup.tar.gpg.md5"));
+        assert!(get_checksum_path(Path::new("/")).is_err());
+    }
+
+    #[test]
+    fn errors() {
+        let not_found = ssh2::Error::new(SFTP_NO_SUCH_FILE, "No such file");
+        let timeout = ssh2::Error::new(SESSION_TIMEOUT, "Timed out waiting on socket");
+
+        assert!(is_not_found(&not_found));
+        assert!(!is_not_found(&timeout));
+
+        assert!(!is_session_error(&not_found.into()));
+        assert!(is_session_error(&timeout.into()));
+        assert!(is_session_error(&io::Error::new(io::ErrorKind::BrokenPipe, "Broken pipe").into()));
+        assert!(!is_session_error(&"Checksum mismatch".into()));
+    }
+}
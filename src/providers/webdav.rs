@@ -0,0 +1,470 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
+use quick_xml::events::Event;
+use reqwest::Url;
+
+use crate::core::{EmptyResult, GenericResult};
+use crate::hash::{Hasher, Md5};
+use crate::http_client::{
+    HttpClient, HttpClientError, HttpRequest, HttpRequestBuildingError, HttpRequestBuildingResult,
+    HttpResponse, Method, StatusCode, ResponseReader, RawResponseReader, ClientOptions, headers,
+};
+use crate::provider::{Provider, ProviderType, ReadProvider, WriteProvider, File, FileType};
+use crate::stream_splitter::{self, ChunkStreamReceiver, ChunkStream};
+
+const NAME: &str = "WebDAV";
+
+const API_REQUEST_TIMEOUT: u64 = 60;
+const UPLOAD_REQUEST_TIMEOUT: u64 = 60 * 60;
+
+const PATH_SEGMENT_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+const PROPFIND_REQUEST: &str = concat!(
+    r#"<?xml version="1.0" encoding="utf-8"?>"#,
+    r#"<d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/><d:getcontentlength/></d:prop></d:propfind>"#,
+);
+
+pub struct WebDav {
+    client: HttpClient,
+    request_timeout: Duration,
+    upload_timeout: Duration,
+    url: String,
+    authorization: String,
+}
+
+impl WebDav {
+    pub fn new(url: &str, user: &str, password: &str) -> GenericResult<WebDav> {
+        let url = url.trim_end_matches('/').to_owned();
+        Url::parse(&url).map_err(|e| format!("Invalid WebDAV URL: {}", e))?;
+
+        Ok(WebDav {
+            client: HttpClient::new().with_metrics_label(NAME),
+            request_timeout: Duration::from_secs(API_REQUEST_TIMEOUT),
+            upload_timeout: Duration::from_secs(UPLOAD_REQUEST_TIMEOUT),
+            url,
+            authorization: format!("Basic {}", base64::encode(format!("{}:{}", user, password))),
+        })
+    }
+
+    pub fn with_client_options(mut self, options: ClientOptions) -> WebDav {
+        self.client = self.client.with_options(&options);
+        self.request_timeout = options.timeouts.request.unwrap_or(self.request_timeout);
+        self.upload_timeout = options.timeouts.upload.unwrap_or(self.upload_timeout);
+        self
+    }
+
+    // Lists the collection or the file itself depending on the specified depth
+    fn propfind(&self, path: &str, depth: &str) -> GenericResult<Option<Vec<Entry>>> {
+        let request = self.request("PROPFIND", path, self.request_timeout, RawResponseReader::new())?
+            .with_header("Depth", depth)?
+            .with_text_body("application/xml", PROPFIND_REQUEST)?;
+
+        let response = match self.client.send(request) {
+            Ok(response) => response,
+            Err(HttpClientError::Api(ref err)) if err.status == StatusCode::NOT_FOUND => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        if response.status != StatusCode::MULTI_STATUS {
+            return Err!("Got an unexpected response status: {}", response.status);
+        }
+
+        Ok(Some(parse_multistatus(&response)?))
+    }
+
+    fn get_size(&self, path: &str) -> GenericResult<u64> {
+        let entries = self.propfind(path, "0")?.ok_or("The file doesn't exist")?;
+
+        match entries.as_slice() {
+            [entry] if !entry.is_collection => Ok(entry.size.ok_or("The server didn't return file size")?),
+            _ => Err!("Got an unexpected PROPFIND response"),
+        }
+    }
+
+    fn delete_temp_file(&self, path: &str) {
+        if let Err(err) = self.delete(path) {
+            error!("Failed to delete a temporary {:?} file from {}: {}.", path, NAME, err);
+        }
+    }
+
+    fn request<'a, R, RR>(
+        &self, method_name: &'static str, path: &str, timeout: Duration, reply_reader: RR,
+    ) -> HttpRequestBuildingResult<'a, R, ApiError>
+        where RR: ResponseReader<Result=R> + 'a
+    {
+        let method = Method::from_bytes(method_name.as_bytes()).map_err(HttpRequestBuildingError::new)?;
+        HttpRequest::new(
+            method, self.get_url(path), timeout, reply_reader, ErrorReader {},
+        ).with_endpoint(method_name).with_header(headers::AUTHORIZATION, &self.authorization)
+    }
+
+    fn get_url(&self, path: &str) -> String {
+        let mut url = self.url.clone();
+
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            url.push('/');
+            url.extend(percent_encoding::utf8_percent_encode(name, PATH_SEGMENT_ENCODE_SET));
+        }
+
+        url
+    }
+}
+
+impl Provider for WebDav {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn type_(&self) -> ProviderType {
+        ProviderType::Cloud
+    }
+}
+
+impl ReadProvider for WebDav {
+    fn list_directory(&self, path: &str) -> GenericResult<Option<Vec<File>>> {
+        let entries = match self.propfind(path, "1")? {
+            Some(entries) => entries,
+            None => return Ok(None),
+        };
+
+        let directory_path = decode(Url::parse(&self.get_url(path))?.path())?;
+        let directory_path = directory_path.trim_end_matches('/');
+
+        let mut files = Vec::new();
+
+        for entry in entries {
+            let entry_path = if entry.href.starts_with("http://") || entry.href.starts_with("https://") {
+                decode(Url::parse(&entry.href)?.path())?
+            } else {
+                decode(&entry.href)?
+            };
+
+            // The response includes the collection itself
+            let entry_path = entry_path.trim_end_matches('/');
+            if entry_path == directory_path {
+                if !entry.is_collection {
+                    return Err!("{:?} is not a directory", path);
+                }
+                continue;
+            }
+
+            let name = match entry_path.strip_prefix(directory_path).and_then(|name| name.strip_prefix('/')) {
+                Some(name) if !name.is_empty() && !name.contains('/') => name.to_owned(),
+                _ => return Err!("Got an unexpected entry in PROPFIND response: {:?}", entry.href),
+            };
+
+            files.push(if entry.is_collection {
+                File {name, type_: FileType::Directory, size: None, modify_time: None}
+            } else {
+                File {name, type_: FileType::File, size: entry.size, modify_time: None}
+            });
+        }
+
+        Ok(Some(files))
+    }
+
+    // There is no checksum to verify the download with, so it's up to the caller
+    fn open_file(&self, path: &str) -> GenericResult<Box<dyn io::Read + Send>> {
+        let request = self.request("GET", path, self.upload_timeout, RawResponseReader::new())?;
+        let (_, data) = self.client.download(request)?;
+        Ok(data)
+    }
+}
+
+impl WriteProvider for WebDav {
+    // WebDAV has no standard way to get checksum of a file, so only size of the uploaded file is
+    // verified
+    fn hasher(&self) -> Box<dyn Hasher> {
+        Box::new(Md5::new())
+    }
+
+    // Many WebDAV servers don't support partial uploads, so the file is always uploaded by a single
+    // request
+    fn max_request_size(&self) -> Option<u64> {
+        None
+    }
+
+    fn create_directory(&self, path: &str) -> EmptyResult {
+        let request = self.request("MKCOL", path, self.request_timeout, RawResponseReader::new())?;
+        self.client.send(request)?;
+        Ok(())
+    }
+
+    fn upload_file(&self, directory_path: &str, temp_name: &str, name: &str,
+                   chunk_streams: ChunkStreamReceiver) -> EmptyResult {
+        let directory_path = directory_path.trim_end_matches('/');
+        let temp_path = format!("{}/{}", directory_path, temp_name);
+        let path = format!("{}/{}", directory_path, name);
+        let mut uploaded = false;
+
+        for result in chunk_streams.iter() {
+            match result {
+                Ok(ChunkStream::Stream(offset, chunk_stream)) => {
+                    assert!(!uploaded);
+                    assert_eq!(offset, 0);
+
+                    let request = self.request(
+                        "PUT", &temp_path, self.upload_timeout, RawResponseReader::new(),
+                    )?.with_body("application/octet-stream", chunk_stream)?;
+
+                    // The server may keep a partially uploaded file, so clean it up on failure
+                    if let Err(err) = self.client.send(request) {
+                        self.delete_temp_file(&temp_path);
+                        return Err(stream_splitter::get_upload_error(&chunk_streams, err));
+                    }
+
+                    uploaded = true;
+                },
+                Ok(ChunkStream::EofWithCheckSum(size, _)) => {
+                    if size == 0 {
+                        return Err!("An attempt to upload an empty file");
+                    }
+
+                    let result = self.get_size(&temp_path).and_then(|uploaded_size| {
+                        if uploaded_size != size {
+                            return Err!("Uploaded file size mismatch: {} vs {}", uploaded_size, size);
+                        }
+                        self.rename(&temp_path, &path)
+                    });
+
+                    if result.is_err() {
+                        self.delete_temp_file(&temp_path);
+                    }
+
+                    return result;
+                },
+                Err(err) => {
+                    if uploaded {
+                        self.delete_temp_file(&temp_path);
+                    }
+                    return Err!("Upload has been aborted: {}", err);
+                },
+            }
+        }
+
+        Err!("Chunk stream sender has been closed without a termination message")
+    }
+
+    fn supports_rename(&self) -> bool {
+        true
+    }
+
+    fn rename(&self, src: &str, dst: &str) -> EmptyResult {
+        let request = self.request("MOVE", src, self.request_timeout, RawResponseReader::new())?
+            .with_header("Destination", self.get_url(dst))?
+            .with_header("Overwrite", "F")?;
+
+        match self.client.send(request) {
+            Ok(_) => Ok(()),
+            Err(HttpClientError::Api(ref err)) if err.status == StatusCode::PRECONDITION_FAILED => {
+                Err!("Unable to rename {:?} to {:?}: the destination already exists", src, dst)
+            },
+            Err(err) => Err!("Unable to rename {:?} to {:?}: {}", src, dst, err),
+        }
+    }
+
+    // Collections are deleted recursively by the server
+    fn delete(&self, path: &str) -> EmptyResult {
+        let request = self.request("DELETE", path, self.request_timeout, RawResponseReader::new())?;
+        self.client.send(request)?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Entry {
+    href: String,
+    is_collection: bool,
+    size: Option<u64>,
+}
+
+// Parses PROPFIND multistatus response. Namespace prefixes vary between servers, so only local names
+// of the elements are taken into account.
+fn parse_multistatus(response: &HttpResponse) -> GenericResult<Vec<Entry>> {
+    let mut reader = quick_xml::Reader::from_reader(response.body.as_slice());
+    reader.trim_text(true);
+
+    let mut entries = Vec::new();
+    let mut entry: Option<Entry> = None;
+    let mut elements: Vec<Vec<u8>> = Vec::new();
+    let mut buf = Vec::new();
+
+    loop {
+        match reader.read_event(&mut buf).map_err(|e| format!("Got an invalid PROPFIND response: {}", e))? {
+            Event::Start(ref element) => {
+                let name = element.local_name().to_vec();
+                match name.as_slice() {
+                    b"response" => entry = Some(Entry::default()),
+                    b"collection" => if let Some(ref mut entry) = entry {
+                        entry.is_collection = true;
+                    },
+                    _ => {},
+                }
+                elements.push(name);
+            },
+            Event::Empty(ref element) => {
+                if let (b"collection", Some(entry)) = (element.local_name(), entry.as_mut()) {
+                    entry.is_collection = true;
+                }
+            },
+            Event::Text(ref text) => {
+                let text = text.unescape_and_decode(&reader).map_err(|e| format!(
+                    "Got an invalid PROPFIND response: {}", e))?;
+
+                if let Some(ref mut entry) = entry {
+                    match elements.last().map(Vec::as_slice) {
+                        Some(b"href") => entry.href = text,
+                        Some(b"getcontentlength") => entry.size = Some(text.parse().map_err(|_| format!(
+                            "Got an invalid file size: {:?}", text))?),
+                        _ => {},
+                    }
+                }
+            },
+            Event::End(ref element) => {
+                elements.pop();
+                if element.local_name() == b"response" {
+                    entries.extend(entry.take());
+                }
+            },
+            Event::Eof => break,
+            _ => {},
+        }
+
+        buf.clear();
+    }
+
+    Ok(entries)
+}
+
+fn decode(path: &str) -> GenericResult<String> {
+    Ok(percent_encoding::percent_decode_str(path).decode_utf8().map_err(|_| format!(
+        "Got an invalid path: {:?}", path))?.into_owned())
+}
+
+struct ErrorReader {
+}
+
+impl ResponseReader for ErrorReader {
+    type Result = ApiError;
+
+    fn read(&self, response: HttpResponse) -> GenericResult<Self::Result> {
+        Ok(ApiError {status: response.status})
+    }
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+}
+
+impl Error for ApiError {
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WebDAV server returned an error: {}", self.status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{TestResponse, TestServer};
+
+    use super::*;
+
+    const NEXTCLOUD_RESPONSE: &str = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:s="http://sabredav.org/ns" xmlns:oc="http://owncloud.org/ns" xmlns:nc="http://nextcloud.org/ns">
+  <d:response>
+    <d:href>/remote.php/dav/files/user/backups/</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype><d:collection/></d:resourcetype>
+        <d:getlastmodified>Wed, 01 Jan 2020 10:00:00 GMT</d:getlastmodified>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+    <d:propstat>
+      <d:prop>
+        <d:getcontentlength/>
+      </d:prop>
+      <d:status>HTTP/1.1 404 Not Found</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/user/backups/2020.01.01/</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype><d:collection/></d:resourcetype>
+        <d:getlastmodified>Wed, 01 Jan 2020 10:00:00 GMT</d:getlastmodified>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+    <d:propstat>
+      <d:prop>
+        <d:getcontentlength/>
+      </d:prop>
+      <d:status>HTTP/1.1 404 Not Found</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/user/backups/backup%20file.tar.gpg</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype/>
+        <d:getcontentlength>1048576</d:getcontentlength>
+        <d:getlastmodified>Wed, 01 Jan 2020 10:05:00 GMT</d:getlastmodified>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+</d:multistatus>
+"#;
+
+    #[test]
+    fn nextcloud_listing() {
+        let server = TestServer::new(|_| {
+            TestResponse::new(207).with_body("application/xml; charset=utf-8", NEXTCLOUD_RESPONSE.as_bytes())
+        });
+
+        let webdav = WebDav::new(&format!("{}/remote.php/dav/files/user/", server.url), "user", "password").unwrap();
+        let files = webdav.list_directory("/backups").unwrap().unwrap();
+
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].name, "2020.01.01");
+        assert_eq!(files[0].type_, FileType::Directory);
+        assert_eq!(files[0].size, None);
+        assert_eq!(files[1].name, "backup file.tar.gpg");
+        assert_eq!(files[1].type_, FileType::File);
+        assert_eq!(files[1].size, Some(1024 * 1024));
+
+        let requests = server.stop();
+        assert_eq!(requests.len(), 1);
+
+        let request = &requests[0];
+        assert_eq!(request.method, "PROPFIND");
+        assert_eq!(request.path, "/remote.php/dav/files/user/backups");
+        assert_eq!(request.header("depth"), Some("1"));
+        assert_eq!(request.header("authorization"), Some("Basic dXNlcjpwYXNzd29yZA=="));
+    }
+
+    #[test]
+    fn not_a_directory() {
+        let server = TestServer::new(|_| {
+            TestResponse::new(207).with_body("application/xml", concat!(
+                r#"<?xml version="1.0"?><d:multistatus xmlns:d="DAV:"><d:response>"#,
+                r#"<d:href>/backups</d:href><d:propstat><d:prop><d:resourcetype/>"#,
+                r#"<d:getcontentlength>10</d:getcontentlength></d:prop></d:propstat>"#,
+                r#"</d:response></d:multistatus>"#,
+            ).as_bytes())
+        });
+
+        let webdav = WebDav::new(&server.url, "user", "password").unwrap();
+        assert_eq!(webdav.list_directory("/backups").unwrap_err().to_string(),
+                   r#""/backups" is not a directory"#);
+    }
+}
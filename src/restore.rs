@@ -0,0 +1,79 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::core::EmptyResult;
+use crate::encryptor::{Decryptor, EncryptionOptions};
+use crate::hash::Md5;
+use crate::storage::Storage;
+use crate::stream_splitter::{Data, DataReceiver};
+use crate::util;
+
+// Downloads the backup and saves it decrypted to the specified directory. The downloaded data is
+// verified by the provider against the checksum of the uploaded one and decrypted data integrity is
+// guaranteed by gpg. Compressed backups aren't decompressed: the restored file keeps the compression
+// extension (.tar.gz, .tar.bz2), so it can be unpacked by tar as is.
+pub fn restore_backup(
+    storage: &Storage, group_name: &str, backup_name: &str, encryption: EncryptionOptions, dst_path: &str,
+) -> EmptyResult {
+    let (file_name, mut data) = storage.open_backup(group_name, backup_name).map_err(|e| format!(
+        "Unable to open {:?} backup on {}: {}", backup_name, storage.name(), e))?;
+
+    let restored_name = file_name.strip_suffix(".gpg").ok_or_else(|| format!(
+        "Got an unexpected backup file name: {:?}", file_name))?;
+
+    let path = Path::new(dst_path).join(restored_name);
+    let temp_path = Path::new(dst_path).join(format!(".{}.part", restored_name));
+
+    if fs::symlink_metadata(&path).is_ok() {
+        return Err!("{:?} already exists", path);
+    }
+
+    info!("Restoring {:?} backup from {} to {:?}...", backup_name, storage.name(), path);
+
+    let (mut decryptor, decrypted_data) = Decryptor::new(encryption, Box::new(Md5::new()))?;
+
+    let downloader = util::spawn_thread("backup downloader", move || {
+        let error = io::copy(&mut data, &mut decryptor).err().map(|e| format!(
+            "Failed to download the backup: {}", e));
+        decryptor.finish(error)
+    })?;
+
+    let write_result = write_data(&temp_path, decrypted_data);
+    let download_result = util::join_thread(downloader);
+
+    let result = write_result.and(download_result).and_then(|_| {
+        fs::rename(&temp_path, &path).map_err(|e| format!(
+            "Unable to rename {:?} to {:?}: {}", temp_path, path, e).into())
+    });
+
+    if let Err(err) = result {
+        if let Err(err) = fs::remove_file(&temp_path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                error!("Failed to delete {:?}: {}.", temp_path, err);
+            }
+        }
+        return Err!("Failed to restore {:?} backup: {}", backup_name, err);
+    }
+
+    info!("{:?} backup has been restored to {:?}.", backup_name, path);
+    Ok(())
+}
+
+fn write_data(path: &Path, data: DataReceiver) -> EmptyResult {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path).map_err(|e| format!(
+        "Unable to create {:?}: {}", path, e))?;
+
+    for message in data.iter() {
+        match message? {
+            Data::Payload(data) => file.write_all(&data).map_err(|e| format!(
+                "Failed to write to {:?}: {}", path, e))?,
+            Data::EofWithChecksum(_) => {
+                file.sync_all().map_err(|e| format!("Unable to fsync() {:?}: {}", path, e))?;
+                return Ok(());
+            },
+        }
+    }
+
+    Err!("Decrypted data stream has been closed without a termination message")
+}
@@ -0,0 +1,45 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+// Limits the number of operations running concurrently across all threads
+pub struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Arc<Semaphore> {
+        Arc::new(Semaphore {
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        })
+    }
+
+    // Blocks until a permit is available. The permit is returned on drop.
+    pub fn acquire(self: &Arc<Semaphore>) -> SemaphorePermit {
+        self.acquire_many(1)
+    }
+
+    // Blocks until the specified number of permits is available. The caller must never request more
+    // permits than the semaphore has been created with.
+    pub fn acquire_many(self: &Arc<Semaphore>, permits: usize) -> SemaphorePermit {
+        let mut available = self.available.lock().unwrap();
+        while *available < permits {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= permits;
+
+        SemaphorePermit {semaphore: self.clone(), permits}
+    }
+}
+
+pub struct SemaphorePermit {
+    semaphore: Arc<Semaphore>,
+    permits: usize,
+}
+
+impl Drop for SemaphorePermit {
+    fn drop(&mut self) {
+        *self.semaphore.available.lock().unwrap() += self.permits;
+        self.semaphore.released.notify_all();
+    }
+}
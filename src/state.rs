@@ -0,0 +1,49 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::core::{EmptyResult, GenericResult};
+
+pub fn get_last_run_time(path: &str) -> GenericResult<Option<SystemTime>> {
+    let mut data = String::new();
+
+    match File::open(path) {
+        Ok(mut file) => file.read_to_string(&mut data)?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let timestamp: u64 = data.trim().parse().map_err(|_| format!(
+        "Invalid last run time: {:?}", data.trim()))?;
+
+    Ok(Some(UNIX_EPOCH + Duration::from_secs(timestamp)))
+}
+
+pub fn save_last_run_time(path: &str, time: SystemTime) -> EmptyResult {
+    let timestamp = time.duration_since(UNIX_EPOCH)?.as_secs();
+    let temp_path = format!("{}.tmp", path);
+
+    if let Err(err) = fs::write(&temp_path, format!("{}\n", timestamp)) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(err.into());
+    }
+
+    Ok(fs::rename(&temp_path, path)?)
+}
+
+// Error reports are stored next to the state file: one per backup
+pub fn get_error_report_path(state_path: &str, backup_name: &str) -> PathBuf {
+    Path::new(state_path).with_file_name(format!("{}.last-error.txt", backup_name))
+}
+
+pub fn save_error_report(path: &Path, report: &str) -> EmptyResult {
+    Ok(fs::write(path, report)?)
+}
+
+pub fn remove_error_report(path: &Path) -> EmptyResult {
+    match fs::remove_file(path) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
@@ -11,6 +11,8 @@ pub struct Backup {
     pub path: String,
     pub name: String,
     metadata_path: Option<String>,
+    // Size of the backup file (archive) or of all backup files (directory) if the provider reports it
+    pub size: Option<u64>,
     pub inner_stat: Option<BackupInnerStat>,
     pub outer_stat: Option<BackupOuterStat>,
 }
@@ -28,11 +30,14 @@ pub struct BackupOuterStat {
 }
 
 impl Backup {
-    pub fn read(provider: &dyn ReadProvider, name: &str, path: &str, archive: bool) -> GenericResult<Backup> {
+    pub fn read(
+        provider: &dyn ReadProvider, name: &str, path: &str, archive: bool, size: Option<u64>,
+    ) -> GenericResult<Backup> {
         let mut backup = Backup {
             path: path.to_owned(),
             name: name.to_owned(),
             metadata_path: None,
+            size: if archive {size} else {None},
             inner_stat: None,
             outer_stat: None,
         };
@@ -71,6 +76,7 @@ impl Backup {
         }
 
         if let (Some(metadata_size), Some(data_size)) = (metadata_size, data_size) {
+            backup.size.replace(metadata_size + data_size);
             backup.outer_stat.replace(BackupOuterStat {metadata_size, data_size});
         }
 
@@ -83,11 +89,6 @@ impl Backup {
         let metadata_path = self.metadata_path.as_ref().ok_or(
             "The backup has no metadata file")?;
 
-        if cfg!(debug_assertions) {
-            warn!("Skip consistency check of {:?}: running in develop mode.", metadata_path);
-            return Ok(true);
-        }
-
         let metadata_file = provider.open_file(&metadata_path)
             .map(BzDecoder::new).map(BufReader::new)
             .map_err(|e| format!("Unable to open metadata file: {}", e))?;
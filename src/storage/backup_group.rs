@@ -1,5 +1,6 @@
 use std::collections::HashSet;
 
+use rayon::prelude::*;
 use regex::{self, Regex};
 
 use crate::core::GenericResult;
@@ -14,9 +15,9 @@ pub struct BackupGroup {
 }
 
 impl BackupGroup {
-    pub fn list(provider: &dyn ReadProvider, path: &str) -> GenericResult<(Vec<BackupGroup>, bool)> {
+    pub fn list(provider: &dyn ReadProvider, path: &str, concurrency: usize) -> GenericResult<(Vec<BackupGroup>, bool)> {
         let mut ok = true;
-        let mut backup_groups = Vec::new();
+        let mut group_names = Vec::new();
         let name_regex = Regex::new(r"^\d{4}\.\d{2}\.\d{2}$")?;
 
         let mut files = provider.list_directory(path)?.ok_or_else(|| format!(
@@ -35,13 +36,29 @@ impl BackupGroup {
                 continue;
             }
 
-            let group_name = &file.name;
+            group_names.push(file.name);
+        }
+
+        let read_group = |group_name: &String| {
             let group_path = format!("{}/{}", path, group_name);
+            BackupGroup::read(provider, group_name, &group_path).map_err(|e| format!(
+                "Unable to list {:?} backup group: {}", group_path, e))
+        };
 
-            let (group, group_ok) = BackupGroup::read(provider, group_name, &group_path).map_err(|e| format!(
-                "Unable to list {:?} backup group: {}", group_path, e))?;
-            ok &= group_ok;
+        // The results are collected in the original order, so the listing is deterministic regardless
+        // of the concurrency.
+        let results: Vec<_> = if concurrency > 1 {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(concurrency).build()?;
+            pool.install(|| group_names.par_iter().map(read_group).collect())
+        } else {
+            group_names.iter().map(read_group).collect()
+        };
+
+        let mut backup_groups = Vec::with_capacity(results.len());
 
+        for result in results {
+            let (group, group_ok) = result?;
+            ok &= group_ok;
             backup_groups.push(group);
         }
 
@@ -78,6 +95,14 @@ impl BackupGroup {
             let backup_name = captures.unwrap().get(1).unwrap().as_str();
             let backup_path = format!("{}/{}", path, file.name);
 
+            // The same backup may be stored with different compression
+            if group.backups.last().map_or(false, |backup| backup.name == backup_name) {
+                error!("{:?} backup group on {} contains a duplicated backup: {:?}.",
+                       path, provider.name(), file.name);
+                ok = false;
+                continue
+            }
+
             if first {
                 first = false;
 
@@ -92,7 +117,7 @@ impl BackupGroup {
 
             let backup = match Backup::read(
                 provider, backup_name, &backup_path,
-                file.type_ != FileType::Directory
+                file.type_ != FileType::Directory, file.size,
             ) {
                 Ok(backup) => backup,
                 Err(e) => {
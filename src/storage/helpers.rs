@@ -1,27 +1,35 @@
+use std::process;
+use std::time::{Duration, SystemTime};
+
+use nix::errno::Errno;
+use nix::sys::signal;
+use nix::unistd::{self, Pid};
 use regex::{self, Regex};
 
+use crate::config::Compression;
+use crate::core::GenericResult;
 use crate::provider::{ProviderType, FileType};
 
+const TEMP_FILE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
 pub struct BackupFileTraits {
     pub type_: FileType,
-    pub extension: &'static str,
+    extensions: &'static [(Compression, &'static str)],
     pub name_re: Regex,
+    pub temp_name_re: Regex,
 }
 
 impl BackupFileTraits {
     pub fn get_for(provider_type: ProviderType) -> &'static BackupFileTraits {
         lazy_static! {
-            static ref LOCAL_TRAITS: BackupFileTraits = BackupFileTraits {
-                type_: FileType::Directory,
-                extension: "",
-                name_re: BackupFileTraits::get_name_re(""),
-            };
-
-            static ref CLOUD_TRAITS: BackupFileTraits = BackupFileTraits {
-                type_: FileType::File,
-                extension: ".tar.gpg",
-                name_re: BackupFileTraits::get_name_re(".tar.gpg"),
-            };
+            static ref LOCAL_TRAITS: BackupFileTraits = BackupFileTraits::new(
+                FileType::Directory, &[(Compression::None, "")]);
+
+            static ref CLOUD_TRAITS: BackupFileTraits = BackupFileTraits::new(FileType::File, &[
+                (Compression::None, ".tar.gpg"),
+                (Compression::Gzip, ".tar.gz.gpg"),
+                (Compression::Bzip2, ".tar.bz2.gpg"),
+            ]);
         }
 
         match provider_type {
@@ -30,9 +38,99 @@ impl BackupFileTraits {
         }
     }
 
-    fn get_name_re(extension: &str) -> Regex {
-        let regex = r"^(\d{4}\.\d{2}\.\d{2}-\d{2}:\d{2}:\d{2})".to_owned()
-            + &regex::escape(extension) + "$";
+    fn new(type_: FileType, extensions: &'static [(Compression, &'static str)]) -> BackupFileTraits {
+        let extensions_re = extensions.iter()
+            .map(|&(_, extension)| regex::escape(extension))
+            .collect::<Vec<_>>().join("|");
+
+        BackupFileTraits {
+            type_,
+            extensions,
+            name_re: BackupFileTraits::get_name_re(&extensions_re),
+            temp_name_re: BackupFileTraits::get_temp_name_re(&extensions_re),
+        }
+    }
+
+    pub fn get_extension(&self, compression: Compression) -> GenericResult<&'static str> {
+        self.extensions.iter()
+            .find(|&&(extension_compression, _)| extension_compression == compression)
+            .map(|&(_, extension)| extension)
+            .ok_or_else(|| "The storage doesn't support compression".into())
+    }
+
+    fn get_name_re(extensions_re: &str) -> Regex {
+        let regex = r"^(\d{4}\.\d{2}\.\d{2}-\d{2}:\d{2}:\d{2})(?:".to_owned()
+            + extensions_re + ")$";
+        Regex::new(&regex).unwrap()
+    }
+
+    // Temporary files of the older versions don't have run ID suffix
+    fn get_temp_name_re(extensions_re: &str) -> Regex {
+        let regex = r"^\.(\d{4}\.\d{2}\.\d{2}-\d{2}:\d{2}:\d{2})(?:".to_owned()
+            + extensions_re + r")(?:\.(.+))?$";
         Regex::new(&regex).unwrap()
     }
-}
\ No newline at end of file
+}
+
+// Returns an ID which uniquely identifies the current process among all hosts that may work with the
+// same storage.
+pub fn get_run_id() -> &'static str {
+    lazy_static! {
+        static ref RUN_ID: String = format!("{}-{}", get_hostname(), process::id());
+    }
+    &RUN_ID
+}
+
+// Checks whether a temporary file created by the specified run (files of the older versions have no
+// run ID) can be safely deleted. Runs on other hosts can't be checked, so their files (as well as the
+// files without run ID) are considered stale only when they haven't been modified for a long time.
+pub fn is_stale_temp_file(run_id: Option<&str>, modify_time: Option<SystemTime>) -> bool {
+    if let Some(run_id) = run_id {
+        if run_id == get_run_id() {
+            return false;
+        } else if is_finished_run(run_id) {
+            return true;
+        }
+    }
+
+    modify_time.and_then(|time| time.elapsed().ok()).map_or(false, |age| age >= TEMP_FILE_MAX_AGE)
+}
+
+// Checks whether the specified run has definitely finished, so its temporary files can be safely
+// deleted. Runs on other hosts can't be checked, so they are always considered as active ones.
+fn is_finished_run(run_id: &str) -> bool {
+    if run_id == get_run_id() {
+        return false;
+    }
+
+    let (hostname, pid) = match run_id.rfind('-') {
+        Some(index) => (&run_id[..index], &run_id[index + 1..]),
+        None => return false,
+    };
+
+    let pid = match pid.parse() {
+        Ok(pid) if hostname == get_hostname() => pid,
+        _ => return false,
+    };
+
+    matches!(signal::kill(Pid::from_raw(pid), None), Err(nix::Error::Sys(Errno::ESRCH)))
+}
+
+fn get_hostname() -> &'static str {
+    lazy_static! {
+        static ref HOSTNAME: String = {
+            let mut buf = [0u8; 256];
+
+            match unistd::gethostname(&mut buf) {
+                Ok(hostname) => hostname.to_string_lossy().replace(|c: char| {
+                    !c.is_ascii_alphanumeric() && c != '-' && c != '.'
+                }, "_"),
+                Err(err) => {
+                    warning!("Unable to get hostname: {}.", err);
+                    "unknown".to_owned()
+                },
+            }
+        };
+    }
+    &HOSTNAME
+}
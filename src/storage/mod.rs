@@ -3,15 +3,28 @@ mod backup;
 mod backup_group;
 mod helpers;
 
-use std::time::SystemTime;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::ptr;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
 
-use chrono::{self, TimeZone};
+use bytes::Bytes;
+use chrono::{self, NaiveDate, TimeZone};
 use rayon::prelude::*;
 
+use crate::audit;
+use crate::bandwidth::ThrottledReader;
+use crate::clock::{Clock, SystemClock};
+use crate::compressor::{Compressor, CompressionOptions};
+use crate::config::{self, CorruptionAction};
 use crate::core::{EmptyResult, GenericResult};
-use crate::encryptor::Encryptor;
-use crate::provider::{ReadProvider, WriteProvider};
-use crate::stream_splitter;
+use crate::encryptor::{Encryptor, EncryptionOptions};
+use crate::hash::{Hasher, HmacSha256, TeeHasher, VerifyingReader};
+use crate::progress::Progress;
+use crate::provider::{ReadProvider, WriteProvider, FileType};
+use crate::stream_splitter::{self, Data, DataReceiver, DataSender};
 use crate::util;
 
 use self::adapters::{AbstractProvider, ReadOnlyProviderAdapter, ReadWriteProviderAdapter};
@@ -23,6 +36,27 @@ pub use self::backup_group::BackupGroup;
 pub struct Storage {
     provider: Box<dyn AbstractProvider>,
     path: String,
+    list_concurrency: usize,
+    verify_uploads: bool,
+    corruption_action: CorruptionAction,
+    allowed_delete_prefixes: Vec<String>,
+    backup_name: Option<String>,
+    integrity_key: Option<Vec<u8>>,
+    clock: Box<dyn Clock>,
+}
+
+// Receives HMAC of the uploaded data when the upload finishes
+type HmacReceiver = mpsc::Receiver<String>;
+
+const TRASH_PREFIX: &str = ".trash.";
+const STAGING_PREFIX: &str = ".staging.";
+const QUARANTINE_NAME: &str = ".quarantine";
+
+pub struct TempFile {
+    pub path: String,
+    pub size: Option<u64>,
+    // The file doesn't belong to an upload that may still be in progress, so it's garbage now
+    pub stale: bool,
 }
 
 impl Storage {
@@ -30,6 +64,13 @@ impl Storage {
         Storage {
             provider: ReadWriteProviderAdapter::new(provider),
             path: path.to_owned(),
+            list_concurrency: 1,
+            verify_uploads: false,
+            corruption_action: CorruptionAction::Delete,
+            allowed_delete_prefixes: Vec::new(),
+            backup_name: None,
+            integrity_key: None,
+            clock: Box::new(SystemClock),
         }
     }
 
@@ -37,16 +78,67 @@ impl Storage {
         Storage {
             provider: ReadOnlyProviderAdapter::new(provider),
             path: path.to_owned(),
+            list_concurrency: 1,
+            verify_uploads: false,
+            corruption_action: CorruptionAction::Delete,
+            allowed_delete_prefixes: Vec::new(),
+            backup_name: None,
+            integrity_key: None,
+            clock: Box::new(SystemClock),
         }
     }
 
+    // Sets the number of backup groups that are listed concurrently
+    pub fn with_list_concurrency(mut self, concurrency: usize) -> Storage {
+        self.list_concurrency = concurrency;
+        self
+    }
+
+    // Enables verification of each uploaded file against the provider after its upload
+    pub fn with_upload_verification(mut self, verify: bool) -> Storage {
+        self.verify_uploads = verify;
+        self
+    }
+
+    // Sets what to do with the uploaded files that have failed the verification
+    pub fn with_corruption_action(mut self, action: CorruptionAction) -> Storage {
+        self.corruption_action = action;
+        self
+    }
+
+    // Forbids deletion of anything outside of the specified paths
+    pub fn with_delete_fence(mut self, allowed_prefixes: &[String]) -> Storage {
+        self.allowed_delete_prefixes = allowed_prefixes.to_vec();
+        self
+    }
+
+    // Sets the backup name the storage belongs to (used in the audit log)
+    pub fn with_backup_name(mut self, name: &str) -> Storage {
+        self.backup_name = Some(name.to_owned());
+        self
+    }
+
+    // Enables integrity metadata: an HMAC of each uploaded backup is stored alongside it and checked
+    // whenever the backup is downloaded
+    pub fn with_integrity_key(mut self, key: Option<&str>) -> Storage {
+        self.integrity_key = key.map(|key| key.as_bytes().to_vec());
+        self
+    }
+
+    // Replaces the clock which backup ages are calculated by
+    #[cfg(test)]
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Storage {
+        self.clock = Box::new(clock);
+        self
+    }
+
     pub fn name(&self) -> &str {
         self.provider.read().name()
     }
 
     pub fn get_backup_groups(&self, verify: bool) -> GenericResult<(Vec<BackupGroup>, bool)> {
         let provider = self.provider.read();
-        let (mut groups, mut ok) = BackupGroup::list(provider, &self.path)?;
+        let (mut groups, mut ok) = BackupGroup::list(provider, &self.path, self.list_concurrency)?;
 
         if verify && !groups.is_empty() {
             info!("Verifying backups on {}...", self.name());
@@ -58,28 +150,274 @@ impl Storage {
         Ok((groups, ok))
     }
 
-    pub fn create_backup_group(&mut self, group_name: &str) -> EmptyResult {
+    pub fn supports_rename(&self) -> bool {
+        self.provider.write().map(|provider| provider.supports_rename()).unwrap_or(false)
+    }
+
+    // Returns a name of hidden backup group which may be used to prepare the specified group before
+    // making it visible.
+    pub fn get_staging_group_name(&self, group_name: &str) -> String {
+        format!("{}{}.{}", STAGING_PREFIX, group_name, helpers::get_run_id())
+    }
+
+    // Atomically makes the staging backup group visible under the specified name
+    pub fn promote_staging_group(&mut self, staging_group_name: &str, group_name: &str) -> EmptyResult {
+        let staging_group_path = self.get_backup_group_path(staging_group_name);
+        let group_path = self.get_backup_group_path(group_name);
+
+        let result = self.provider.write()?.rename(&staging_group_path, &group_path);
+        self.audit("promote-group", &group_path, None, &result);
+
+        result
+    }
+
+    // Returns names of the backups stored in the specified group without any group validation
+    pub fn get_backup_names(&self, group_name: &str) -> GenericResult<Vec<String>> {
+        let provider = self.provider.read();
+        let traits = BackupFileTraits::get_for(provider.type_());
         let group_path = self.get_backup_group_path(group_name);
-        self.provider.write()?.create_directory(&group_path)
+
+        let files = provider.list_directory(&group_path)?.ok_or_else(|| format!(
+            "{:?} backup group doesn't exist", group_name))?;
+
+        Ok(files.into_iter().filter(|file| file.type_ == traits.type_).filter_map(|file| {
+            traits.name_re.captures(&file.name).map(|captures| captures[1].to_owned())
+        }).collect())
+    }
+
+    // Opens the backup for reading. Returns the backup file name and its data verified by the provider and
+    // by its HMAC if integrity metadata is enabled.
+    pub fn open_backup(
+        &self, group_name: &str, backup_name: &str,
+    ) -> GenericResult<(String, Box<dyn Read + Send>)> {
+        let provider = self.provider.read();
+        let traits = BackupFileTraits::get_for(provider.type_());
+        let group_path = self.get_backup_group_path(group_name);
+
+        let files = provider.list_directory(&group_path)?.ok_or_else(|| format!(
+            "{:?} backup group doesn't exist", group_name))?;
+
+        let file_name = files.into_iter()
+            .filter(|file| file.type_ == traits.type_)
+            .map(|file| file.name)
+            .find(|file_name| match traits.name_re.captures(file_name) {
+                Some(captures) => &captures[1] == backup_name,
+                None => false,
+            })
+            .ok_or_else(|| format!(
+                "{:?} backup doesn't exist in {:?} backup group", backup_name, group_name))?;
+
+        let data = self.open_file(&format!("{}/{}", group_path, file_name))?;
+        Ok((file_name, data))
     }
 
-    pub fn upload_backup(&mut self, local_backup_path: &str, group_name: &str, backup_name: &str,
-                         encryption_passphrase: &str) -> EmptyResult {
+    // Downloads the backup and compares its checksum with the server-side one. Returns false if the
+    // provider doesn't expose server-side checksums, so only the download has been checked.
+    pub fn verify_backup(&self, path: &str) -> GenericResult<bool> {
         let provider = self.provider.write()?;
-        let (encryptor, data_stream) = Encryptor::new(encryption_passphrase, provider.hasher())?;
 
-        let backup_name = backup_name.to_owned();
+        let checksum = if self.integrity_key.is_some() {
+            let mut hasher = provider.hasher();
+            io::copy(&mut ThrottledReader::new(self.open_file(path)?), &mut hasher).map_err(|e| format!(
+                "Failed to download the file: {}", e))?;
+            hasher.finish()
+        } else {
+            provider.download_checksum(path)?
+        };
+
+        Ok(match provider.get_checksum(path)? {
+            Some(expected_checksum) => {
+                if checksum != expected_checksum {
+                    return Err!("Checksum mismatch: {} vs {}", checksum, expected_checksum);
+                }
+                true
+            },
+            None => false,
+        })
+    }
+
+    pub fn create_backup_group(&mut self, group_name: &str) -> EmptyResult {
+        let group_path = self.get_backup_group_path(group_name);
+
+        let result = self.provider.write()?.create_directory(&group_path);
+        self.audit("create-group", &group_path, None, &result);
+
+        result
+    }
+
+    // Each upload has its own archiver, encryption and splitting pipeline, so backups may be uploaded
+    // concurrently
+    pub fn upload_backup(&self, local_backup_path: &str, group_name: &str, backup_name: &str,
+                         encryption: EncryptionOptions, compression: CompressionOptions) -> EmptyResult {
+        let archive_name = backup_name.to_owned();
+        let progress = Progress::new(
+            &format!("{:?} backup", local_backup_path), get_directory_size(Path::new(local_backup_path)));
         let local_backup_path = local_backup_path.to_owned();
+
+        self.upload(group_name, backup_name, encryption, compression, Some(progress), move |compressor| {
+            archive_backup(&archive_name, &local_backup_path, compressor)
+        })?;
+
+        Ok(())
+    }
+
+    // Uploads arbitrary data as a backup, passing it through the same encryption and upload pipeline
+    // as local backups. Returns checksum of the uploaded data. It's an API for embedders: the program
+    // itself uploads only local backups.
+    #[allow(dead_code)]
+    pub fn upload_stream<R: Read + Send + 'static>(
+        &mut self, data: R, group_name: &str, backup_name: &str, encryption: EncryptionOptions,
+    ) -> GenericResult<String> {
+        let compression = CompressionOptions::none();
+        self.upload(group_name, backup_name, encryption, compression, None, move |compressor| {
+            copy_data(data, compressor)
+        })
+    }
+
+    fn upload<F>(&self, group_name: &str, backup_name: &str, encryption: EncryptionOptions,
+                 compression: CompressionOptions, progress: Option<Progress>,
+                 producer: F) -> GenericResult<String>
+        where F: FnOnce(Compressor) -> EmptyResult + Send + 'static
+    {
+        let file_name = self.get_backup_file_name(backup_name, compression)?;
+
+        let (hasher, hmac) = self.get_hasher()?;
+        let (encryptor, data_stream) = Encryptor::new(encryption, hasher)?;
+        let compressor = Compressor::new(encryptor, compression);
+
+        let (_, checksum) = self.upload_data(group_name, &file_name, data_stream, progress, hmac, move || {
+            producer(compressor)
+        })?;
+
+        Ok(checksum)
+    }
+
+    // Copies the backup from another storage as is, without re-encryption. The data is verified end to
+    // end: against the source's server-side checksum if the source exposes it and then by the upload.
+    pub fn copy_backup(&mut self, src_storage: &Storage, group_name: &str, backup: &Backup) -> EmptyResult {
+        let src_traits = BackupFileTraits::get_for(src_storage.provider.read().type_());
+        let dst_traits = BackupFileTraits::get_for(self.provider.read().type_());
+        if !ptr::eq(src_traits, dst_traits) {
+            return Err!("Backups can't be copied from {} to {}: the storages have different backup formats",
+                        src_storage.name(), self.name());
+        }
+
+        let file_name = backup.path.rsplit('/').next().unwrap().to_owned();
+        let mut data = src_storage.open_file(&backup.path)?;
+
+        if let Ok(src_provider) = src_storage.provider.write() {
+            if let Some(checksum) = src_provider.get_checksum(&backup.path)? {
+                data = Box::new(VerifyingReader::new(data, src_provider.hasher(), &checksum));
+            }
+        }
+
+        let (hasher, hmac) = self.get_hasher()?;
+        let (tx, data_stream) = mpsc::sync_channel(1);
+
+        self.upload_data(group_name, &file_name, data_stream, None, hmac, move || {
+            read_data(data, hasher, tx)
+        })?;
+
+        Ok(())
+    }
+
+    // Returns the provider's hasher, which also calculates HMAC of the data if integrity metadata is
+    // enabled
+    fn get_hasher(&self) -> GenericResult<(Box<dyn Hasher>, Option<HmacReceiver>)> {
+        let hasher = self.provider.write()?.hasher();
+
+        Ok(match self.integrity_key {
+            Some(ref key) => {
+                let (hasher, hmac) = TeeHasher::new(hasher, Box::new(HmacSha256::new(key)));
+                (Box::new(hasher), Some(hmac))
+            },
+            None => (hasher, None),
+        })
+    }
+
+    // Opens the file for reading. If integrity metadata is enabled, the data is checked against the
+    // stored HMAC, so reading of tampered data fails on EOF.
+    fn open_file(&self, path: &str) -> GenericResult<Box<dyn Read + Send>> {
+        let provider = self.provider.read();
+        let data = provider.open_file(path)?;
+
+        let key = match self.integrity_key {
+            Some(ref key) => key,
+            None => return Ok(data),
+        };
+
+        let hmac_path = self.get_hmac_path(path)?;
+        let mut hmac = String::new();
+
+        provider.open_file(&hmac_path).and_then(|mut file| {
+            file.read_to_string(&mut hmac)?;
+            Ok(())
+        }).map_err(|e| format!("Unable to read integrity metadata of {:?}: {}", path, e))?;
+
+        Ok(Box::new(VerifyingReader::new(data, Box::new(HmacSha256::new(key)), hmac.trim())
+            .with_mismatch_error("HMAC mismatch: the data has been tampered with")))
+    }
+
+    // HMAC of a backup is stored in a hidden file next to it, which is neither a backup nor a temporary
+    // file for the listings
+    fn get_hmac_path(&self, path: &str) -> GenericResult<String> {
+        let index = path.rfind('/').ok_or_else(|| format!("Invalid backup path: {:?}", path))?;
+        let (group_path, file_name) = (&path[..index], &path[index + 1..]);
+
+        let traits = BackupFileTraits::get_for(self.provider.read().type_());
+        let captures = traits.name_re.captures(file_name).ok_or_else(|| format!(
+            "Invalid backup file name: {:?}", file_name))?;
+
+        Ok(format!("{}/.{}.hmac", group_path, &captures[1]))
+    }
+
+    // Returns size and checksum of the uploaded data
+    fn upload_data<F>(&self, group_name: &str, file_name: &str, data_stream: DataReceiver,
+                      progress: Option<Progress>, hmac: Option<HmacReceiver>,
+                      producer: F) -> GenericResult<(u64, String)>
+        where F: FnOnce() -> EmptyResult + Send + 'static
+    {
         let group_path = self.get_backup_group_path(group_name);
-        let temp_file_name = self.get_backup_file_name(&backup_name, true);
-        let file_name = self.get_backup_file_name(&backup_name, false);
+        let path = format!("{}/{}", group_path, file_name);
+
+        let result = self.upload_data_to(&group_path, file_name, data_stream, progress, producer);
+        self.audit("upload", &path, result.as_ref().ok().map(|&(size, _)| size), &result);
+
+        if let (Ok(_), Some(hmac)) = (&result, hmac) {
+            let hmac = hmac.recv().map_err(|_| "HMAC of the uploaded data hasn't been calculated")?;
+            self.upload_hmac(&group_path, &path, hmac).map_err(|e| format!(
+                "Failed to upload integrity metadata: {}", e))?;
+        }
+
+        result
+    }
+
+    fn upload_hmac(&self, group_path: &str, path: &str, hmac: String) -> EmptyResult {
+        let hmac_path = self.get_hmac_path(path)?;
+        let file_name = hmac_path.rsplit('/').next().unwrap();
+
+        let hasher = self.provider.write()?.hasher();
+        let (tx, data_stream) = mpsc::sync_channel(1);
+
+        let result = self.upload_data_to(group_path, file_name, data_stream, None, move || {
+            read_data(Box::new(io::Cursor::new(hmac.into_bytes())), hasher, tx)
+        });
+        self.audit("upload", &hmac_path, result.as_ref().ok().map(|&(size, _)| size), &result);
+
+        result.map(|_| ())
+    }
+
+    fn upload_data_to<F>(&self, group_path: &str, file_name: &str, data_stream: DataReceiver,
+                         progress: Option<Progress>, producer: F) -> GenericResult<(u64, String)>
+        where F: FnOnce() -> EmptyResult + Send + 'static
+    {
+        let provider = self.provider.write()?;
+        let temp_file_name = get_temp_file_name(file_name);
 
         let (chunk_streams, splitter_thread) = stream_splitter::split(
-            data_stream, provider.max_request_size())?;
+            data_stream, provider.max_request_size(), progress)?;
 
-        let archive_thread = match util::spawn_thread("backup archiver", move || {
-            archive_backup(&backup_name, &local_backup_path, encryptor)
-        }) {
+        let producer_thread = match util::spawn_thread("backup archiver", producer) {
             Ok(handle) => handle,
             Err(err) => {
                 util::join_thread_ignoring_result(splitter_thread);
@@ -88,9 +426,9 @@ impl Storage {
         };
 
         let upload_result = provider.upload_file(
-            &group_path, &temp_file_name, &file_name, chunk_streams);
+            group_path, &temp_file_name, file_name, chunk_streams);
 
-        let archive_result = util::join_thread(archive_thread).map_err(|e| format!(
+        let producer_result = util::join_thread(producer_thread).map_err(|e| format!(
             "Archive operation has failed: {}", e));
 
         let splitter_result = util::join_thread(splitter_thread);
@@ -99,15 +437,197 @@ impl Storage {
         upload_result?;
 
         // ... just in case, check these results too, to not miss anything.
-        archive_result?;
-        splitter_result?;
+        producer_result?;
+        let (size, checksum) = splitter_result?.ok_or(
+            "The data stream has been terminated without a checksum")?;
 
-        Ok(())
+        if self.verify_uploads {
+            let path = format!("{}/{}", group_path, file_name);
+
+            // The file is deleted only if it's definitely corrupted: a failed verification request says
+            // nothing about the uploaded data.
+            if !provider.verify(&path, &checksum).map_err(|e| format!(
+                "Unable to verify the upload: {}", e))? {
+                self.handle_corrupted_file(&path);
+                return Err!("Upload verification has failed: checksum mismatch");
+            }
+        }
+
+        Ok((size, checksum))
+    }
+
+    // Returns temporary files left by the uploads in backup groups and backup groups that have been
+    // moved to trash, but haven't been deleted
+    pub fn get_temp_files(&self) -> GenericResult<Vec<TempFile>> {
+        let provider = self.provider.read();
+        let traits = BackupFileTraits::get_for(provider.type_());
+        let mut temp_files = Vec::new();
+
+        let groups = match provider.list_directory(&self.path)? {
+            Some(groups) => groups,
+            None => return Ok(temp_files),
+        };
+
+        for group in groups {
+            if group.type_ != FileType::Directory {
+                continue;
+            }
+
+            if group.name.starts_with(TRASH_PREFIX) {
+                temp_files.push(TempFile {
+                    path: self.get_backup_group_path(&group.name),
+                    size: None,
+                    stale: true,
+                });
+                continue;
+            } else if let Some(name) = group.name.strip_prefix(STAGING_PREFIX) {
+                // .staging.$group_name.$run_id
+                let run_id = name.splitn(4, '.').nth(3);
+                temp_files.push(TempFile {
+                    path: self.get_backup_group_path(&group.name),
+                    size: None,
+                    stale: helpers::is_stale_temp_file(run_id, group.modify_time),
+                });
+                continue;
+            } else if group.name.starts_with('.') {
+                continue;
+            }
+
+            let group_path = self.get_backup_group_path(&group.name);
+            let files = match provider.list_directory(&group_path)? {
+                Some(files) => files,
+                None => continue,
+            };
+
+            for file in files {
+                if file.type_ != traits.type_ {
+                    continue;
+                }
+
+                if let Some(captures) = traits.temp_name_re.captures(&file.name) {
+                    temp_files.push(TempFile {
+                        path: format!("{}/{}", group_path, file.name),
+                        size: file.size,
+                        stale: helpers::is_stale_temp_file(
+                            captures.get(2).map(|m| m.as_str()), file.modify_time),
+                    });
+                }
+            }
+        }
+
+        Ok(temp_files)
     }
 
+    pub fn delete_temp_file(&mut self, path: &str) -> EmptyResult {
+        self.check_deletion(path)?;
+
+        let result = self.provider.write()?.delete(path);
+        self.audit("delete-temp-file", path, None, &result);
+
+        result
+    }
+
+    // Unless configured otherwise, the corrupted file is removed from the backup group: it would be treated
+    // as an uploaded backup otherwise
+    fn handle_corrupted_file(&self, path: &str) {
+        match self.corruption_action {
+            CorruptionAction::Delete => if let Err(err) = self.delete_corrupted_file(path) {
+                error!("Failed to delete corrupted {:?} from {}: {}.", path, self.name(), err);
+            },
+            CorruptionAction::Quarantine => match self.quarantine_corrupted_file(path) {
+                Ok(quarantine_path) => error!(
+                    "Corrupted {:?} has been moved to {:?} on {}.", path, quarantine_path, self.name()),
+                Err(err) => error!(
+                    "Failed to move corrupted {:?} to quarantine on {}: {}.", path, self.name(), err),
+            },
+            CorruptionAction::Warn => warning!(
+                "Corrupted {:?} is left on {} as configured.", path, self.name()),
+        }
+    }
+
+    fn delete_corrupted_file(&self, path: &str) -> EmptyResult {
+        self.check_deletion(path)?;
+
+        let result = self.provider.write()?.delete(path);
+        self.audit("delete-corrupted-file", path, None, &result);
+
+        result
+    }
+
+    fn quarantine_corrupted_file(&self, path: &str) -> GenericResult<String> {
+        let file_name = path.rsplit('/').next().unwrap();
+        let quarantine_path = self.get_backup_group_path(QUARANTINE_NAME);
+        let dst_path = format!("{}/{}.{}", quarantine_path, file_name, helpers::get_run_id());
+
+        let result = self.move_to_quarantine(path, &quarantine_path, &dst_path);
+        self.audit("quarantine-corrupted-file", path, None, &result);
+
+        result.map(|_| dst_path)
+    }
+
+    fn move_to_quarantine(&self, path: &str, quarantine_path: &str, dst_path: &str) -> EmptyResult {
+        let provider = self.provider.write()?;
+        if !provider.supports_rename() {
+            return Err!("{} doesn't support renaming", self.name());
+        }
+
+        // The directory may be created by a concurrent upload at the same time
+        if provider.list_directory(quarantine_path)?.is_none() {
+            if let Err(err) = provider.create_directory(quarantine_path) {
+                if provider.list_directory(quarantine_path)?.is_none() {
+                    return Err(err);
+                }
+            }
+        }
+
+        provider.rename(path, dst_path)
+    }
+
+    // Deletion of a group isn't atomic, so when possible, the group is hidden by renaming at first
+    // to never leave a partially deleted group on interruption. Leftovers are deleted by garbage
+    // collection then.
     pub fn delete_backup_group(&mut self, group_name: &str) -> EmptyResult {
         let group_path = self.get_backup_group_path(group_name);
-        self.provider.write()?.delete(&group_path)
+        self.check_deletion(&group_path)?;
+
+        let result = self.delete_backup_group_path(group_name, &group_path);
+        self.audit("delete-group", &group_path, None, &result);
+
+        result
+    }
+
+    fn delete_backup_group_path(&self, group_name: &str, group_path: &str) -> EmptyResult {
+        let provider = self.provider.write()?;
+
+        if provider.supports_rename() {
+            let trash_path = self.get_backup_group_path(&(TRASH_PREFIX.to_owned() + group_name));
+            self.check_deletion(&trash_path)?;
+
+            match provider.rename(group_path, &trash_path) {
+                Ok(()) => return provider.delete(&trash_path),
+                Err(err) => warning!(
+                    "Failed to move {:?} backup group to trash on {}: {}. Deleting it directly...",
+                    group_name, self.name(), err),
+            }
+        }
+
+        provider.delete(group_path)
+    }
+
+    fn audit<T>(&self, operation: &str, path: &str, size: Option<u64>, result: &GenericResult<T>) {
+        audit::record(operation, self.backup_name.as_deref(), self.name(), path, size, result);
+    }
+
+    fn check_deletion(&self, path: &str) -> EmptyResult {
+        if !self.allowed_delete_prefixes.is_empty() &&
+            !config::is_path_under_prefixes(path, &self.allowed_delete_prefixes) {
+            error!(concat!(
+                "Attention! Blocked an attempt to delete {:?} from {}: ",
+                "it's outside of allowed destination prefixes."), path, self.name());
+            return Err!("{:?} is outside of allowed destination prefixes", path);
+        }
+
+        Ok(())
     }
 
     pub fn get_backup_group_path(&self, group_name: &str) -> String {
@@ -115,19 +635,13 @@ impl Storage {
     }
 
     pub fn get_backup_path(&self, group_name: &str, backup_name: &str) -> String {
-        self.get_backup_group_path(group_name) + "/" + &self.get_backup_file_name(backup_name, false)
+        let file_name = self.get_backup_file_name(backup_name, CompressionOptions::none()).unwrap();
+        self.get_backup_group_path(group_name) + "/" + &file_name
     }
 
-    fn get_backup_file_name(&self, backup_name: &str, temporary: bool) -> String {
-        let extension = BackupFileTraits::get_for(self.provider.read().type_()).extension;
-
-        let prefix = if temporary {
-            "."
-        } else {
-            ""
-        }.to_owned();
-
-        prefix + backup_name + extension
+    fn get_backup_file_name(&self, backup_name: &str, compression: CompressionOptions) -> GenericResult<String> {
+        let traits = BackupFileTraits::get_for(self.provider.read().type_());
+        Ok(backup_name.to_owned() + traits.get_extension(compression.compression)?)
     }
 
     pub fn get_backup_time(&self, backup_name: &str) -> GenericResult<SystemTime> {
@@ -136,10 +650,80 @@ impl Storage {
 
         Ok(SystemTime::from(backup_time))
     }
+
+    // Returns time elapsed since the backup creation or None if it's been created in the future
+    pub fn get_backup_age(&self, backup_name: &str) -> GenericResult<Option<Duration>> {
+        let backup_time = self.get_backup_time(backup_name)?;
+        Ok(self.clock.now().duration_since(backup_time).ok())
+    }
+
+    pub fn get_backup_group_date(&self, group_name: &str) -> GenericResult<NaiveDate> {
+        Ok(NaiveDate::parse_from_str(group_name, "%Y.%m.%d").map_err(|_| format!(
+            "Invalid backup group name: {:?}", group_name))?)
+    }
+}
+
+// Temporary files are namespaced by the run to not clobber temporary files of concurrent uploads to
+// the same destination.
+fn get_temp_file_name(file_name: &str) -> String {
+    format!(".{}.{}", file_name, helpers::get_run_id())
+}
+
+fn read_data(mut data: Box<dyn Read + Send>, mut hasher: Box<dyn Hasher>, tx: DataSender) -> EmptyResult {
+    let mut buf = vec![0; 1024 * 1024];
+
+    loop {
+        let size = match data.read(&mut buf) {
+            Ok(size) => size,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                let error = format!("Failed to read the backup: {}", err);
+                let _ = tx.send(Err(error.clone()));
+                return Err(error.into());
+            },
+        };
+
+        if size == 0 {
+            let _ = tx.send(Ok(Data::EofWithChecksum(hasher.finish())));
+            return Ok(());
+        }
+
+        hasher.write_all(&buf[..size]).map_err(|e| format!("Unable to hash the data: {}", e))?;
+
+        tx.send(Ok(Data::Payload(Bytes::copy_from_slice(&buf[..size])))).map_err(|_|
+            "Unable to send the data: the receiver has been closed".to_owned())?;
+    }
 }
 
-fn archive_backup(backup_name: &str, backup_path: &str, encryptor: Encryptor) -> EmptyResult {
-    let mut archive = tar::Builder::new(encryptor);
+#[allow(dead_code)]
+fn copy_data<R: Read>(mut data: R, mut compressor: Compressor) -> EmptyResult {
+    if let Err(err) = io::copy(&mut data, &mut compressor) {
+        return Err(compressor.finish(Some(err.to_string())).unwrap_err());
+    }
+
+    compressor.finish(None)
+}
+
+// Estimates the upload size: the backup files are already compressed, so the archive has about the same size
+fn get_directory_size(path: &Path) -> Option<u64> {
+    let mut size = 0;
+
+    for entry in fs::read_dir(path).ok()? {
+        let entry = entry.ok()?;
+        let metadata = entry.metadata().ok()?;
+
+        size += if metadata.is_dir() {
+            get_directory_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+
+    Some(size)
+}
+
+fn archive_backup(backup_name: &str, backup_path: &str, compressor: Compressor) -> EmptyResult {
+    let mut archive = tar::Builder::new(compressor);
 
     if let Err(err) = archive.append_dir_all(backup_name, backup_path) {
         let _ = archive.finish();
@@ -151,4 +735,442 @@ fn archive_backup(backup_name: &str, backup_path: &str, encryptor: Encryptor) ->
     }
 
     archive.into_inner().unwrap().finish(None)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::GpgRlimits;
+    use crate::encryptor::Encryption;
+    use crate::provider::ProviderType;
+    use crate::providers::mock::MockProvider;
+    use crate::restore;
+    use crate::tests::{TempDir, get_backup_metadata};
+
+    use super::*;
+
+    const GROUP_NAME: &str = "2020.01.01";
+    const BACKUP_PATH: &str = "/dst/2020.01.01/2020.01.01-00:00:00.tar.gpg";
+
+    // Passes a backup through the whole pipeline: archiving, encryption, splitting into chunk streams,
+    // upload, download, integrity check and decryption
+    #[test]
+    fn upload_and_restore() {
+        let temp_dir = TempDir::new("upload_and_restore");
+        let backup_path = temp_dir.0.join("src/2020.01.01-00:00:00");
+        let restore_path = temp_dir.0.join("restore");
+        fs::create_dir_all(&backup_path).unwrap();
+        fs::create_dir(&restore_path).unwrap();
+
+        // Incompressible data, so its encrypted version is split into several chunk streams
+        let mut state: u32 = 1;
+        let data: Vec<u8> = (0..3 * 1024 * 1024 + 1000).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect();
+
+        let metadata = get_backup_metadata();
+        fs::write(backup_path.join("metadata.bz2"), &metadata).unwrap();
+        fs::write(backup_path.join("data.tar.gz"), &data).unwrap();
+
+        let max_request_size = 1024 * 1024;
+        let provider = MockProvider::new(ProviderType::Cloud);
+        provider.add_directory("/dst");
+        provider.add_directory(&format!("/dst/{}", GROUP_NAME));
+        provider.set_max_request_size(max_request_size);
+
+        let rlimits = GpgRlimits::default();
+        let encryption = EncryptionOptions {
+            encryption: Encryption::Passphrase("secret"),
+            cipher: None,
+            homedir: None,
+            rlimits: &rlimits,
+        };
+
+        let storage = Storage::new(provider.clone(), "/dst").with_integrity_key(Some("secret"));
+        storage.upload_backup(
+            backup_path.to_str().unwrap(), GROUP_NAME, "2020.01.01-00:00:00", encryption,
+            CompressionOptions::none()).unwrap();
+
+        let uploaded_size = provider.list_directory(&format!("/dst/{}", GROUP_NAME)).unwrap().unwrap()
+            .iter().find(|file| file.name == "2020.01.01-00:00:00.tar.gpg").unwrap().size.unwrap();
+        assert!(uploaded_size > 3 * max_request_size);
+
+        restore::restore_backup(
+            &storage, GROUP_NAME, "2020.01.01-00:00:00", encryption, restore_path.to_str().unwrap()).unwrap();
+
+        let archive = fs::File::open(restore_path.join("2020.01.01-00:00:00.tar")).unwrap();
+        let mut files = Vec::new();
+
+        for entry in tar::Archive::new(archive).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents).unwrap();
+            files.push((entry.path().unwrap().to_str().unwrap().to_owned(), contents));
+        }
+        files.sort();
+
+        assert_eq!(files, vec![
+            ("2020.01.01-00:00:00/".to_owned(), Vec::new()),
+            ("2020.01.01-00:00:00/data.tar.gz".to_owned(), data),
+            ("2020.01.01-00:00:00/metadata.bz2".to_owned(), metadata),
+        ]);
+    }
+
+    #[test]
+    fn compressed_upload() {
+        let temp_dir = TempDir::new("compressed_upload");
+        let backup_path = temp_dir.0.join("src/2020.01.01-00:00:00");
+        let restore_path = temp_dir.0.join("restore");
+        fs::create_dir_all(&backup_path).unwrap();
+        fs::create_dir(&restore_path).unwrap();
+
+        let data = "Uncompressed data\n".repeat(100_000).into_bytes();
+        fs::write(backup_path.join("metadata.bz2"), get_backup_metadata()).unwrap();
+        fs::write(backup_path.join("data.tar"), &data).unwrap();
+
+        let provider = MockProvider::new(ProviderType::Cloud);
+        provider.add_directory("/dst");
+        provider.add_directory(&format!("/dst/{}", GROUP_NAME));
+
+        let rlimits = GpgRlimits::default();
+        let encryption = EncryptionOptions {
+            encryption: Encryption::Passphrase("secret"),
+            cipher: None,
+            homedir: None,
+            rlimits: &rlimits,
+        };
+        let compression = CompressionOptions {
+            compression: config::Compression::Gzip,
+            level: Some(9),
+        };
+
+        let storage = Storage::new(provider.clone(), "/dst");
+        storage.upload_backup(
+            backup_path.to_str().unwrap(), GROUP_NAME, "2020.01.01-00:00:00", encryption, compression).unwrap();
+
+        let files = provider.list_directory(&format!("/dst/{}", GROUP_NAME)).unwrap().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "2020.01.01-00:00:00.tar.gz.gpg");
+        assert!(files[0].size.unwrap() < data.len() as u64 / 10);
+
+        let (groups, ok) = storage.get_backup_groups(false).unwrap();
+        assert!(ok);
+        assert_eq!(groups[0].backups.iter().map(|backup| backup.name.as_str()).collect::<Vec<_>>(),
+                   vec!["2020.01.01-00:00:00"]);
+
+        // The restored archive keeps its compression
+        restore::restore_backup(
+            &storage, GROUP_NAME, "2020.01.01-00:00:00", encryption, restore_path.to_str().unwrap()).unwrap();
+
+        let archive = fs::File::open(restore_path.join("2020.01.01-00:00:00.tar.gz")).unwrap();
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(archive));
+
+        let mut entry = archive.entries().unwrap().map(Result::unwrap).find(|entry| {
+            entry.path().unwrap().to_str() == Some("2020.01.01-00:00:00/data.tar")
+        }).unwrap();
+
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents).unwrap();
+        assert!(contents == data);
+    }
+
+    #[test]
+    fn stream_upload() {
+        let provider = MockProvider::new(ProviderType::Cloud);
+        provider.add_directory("/dst");
+        provider.add_directory(&format!("/dst/{}", GROUP_NAME));
+
+        let rlimits = GpgRlimits::default();
+        let encryption = EncryptionOptions {
+            encryption: Encryption::Passphrase("secret"),
+            cipher: None,
+            homedir: None,
+            rlimits: &rlimits,
+        };
+
+        let data: Vec<u8> = (0..1024 * 1024).map(|index| (index % 251) as u8).collect();
+        let mut storage = Storage::new(provider.clone(), "/dst");
+        let checksum = storage.upload_stream(
+            io::Cursor::new(data.clone()), GROUP_NAME, "2020.01.01-00:00:00", encryption).unwrap();
+
+        let mut hasher = provider.hasher();
+        io::copy(&mut provider.open_file(BACKUP_PATH).unwrap(), &mut hasher).unwrap();
+        assert_eq!(hasher.finish(), checksum);
+
+        let temp_dir = TempDir::new("stream_upload");
+        restore::restore_backup(
+            &storage, GROUP_NAME, "2020.01.01-00:00:00", encryption, temp_dir.0.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(temp_dir.0.join("2020.01.01-00:00:00.tar")).unwrap(), data);
+    }
+
+    #[test]
+    fn upload_verification() {
+        let (dst_provider, result) = copy_backup(&[], |_| {});
+        result.unwrap();
+        assert!(dst_provider.exists(BACKUP_PATH));
+    }
+
+    #[test]
+    fn upload_verification_checksum_mismatch() {
+        let (dst_provider, result) = copy_backup(&[], |provider| provider.set_checksum("invalid"));
+        assert!(result.unwrap_err().to_string().contains("checksum mismatch"));
+        assert!(!dst_provider.exists(BACKUP_PATH));
+        assert_eq!(dst_provider.modifications().last().unwrap(), &format!("delete {}", BACKUP_PATH));
+    }
+
+    #[test]
+    fn upload_verification_quarantine() {
+        let (dst_provider, result) = copy_backup_from(ProviderType::Cloud, |_| {}, |storage| {
+            storage.with_corruption_action(CorruptionAction::Quarantine)
+        }, |provider| {
+            provider.enable_rename();
+            provider.set_checksum("invalid");
+        });
+        assert!(result.unwrap_err().to_string().contains("checksum mismatch"));
+        assert!(!dst_provider.exists(BACKUP_PATH));
+
+        let quarantine_path = format!("/dst/.quarantine/2020.01.01-00:00:00.tar.gpg.{}", helpers::get_run_id());
+        assert!(dst_provider.exists(&quarantine_path));
+
+        let storage = Storage::new(dst_provider, "/dst");
+        let (groups, ok) = storage.get_backup_groups(false).unwrap();
+        assert!(ok);
+        assert_eq!(groups.len(), 1);
+        assert!(groups[0].backups.is_empty());
+        assert!(storage.get_temp_files().unwrap().is_empty());
+    }
+
+    #[test]
+    fn upload_verification_warn() {
+        let (dst_provider, result) = copy_backup_from(ProviderType::Cloud, |_| {}, |storage| {
+            storage.with_corruption_action(CorruptionAction::Warn)
+        }, |provider| provider.set_checksum("invalid"));
+        assert!(result.unwrap_err().to_string().contains("checksum mismatch"));
+        assert!(dst_provider.exists(BACKUP_PATH));
+    }
+
+    #[test]
+    fn upload_verification_error() {
+        let (dst_provider, result) = copy_backup(&[], |provider| provider.set_checksum_error("Network error"));
+        assert!(result.unwrap_err().to_string().contains("Network error"));
+        assert!(dst_provider.exists(BACKUP_PATH));
+    }
+
+    #[test]
+    fn copy_source_checksum_mismatch() {
+        let (dst_provider, result) = copy_backup_from(ProviderType::Cloud, |provider| {
+            provider.set_checksum("invalid");
+        }, |storage| storage, |_| {});
+        assert!(result.unwrap_err().to_string().contains("Checksum mismatch"));
+        assert!(!dst_provider.exists(BACKUP_PATH));
+    }
+
+    #[test]
+    fn copy_between_different_storages() {
+        let (dst_provider, result) = copy_backup_from(ProviderType::Local, |_| {}, |storage| storage, |_| {});
+        assert!(result.unwrap_err().to_string().contains("different backup formats"));
+        assert_eq!(dst_provider.modifications(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn upload_verification_delete_fence() {
+        let (dst_provider, result) = copy_backup(&["/other".to_owned()], |provider| {
+            provider.set_checksum("invalid");
+        });
+        assert!(result.is_err());
+        assert!(dst_provider.exists(BACKUP_PATH));
+    }
+
+    #[test]
+    fn integrity_tampering() {
+        let (provider, result) = copy_backup_from(ProviderType::Cloud, |_| {}, |storage| {
+            storage.with_integrity_key(Some("secret"))
+        }, |_| {});
+        result.unwrap();
+
+        let hmac_path = "/dst/2020.01.01/.2020.01.01-00:00:00.hmac";
+        assert!(provider.exists(hmac_path));
+
+        let storage = Storage::new(provider.clone(), "/dst");
+        let protected_storage = Storage::new(provider.clone(), "/dst").with_integrity_key(Some("secret"));
+
+        let (groups, ok) = protected_storage.get_backup_groups(false).unwrap();
+        assert!(ok);
+        assert_eq!(groups[0].backups.len(), 1);
+        assert!(protected_storage.get_temp_files().unwrap().is_empty());
+        assert!(!protected_storage.verify_backup(BACKUP_PATH).unwrap());
+
+        // An attacker is able to replace the data and its server-side checksum, but not the HMAC
+        provider.add_file(BACKUP_PATH, b"tampered data");
+        let mut hasher = provider.hasher();
+        hasher.write_all(b"tampered data").unwrap();
+        provider.set_checksum(&hasher.finish());
+
+        assert!(storage.verify_backup(BACKUP_PATH).unwrap());
+        assert!(protected_storage.verify_backup(BACKUP_PATH).unwrap_err().to_string().contains(
+            "HMAC mismatch"));
+
+        let (_, mut data) = protected_storage.open_backup(GROUP_NAME, "2020.01.01-00:00:00").unwrap();
+        assert!(data.read_to_end(&mut Vec::new()).unwrap_err().to_string().contains("HMAC mismatch"));
+
+        // Deletion of the HMAC doesn't help either
+        provider.delete(hmac_path).unwrap();
+        assert!(protected_storage.verify_backup(BACKUP_PATH).unwrap_err().to_string().contains(
+            "Unable to read integrity metadata"));
+    }
+
+    #[test]
+    fn list_concurrency() {
+        let provider = MockProvider::new(ProviderType::Cloud);
+        provider.add_directory("/dst");
+
+        let group_names: Vec<_> = (1..=8).map(|day| format!("2020.01.{:02}", day)).collect();
+        for group_name in &group_names {
+            provider.add_directory(&format!("/dst/{}", group_name));
+            for time in &["00:00:00", "12:00:00"] {
+                provider.add_file(&format!("/dst/{0}/{0}-{1}.tar.gpg", group_name, time), b"data");
+            }
+        }
+
+        // Groups are listed in the same order regardless of the concurrency
+        for &concurrency in &[1, 4] {
+            let storage = Storage::new(provider.with_type(ProviderType::Cloud), "/dst")
+                .with_list_concurrency(concurrency);
+
+            let (groups, ok) = storage.get_backup_groups(false).unwrap();
+            assert!(ok);
+
+            assert_eq!(groups.iter().map(|group| group.name.clone()).collect::<Vec<_>>(), group_names);
+            for group in &groups {
+                assert_eq!(group.backups.iter().map(|backup| backup.name.clone()).collect::<Vec<_>>(), vec![
+                    format!("{}-00:00:00", group.name), format!("{}-12:00:00", group.name)]);
+            }
+        }
+    }
+
+    #[test]
+    fn group_deletion() {
+        let provider = MockProvider::new(ProviderType::Cloud);
+        provider.add_directory("/dst");
+        for group_name in &["2020.01.01", "2020.01.02", "2020.01.03"] {
+            provider.add_directory(&format!("/dst/{}", group_name));
+            provider.add_file(&format!("/dst/{0}/{0}-00:00:00.tar.gpg", group_name), b"data");
+        }
+
+        // Without rename support the group is deleted directly
+        let mut storage = Storage::new(provider.clone(), "/dst");
+        storage.delete_backup_group("2020.01.01").unwrap();
+        assert_eq!(provider.modifications(), vec!["delete /dst/2020.01.01".to_owned()]);
+
+        // The group is moved to trash at first, so it's never seen partially deleted
+        provider.enable_rename();
+        storage.delete_backup_group("2020.01.02").unwrap();
+
+        // A leftover of the previous deletion blocks the move to trash
+        provider.add_directory("/dst/.trash.2020.01.03");
+        storage.delete_backup_group("2020.01.03").unwrap();
+
+        assert_eq!(provider.modifications()[1..].to_vec(), vec![
+            "rename /dst/2020.01.02 /dst/.trash.2020.01.02",
+            "delete /dst/.trash.2020.01.02",
+            "rename /dst/2020.01.03 /dst/.trash.2020.01.03",
+            "delete /dst/2020.01.03",
+        ]);
+
+        for group_name in &["2020.01.01", "2020.01.02", "2020.01.03", ".trash.2020.01.02"] {
+            assert!(!provider.exists(&format!("/dst/{}", group_name)));
+        }
+    }
+
+    #[test]
+    fn temp_files() {
+        let provider = MockProvider::new(ProviderType::Cloud);
+        provider.add_directory("/dst");
+        provider.add_directory("/dst/2020.01.01");
+
+        let run_id = helpers::get_run_id();
+        let hostname = &run_id[..run_id.rfind('-').unwrap()];
+
+        // A process which has definitely finished
+        let mut process = std::process::Command::new("true").spawn().unwrap();
+        let finished_pid = process.id();
+        process.wait().unwrap();
+
+        let old = SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60);
+        let recent = SystemTime::now() - Duration::from_secs(60);
+
+        let temp_files = [
+            (format!(".2020.01.01-00:00:00.tar.gpg.{}", run_id), None, false),
+            (format!(".2020.01.01-00:00:01.tar.gpg.{}-{}", hostname, finished_pid), Some(recent), true),
+            (".2020.01.01-00:00:02.tar.gpg.other-host-1".to_owned(), Some(recent), false),
+            (".2020.01.01-00:00:03.tar.gpg.other-host-1".to_owned(), Some(old), true),
+            (".2020.01.01-00:00:04.tar.gpg".to_owned(), None, false),
+            (".2020.01.01-00:00:05.tar.gpg".to_owned(), Some(old), true),
+        ];
+
+        for (name, modify_time, _) in &temp_files {
+            let path = format!("/dst/2020.01.01/{}", name);
+            provider.add_file(&path, b"data");
+            if let Some(time) = modify_time {
+                provider.set_modify_time(&path, *time);
+            }
+        }
+
+        provider.add_file("/dst/2020.01.01/2020.01.01-00:00:00.tar.gpg", b"data");
+        provider.add_directory(&format!("/dst/.staging.2020.01.02.{}", run_id));
+        provider.add_directory("/dst/.trash.2020.01.03");
+
+        let storage = Storage::new(provider, "/dst");
+        let mut actual: Vec<_> = storage.get_temp_files().unwrap().into_iter()
+            .map(|file| (file.path, file.stale)).collect();
+        actual.sort();
+
+        let mut expected: Vec<_> = temp_files.iter()
+            .map(|(name, _, stale)| (format!("/dst/2020.01.01/{}", name), *stale))
+            .chain(vec![
+                (format!("/dst/.staging.2020.01.02.{}", run_id), false),
+                ("/dst/.trash.2020.01.03".to_owned(), true),
+            ])
+            .collect();
+        expected.sort();
+
+        assert_eq!(actual, expected);
+        assert!(get_temp_file_name("2020.01.01-00:00:00.tar.gpg").ends_with(&format!(".{}", run_id)));
+    }
+
+    fn copy_backup<F: Fn(&MockProvider)>(
+        allowed_delete_prefixes: &[String], configure: F,
+    ) -> (MockProvider, EmptyResult) {
+        copy_backup_from(ProviderType::Cloud, |_| {}, |storage| {
+            storage.with_delete_fence(allowed_delete_prefixes)
+        }, configure)
+    }
+
+    fn copy_backup_from<S, T, F>(
+        src_type: ProviderType, configure_src: S, configure_storage: T, configure: F,
+    ) -> (MockProvider, EmptyResult)
+        where S: Fn(&MockProvider), T: FnOnce(Storage) -> Storage, F: Fn(&MockProvider)
+    {
+        let src_provider = MockProvider::new(ProviderType::Cloud);
+        src_provider.add_directory("/src");
+        src_provider.add_directory(&format!("/src/{}", GROUP_NAME));
+        src_provider.add_file(&format!("/src/{}/{}-00:00:00.tar.gpg", GROUP_NAME, GROUP_NAME), b"backup data");
+        configure_src(&src_provider);
+
+        let (groups, ok) = Storage::new(src_provider.clone(), "/src").get_backup_groups(false).unwrap();
+        assert!(ok);
+
+        let src_storage = Storage::new(src_provider.with_type(src_type), "/src");
+
+        let dst_provider = MockProvider::new(ProviderType::Cloud);
+        dst_provider.add_directory("/dst");
+        dst_provider.add_directory(&format!("/dst/{}", GROUP_NAME));
+        configure(&dst_provider);
+
+        let mut dst_storage = configure_storage(
+            Storage::new(dst_provider.clone(), "/dst").with_upload_verification(true));
+        let result = dst_storage.copy_backup(&src_storage, GROUP_NAME, &groups[0].backups[0]);
+
+        (dst_provider, result)
+    }
+}
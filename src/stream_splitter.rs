@@ -5,7 +5,10 @@ use std::thread::JoinHandle;
 
 use bytes::Bytes;
 
-use crate::core::{EmptyResult, GenericResult};
+use crate::bandwidth;
+use crate::core::{GenericResult, GenericError};
+use crate::pause;
+use crate::progress::Progress;
 use crate::util;
 
 pub enum Data {
@@ -27,21 +30,35 @@ pub type ChunkStreamReceiver = mpsc::Receiver<Result<ChunkStream, String>>;
 pub type ChunkReceiver = mpsc::Receiver<ChunkResult>;
 pub type ChunkResult = Result<Bytes, String>;
 
-pub fn split(data_stream: DataReceiver, stream_max_size: Option<u64>)
-    -> GenericResult<(ChunkStreamReceiver, JoinHandle<EmptyResult>)>
+// Returns the data size and checksum if the stream has been successfully terminated
+pub type SplitterThread = JoinHandle<GenericResult<Option<(u64, String)>>>;
+
+// Splits the data stream into chunk streams
+pub fn split(data_stream: DataReceiver, stream_max_size: Option<u64>, progress: Option<Progress>)
+    -> GenericResult<(ChunkStreamReceiver, SplitterThread)>
 {
     let (streams_tx, streams_rx) = mpsc::sync_channel(0);
 
     let splitter_thread = util::spawn_thread("stream splitter", move || {
-        Ok(splitter(data_stream, streams_tx, stream_max_size)?)
+        Ok(splitter(data_stream, streams_tx, stream_max_size, progress)?)
     })?;
 
     Ok((streams_rx, splitter_thread))
 }
 
-fn splitter(data_stream: DataReceiver, chunk_streams: ChunkStreamSender,
-            stream_max_size: Option<u64>) -> Result<(), StreamSplitterError> {
-    let mut chunk_stream = None;
+// When chunk stream upload fails because of an upstream error, the upload request gets only a vague
+// I/O error, but the original error is delivered right after it, so prefer it if it's there.
+pub fn get_upload_error<E: Into<GenericError>>(chunk_streams: &ChunkStreamReceiver, error: E) -> GenericError {
+    match chunk_streams.recv() {
+        Ok(Err(err)) => err.into(),
+        _ => error.into(),
+    }
+}
+
+fn splitter(data_stream: DataReceiver, chunk_streams: ChunkStreamSender, stream_max_size: Option<u64>,
+            mut progress: Option<Progress>) -> Result<Option<(u64, String)>, StreamSplitterError> {
+    let mut result = None;
+    let mut chunk_stream: Option<mpsc::SyncSender<ChunkResult>> = None;
     let mut stream_size: u64 = 0;
     let mut offset: u64 = 0;
 
@@ -53,14 +70,23 @@ fn splitter(data_stream: DataReceiver, chunk_streams: ChunkStreamSender,
         };
 
         let mut data = match message {
-            Ok(Data::Payload(data)) => data,
+            Ok(Data::Payload(data)) => {
+                bandwidth::throttle(data.len());
+                data
+            },
             Ok(Data::EofWithChecksum(checksum)) => {
                 chunk_stream.take();
-                chunk_streams.send(Ok(ChunkStream::EofWithCheckSum(offset, checksum)))?;
+                chunk_streams.send(Ok(ChunkStream::EofWithCheckSum(offset, checksum.clone())))?;
+                result.replace((offset, checksum));
                 break;
             },
             Err(err) => {
-                chunk_stream.take();
+                // Fail the request that is uploading the current chunk stream instead of letting it
+                // to successfully complete with truncated data.
+                if let Some(chunk_stream) = chunk_stream.take() {
+                    let _ = chunk_stream.send(Err(err.clone()));
+                }
+
                 chunk_streams.send(Err(err))?;
                 break;
             }
@@ -73,6 +99,7 @@ fn splitter(data_stream: DataReceiver, chunk_streams: ChunkStreamSender,
             }
 
             if chunk_stream.is_none() {
+                pause::wait_if_paused();
                 let (tx, rx) = mpsc::sync_channel(0);
 
                 chunk_stream = Some(tx);
@@ -90,6 +117,9 @@ fn splitter(data_stream: DataReceiver, chunk_streams: ChunkStreamSender,
                 chunk_stream.as_mut().unwrap().send(Ok(data))?;
                 stream_size += data_size;
                 offset += data_size;
+                if let Some(progress) = progress.as_mut() {
+                    progress.add(data_size);
+                }
                 break;
             }
 
@@ -97,6 +127,9 @@ fn splitter(data_stream: DataReceiver, chunk_streams: ChunkStreamSender,
                 chunk_stream.take().unwrap().send(Ok(data.split_to(available_size as usize)))?;
                 stream_size += available_size;
                 offset += available_size;
+                if let Some(progress) = progress.as_mut() {
+                    progress.add(available_size);
+                }
             } else {
                 chunk_stream.take();
             }
@@ -107,7 +140,7 @@ fn splitter(data_stream: DataReceiver, chunk_streams: ChunkStreamSender,
         return Err(StreamSplitterError("Got a message after a termination message"))
     }
 
-    Ok(())
+    Ok(result)
 }
 
 #[derive(Debug)]
@@ -129,4 +162,46 @@ impl<T> From<mpsc::SendError<T>> for StreamSplitterError {
     fn from(_err: mpsc::SendError<T>) -> StreamSplitterError {
         StreamSplitterError("Unable to send a new stream: the receiver has been closed")
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn partial_stream_failure() {
+        let (data_tx, data_rx) = mpsc::sync_channel(0);
+        let (chunk_streams, splitter) = split(data_rx, Some(10), None).unwrap();
+
+        let sender = thread::spawn(move || {
+            data_tx.send(Ok(Data::Payload(Bytes::from_static(b"0123456789abc")))).unwrap();
+            data_tx.send(Err("Archive error".to_owned())).unwrap();
+        });
+
+        let receive_stream = |expected_offset| match chunk_streams.recv().unwrap() {
+            Ok(ChunkStream::Stream(offset, chunk_stream)) => {
+                assert_eq!(offset, expected_offset);
+                chunk_stream.iter().collect::<Vec<_>>()
+            },
+            _ => panic!("Got an unexpected chunk stream message"),
+        };
+
+        assert_eq!(receive_stream(0), vec![Ok(Bytes::from_static(b"0123456789"))]);
+
+        // The request uploading the current chunk stream must fail instead of completing with
+        // truncated data
+        assert_eq!(receive_stream(10), vec![Ok(Bytes::from_static(b"abc")), Err("Archive error".to_owned())]);
+
+        // And the upload gets the original error instead of the vague one
+        let error = get_upload_error(&chunk_streams, "Broken pipe");
+        assert_eq!(error.to_string(), "Archive error");
+
+        sender.join().unwrap();
+        assert!(splitter.join().unwrap().unwrap().is_none());
+
+        let error = get_upload_error(&chunk_streams, "Broken pipe");
+        assert_eq!(error.to_string(), "Broken pipe");
+    }
+}
@@ -1,38 +1,128 @@
 use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
+use chrono::{Datelike, NaiveDate};
+use rayon::prelude::*;
+use regex::Regex;
+
+use crate::config::{Backup, PruneOrder, RetentionPolicy};
 use crate::core::EmptyResult;
+use crate::gc;
 use crate::storage::{Storage, BackupGroup};
 
 pub fn sync_backups(local_storage: &Storage, local_groups: &[BackupGroup],
                     cloud_storage: &mut Storage, cloud_groups: &[BackupGroup],
-                    mut ok: bool, max_backup_groups: usize, encryption_passphrase: &str) -> bool {
-    if cfg!(debug_assertions) {
-        error!("Attention! Running in develop mode.");
-        ok = false;
-    };
+                    mut ok: bool, backup_config: &Backup, fail_fast: bool,
+                    confirm_large_prune: bool, dry_run: bool) -> bool {
+    if dry_run {
+        info!("Running in dry run mode: {} won't be modified.", cloud_storage.name());
+    } else if backup_config.cleanup_temp_files {
+        // Temporary files of the uploads that may still be in progress are never deleted
+        if let Err(err) = gc::collect_garbage(cloud_storage, false) {
+            warning!("Temporary files cleanup has failed: {}.", err);
+        }
+    }
 
     if let Err(err) = check_backup_groups(local_groups, cloud_groups) {
         error!("{}.", err);
         ok = false;
     }
 
-    let target_groups = get_target_backup_groups(local_groups, cloud_groups, max_backup_groups);
+    let target_groups = get_target_backup_groups(local_storage, local_groups, cloud_groups, backup_config);
     let cloud_groups = get_group_to_backups_mapping(cloud_groups);
+
+    match backup_config.prune_order {
+        PruneOrder::After => {
+            ok &= upload_backups(
+                local_storage, cloud_storage, &cloud_groups, &target_groups, backup_config, fail_fast,
+                dry_run);
+            ok = prune_backup_groups(
+                cloud_storage, &cloud_groups, &target_groups, ok, backup_config, confirm_large_prune,
+                dry_run);
+        },
+        PruneOrder::Before => {
+            let kept_groups = get_kept_backup_groups(&cloud_groups, &target_groups);
+            ok = prune_backup_groups(
+                cloud_storage, &cloud_groups, &kept_groups, ok, backup_config, confirm_large_prune,
+                dry_run);
+
+            if !ok && fail_fast {
+                return ok;
+            }
+
+            ok &= upload_backups(
+                local_storage, cloud_storage, &cloud_groups, &target_groups, backup_config, fail_fast,
+                dry_run);
+        },
+    }
+
+    ok
+}
+
+fn upload_backups(
+    local_storage: &Storage, cloud_storage: &mut Storage, cloud_groups: &BTreeMap<&str, BTreeSet<&str>>,
+    target_groups: &BTreeMap<&str, BTreeSet<&str>>, backup_config: &Backup, fail_fast: bool,
+    dry_run: bool,
+) -> bool {
+    let mut ok = true;
     let no_backups = BTreeSet::new();
 
+    let staged_groups = backup_config.staged_groups && cloud_storage.supports_rename();
+    if backup_config.staged_groups && !staged_groups {
+        warning!("{} doesn't support renaming: new backup groups will be uploaded in place.",
+              cloud_storage.name());
+    }
+
+    // Backups are uploaded sequentially along with creation of their groups unless concurrent uploads
+    // are enabled. In this case the groups are created first and then all their backups are uploaded
+    // by the pool.
+    let pool = if backup_config.upload_concurrency > 1 && !dry_run {
+        match rayon::ThreadPoolBuilder::new().num_threads(backup_config.upload_concurrency).build() {
+            Ok(pool) => Some(pool),
+            Err(err) => {
+                error!("Unable to create upload thread pool: {}.", err);
+                return false;
+            },
+        }
+    } else {
+        None
+    };
+    let mut pending_uploads = Vec::new();
+
     for (&group_name, target_backups) in target_groups.iter() {
         if target_backups.is_empty() {
             continue;
         }
 
+        if staged_groups && !dry_run && !cloud_groups.contains_key(group_name) {
+            ok &= upload_staged_backup_group(
+                local_storage, cloud_storage, group_name, target_backups, backup_config);
+
+            if !ok && fail_fast {
+                return ok;
+            }
+
+            continue;
+        }
+
         let cloud_backups = match cloud_groups.get(group_name) {
             Some(backups) => backups,
+            None if dry_run => {
+                info!("Would create {:?} backup group on {}.", group_name, cloud_storage.name());
+                &no_backups
+            },
             None => {
                 info!("Creating {:?} backup group on {}...", group_name, cloud_storage.name());
 
                 if let Err(err) = cloud_storage.create_backup_group(group_name) {
                     error!("Failed to create {:?} backup group on {}: {}.",
                            group_name, cloud_storage.name(), err);
+
+                    if fail_fast {
+                        return false;
+                    }
+
                     ok = false;
                     continue;
                 }
@@ -46,30 +136,192 @@ pub fn sync_backups(local_storage: &Storage, local_groups: &[BackupGroup],
                 continue;
             }
 
-            let backup_path = local_storage.get_backup_path(group_name, backup_name);
-            info!("Uploading {:?} backup to {}...", backup_path, cloud_storage.name());
+            if dry_run {
+                let backup_path = local_storage.get_backup_path(group_name, backup_name);
+                info!("Would upload {:?} backup to {}.", backup_path, cloud_storage.name());
+                continue;
+            }
+
+            if pool.is_some() {
+                pending_uploads.push((group_name, backup_name));
+                continue;
+            }
+
+            if !upload_backup(local_storage, cloud_storage, group_name, backup_name, backup_config) {
+                if fail_fast {
+                    return false;
+                }
 
-            if let Err(err) = cloud_storage.upload_backup(
-                &backup_path, group_name, backup_name, encryption_passphrase
-            ) {
-                error!("Failed to upload {:?} backup to {}: {}.",
-                       backup_path, cloud_storage.name(), err);
                 ok = false;
             }
         }
     }
 
+    if let Some(pool) = pool {
+        ok &= upload_backups_concurrently(
+            &pool, local_storage, cloud_storage, &pending_uploads, backup_config, fail_fast);
+    }
+
+    ok
+}
+
+// A failed upload doesn't abort the others. In fail fast mode only the uploads that haven't been started
+// yet are skipped.
+fn upload_backups_concurrently(
+    pool: &rayon::ThreadPool, local_storage: &Storage, cloud_storage: &Storage,
+    uploads: &[(&str, &str)], backup_config: &Backup, fail_fast: bool,
+) -> bool {
+    let failed = AtomicBool::new(false);
+
+    pool.install(|| uploads.par_iter().for_each(|&(group_name, backup_name)| {
+        if fail_fast && failed.load(Ordering::SeqCst) {
+            return;
+        }
+
+        if !upload_backup(local_storage, cloud_storage, group_name, backup_name, backup_config) {
+            failed.store(true, Ordering::SeqCst);
+        }
+    }));
+
+    !failed.into_inner()
+}
+
+fn upload_backup(
+    local_storage: &Storage, cloud_storage: &Storage, group_name: &str, backup_name: &str,
+    backup_config: &Backup,
+) -> bool {
+    let backup_path = local_storage.get_backup_path(group_name, backup_name);
+    info!("Uploading {:?} backup to {}...", backup_path, cloud_storage.name());
+
+    if let Err(err) = cloud_storage.upload_backup(
+        &backup_path, group_name, backup_name,
+        backup_config.encryption_options(), backup_config.compression_options(),
+    ) {
+        error!("Failed to upload {:?} backup to {}: {}.", backup_path, cloud_storage.name(), err);
+        return false;
+    }
+
+    true
+}
+
+// Uploads a new backup group into a hidden staging group, verifies it and only then atomically makes it
+// visible, so the cloud never has a partially uploaded group.
+fn upload_staged_backup_group(
+    local_storage: &Storage, cloud_storage: &mut Storage, group_name: &str,
+    backups: &BTreeSet<&str>, backup_config: &Backup,
+) -> bool {
+    let staging_group_name = cloud_storage.get_staging_group_name(group_name);
+    info!("Creating {:?} staging backup group on {}...", staging_group_name, cloud_storage.name());
+
+    if let Err(err) = cloud_storage.create_backup_group(&staging_group_name) {
+        error!("Failed to create {:?} staging backup group on {}: {}.",
+               staging_group_name, cloud_storage.name(), err);
+        return false;
+    }
+
+    let result = upload_staging_backup_group(
+        local_storage, cloud_storage, group_name, &staging_group_name, backups, backup_config);
+
+    if let Err(err) = result {
+        error!("Failed to upload {:?} backup group to {}: {}.", group_name, cloud_storage.name(), err);
+
+        info!("Deleting {:?} staging backup group from {}...", staging_group_name, cloud_storage.name());
+        if let Err(err) = cloud_storage.delete_backup_group(&staging_group_name) {
+            error!("Failed to delete {:?} staging backup group from {}: {}.",
+                   staging_group_name, cloud_storage.name(), err)
+        }
+
+        return false;
+    }
+
+    true
+}
+
+fn upload_staging_backup_group(
+    local_storage: &Storage, cloud_storage: &mut Storage, group_name: &str, staging_group_name: &str,
+    backups: &BTreeSet<&str>, backup_config: &Backup,
+) -> EmptyResult {
+    for &backup_name in backups {
+        let backup_path = local_storage.get_backup_path(group_name, backup_name);
+        info!("Uploading {:?} backup to {}...", backup_path, cloud_storage.name());
+
+        cloud_storage.upload_backup(
+            &backup_path, staging_group_name, backup_name,
+            backup_config.encryption_options(), backup_config.compression_options(),
+        ).map_err(|e| format!("Failed to upload {:?} backup: {}", backup_path, e))?;
+    }
+
+    let uploaded_backups = cloud_storage.get_backup_names(staging_group_name).map_err(|e| format!(
+        "Failed to verify the uploaded backups: {}", e))?;
+
+    for &backup_name in backups {
+        if !uploaded_backups.iter().any(|name| name == backup_name) {
+            return Err!("{:?} backup is missing after the upload", backup_name);
+        }
+    }
+
+    info!("Promoting {:?} staging backup group on {}...", staging_group_name, cloud_storage.name());
+    cloud_storage.promote_staging_group(staging_group_name, group_name).map_err(|e| format!(
+        "Failed to promote the staging group: {}", e))?;
+
+    Ok(())
+}
+
+// Deletes all cloud backup groups except the kept ones
+fn prune_backup_groups(
+    cloud_storage: &mut Storage, cloud_groups: &BTreeMap<&str, BTreeSet<&str>>,
+    kept_groups: &BTreeMap<&str, BTreeSet<&str>>, mut ok: bool, backup_config: &Backup,
+    confirm_large_prune: bool, dry_run: bool,
+) -> bool {
+    let outdated_groups = cloud_groups.keys().filter(|&group_name| {
+        !kept_groups.contains_key(group_name)
+    }).count();
+
+    // Pinned groups are never deleted, so they don't count as retained by the policy either
+    let retained_groups = kept_groups.keys().filter(|&group_name| {
+        !is_pinned_group(group_name, &backup_config.pinned_groups)
+    }).count();
+
+    // Deleting more groups than the retention policy keeps usually means that the policy has just been
+    // tightened by mistake, so mass deletion requires an explicit confirmation
+    if outdated_groups > retained_groups {
+        if confirm_large_prune {
+            warning!("Attention! Pruning {} of {} backup groups from {} as confirmed.",
+                     outdated_groups, cloud_groups.len(), cloud_storage.name());
+        } else {
+            error!(concat!(
+                "Attention! {} of {} backup groups are going to be deleted from {} which is more than the ",
+                "retention policy keeps ({}). Refusing to prune them without --confirm-large-prune."),
+                outdated_groups, cloud_groups.len(), cloud_storage.name(), retained_groups);
+            ok = false;
+        }
+    }
+
+    if ok && backup_config.verify_before_prune && outdated_groups != 0 {
+        info!("Verifying backups on {} before pruning...", cloud_storage.name());
+
+        if let Err(err) = verify_kept_backup_groups(cloud_storage, kept_groups) {
+            error!("Refusing to prune outdated backup groups on {}: {}.", cloud_storage.name(), err);
+            ok = false;
+        }
+    }
+
     for &group_name in cloud_groups.keys() {
-        if target_groups.contains_key(group_name) {
+        if kept_groups.contains_key(group_name) {
             continue
         }
 
         if !ok {
-            warn!("Skipping deletion of {:?} backup group from {} because of the errors above.",
+            warning!("Skipping deletion of {:?} backup group from {} because of the errors above.",
                   group_name, cloud_storage.name());
             continue;
         }
 
+        if dry_run {
+            info!("Would delete {:?} backup group from {}.", group_name, cloud_storage.name());
+            continue;
+        }
+
         info!("Deleting {:?} backup group from {}...", group_name, cloud_storage.name());
         if let Err(err) = cloud_storage.delete_backup_group(group_name) {
             error!("Failed to delete {:?} backup backup group from {}: {}.",
@@ -92,39 +344,254 @@ fn check_backup_groups(local_groups: &[BackupGroup], cloud_groups: &[BackupGroup
     Ok(())
 }
 
+// Ensures that all backups we're going to keep are actually stored in the cloud and are healthy: each
+// of them is downloaded and its checksum is compared with the server-side one (if the provider exposes
+// it). Please note that it downloads all the kept backups.
+fn verify_kept_backup_groups(
+    cloud_storage: &Storage, kept_groups: &BTreeMap<&str, BTreeSet<&str>>,
+) -> EmptyResult {
+    let (cloud_groups, ok) = cloud_storage.get_backup_groups(false)?;
+    if !ok {
+        return Err!("the backups are in inconsistent state");
+    }
+
+    for (&group_name, kept_backups) in kept_groups.iter() {
+        if kept_backups.is_empty() {
+            continue;
+        }
+
+        let cloud_group = cloud_groups.iter().find(|group| group.name == group_name).ok_or_else(|| format!(
+            "{:?} backup group is missing", group_name))?;
+
+        for &backup_name in kept_backups {
+            let backup = cloud_group.backups.iter().find(|backup| backup.name == backup_name).ok_or_else(||
+                format!("{:?} backup is missing in {:?} group", backup_name, group_name))?;
+
+            debug!("Verifying {:?}...", backup.path);
+            cloud_storage.verify_backup(&backup.path).map_err(|e| format!(
+                "{:?} backup is corrupted: {}", backup.path, e))?;
+        }
+    }
+
+    Ok(())
+}
+
 fn get_target_backup_groups<'a>(
-    local_groups: &'a [BackupGroup], cloud_groups: &'a [BackupGroup], max_groups: usize,
+    storage: &Storage, local_groups: &'a [BackupGroup], cloud_groups: &'a [BackupGroup],
+    backup_config: &Backup,
 ) -> BTreeMap<&'a str, BTreeSet<&'a str>> {
-    let mut target_groups = get_group_to_backups_mapping(local_groups);
+    let pinned_groups = &backup_config.pinned_groups;
+    let excluded_backups = &backup_config.exclude_backups;
+
+    let mut target_groups: BTreeMap<&str, BTreeSet<&str>> = local_groups.iter().map(|group| {
+        let backups = group.backups.iter().map(|backup| backup.name.as_str()).filter(|&backup_name| {
+            if is_excluded_backup(backup_name, excluded_backups) {
+                debug!("Excluding {:?} backup from sync.", backup_name);
+                return false;
+            }
+            true
+        }).collect();
+        (group.name.as_str(), backups)
+    }).collect();
 
     for group in cloud_groups {
         target_groups.entry(&group.name).or_insert_with(BTreeSet::new).extend(
             group.backups.iter().map(|backup| backup.name.as_str()));
     }
 
-    if target_groups.len() > max_groups {
-        let mut groups_num = 0;
-        let mut first_group_name = None;
+    // Pinned groups don't consume any quota and survive any retention. Tagged groups are retained only
+    // by their own policies.
+    let get_tag = |group_name: &str| -> Option<usize> {
+        backup_config.tag_retention.iter().position(|policy| {
+            policy.groups.iter().any(|pattern| pattern.is_match(group_name))
+        })
+    };
 
-        for (group_name, backups) in target_groups.iter().rev() {
-            if backups.is_empty() {
-                continue
+    let is_managed_by = |group_name: &str, tag: Option<usize>| {
+        !is_pinned_group(group_name, pinned_groups) && get_tag(group_name) == tag
+    };
+
+    let first_group_name = get_first_group(
+        storage, &target_groups, backup_config.max_backup_groups, backup_config.max_backup_age,
+        |group_name| is_managed_by(group_name, None));
+
+    let gfs_groups = backup_config.retention.as_ref().map(|policy| {
+        get_gfs_groups(storage, &target_groups, policy, |group_name| is_managed_by(group_name, None))
+    });
+
+    let tag_first_group_names: Vec<Option<&str>> = backup_config.tag_retention.iter().enumerate()
+        .map(|(index, policy)| {
+            get_first_group(
+                storage, &target_groups, policy.max_backup_groups, policy.max_backup_age,
+                |group_name| is_managed_by(group_name, Some(index)))
+        }).collect();
+
+    target_groups.into_iter().filter(|&(group_name, _)| {
+        if is_pinned_group(group_name, pinned_groups) {
+            return true;
+        }
+
+        let first_group_name = match (get_tag(group_name), gfs_groups.as_ref()) {
+            (Some(index), _) => tag_first_group_names[index],
+            (None, Some(gfs_groups)) => return gfs_groups.contains(group_name),
+            (None, None) => first_group_name,
+        };
+
+        first_group_name.map_or(true, |first_group_name| group_name >= first_group_name)
+    }).collect()
+}
+
+// Returns the oldest backup group that is retained by the specified policy among the groups it manages.
+// All groups are retained if the policy has no limits.
+fn get_first_group<'a, F: Fn(&str) -> bool>(
+    storage: &Storage, groups: &BTreeMap<&'a str, BTreeSet<&'a str>>,
+    max_groups: Option<usize>, max_age: Option<Duration>, is_managed: F,
+) -> Option<&'a str> {
+    match (max_groups, max_age) {
+        (Some(max_groups), _) => get_first_group_by_count(groups, max_groups, is_managed),
+        (None, Some(max_age)) => get_first_group_by_age(storage, groups, max_age, is_managed),
+        (None, None) => None,
+    }
+}
+
+// Returns the oldest backup group that fits into the maximum groups number
+fn get_first_group_by_count<'a, F: Fn(&str) -> bool>(
+    groups: &BTreeMap<&'a str, BTreeSet<&'a str>>, max_groups: usize, is_managed: F,
+) -> Option<&'a str> {
+    let mut groups_num = 0;
+
+    for (&group_name, backups) in groups.iter().rev() {
+        if backups.is_empty() || !is_managed(group_name) {
+            continue
+        }
+
+        groups_num += 1;
+
+        if groups_num >= max_groups {
+            return Some(group_name);
+        }
+    }
+
+    None
+}
+
+// Returns the oldest backup group which latest backup isn't older than the maximum age. The latest
+// backup group is always kept regardless of its age.
+fn get_first_group_by_age<'a, F: Fn(&str) -> bool>(
+    storage: &Storage, groups: &BTreeMap<&'a str, BTreeSet<&'a str>>, max_age: Duration, is_managed: F,
+) -> Option<&'a str> {
+    let mut first_group_name = None;
+
+    for (&group_name, backups) in groups.iter().rev() {
+        if backups.is_empty() || !is_managed(group_name) {
+            continue
+        }
+
+        if first_group_name.is_some() {
+            let last_backup_name = backups.iter().next_back().unwrap();
+
+            match storage.get_backup_age(last_backup_name) {
+                // Backups from the future are considered as new ones
+                Ok(backup_age) => if backup_age.unwrap_or_default() > max_age {
+                    break;
+                },
+                Err(err) => {
+                    error!("Unable to determine age of {:?} backup group: {}. Keeping it.",
+                           group_name, err);
+                },
             }
+        }
+
+        first_group_name.replace(group_name);
+    }
+
+    first_group_name
+}
+
+// Returns the backup groups retained by grandfather-father-son policy among the groups it manages: the
+// latest group of each of the last N days, weeks and months. A group may be retained by several periods
+// at once. Empty groups are retained only if there are no newer non-empty groups.
+fn get_gfs_groups<'a, F: Fn(&str) -> bool>(
+    storage: &Storage, groups: &BTreeMap<&'a str, BTreeSet<&'a str>>, policy: &RetentionPolicy,
+    is_managed: F,
+) -> BTreeSet<&'a str> {
+    struct Period {
+        limit: usize,
+        get_key: fn(NaiveDate) -> (i32, u32),
+        last_key: Option<(i32, u32)>,
+        retained: usize,
+    }
 
-            groups_num += 1;
+    let new_period = |limit, get_key: fn(NaiveDate) -> (i32, u32)| {
+        Period {limit, get_key, last_key: None, retained: 0}
+    };
+
+    let mut periods = [
+        new_period(policy.daily, |date| (date.year(), date.ordinal())),
+        new_period(policy.weekly, |date| (date.iso_week().year(), date.iso_week().week())),
+        new_period(policy.monthly, |date| (date.year(), date.month())),
+    ];
+
+    let mut gfs_groups = BTreeSet::new();
+    let mut got_backups = false;
 
-            if groups_num >= max_groups {
-                first_group_name.replace(group_name.to_owned());
-                break
+    for (&group_name, backups) in groups.iter().rev() {
+        if !is_managed(group_name) {
+            continue
+        }
+
+        if backups.is_empty() {
+            if !got_backups {
+                gfs_groups.insert(group_name);
             }
+            continue
         }
+        got_backups = true;
+
+        let date = match storage.get_backup_group_date(group_name) {
+            Ok(date) => date,
+            Err(err) => {
+                error!("Unable to determine date of {:?} backup group: {}. Keeping it.", group_name, err);
+                gfs_groups.insert(group_name);
+                continue
+            },
+        };
+
+        // Groups are iterated from the newest to the oldest, so the first group of each period is the
+        // latest one in it
+        for period in &mut periods {
+            let key = (period.get_key)(date);
+
+            if period.last_key != Some(key) && period.retained < period.limit {
+                period.retained += 1;
+                gfs_groups.insert(group_name);
+            }
 
-        if let Some(first_group_name) = first_group_name {
-            target_groups = target_groups.split_off(first_group_name)
+            period.last_key.replace(key);
         }
     }
 
-    target_groups
+    gfs_groups
+}
+
+fn is_pinned_group(group_name: &str, pinned_groups: &[Regex]) -> bool {
+    pinned_groups.iter().any(|pattern| pattern.is_match(group_name))
+}
+
+fn is_excluded_backup(backup_name: &str, excluded_backups: &[Regex]) -> bool {
+    excluded_backups.iter().any(|pattern| pattern.is_match(backup_name))
+}
+
+// Returns the target groups limited to the backups that are already stored in the cloud
+fn get_kept_backup_groups<'a>(
+    cloud_groups: &BTreeMap<&'a str, BTreeSet<&'a str>>, target_groups: &BTreeMap<&'a str, BTreeSet<&'a str>>,
+) -> BTreeMap<&'a str, BTreeSet<&'a str>> {
+    target_groups.iter().map(|(&group_name, target_backups)| {
+        let kept_backups = cloud_groups.get(group_name).map(|cloud_backups| {
+            cloud_backups.intersection(target_backups).cloned().collect()
+        }).unwrap_or_default();
+        (group_name, kept_backups)
+    }).collect()
 }
 
 fn get_group_to_backups_mapping(groups: &[BackupGroup]) -> BTreeMap<&str, BTreeSet<&str>> {
@@ -132,4 +599,466 @@ fn get_group_to_backups_mapping(groups: &[BackupGroup]) -> BTreeMap<&str, BTreeS
         let backups = group.backups.iter().map(|backup| backup.name.as_str()).collect();
         (group.name.as_str(), backups)
     }).collect()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::clock::MockClock;
+    use crate::provider::{ProviderType, WriteProvider};
+    use crate::providers::filesystem::Filesystem;
+    use crate::providers::mock::MockProvider;
+    use crate::tests::{TempDir, get_backup_metadata};
+
+    use super::*;
+
+    #[test]
+    fn dry_run() {
+        let local_storage = Storage::new_read_only(get_local_provider(&["2020.01.01", "2020.01.02"]), "/src");
+        let cloud_provider = get_cloud_provider(&["2019.12.01", "2019.12.02", "2019.12.03"]);
+        let mut cloud_storage = Storage::new(cloud_provider.clone(), "/dst");
+
+        let backup_config: Backup = serde_yaml::from_str(concat!(
+            "{name: test, src: /src, dst: /dst, provider: {name: filesystem}, ",
+            "max_backup_groups: 3, cleanup_temp_files: true}",
+        )).unwrap();
+
+        let (local_groups, local_ok) = local_storage.get_backup_groups(false).unwrap();
+        let (cloud_groups, cloud_ok) = cloud_storage.get_backup_groups(false).unwrap();
+        assert!(local_ok && cloud_ok);
+        assert_eq!(local_groups.len(), 2);
+        assert_eq!(cloud_groups.len(), 3);
+
+        assert!(sync_backups(
+            &local_storage, &local_groups, &mut cloud_storage, &cloud_groups, true, &backup_config,
+            false, false, true));
+
+        assert_eq!(cloud_provider.modifications(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn prune_order() {
+        let local_path = get_local_backups("prune_order", &["2020.01.01", "2020.01.02"]);
+
+        for &prune_order in &["after", "before"] {
+            let local_storage = Storage::new_read_only(Filesystem::new(false), local_path.0.to_str().unwrap());
+            let cloud_provider = get_cloud_provider(&["2019.12.01", "2019.12.02", "2019.12.03"]);
+            let mut cloud_storage = Storage::new(cloud_provider.clone(), "/dst");
+
+            let backup_config: Backup = serde_yaml::from_str(&format!(concat!(
+                "{{name: test, src: /src, dst: /dst, provider: {{name: filesystem}}, ",
+                "max_backup_groups: 3, encryption_passphrase: secret, prune_order: {}}}",
+            ), prune_order)).unwrap();
+
+            let (local_groups, _) = local_storage.get_backup_groups(false).unwrap();
+            let (cloud_groups, _) = cloud_storage.get_backup_groups(false).unwrap();
+
+            assert!(sync_backups(
+                &local_storage, &local_groups, &mut cloud_storage, &cloud_groups, true, &backup_config,
+                false, false, false));
+
+            let uploads = vec![
+                "create_directory /dst/2020.01.01",
+                "upload_file /dst/2020.01.01/2020.01.01-00:00:00.tar.gpg",
+                "create_directory /dst/2020.01.02",
+                "upload_file /dst/2020.01.02/2020.01.02-00:00:00.tar.gpg",
+            ];
+
+            // Outdated groups are deleted from the oldest to the newest
+            let deletions = vec!["delete /dst/2019.12.01", "delete /dst/2019.12.02"];
+
+            let expected: Vec<&str> = if prune_order == "after" {
+                uploads.into_iter().chain(deletions).collect()
+            } else {
+                deletions.into_iter().chain(uploads).collect()
+            };
+            assert_eq!(cloud_provider.modifications(), expected);
+        }
+    }
+
+    #[test]
+    fn staged_groups() {
+        let local_path = get_local_backups("staged_groups", &["2020.01.01"]);
+
+        for &supports_rename in &[true, false] {
+            let local_storage = Storage::new_read_only(Filesystem::new(false), local_path.0.to_str().unwrap());
+            let cloud_provider = get_cloud_provider(&["2019.12.01"]);
+            if supports_rename {
+                cloud_provider.enable_rename();
+            }
+            let mut cloud_storage = Storage::new(cloud_provider.clone(), "/dst");
+
+            let backup_config: Backup = serde_yaml::from_str(concat!(
+                "{name: test, src: /src, dst: /dst, provider: {name: filesystem}, ",
+                "max_backup_groups: 2, encryption_passphrase: secret, staged_groups: true}",
+            )).unwrap();
+
+            let (local_groups, _) = local_storage.get_backup_groups(false).unwrap();
+            let (cloud_groups, _) = cloud_storage.get_backup_groups(false).unwrap();
+
+            assert!(sync_backups(
+                &local_storage, &local_groups, &mut cloud_storage, &cloud_groups, true, &backup_config,
+                false, false, false));
+
+            // The new group becomes visible only when it's completely uploaded
+            let staging_path = format!("/dst/{}", cloud_storage.get_staging_group_name("2020.01.01"));
+            let expected = if supports_rename {
+                vec![
+                    format!("create_directory {}", staging_path),
+                    format!("upload_file {}/2020.01.01-00:00:00.tar.gpg", staging_path),
+                    format!("rename {} /dst/2020.01.01", staging_path),
+                ]
+            } else {
+                vec![
+                    "create_directory /dst/2020.01.01".to_owned(),
+                    "upload_file /dst/2020.01.01/2020.01.01-00:00:00.tar.gpg".to_owned(),
+                ]
+            };
+            assert_eq!(cloud_provider.modifications(), expected);
+
+            let (cloud_groups, ok) = cloud_storage.get_backup_groups(false).unwrap();
+            assert!(ok);
+            assert_eq!(cloud_groups.iter().map(|group| group.name.as_str()).collect::<Vec<_>>(), vec![
+                "2019.12.01", "2020.01.01"]);
+        }
+    }
+
+    #[test]
+    fn fail_fast() {
+        let local_path = get_local_backups("fail_fast", &["2020.01.01", "2020.01.02"]);
+
+        for &fail_fast in &[false, true] {
+            let local_storage = Storage::new_read_only(Filesystem::new(false), local_path.0.to_str().unwrap());
+            let cloud_provider = get_cloud_provider(&["2019.12.01", "2019.12.02"]);
+            let mut cloud_storage = Storage::new(cloud_provider.clone(), "/dst");
+
+            let backup_config: Backup = serde_yaml::from_str(concat!(
+                "{name: test, src: /src, dst: /dst, provider: {name: filesystem}, ",
+                "max_backup_groups: 2, encryption_passphrase: secret, gpg_cipher: ROT13}", // All uploads fail
+            )).unwrap();
+
+            let (local_groups, _) = local_storage.get_backup_groups(false).unwrap();
+            let (cloud_groups, _) = cloud_storage.get_backup_groups(false).unwrap();
+
+            assert!(!sync_backups(
+                &local_storage, &local_groups, &mut cloud_storage, &cloud_groups, true, &backup_config,
+                fail_fast, false, false));
+
+            // Nothing is pruned on errors, and with fail fast the sync stops on the first failed upload
+            let mut expected = vec!["create_directory /dst/2020.01.01"];
+            if !fail_fast {
+                expected.push("create_directory /dst/2020.01.02");
+            }
+            assert_eq!(cloud_provider.modifications(), expected);
+        }
+    }
+
+    #[test]
+    fn verify_before_prune() {
+        let (cloud_provider, result) = prune_verified_backup_groups(|provider| {
+            let mut hasher = provider.hasher();
+            hasher.write_all(b"backup").unwrap();
+            provider.set_checksum(&hasher.finish());
+        });
+        assert!(result);
+        assert_eq!(cloud_provider.modifications(), vec!["delete /dst/2019.12.01".to_owned()]);
+    }
+
+    #[test]
+    fn verify_before_prune_corrupted() {
+        let (cloud_provider, result) = prune_verified_backup_groups(|provider| {
+            provider.set_checksum("corrupted");
+        });
+        assert!(!result);
+        assert_eq!(cloud_provider.modifications(), Vec::<String>::new());
+        assert!(cloud_provider.exists("/dst/2019.12.01"));
+    }
+
+    #[test]
+    fn pinned_groups() {
+        let (cloud_storage, cloud_groups) = get_cloud_backup_groups(&[
+            "2020.01.01", "2020.01.02", "2020.01.03", "2020.01.04", "2020.01.05"]);
+
+        let backup_config: Backup = serde_yaml::from_str(concat!(
+            "{name: test, src: /src, dst: /dst, provider: {name: filesystem}, ",
+            "max_backup_groups: 2, pinned_groups: [2020.01.01, 2020.01.03]}",
+        )).unwrap();
+
+        // Pinned groups don't consume the quota
+        let target_groups = get_target_backup_groups(&cloud_storage, &[], &cloud_groups, &backup_config);
+        assert_eq!(target_groups.keys().cloned().collect::<Vec<_>>(), vec![
+            "2020.01.01", "2020.01.03", "2020.01.04", "2020.01.05"]);
+    }
+
+    #[test]
+    fn excluded_backups() {
+        let local_provider = get_local_provider(&["2020.01.01", "2020.01.02", "2020.01.03"]);
+        let backup_path = "/src/2020.01.01/2020.01.01-12:00:00";
+        local_provider.add_directory(backup_path);
+        local_provider.add_file(&format!("{}/metadata.bz2", backup_path), &get_backup_metadata());
+        local_provider.add_file(&format!("{}/data.tar.gz", backup_path), b"data");
+
+        let local_storage = Storage::new_read_only(local_provider, "/src");
+        let (local_groups, ok) = local_storage.get_backup_groups(false).unwrap();
+        assert!(ok);
+
+        let (cloud_storage, cloud_groups) = get_cloud_backup_groups(&["2019.12.31"]);
+
+        let backup_config: Backup = serde_yaml::from_str(concat!(
+            "{name: test, src: /src, dst: /dst, provider: {name: filesystem}, ",
+            "max_backup_groups: 2, exclude_backups: ['*-12:00:00', '2020.01.02-*']}",
+        )).unwrap();
+
+        let target_groups = get_target_backup_groups(&cloud_storage, &local_groups, &cloud_groups, &backup_config);
+
+        // The group with only excluded backups isn't counted by the retention policy
+        assert_eq!(target_groups.into_iter().collect::<Vec<_>>(), vec![
+            ("2020.01.01", vec!["2020.01.01-00:00:00"].into_iter().collect()),
+            ("2020.01.02", BTreeSet::new()),
+            ("2020.01.03", vec!["2020.01.03-00:00:00"].into_iter().collect()),
+        ]);
+    }
+
+    #[test]
+    fn tag_retention() {
+        let (cloud_storage, cloud_groups) = get_cloud_backup_groups(&[
+            "2020.01.01", "2020.01.02", "2020.01.03", "2020.01.04", "2020.01.05", "2020.01.06",
+            "2020.02.01", "2020.03.01"]);
+
+        // Groups are tagged by the first matching policy
+        let backup_config: Backup = serde_yaml::from_str(concat!(
+            "{name: test, src: /src, dst: /dst, provider: {name: filesystem}, max_backup_groups: 2, ",
+            "tag_retention: [{tag: release, groups: [2020.01.01, 2020.01.03]}, ",
+            "{tag: monthly, groups: ['*.*.01'], max_backup_groups: 2}]}",
+        )).unwrap();
+
+        let target_groups = get_target_backup_groups(&cloud_storage, &[], &cloud_groups, &backup_config);
+        assert_eq!(target_groups.keys().cloned().collect::<Vec<_>>(), vec![
+            "2020.01.01", "2020.01.03", // Release
+            "2020.01.05", "2020.01.06", // Untagged
+            "2020.02.01", "2020.03.01", // Monthly
+        ]);
+    }
+
+    #[test]
+    fn age_retention() {
+        let (cloud_storage, cloud_groups) = get_cloud_backup_groups(&[
+            "2020.01.01", "2020.01.02", "2020.01.03", "2020.01.04", "2020.01.05"]);
+
+        let now = cloud_storage.get_backup_time("2020.01.05-12:00:00").unwrap();
+        let cloud_storage = cloud_storage.with_clock(MockClock(now));
+
+        let backup_config: Backup = serde_yaml::from_str(
+            "{name: test, src: /src, dst: /dst, provider: {name: filesystem}, max_backup_age: 2d}",
+        ).unwrap();
+
+        let target_groups = get_target_backup_groups(&cloud_storage, &[], &cloud_groups, &backup_config);
+        assert_eq!(target_groups.keys().cloned().collect::<Vec<_>>(), vec![
+            "2020.01.04", "2020.01.05"]);
+    }
+
+    #[test]
+    fn age_retention_boundary() {
+        let (cloud_storage, cloud_groups) = get_cloud_backup_groups(&["2020.01.01", "2020.01.02"]);
+        let backup_time = cloud_storage.get_backup_time("2020.01.01-00:00:00").unwrap();
+        let max_backup_age = Duration::from_secs(24 * 60 * 60);
+
+        let backup_config: Backup = serde_yaml::from_str(
+            "{name: test, src: /src, dst: /dst, provider: {name: filesystem}, max_backup_age: 1d}",
+        ).unwrap();
+
+        // A group exactly at the limit is kept
+        let cloud_storage = cloud_storage.with_clock(MockClock(backup_time + max_backup_age));
+        let target_groups = get_target_backup_groups(&cloud_storage, &[], &cloud_groups, &backup_config);
+        assert_eq!(target_groups.keys().cloned().collect::<Vec<_>>(), vec!["2020.01.01", "2020.01.02"]);
+
+        // And a group just past it is pruned
+        let cloud_storage = cloud_storage.with_clock(MockClock(backup_time + max_backup_age + Duration::from_secs(1)));
+        let target_groups = get_target_backup_groups(&cloud_storage, &[], &cloud_groups, &backup_config);
+        assert_eq!(target_groups.keys().cloned().collect::<Vec<_>>(), vec!["2020.01.02"]);
+    }
+
+    #[test]
+    fn gfs_retention() {
+        let group_names: Vec<String> = NaiveDate::from_ymd(2020, 1, 1).iter_days()
+            .take_while(|date| date.year() == 2020)
+            .map(|date| date.format("%Y.%m.%d").to_string())
+            .collect();
+        let group_names: Vec<&str> = group_names.iter().map(String::as_str).collect();
+        assert_eq!(group_names.len(), 366);
+
+        let (cloud_storage, cloud_groups) = get_cloud_backup_groups(&group_names);
+
+        let backup_config: Backup = serde_yaml::from_str(concat!(
+            "{name: test, src: /src, dst: /dst, provider: {name: filesystem}, ",
+            "retention: {daily: 7, weekly: 4, monthly: 12}}",
+        )).unwrap();
+
+        let target_groups = get_target_backup_groups(&cloud_storage, &[], &cloud_groups, &backup_config);
+        assert_eq!(target_groups.keys().cloned().collect::<Vec<_>>(), vec![
+            // Monthly
+            "2020.01.31", "2020.02.29", "2020.03.31", "2020.04.30", "2020.05.31", "2020.06.30",
+            "2020.07.31", "2020.08.31", "2020.09.30", "2020.10.31", "2020.11.30",
+            // Weekly (ISO weeks end on Sunday)
+            "2020.12.13", "2020.12.20",
+            // Daily + the latest week and month
+            "2020.12.25", "2020.12.26", "2020.12.27", "2020.12.28", "2020.12.29", "2020.12.30",
+            "2020.12.31",
+        ]);
+    }
+
+    #[test]
+    fn large_prune() {
+        let group_names = ["2020.01.01", "2020.01.02", "2020.01.03", "2020.01.04", "2020.01.05"];
+
+        for &confirm_large_prune in &[false, true] {
+            let (mut cloud_storage, cloud_groups) = get_cloud_backup_groups(&group_names);
+            let backup_config: Backup = serde_yaml::from_str(
+                "{name: test, src: /src, dst: /dst, provider: {name: filesystem}, max_backup_groups: 2}",
+            ).unwrap();
+
+            let target_groups = get_target_backup_groups(&cloud_storage, &[], &cloud_groups, &backup_config);
+            let cloud_groups = get_group_to_backups_mapping(&cloud_groups);
+
+            let result = prune_backup_groups(
+                &mut cloud_storage, &cloud_groups, &target_groups, true, &backup_config,
+                confirm_large_prune, false);
+            assert_eq!(result, confirm_large_prune);
+
+            let (cloud_groups, _) = cloud_storage.get_backup_groups(false).unwrap();
+            assert_eq!(cloud_groups.len(), if confirm_large_prune {2} else {5});
+        }
+
+        // Pruning up to twice the limit doesn't require a confirmation
+        let (mut cloud_storage, cloud_groups) = get_cloud_backup_groups(&group_names[1..]);
+        let backup_config: Backup = serde_yaml::from_str(
+            "{name: test, src: /src, dst: /dst, provider: {name: filesystem}, max_backup_groups: 2}",
+        ).unwrap();
+
+        let target_groups = get_target_backup_groups(&cloud_storage, &[], &cloud_groups, &backup_config);
+        let cloud_groups = get_group_to_backups_mapping(&cloud_groups);
+
+        assert!(prune_backup_groups(
+            &mut cloud_storage, &cloud_groups, &target_groups, true, &backup_config, false, false));
+    }
+
+    #[test]
+    fn large_prune_with_pinned_groups() {
+        let (mut cloud_storage, cloud_groups) = get_cloud_backup_groups(&[
+            "2019.12.01", "2019.12.02", "2019.12.03", "2019.12.04", "2019.12.05",
+            "2020.01.01", "2020.01.02", "2020.01.03"]);
+
+        // Pinned groups are never deleted, so only one group is actually pruned here
+        let backup_config: Backup = serde_yaml::from_str(concat!(
+            "{name: test, src: /src, dst: /dst, provider: {name: filesystem}, ",
+            "max_backup_groups: 2, pinned_groups: [2019.*]}",
+        )).unwrap();
+
+        let target_groups = get_target_backup_groups(&cloud_storage, &[], &cloud_groups, &backup_config);
+        let cloud_groups = get_group_to_backups_mapping(&cloud_groups);
+
+        assert!(prune_backup_groups(
+            &mut cloud_storage, &cloud_groups, &target_groups, true, &backup_config, false, false));
+
+        let (cloud_groups, _) = cloud_storage.get_backup_groups(false).unwrap();
+        assert_eq!(cloud_groups.iter().map(|group| group.name.as_str()).collect::<Vec<_>>(), vec![
+            "2019.12.01", "2019.12.02", "2019.12.03", "2019.12.04", "2019.12.05", "2020.01.02", "2020.01.03"]);
+    }
+
+    #[test]
+    fn large_prune_by_age() {
+        let (mut cloud_storage, cloud_groups) = get_cloud_backup_groups(&[
+            "2020.01.01", "2020.01.02", "2020.01.03"]);
+
+        // All the groups are outdated, so only the latest one is kept
+        let backup_config: Backup = serde_yaml::from_str(
+            "{name: test, src: /src, dst: /dst, provider: {name: filesystem}, max_backup_age: 30d}",
+        ).unwrap();
+
+        let target_groups = get_target_backup_groups(&cloud_storage, &[], &cloud_groups, &backup_config);
+        let cloud_groups = get_group_to_backups_mapping(&cloud_groups);
+
+        assert!(!prune_backup_groups(
+            &mut cloud_storage, &cloud_groups, &target_groups, true, &backup_config, false, false));
+
+        let (cloud_groups, _) = cloud_storage.get_backup_groups(false).unwrap();
+        assert_eq!(cloud_groups.len(), 3);
+    }
+
+    fn prune_verified_backup_groups<F: Fn(&MockProvider)>(configure: F) -> (MockProvider, bool) {
+        let cloud_provider = get_cloud_provider(&["2019.12.01", "2019.12.02", "2019.12.03"]);
+        configure(&cloud_provider);
+
+        let mut cloud_storage = Storage::new(cloud_provider.clone(), "/dst");
+        let backup_config: Backup = serde_yaml::from_str(concat!(
+            "{name: test, src: /src, dst: /dst, provider: {name: filesystem}, ",
+            "max_backup_groups: 2, verify_before_prune: true}",
+        )).unwrap();
+
+        let (cloud_groups, ok) = cloud_storage.get_backup_groups(false).unwrap();
+        assert!(ok);
+
+        let cloud_groups = get_group_to_backups_mapping(&cloud_groups);
+        let kept_groups = cloud_groups.iter()
+            .filter(|(&group_name, _)| group_name != "2019.12.01")
+            .map(|(&group_name, backups)| (group_name, backups.clone()))
+            .collect();
+
+        let result = prune_backup_groups(
+            &mut cloud_storage, &cloud_groups, &kept_groups, true, &backup_config, false, false);
+
+        (cloud_provider, result)
+    }
+
+    fn get_cloud_backup_groups(group_names: &[&str]) -> (Storage, Vec<BackupGroup>) {
+        let cloud_storage = Storage::new(get_cloud_provider(group_names), "/dst");
+        let (cloud_groups, ok) = cloud_storage.get_backup_groups(false).unwrap();
+        assert!(ok);
+
+        (cloud_storage, cloud_groups)
+    }
+
+    // Creates a local backup directory with one backup in each of the specified groups
+    fn get_local_backups(name: &str, group_names: &[&str]) -> TempDir {
+        let local_path = TempDir::new(name);
+
+        for &group_name in group_names {
+            let backup_path = local_path.0.join(group_name).join(format!("{}-00:00:00", group_name));
+            fs::create_dir_all(&backup_path).unwrap();
+            fs::write(backup_path.join("metadata.bz2"), get_backup_metadata()).unwrap();
+            fs::write(backup_path.join("data.tar.gz"), "data").unwrap();
+        }
+
+        local_path
+    }
+
+    // Creates a local storage with one backup in each of the specified groups
+    fn get_local_provider(group_names: &[&str]) -> MockProvider {
+        let local_provider = MockProvider::new(ProviderType::Local);
+        local_provider.add_directory("/src");
+
+        for &group_name in group_names {
+            let backup_path = format!("/src/{}/{}-00:00:00", group_name, group_name);
+            local_provider.add_directory(&format!("/src/{}", group_name));
+            local_provider.add_directory(&backup_path);
+            local_provider.add_file(&format!("{}/metadata.bz2", backup_path), &get_backup_metadata());
+            local_provider.add_file(&format!("{}/data.tar.gz", backup_path), b"data");
+        }
+
+        local_provider
+    }
+
+    // Creates a cloud storage with one backup in each of the specified groups
+    fn get_cloud_provider(group_names: &[&str]) -> MockProvider {
+        let cloud_provider = MockProvider::new(ProviderType::Cloud);
+        cloud_provider.add_directory("/dst");
+
+        for &group_name in group_names {
+            cloud_provider.add_directory(&format!("/dst/{}", group_name));
+            cloud_provider.add_file(&format!("/dst/{}/{}-00:00:00.tar.gpg", group_name, group_name), b"backup");
+        }
+
+        cloud_provider
+    }
+}
@@ -34,6 +34,26 @@ pub fn join_thread_ignoring_result<T>(handle: thread::JoinHandle<T>) {
     }
 }
 
+pub fn format_size(size: u64) -> String {
+    let mut unit = "B";
+    let mut value = size as f64;
+
+    for &next_unit in &["KB", "MB", "GB", "TB"] {
+        if value < 1024.0 {
+            break;
+        }
+
+        value /= 1024.0;
+        unit = next_unit;
+    }
+
+    if unit == "B" {
+        format!("{} {}", size, unit)
+    } else {
+        format!("{:.1} {}", value, unit)
+    }
+}
+
 fn get_thread_name(thread: &thread::Thread) -> String {
     match thread.name() {
         Some(name) => name.to_owned(),
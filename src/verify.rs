@@ -0,0 +1,127 @@
+use rayon::prelude::*;
+
+use crate::core::{EmptyResult, GenericResult};
+use crate::storage::Storage;
+
+// Checks integrity of the backups stored on the destination without restoring them: each backup is
+// downloaded and its checksum is compared with the server-side one if the provider exposes it.
+//
+// Backups are hashed on the fly during the download, so memory usage doesn't depend on their size and
+// grows only with the concurrency.
+pub fn verify_backups(storage: &Storage, concurrency: usize) -> EmptyResult {
+    info!("Verifying backups on {}...", storage.name());
+
+    let (groups, ok) = storage.get_backup_groups(false).map_err(|e| format!(
+        "Failed to list backup groups on {}: {}", storage.name(), e))?;
+
+    let backups: Vec<&str> = groups.iter()
+        .flat_map(|group| group.backups.iter().map(|backup| backup.path.as_str()))
+        .collect();
+
+    let (checked, mut failed, mut unverifiable) = (backups.len(), 0, 0);
+
+    for result in verify_backups_concurrently(storage, &backups, concurrency)? {
+        match result {
+            Ok(true) => {},
+            Ok(false) => unverifiable += 1,
+            Err(_) => failed += 1,
+        }
+    }
+
+    if unverifiable == 0 {
+        info!("{} backups have been checked on {}: {} failed.", checked, storage.name(), failed);
+    } else {
+        info!(concat!(
+            "{} backups have been checked on {}: {} failed, {} have been only downloaded since the ",
+            "provider has no checksums for them."), checked, storage.name(), failed, unverifiable);
+    }
+
+    if failed != 0 {
+        return Err!("{} backups have failed the verification", failed);
+    } else if !ok {
+        return Err!("the backup destination has errors");
+    }
+
+    Ok(())
+}
+
+// Verifies the backups using up to the specified number of concurrent downloads. The results are returned
+// in the original order and each of them is also logged as soon as the backup is verified.
+fn verify_backups_concurrently(
+    storage: &Storage, backups: &[&str], concurrency: usize,
+) -> GenericResult<Vec<GenericResult<bool>>> {
+    let verify_backup = |&path: &&str| {
+        info!("Verifying {:?}...", path);
+
+        let result = storage.verify_backup(path);
+        match result {
+            Ok(true) => info!("{:?} is OK.", path),
+            Ok(false) => debug!("{} provides no checksum for {:?}: only its download has been checked.",
+                                storage.name(), path),
+            Err(ref err) => error!("{:?} verification has failed: {}.", path, err),
+        }
+
+        result
+    };
+
+    Ok(if concurrency > 1 {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(concurrency).build().map_err(|e| format!(
+            "Unable to create verification thread pool: {}", e))?;
+        pool.install(|| backups.par_iter().map(verify_backup).collect())
+    } else {
+        backups.iter().map(verify_backup).collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::time::Duration;
+
+    use crate::provider::{ProviderType, WriteProvider};
+    use crate::providers::mock::MockProvider;
+
+    use super::*;
+
+    #[test]
+    fn concurrency() {
+        let provider = MockProvider::new(ProviderType::Cloud);
+        provider.add_directory("/dst");
+        provider.set_read_delay(Duration::from_millis(50));
+
+        let mut hasher = provider.hasher();
+        hasher.write_all(b"backup").unwrap();
+        provider.set_checksum(&hasher.finish());
+
+        let mut backups = Vec::new();
+        for day in 1..=3 {
+            let group_path = format!("/dst/2020.01.0{}", day);
+            provider.add_directory(&group_path);
+
+            for hour in 0..4 {
+                let path = format!("{}/2020.01.0{}-0{}:00:00.tar.gpg", group_path, day, hour);
+                provider.add_file(&path, if day == 2 && hour == 1 {b"corrupted"} else {b"backup"});
+                backups.push(path);
+            }
+        }
+        let backups: Vec<&str> = backups.iter().map(String::as_str).collect();
+
+        let storage = Storage::new(provider.clone(), "/dst");
+        let results = verify_backups_concurrently(&storage, &backups, 3).unwrap();
+
+        assert_eq!(results.len(), backups.len());
+        for (&path, result) in backups.iter().zip(results) {
+            if path == "/dst/2020.01.02/2020.01.02-01:00:00.tar.gpg" {
+                assert!(result.is_err());
+            } else {
+                assert!(result.unwrap());
+            }
+        }
+
+        assert!(provider.max_concurrent_reads() > 1);
+        assert!(provider.max_concurrent_reads() <= 3);
+
+        assert_eq!(verify_backups(&storage, 3).unwrap_err().to_string(),
+                   "1 backups have failed the verification");
+    }
+}